@@ -0,0 +1,211 @@
+//! Benchmarks for the hot loop of the distribution phase.
+//!
+//! Run with `cargo bench`. The ballots are synthetic, but shaped like a real Senate paper:
+//! a couple of dozen tickets of two candidates each, with ballots numbered 1 to 6 ATL or 1 to 12 BTL.
+#![allow(dead_code, unused_imports)]
+// there's a bunch of imports from the modules we need that won't be used.
+
+#[macro_use]
+extern crate serde_derive;
+
+#[path = "../src/booths.rs"]
+mod booths;
+#[path = "../src/term.rs"]
+mod term;
+#[path = "../src/utils.rs"]
+mod utils;
+
+use booths::{
+    calculate_index, distribute_preference, handle_below, make_candidate_info, parse_u8_b10,
+    Parties,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use utils::{BallotNumber, ToTicket};
+
+/// The six fixed columns at the start of each ballot
+const ABOVE_START: usize = 6;
+const TICKETS: usize = 24;
+const CANDIDATES_PER_TICKET: usize = 2;
+/// How many ballots to cycle through, so that branch prediction doesn't get too comfortable
+const BALLOTS: usize = 64;
+
+/// The ticket label (A, B, ... AA, ...) of the `t`th ticket.
+fn ticket(t: usize) -> String {
+    BallotNumber::try_from(t).expect("few tickets").to_ticket()
+}
+
+/// The header of a preferences file: fixed columns, then tickets, then candidates.
+fn headers() -> Vec<String> {
+    let mut out: Vec<String> = [
+        "State",
+        "Division",
+        "Vote Collection Point Name",
+        "Vote Collection Point ID",
+        "Batch No",
+        "Paper No",
+    ]
+    .map(String::from)
+    .to_vec();
+    for t in 1..=TICKETS {
+        out.push(format!("{}:Party {}", ticket(t), t));
+    }
+    for t in 1..=TICKETS {
+        for c in 1..=CANDIDATES_PER_TICKET {
+            out.push(format!("{}:CANDIDATE {}", ticket(t), c));
+        }
+    }
+    out
+}
+
+/// The first `n` tickets, as groups of one ticket each (with its candidates).
+fn parties(n: usize) -> Parties {
+    let mut parties = Parties::new();
+    for t in 1..=n {
+        let ticket = ticket(t);
+        let mut group = vec![format!("{}:Party {}", ticket, t)];
+        for c in 1..=CANDIDATES_PER_TICKET {
+            group.push(format!("{}:CANDIDATE {}", ticket, c));
+        }
+        parties.insert(format!("P{t:02}"), group);
+    }
+    parties
+}
+
+/// A deterministic shuffle of `0..len`, so that runs are comparable.
+fn shuffled(len: usize, seed: usize) -> Vec<usize> {
+    let mut out: Vec<usize> = (0..len).collect();
+    let mut state = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+    for i in (1..len).rev() {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        out.swap(i, (state >> 33) % (i + 1));
+    }
+    out
+}
+
+/// Ballots numbering `count` squares from `first` (an offset into the preference fields)
+/// among `squares` of them.
+fn ballots(first: usize, squares: usize, count: usize) -> Vec<csv::ByteRecord> {
+    let width = TICKETS * (1 + CANDIDATES_PER_TICKET);
+    (0..BALLOTS)
+        .map(|seed| {
+            let mut prefs = vec![String::new(); width];
+            for (n, square) in shuffled(squares, seed).into_iter().take(count).enumerate() {
+                prefs[first + square] = (n + 1).to_string();
+            }
+            let fixed = ["QLD", "Brisbane", "Town Hall", "1", "1", "1"];
+            csv::ByteRecord::from(
+                fixed
+                    .iter()
+                    .map(|s| s.as_bytes())
+                    .chain(prefs.iter().map(String::as_bytes))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect()
+}
+
+/// Above-the-line ballots, numbered 1 to 6.
+fn atl_ballots() -> Vec<csv::ByteRecord> {
+    ballots(0, TICKETS, 6)
+}
+
+/// Below-the-line ballots, numbered 1 to 12.
+fn btl_ballots() -> Vec<csv::ByteRecord> {
+    ballots(TICKETS, TICKETS * CANDIDATES_PER_TICKET, 12)
+}
+
+fn bench_calculate_index(c: &mut Criterion) {
+    c.bench_function("calculate_index", |b| {
+        b.iter(|| calculate_index(black_box(&[2, 0, 3, 1]), black_box(4)));
+    });
+}
+
+fn bench_parse_u8_b10(c: &mut Criterion) {
+    c.bench_function("parse_u8_b10", |b| {
+        b.iter(|| {
+            parse_u8_b10(black_box(b"7"))
+                + parse_u8_b10(black_box(b"42"))
+                + parse_u8_b10(black_box(b""))
+        });
+    });
+}
+
+fn bench_distribute_preference(c: &mut Criterion) {
+    let headers = headers();
+    let ballots = atl_ballots();
+    let mut group = c.benchmark_group("distribute_preference");
+    // two groups take the fast path
+    for n in [2, 4] {
+        let (_, below_start, groups_above, _) =
+            make_candidate_info(&parties(n), &headers, ABOVE_START)
+                .expect("synthetic candidate info");
+        let cands_count = headers.len() - ABOVE_START;
+        let mut bests = Vec::with_capacity(n);
+        let mut order = Vec::with_capacity(n);
+        group.bench_function(&format!("{n} groups"), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % ballots.len();
+                distribute_preference(
+                    black_box(&ballots[i]),
+                    &groups_above,
+                    ABOVE_START,
+                    below_start,
+                    cands_count,
+                    &mut bests,
+                    &mut order,
+                )
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_handle_below(c: &mut Criterion) {
+    let headers = headers();
+    let ballots = btl_ballots();
+    let mut group = c.benchmark_group("handle_below");
+    for n in [2, 4] {
+        let (_, below_start, _, groups_below) =
+            make_candidate_info(&parties(n), &headers, ABOVE_START)
+                .expect("synthetic candidate info");
+        // as in `BallotReader`
+        let mut below_groups = vec![usize::MAX; headers.len()];
+        for (g, v) in &groups_below {
+            for c in v {
+                below_groups[*c + ABOVE_START - 1] = *g;
+            }
+        }
+        let mut bests = Vec::with_capacity(n);
+        let mut order = Vec::with_capacity(n);
+        let mut btl_count = 0;
+        group.bench_function(&format!("{n} groups"), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % ballots.len();
+                handle_below(
+                    black_box(&ballots[i]),
+                    below_start,
+                    &below_groups,
+                    &mut bests,
+                    &mut order,
+                    groups_below.len(),
+                    &mut btl_count,
+                )
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_calculate_index,
+    bench_parse_u8_b10,
+    bench_distribute_preference,
+    bench_handle_below
+);
+criterion_main!(benches);
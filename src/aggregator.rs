@@ -6,81 +6,191 @@
 //! (4) Also split (3) according to (2) where necessary/available  
 //! (5) Aggregate (4) by district.  
 //! (6) Write to file(s)  
-use super::utils::PrefsMap;
-use color_eyre::eyre::{Context, ContextCompat, Result};
-use csv::{StringRecord, StringRecordsIntoIter};
+use super::booths::{group_orders, sorted_groups};
+use super::multiplier::{round_row, Rounding};
+use super::utils::{create_encoded, open_encoded, PrefsMap, PrefsRows};
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat, Result};
+use color_eyre::Section;
+use csv::StringRecord;
+use encoding_rs::Encoding;
 use indexmap::IndexMap;
-use serde_json::json;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
 use std::fs::{create_dir_all, File};
 use std::io::{self, Write};
 use std::path::Path;
-use tracing::info;
+use tracing::{debug, info, warn};
 
 // TODO: isn't this phase basically all doable in SQL, but if so, does that preclude a future WASMfication?
 
 /// Load up SA1 NPP data (step 1)
 ///
 /// Returns both the data keyed by the first column (SA1 ID), and the file headers
-fn load_sa1_prefs(sa1_prefs_path: &Path) -> Result<(PrefsMap, StringRecord)> {
-    let mut sa1_prefs: BTreeMap<String, Vec<f64>> = BTreeMap::new();
-
-    let mut sa1_prefs_rdr = csv::ReaderBuilder::new()
+fn load_sa1_prefs(
+    sa1_prefs_path: &Path,
+    encoding: &'static Encoding,
+) -> Result<(PrefsMap, StringRecord)> {
+    let sa1_prefs_rdr = csv::ReaderBuilder::new()
         .flexible(true)
         .has_headers(true)
-        .from_path(sa1_prefs_path)
-        .with_context(|| {
+        .from_reader(open_encoded(sa1_prefs_path, encoding).with_context(|| {
             format!(
                 "Could not find SA1s to preferences file, does this path exist?\n\t{}",
                 sa1_prefs_path.display()
             )
-        })?;
+        })?);
 
-    for record in sa1_prefs_rdr.records() {
-        let row = record?;
-        let id = row.get(0).context("empty row in SA1 prefs file")?;
-        let mut numbers = Vec::with_capacity(row.len() - 1);
-        for i in 1..row.len() {
-            let x: f64 = row
-                .get(i)
-                .and_then(|x| x.parse::<f64>().ok())
-                .unwrap_or(0.0_f64);
-            numbers.push(x);
-        }
-        sa1_prefs.insert(id.to_string(), numbers);
-    }
+    PrefsMap::from_csv_reader(sa1_prefs_rdr).context("Could not read the SA1s to preferences file")
+}
 
-    let sa1_headers = sa1_prefs_rdr.headers()?.clone();
+/// An SA1-to-districts correspondence, in file order.
+///
+/// For each SA1 ID, the districts it is (partly) in, each with the population of that part if known.
+pub type Sa1Districts = IndexMap<String, Vec<(String, Option<f64>)>>;
+
+/// Header names (compared case-insensitively) for the columns of an SA1s-to-districts file.
+/// (The README lists them too.)
+const SA1_ID_NAMES: [&str; 4] = ["SA1_id", "SA1", "SA1_7DIGITCODE", "SA1_CODE"];
+const DISTRICT_NAMES: [&str; 5] = ["Dist_Name", "District", "Dist", "Division", "Electorate"];
+const POP_NAMES: [&str; 2] = ["Pop", "Population"];
+
+/// Where the columns we need are in an SA1s-to-districts file.
+#[derive(Debug, PartialEq, Eq)]
+struct Sa1DistrictsColumns {
+    id: usize,
+    dist: usize,
+    pop: Option<usize>,
+}
 
-    Ok((sa1_prefs, sa1_headers))
+impl Sa1DistrictsColumns {
+    /// The traditional layout: SA1 ID, district, then population (if there's a third column).
+    const POSITIONAL: Self = Self {
+        id: 0,
+        dist: 1,
+        pop: Some(2),
+    };
+
+    /// Work out the columns from the first row of a file, and whether that row is a header.
+    ///
+    /// If the header names the SA1 ID and district columns, we go by name.
+    /// Otherwise we go by position; and if the first row looks like data
+    /// (an all-digits SA1 ID) then there's no header at all.
+    fn sniff(first: &StringRecord) -> (Self, bool) {
+        let find = |names: &[&str]| {
+            first
+                .iter()
+                .position(|h| names.iter().any(|n| h.trim().eq_ignore_ascii_case(n)))
+        };
+        match (find(&SA1_ID_NAMES), find(&DISTRICT_NAMES)) {
+            (Some(id), Some(dist)) => {
+                let pop = find(&POP_NAMES);
+                if pop.is_none() {
+                    debug!("No population column in the SA1s-to-districts file's header");
+                }
+                (Self { id, dist, pop }, true)
+            }
+            (None, None) => {
+                let is_data = first
+                    .get(0)
+                    .map(str::trim)
+                    .is_some_and(|x| !x.is_empty() && x.bytes().all(|b| b.is_ascii_digit()));
+                (Self::POSITIONAL, !is_data)
+            }
+            _ => {
+                warn!(
+                    "The SA1s-to-districts file's header ({:?}) only names some of the SA1 ID and district columns, so reading by position",
+                    first
+                );
+                (Self::POSITIONAL, true)
+            }
+        }
+    }
 }
 
-type Sa1DistsRdr = StringRecordsIntoIter<File>;
+/// 2a. Load up SA1 to district data
+fn get_sa1_districts(sa1_districts_path: &Path) -> Result<Sa1Districts> {
+    let file = File::open(sa1_districts_path).with_context(|| {
+        format!(
+            "Could not find SA1s to districts correspondence file, does this path exist?\n\t{}",
+            sa1_districts_path.display()
+        )
+    })?;
+    read_sa1_districts(file)
+}
 
-/// 2a. Load up SA1 to district data (as an iterator over a file)
-fn get_sa1_districts(sa1_districts_path: &Path) -> Result<Sa1DistsRdr> {
-    let rdr = csv::ReaderBuilder::new()
+/// Read an SA1-to-districts correspondence (see [`Sa1DistrictsColumns::sniff`] for the columns).
+fn read_sa1_districts<R: io::Read>(sa1_districts: R) -> Result<Sa1Districts> {
+    let mut records = csv::ReaderBuilder::new()
         .flexible(true)
-        .has_headers(true)
-        .from_path(sa1_districts_path)
-        .with_context(|| {
-            format!(
-                "Could not find SA1s to districts correspondence file, does this path exist?\n\t{}",
-                sa1_districts_path.display()
-            )
-        })?
-        .into_records();
-    Ok(rdr)
+        .has_headers(false)
+        .from_reader(sa1_districts)
+        .into_records()
+        .peekable();
+
+    let cols = match records.peek() {
+        Some(Ok(first)) => {
+            let (cols, is_header) = Sa1DistrictsColumns::sniff(first);
+            debug!("SA1s-to-districts columns: {:?}", cols);
+            if is_header {
+                records.next();
+            }
+            cols
+        }
+        _ => Sa1DistrictsColumns::POSITIONAL,
+    };
+
+    let mut sa1_districts = Sa1Districts::new();
+
+    for record in records {
+        let row = record?;
+
+        let (Some(id), Some(dist)) = (row.get(cols.id), row.get(cols.dist)) else {
+            continue;
+        };
+        let (id, dist) = (id.trim(), dist.trim());
+        // Fun fact: we don't actually need `Pop_Share` for anything
+        let pop = cols
+            .pop
+            .and_then(|i| row.get(i))
+            .map(|x| x.parse::<f64>().unwrap_or(0.0_f64));
+
+        sa1_districts
+            .entry(id.to_string())
+            .or_default()
+            .push((dist.to_string(), pop));
+    }
+    Ok(sa1_districts)
+}
+
+/// Each combination's share of a district's `row` (with the total last), as a percentage.
+///
+/// If the total is zero, so are the shares.
+fn shares(row: &[f64]) -> Vec<f64> {
+    let Some((total, combos)) = row.split_last() else {
+        return Vec::new();
+    };
+    combos
+        .iter()
+        .map(|x| {
+            if *total == 0.0 {
+                0.0
+            } else {
+                100.0 * x / total
+            }
+        })
+        .collect()
 }
 
 /// 6a. Output CSV to `npp_dists_path`
 ///
 /// The format is the district name, then the same columns
-/// (from `None` through `Total`) as in `sa1_prefs_path`.
+/// (from `Exhausted` through `Total`) as in `sa1_prefs_path`;
+/// then if `with_shares`, the [`shares`] of each combination, labelled like `Alp%`.
 fn write_aggregate_csv(
     npp_dists_path: &Path,
     districts: &PrefsMap,
     header: &[String],
+    with_shares: bool,
+    encoding: &'static Encoding,
 ) -> Result<()> {
     create_dir_all(
         npp_dists_path
@@ -88,18 +198,30 @@ fn write_aggregate_csv(
             .with_context(|| format!("{} has no parent", npp_dists_path.display()))?,
     )?;
 
-    let mut dist_wtr = csv::Writer::from_path(npp_dists_path)?;
+    let mut dist_wtr = csv::Writer::from_writer(create_encoded(npp_dists_path, encoding)?);
 
+    let mut full_header = header.to_vec();
+    if with_shares {
+        // skipping `District` and `Total`
+        full_header.extend(
+            header[1..header.len().saturating_sub(1)]
+                .iter()
+                .map(|x| format!("{x}%")),
+        );
+    }
     dist_wtr
-        .write_record(header)
+        .write_record(&full_header)
         .context("error writing npp_dists header")?;
 
     for (id, row) in districts {
-        let mut out: Vec<String> = Vec::with_capacity(header.len());
+        let mut out: Vec<String> = Vec::with_capacity(full_header.len());
         out.push(id.clone());
         for i in row {
             out.push(i.to_string());
         }
+        if with_shares {
+            out.extend(shares(row).iter().map(ToString::to_string));
+        }
         // trace!("{:?}", out);
         dist_wtr
             .write_record(out)
@@ -111,165 +233,329 @@ fn write_aggregate_csv(
     Ok(())
 }
 
-/// 6b. Output to `npp_dists_path` (but as .json rather than .csv)
+/// 6b. Output to `js_path` (usually the `npp_dists_path`, but as .json rather than .csv)
 ///
 /// This JSON includes "CSV-style" district results (values are given
 /// as arrays, with field names specified in corresponding order) and
 /// *also* includes information about the parties in the distribution.
 ///
+/// `combinations` gives the ordered list of parties for each of the `field_names`
+/// (bar the final `Total`), so that clients needn't split up the labels.
+///
 ///  ```json
 ///  {
 ///     parties : {abbr: full name},
 ///     field_names: [...],
-///     data: {district: [values]}
+///     combinations: [[], [abbr], ..., [abbr, abbr, ...]],
+///     data: {district: [values]},
+///     shares: {district: [percentages]}
 /// }
 ///  ```
+///
+/// `shares` (each combination's [`shares`] of the total, so without one for `Total`)
+/// is only there if `with_shares`.
+/// The JSON is minified, unless `pretty` (which is handy for debugging).
 fn write_aggregate_js(
-    npp_dists_path: &Path,
+    js_path: &Path,
     districts: &PrefsMap,
     parties: &IndexMap<String, Vec<String>>,
     header: &[String],
+    with_shares: bool,
+    pretty: bool,
 ) -> Result<()> {
     create_dir_all(
-        npp_dists_path
+        js_path
             .parent()
-            .with_context(|| format!("{} has no parent", npp_dists_path.display()))?,
+            .with_context(|| format!("{} has no parent", js_path.display()))?,
     )?;
 
+    let out = make_aggregate_js(districts, parties, header, with_shares)?;
+    let json_file = File::create(js_path).context("Error creating SA1s aggregate JSON file")?;
+    if pretty {
+        serde_json::to_writer_pretty(json_file, &out)
+    } else {
+        serde_json::to_writer(json_file, &out)
+    }
+    .context("Error writing SA1s aggregate JSON file")?;
+
+    Ok(())
+}
+
+/// The contents of the aggregate JSON file; see [`write_aggregate_js`].
+///
+/// Keys are serialised in a fixed order (fields alphabetically, maps sorted)
+/// so that the same inputs always give byte-identical output.
+#[derive(Debug, Serialize)]
+struct AggregateJs<'a> {
+    combinations: Vec<Vec<String>>,
+    data: &'a PrefsMap,
+    field_names: &'a [String],
+    parties: BTreeMap<&'a str, &'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shares: Option<BTreeMap<&'a str, Vec<f64>>>,
+}
+
+/// Assemble the [`AggregateJs`], checking that the `header` matches the `parties`.
+fn make_aggregate_js<'a>(
+    districts: &'a PrefsMap,
+    parties: &'a IndexMap<String, Vec<String>>,
+    header: &'a [String],
+    with_shares: bool,
+) -> Result<AggregateJs<'a>> {
     // 6.b JS
     // format: {parties : {abbr: full name}, field_names: [], data: {district: [values]}}
     // note that data is our Districts variable
     // and field_names are just the header (well, skipping the district column)
     // and, well, parties are parties
-    let out = json!({
-        "parties": parties, // empty for now
-        "field_names": header[1..],
-        "data": districts
-    });
-    let json_path = npp_dists_path.with_extension("json");
-    let json_file = File::create(json_path).context("Error creating SA1s aggregate JSON file")?;
-    serde_json::to_writer(json_file, &out).context("Error writing SA1s aggregate JSON file")?;
+    let combinations = group_orders(&sorted_groups(parties));
+    // The header should be `District`, then one label per combination, then `Total`
+    let labels_match = header.len() == combinations.len() + 2
+        && header[1..]
+            .iter()
+            .zip(&combinations)
+            .all(|(label, order)| order.is_empty() || *label == order.concat());
+    if !labels_match {
+        return Err(eyre!(
+            "The SA1s to preferences file's columns don't match the parties in this scenario."
+        ))
+        .suggestion("If the SA1 prefs were projected with --min-column-votes, project them again without it: JSON output needs every combination.");
+    }
 
-    Ok(())
+    Ok(AggregateJs {
+        combinations,
+        data: districts,
+        field_names: &header[1..],
+        parties: parties
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_slice()))
+            .collect(),
+        shares: with_shares.then(|| {
+            districts
+                .iter()
+                .map(|(k, v)| (k.as_str(), shares(v)))
+                .collect()
+        }),
+    })
 }
 
-/// Perform the actual summation (steps 2b through 5)
+/// How to split an SA1 that's in several districts, if we don't know their populations.
+#[derive(clap::ArgEnum, Debug, PartialEq, Eq, Clone, Copy, Default, Serialize)]
+pub enum SplitFallback {
+    /// Split it evenly between the districts
+    #[default]
+    Even,
+    /// Put it all in the first district listed (as older versions did)
+    First,
+}
+
+/// Perform the actual summation (steps 3 through 5)
 ///
-/// Returns a map of district names to summed NPP values
+/// Returns a map of district names to summed NPP values.
+/// SA1s split between districts without populations are split as per `fallback`.
 fn make_districts(
     sa1_prefs: &PrefsMap,
-    sa1_dists_rdr: Sa1DistsRdr,
-) -> Result<BTreeMap<String, Vec<f64>>> {
-    // 2b. Load up SA1 to district data
+    sa1_districts: &Sa1Districts,
+    fallback: SplitFallback,
+) -> Result<PrefsMap> {
+    let mut districts = PrefsMap::new();
 
-    let mut districts: BTreeMap<String, Vec<f64>> = BTreeMap::new();
-    let mut seen_sa1s: BTreeSet<String> = BTreeSet::new();
+    for (id, dists) in sa1_districts {
+        let Some(sa1_npps) = sa1_prefs.get(id) else {
+            continue;
+        };
 
-    for record in sa1_dists_rdr {
-        let row = record?;
+        // What happens if there are SA1 splits but we don't have info?
+        let unpopulated = dists.iter().filter(|(_, pop)| pop.is_none()).count();
+        #[allow(clippy::cast_precision_loss)] // there won't be 2^52 districts
+        let unpopulated_share = match fallback {
+            SplitFallback::Even => 1.0 / unpopulated as f64,
+            SplitFallback::First => 1.0,
+        };
+        let mut allocated_unsplit = false;
+
+        for (dist, pop) in dists {
+            if pop.is_none() && fallback == SplitFallback::First {
+                // just allocate to whichever was seen first
+                if allocated_unsplit {
+                    continue;
+                }
+                allocated_unsplit = true;
+            }
 
-        if row.len() < 2 {
-            continue;
+            // 3. Scale (1) to fit (2)
+            // 4. is along for the ride?
+            let multiplier = match pop {
+                Some(sa1_pop) => {
+                    let sa1_total = sa1_prefs
+                        .row_total(id)
+                        .context("missing 'total' field in SA1s-to-districts file")?;
+                    if *sa1_pop == 0.0_f64 {
+                        0.0_f64
+                    } else {
+                        sa1_pop / sa1_total
+                    }
+                }
+                None => unpopulated_share,
+            };
+
+            // 5. Aggregates (4) by district.
+            districts.add_scaled(dist, sa1_npps, multiplier);
         }
+    }
+    // trace!("{:#?}", districts);
+    Ok(districts)
+}
 
-        let id = row
-            .get(0)
-            .context("empty row in SA1s-to-districts file")?
-            .trim();
-        let dist = row
-            .get(1)
-            .context("empty row in SA1s-to-districts file")?
-            .trim();
-
-        // 3. Scale (1) to fit (2)
-        // 4. is along for the ride?
+/// How far (as a fraction of the total) a district's combinations may stray from its total
+/// before [`check_district_totals`] complains.
+const DISTRICT_TOTAL_TOLERANCE: f64 = 1e-6;
 
-        let Some(sa1_npps) = sa1_prefs.get(id) else {
+/// Check that each district's combinations add up to its total (the last column).
+///
+/// Each SA1's row is scaled as a whole, so they should; but an SA1 prefs file whose own rows
+/// don't add up, or an SA1 split without populations (see [`make_districts`]), can make them stray.
+/// Divergent districts are warned about, and if `renormalise`, their combinations are scaled
+/// to match the total. Returns how many districts diverged.
+fn check_district_totals(districts: &mut PrefsMap, renormalise: bool) -> usize {
+    let mut diverged = 0;
+    for (id, row) in districts.iter_mut() {
+        let Some((total, combos)) = row.split_last_mut() else {
             continue;
         };
-        let mut multiplier = 1.0_f64;
-
-        if row.len() >= 3 {
-            // Fun fact: we don't actually need `Pop_Share` for anything
-            let sa1_total = sa1_npps
-                .last()
-                .context("missing 'total' field in SA1s-to-districts file")?;
-            let sa1_pop = row
-                .get(2)
-                .and_then(|x| x.parse::<f64>().ok())
-                .unwrap_or(0.0_f64);
-
-            if sa1_pop == 0.0_f64 {
-                multiplier = 0.0_f64;
-            } else {
-                multiplier = sa1_pop / sa1_total;
-            }
-        } else {
-            // What happens if there are SA1 splits but we don't have info?
-            // Hack: just allocate to whichever was seen first for now
-            let sa1 = id.to_string();
-            if seen_sa1s.contains(&sa1) {
-                continue;
-            }
-            seen_sa1s.insert(sa1);
+        let sum: f64 = combos.iter().sum();
+        if (sum - *total).abs() <= DISTRICT_TOTAL_TOLERANCE * total.abs().max(1.0) {
+            continue;
         }
-        // 5. Aggregates (4) by district.
-
-        if districts.contains_key(dist) {
-            let dist_npps = districts.get_mut(dist).context("TOCTOU in aggregation")?;
-            for j in 0..sa1_npps.len() {
-                dist_npps[j] += sa1_npps[j] * multiplier;
-            }
-        } else {
-            let mut dist_npps = Vec::with_capacity(sa1_npps.len());
-            for s in sa1_npps {
-                dist_npps.push(s * multiplier);
+        diverged += 1;
+        warn!(
+            "District {}: the combinations add up to {:.1}, but the total is {:.1}{}",
+            id,
+            sum,
+            total,
+            if renormalise { " (renormalising)" } else { "" }
+        );
+        if renormalise && sum > 0.0 {
+            let factor = *total / sum;
+            for x in combos.iter_mut() {
+                *x *= factor;
             }
-            districts.insert(dist.to_string(), dist_npps);
         }
     }
-    // trace!("{:#?}", districts);
-    Ok(districts)
+    diverged
+}
+
+/// Options for [`aggregate`], beyond its inputs.
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::struct_excessive_bools)] // these are all independent options
+pub struct AggregationOptions<'a> {
+    /// Write the CSV output (to the `npp_dists_path`)
+    pub write_csv: bool,
+    /// Also write the output as JSON, to this path; see [`write_aggregate_js`]
+    pub js_path: Option<&'a Path>,
+    /// Pretty-print the JSON
+    pub pretty_js: bool,
+    /// Scale any district whose combinations don't add up to its total; see [`check_district_totals`]
+    pub renormalise: bool,
+    /// How to split SA1s that are in several districts, without populations
+    pub fallback: SplitFallback,
+    /// How to round each district's votes; see [`round_row`]
+    pub rounding: Rounding,
+    /// Also write each combination's share of each district's total; see [`shares`]
+    pub with_shares: bool,
+    /// The encoding of the SA1 prefs (read) and of the CSV output (written);
+    /// see [`crate::utils::output_encoding`]
+    pub encoding: &'static Encoding,
 }
 
 pub fn aggregate(
     sa1_prefs_path: &Path,
     sa1_districts_path: &Path,
     npp_dists_path: &Path,
-    write_js: bool,
     parties: &IndexMap<String, Vec<String>>,
+    options: AggregationOptions,
 ) -> Result<()> {
     //! 1. Take SA1-by-SA1 NPP data from `sa1_prefs_path`
     //! 2. Take SA1 population & district split data from `sa1_districts_path`
     //! 3. Scale (1) to fit (2) [if 3rd & 4th columns exist in (2)]
-    //! 4. Also split (3) according to (2) where necessary/available
-    //! 5. Aggregates (4) by district.
-    //! 6. Output to `npp_dists_path`
+    //! 4. Also split (3) according to (2) where necessary/available,
+    //!    or as per `options.fallback` where the populations aren't available
+    //! 5. Aggregates (4) by district, checking (and if `options.renormalise`, fixing) each district's total.
+    //! 6. Output to `npp_dists_path` (unless not `options.write_csv`) and `options.js_path`,
+    //!    rounded as per `options.rounding`
+
+    let sa1_districts = get_sa1_districts(sa1_districts_path)?;
+
+    aggregate_with_map(
+        sa1_prefs_path,
+        &sa1_districts,
+        npp_dists_path,
+        parties,
+        options,
+    )
+}
 
+/// As for [`aggregate`], but with an SA1-to-districts correspondence that's already in memory.
+pub fn aggregate_with_map(
+    sa1_prefs_path: &Path,
+    sa1_districts: &Sa1Districts,
+    npp_dists_path: &Path,
+    parties: &IndexMap<String, Vec<String>>,
+    options: AggregationOptions,
+) -> Result<()> {
     // TODO convert all of the above to streams for WASM compatibility
     // [x] factored out IO code
     // [x] factored out calculation code
     // [ ] handle WASM IO
 
-    info!("\tCombining SA1s into Districts");
+    if !options.write_csv && options.js_path.is_none() {
+        bail!("The combination phase needs to write CSV or JSON (or both)");
+    }
 
-    let (sa1_prefs, sp_headers) = load_sa1_prefs(sa1_prefs_path)?;
+    info!("\tCombining SA1s into Districts");
 
-    let sa1_dists_rdr = get_sa1_districts(sa1_districts_path)?;
+    let (sa1_prefs, sp_headers) = load_sa1_prefs(sa1_prefs_path, options.encoding)?;
 
-    let districts = make_districts(&sa1_prefs, sa1_dists_rdr)?;
+    let mut districts = make_districts(&sa1_prefs, sa1_districts, options.fallback)?;
+    let diverged = check_district_totals(&mut districts, options.renormalise);
+    if diverged > 0 && !options.renormalise {
+        warn!(
+            "{} districts' combinations don't add up to their totals; pass --renormalise-districts to scale them to match.",
+            diverged
+        );
+    }
 
     // 6. Output to `npp_dists_path`
 
+    if options.rounding != Rounding::None {
+        for row in districts.values_mut() {
+            round_row(row, options.rounding);
+        }
+    }
+
     let mut header = vec![String::from("District")];
     for i in sp_headers.iter().skip(1) {
         header.push(i.to_string());
     }
 
-    write_aggregate_csv(npp_dists_path, &districts, &header)?;
+    if options.write_csv {
+        write_aggregate_csv(
+            npp_dists_path,
+            &districts,
+            &header,
+            options.with_shares,
+            options.encoding,
+        )?;
+    }
 
-    if write_js {
-        write_aggregate_js(npp_dists_path, &districts, parties, &header)?;
+    if let Some(js_path) = options.js_path {
+        write_aggregate_js(
+            js_path,
+            &districts,
+            parties,
+            &header,
+            options.with_shares,
+            options.pretty_js,
+        )?;
     }
 
     info!("\t\tDone!");
@@ -277,3 +563,120 @@ pub fn aggregate(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn district_totals() {
+        let mut districts = PrefsMap::new();
+        districts.insert("Alpha".into(), vec![1.0, 2.0, 3.0, 6.0]);
+        districts.insert("Beta".into(), vec![1.0, 1.0, 2.0, 8.0]);
+        assert_eq!(check_district_totals(&mut districts.clone(), false), 1);
+        assert_eq!(check_district_totals(&mut districts, true), 1);
+        assert_eq!(districts["Alpha"], [1.0, 2.0, 3.0, 6.0]);
+        assert_eq!(districts["Beta"], [2.0, 2.0, 4.0, 8.0]);
+        assert_eq!(check_district_totals(&mut districts, false), 0);
+    }
+
+    #[test]
+    fn unpopulated_splits() {
+        let mut sa1_prefs = PrefsMap::new();
+        sa1_prefs.insert("3000001".into(), vec![2.0, 4.0, 6.0]);
+        sa1_prefs.insert("3000002".into(), vec![1.0, 1.0, 2.0]);
+        // no population column
+        let sa1_districts =
+            read_sa1_districts("3000001,North\n3000001,South\n3000002,South\n".as_bytes()).unwrap();
+
+        let even = make_districts(&sa1_prefs, &sa1_districts, SplitFallback::Even).unwrap();
+        assert_eq!(even["North"], [1.0, 2.0, 3.0]);
+        assert_eq!(even["South"], [2.0, 3.0, 5.0]);
+
+        let first = make_districts(&sa1_prefs, &sa1_districts, SplitFallback::First).unwrap();
+        assert_eq!(first["North"], [2.0, 4.0, 6.0]);
+        assert_eq!(first["South"], [1.0, 1.0, 2.0]);
+    }
+    #[test]
+    fn aggregate_json_is_deterministic() {
+        let header: Vec<String> = [
+            "District",
+            "Exhausted",
+            "Alp",
+            "Lnp",
+            "AlpLnp",
+            "LnpAlp",
+            "Total",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        let mut districts = PrefsMap::new();
+        districts.insert("South".into(), vec![0.0, 1.0, 2.0, 3.0, 4.0, 10.0]);
+        districts.insert("North".into(), vec![1.0, 1.0, 1.0, 1.0, 1.0, 5.0]);
+        // configured out of order, but the combinations are sorted, as in the distribution
+        let mut parties = IndexMap::new();
+        parties.insert("Lnp".to_string(), vec!["B:Lnp".to_string()]);
+        parties.insert("Alp".to_string(), vec!["A:Alp".to_string()]);
+
+        let first = serde_json::to_string(
+            &make_aggregate_js(&districts, &parties, &header, false).unwrap(),
+        )
+        .unwrap();
+        let second = serde_json::to_string(
+            &make_aggregate_js(&districts, &parties, &header, false).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(first, second);
+        assert!(first.starts_with(
+            r#"{"combinations":[[],["Alp"],["Lnp"],["Alp","Lnp"],["Lnp","Alp"]],"data":{"North""#
+        ));
+        assert!(first.ends_with(r#""parties":{"Alp":["A:Alp"],"Lnp":["B:Lnp"]}}"#));
+
+        // Mismatched header
+        assert!(make_aggregate_js(&districts, &parties, &header[..4], false).is_err());
+
+        districts.insert("Empty".into(), vec![0.0; 6]);
+        let with_shares = make_aggregate_js(&districts, &parties, &header, true).unwrap();
+        let shares = with_shares.shares.unwrap();
+        assert_eq!(shares["South"], [0.0, 10.0, 20.0, 30.0, 40.0]);
+        // not NaN
+        assert_eq!(shares["Empty"], [0.0; 5]);
+    }
+
+    #[test]
+    fn sa1_districts_columns() {
+        let expected = |rows: &[(&str, &str, Option<f64>)]| {
+            let mut out = Sa1Districts::new();
+            for (id, dist, pop) in rows {
+                out.entry(String::from(*id))
+                    .or_default()
+                    .push((String::from(*dist), *pop));
+            }
+            out
+        };
+        // named columns, in any order
+        let named = "Pop_Share,Dist_Name,Notes,SA1_id,Pop\n1,North,,3000001,10\n0.5,North,,3000002,5\n0.5,South,,3000002,5\n";
+        assert_eq!(
+            read_sa1_districts(named.as_bytes()).unwrap(),
+            expected(&[
+                ("3000001", "North", Some(10.0)),
+                ("3000002", "North", Some(5.0)),
+                ("3000002", "South", Some(5.0))
+            ])
+        );
+        // unrecognised header names: by position
+        let positional = "A,B\n3000001,North\n3000002,South\n";
+        let two_columns = expected(&[("3000001", "North", None), ("3000002", "South", None)]);
+        assert_eq!(
+            read_sa1_districts(positional.as_bytes()).unwrap(),
+            two_columns
+        );
+        // no header at all
+        let headerless = "3000001,North\n3000002,South\n";
+        assert_eq!(
+            read_sa1_districts(headerless.as_bytes()).unwrap(),
+            two_columns
+        );
+    }
+}
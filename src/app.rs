@@ -3,15 +3,26 @@ use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::PathBuf;
 
+use crate::aggregator::{AggregationOptions, SplitFallback};
+use crate::booths::{
+    BoothType, DistributionOptions, FormalityAudit, InputFormat, OutputFilter, OutputFormat, Sample,
+};
 use crate::config::{KnownConfigOptions, Scenario};
-use crate::utils::ToStateAb;
-use crate::{aggregator, booths, config, data, multiplier, upgrades, utils};
+use crate::manifest::Manifest;
+use crate::multiplier::{parse_turnout_scale, ProjectionOptions, Rounding, TurnoutScale};
+use crate::term::ColorChoice;
+use crate::utils::{input, output_encoding, StateAb, ToStateAb};
+use crate::{
+    aggregator, booths, compare, config, data, doctor, manifest, multiplier, upgrades, utils,
+};
 use clap::{AppSettings, ArgEnum, Parser, Subcommand, ValueHint};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use encoding_rs::Encoding;
 
-use color_eyre::eyre::{Context, ContextCompat};
+use color_eyre::eyre::{bail, Context, ContextCompat};
 use color_eyre::Help;
-use tracing::info;
+use itertools::Itertools;
+use tracing::{debug, info, warn};
 
 #[derive(Parser, Debug)]
 #[clap(version, about)]
@@ -23,39 +34,84 @@ pub struct Cli {
     /// -qqq for OFF through -vv for TRACE
     #[clap(flatten)]
     pub verbose: Verbosity<InfoLevel>,
+    /// When to use colours and other terminal escape codes
+    #[clap(long, arg_enum, global = true, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
 }
 
-#[derive(Subcommand, Debug, PartialEq, Eq)]
+#[derive(Subcommand, Debug, PartialEq)]
 pub enum CliCommands {
+    Combinations(CliCombinations),
+    Compare(CliCompare),
     Configure(CliConfigure),
     #[clap(subcommand)]
     Data(CliData),
+    Doctor(CliDoctor),
     Example(CliExample),
     /// View license information and acknowledgements
     License,
     List(CliList),
+    ProjectBooth(CliProjectBooth),
     /// View project README.md
     Readme,
     Run(CliRun),
+    Show(CliShow),
     #[clap(subcommand)]
     Upgrade(CliUpgrade),
+    Validate(CliValidate),
 }
 
 /// Either download all necessary AEC data directly, or examine the URLs to the relevant files.
 #[derive(Parser, Debug, PartialEq, Eq)]
 #[allow(non_snake_case)]
 #[clap(
-    after_help = "Please note that you'll also need to convert XLSX to CSV manually. At least for now..."
+    after_help = "XLSX spreadsheets are converted to CSV (from their first non-empty worksheet) as they're downloaded, keeping the original too."
 )]
 pub enum CliData {
     /// download everything to specified folder
     Download {
+        /// re-download files even if they're already present
+        #[clap(long)]
+        force: bool,
+        /// put every file directly in DL_FOLDER, rather than in a subfolder for each year
+        #[clap(long)]
+        flat: bool,
+        /// don't convert XLSX spreadsheets to CSV
+        #[clap(long)]
+        no_convert: bool,
+        /// download at most N files at a time
+        #[clap(long, value_name = "N", default_value_t = 4)]
+        concurrency: usize,
+        /// also download the elections in this catalogue (default: ~/.config/nparty/downloads.ron, if it exists)
+        #[clap(long, value_name = "RON_FILE", parse(from_os_str), value_hint = ValueHint::FilePath)]
+        catalogue: Option<PathBuf>,
+        /// use only the --catalogue, in place of nparty's own
+        #[clap(long, requires = "catalogue")]
+        catalogue_only: bool,
+        /// only download the election in this year (can be given multiple times)
+        #[clap(long, value_name = "YEAR", number_of_values = 1)]
+        year: Vec<String>,
+        /// only download this state or territory's preferences (can be given multiple times)
+        #[clap(long, value_name = "STATE", number_of_values = 1)]
+        state: Vec<StateAb>,
         #[clap(value_hint = ValueHint::DirPath)]
         #[clap(parse(from_os_str))]
         DL_FOLDER: PathBuf,
     },
     /// write list of downloads to FILE as HTML, or as plain text to stdout if no file is specified
     Examine {
+        /// also list the elections in this catalogue (default: ~/.config/nparty/downloads.ron, if it exists)
+        #[clap(long, value_name = "RON_FILE", parse(from_os_str), value_hint = ValueHint::FilePath)]
+        catalogue: Option<PathBuf>,
+        /// use only the --catalogue, in place of nparty's own
+        #[clap(long, requires = "catalogue")]
+        catalogue_only: bool,
+        /// only list the election in this year (can be given multiple times)
+        #[clap(long, value_name = "YEAR", number_of_values = 1)]
+        year: Vec<String>,
+        /// only list this state or territory's preferences (can be given multiple times)
+        #[clap(long, value_name = "STATE", number_of_values = 1)]
+        state: Vec<StateAb>,
         #[clap(value_hint = ValueHint::FilePath)]
         #[clap(parse(from_os_str))]
         FILE: Option<PathBuf>,
@@ -73,13 +129,14 @@ pub struct CliExample {
 
 /// The year for the example configuration
 #[derive(Debug, PartialEq, Eq, Clone, ArgEnum)]
+#[allow(clippy::enum_variant_names)] // they're years
 pub enum CliExampleYear {
     #[clap(name = "2016")]
     TwentySixteen = 2016,
     #[clap(name = "2019")]
     TwentyNineteen = 2019,
-    // #[clap(name = "2022")]
-    // TwentyTwentyTwo = 2022,
+    #[clap(name = "2022")]
+    TwentyTwentyTwo = 2022,
 }
 
 /// We define the `Display` format for a `CliExample` to be the contents of the relevant year's example.
@@ -91,6 +148,7 @@ impl std::fmt::Display for CliExample {
             match self.year {
                 CliExampleYear::TwentySixteen => include_str!("../example_config_2016.toml"),
                 CliExampleYear::TwentyNineteen => include_str!("../example_config_2019.toml"),
+                CliExampleYear::TwentyTwentyTwo => include_str!("../example_config_2022.toml"),
             }
         )
     }
@@ -126,6 +184,10 @@ pub struct CliUpgradePrefs {
     /// output file or directory
     #[clap(parse(from_os_str), value_hint = ValueHint::AnyPath)]
     pub output: PathBuf,
+
+    /// Only read and check the input, reporting what would be written, without writing anything
+    #[clap(long)]
+    pub validate_only: bool,
 }
 
 /// Convert an SA1s-Districts file from old SA1s to new (e.g. 2011 to 2016 ASGS)
@@ -146,6 +208,10 @@ pub struct CliUpgradeSa1s {
     /// output file; columns will be 'SA1_Id', 'Dist_Name', 'Pop', 'Pop_Share'
     #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
     pub output: PathBuf,
+
+    /// Only read and check the input, reporting what would be written, without writing anything
+    #[clap(long)]
+    pub validate_only: bool,
 }
 
 /// Convert an SA1s-Booths file from old SA1s to new (e.g. 2011 to 2016 ASGS)
@@ -166,6 +232,44 @@ pub struct CliUpgradeBooths {
     /// output file; columns will be same as input file
     #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
     pub output: PathBuf,
+
+    /// Also write the input entries that couldn't be matched to this CSV file (columns: 'SA1_id', 'votes')
+    #[clap(long, value_name = "PATH", parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub unmatched_out: Option<PathBuf>,
+
+    /// Only read and check the input, reporting what would be written, without writing anything
+    #[clap(long)]
+    pub validate_only: bool,
+}
+
+/// List the preference combinations for some groups, in the order of the output columns.
+#[derive(Parser, Debug, PartialEq, Eq)]
+#[clap(
+    after_help = "Prints each combination's index and label, tab-separated. In the distribution output, combination 0 is the sixth column (after ID, Division, Booth, Latitude and Longitude); in the SA1 prefs, it's the second (after SA1_id)."
+)]
+pub struct CliCombinations {
+    /// The group names, as in a scenario's GROUPS (in any order)
+    #[clap(required = true, value_name = "GROUP")]
+    pub groups: Vec<String>,
+}
+
+/// Compare two output files, allowing numeric cells to differ slightly.
+#[derive(Parser, Debug, PartialEq)]
+#[clap(
+    after_help = "Differing cells are printed to standard output as tab-separated line, column, left value and right value. Text cells (IDs, names) must match exactly. Exits with an error if any cells differ."
+)]
+pub struct CliCompare {
+    /// The largest difference allowed between numeric cells
+    #[clap(long, default_value_t = 1e-6)]
+    pub tolerance: f64,
+
+    /// The first CSV file
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub left: PathBuf,
+
+    /// The second CSV file, with the same structure as the first
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub right: PathBuf,
 }
 
 /// Generate a configuration file interactively, possibly using an existing file as a basis.
@@ -217,27 +321,267 @@ pub struct CliConfigure {
     after_help = "Scenario tables are printed to standard output. If that's a terminal, they'll be pretty-printed with elastic tabstops. If that's a pipe or file, they'll be tab-separated to make further processing as straightforward as possible."
 )]
 pub struct CliList {
-    /// The configuration file to list scenarios from
+    /// The configuration file to list scenarios from (`-` for standard input)
     #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
     pub configfile: PathBuf,
+
+    /// The AEC's 'Political Parties' CSV, to show party names alongside groups named by abbreviation
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub party_details: Option<PathBuf>,
 }
 
-/// Run scenarios from the configuration file.
+/// Check that the scenarios in a configuration file are ready to run: that their input files
+/// exist and are the right kind, the groups' candidates are in the preferences files, the year
+/// is a known election, and so on. (`nparty validate` does the same checks, but only summarises them.)
+#[derive(Parser, Debug, PartialEq, Eq)]
+#[clap(
+    after_help = "Prints a checklist for each scenario, with a hint for each problem found. Nothing is changed, except that a probe file is briefly written to each output directory."
+)]
+pub struct CliDoctor {
+    /// The configuration file to check (`-` for standard input)
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub configfile: PathBuf,
+}
+
+/// Check that the scenarios in a configuration file are ready to run, as `nparty doctor` does,
+/// and print a table of which passed and which failed.
+#[derive(Parser, Debug, PartialEq, Eq)]
+#[clap(
+    after_help = "Exits with an error if any scenario fails a check, so it can be used before a long run. `nparty doctor` lists every problem, with hints for fixing them."
+)]
+pub struct CliValidate {
+    /// The configuration file to check (`-` for standard input)
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub configfile: PathBuf,
+}
+
+/// Show one scenario from the configuration file in full, including each group's candidates.
 #[derive(Parser, Debug, PartialEq, Eq)]
+pub struct CliShow {
+    /// The configuration file (`-` for standard input)
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub configfile: PathBuf,
+
+    /// The scenario to show
+    pub scenario: String,
+}
+
+/// Show how the projection phase spreads one booth's results across the SA1s that voted there.
+#[derive(Parser, Debug, PartialEq, Eq)]
+#[clap(
+    after_help = "Needs the scenario's distribution output, so run that phase first. Prints a row for each SA1: its ID, how many of its votes were cast at the booth, those as a share of all the booth's votes, and then the booth's results scaled to those votes (i.e. the booth's contribution to that SA1's row of the SA1 prefs)."
+)]
+pub struct CliProjectBooth {
+    /// The configuration file (`-` for standard input)
+    #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub configfile: PathBuf,
+
+    /// The scenario (which needs an SA1s breakdown)
+    pub scenario: String,
+
+    /// The booth's division
+    pub division: String,
+
+    /// The booth's name, as in the distribution output
+    pub booth: String,
+
+    /// The character encoding of the distribution output, if it was written with --encoding
+    #[clap(long, default_value = "utf-8", parse(try_from_str = output_encoding))]
+    pub encoding: &'static Encoding,
+}
+
+/// Run scenarios from the configuration file.
+#[derive(Parser, Debug, PartialEq)]
+#[allow(clippy::struct_excessive_bools)] // these are all independent CLI flags
 pub struct CliRun {
     /// Run a specific phase of analysis
     #[clap(long, arg_enum, default_value_t = CliRunPhase::All)]
     pub phase: CliRunPhase,
 
     /// Also output JavaScript from the combination phase, for the website predictor
+    /// (overrides each scenario's WRITE_JS setting)
     #[clap(long)]
     pub js: bool,
 
-    /// Run a SPECIFIC scenario from the configuration file (can be given multiple times to run several scenarios)
+    /// Pretty-print the JavaScript output, for debugging (implies --js)
+    #[clap(long)]
+    pub json_pretty: bool,
+
+    /// Only output JavaScript from the combination phase, not the CSV (implies --js)
+    #[clap(long)]
+    pub js_only: bool,
+
+    /// When running all phases, warn if the projected SA1 totals don't reconcile with the distributed totals
+    #[clap(long)]
+    pub check_totals: bool,
+
+    /// In the combination phase, scale any district whose combinations don't add up to its total so that they do (they're warned about either way)
+    #[clap(long)]
+    pub renormalise_districts: bool,
+
+    /// In the combination phase, how to split an SA1 that's in several districts when the SA1s-to-districts file has no populations
+    #[clap(long, arg_enum, default_value_t = SplitFallback::Even)]
+    pub split_fallback: SplitFallback,
+
+    /// How to round each district's votes in the combination phase (as for --rounding)
+    #[clap(long, arg_enum, default_value_t = Rounding::None)]
+    pub district_rounding: Rounding,
+
+    /// In the combination phase, also write each combination's percentage of each district's total (as columns like "Alp%", and under "shares" in the JSON)
+    #[clap(long)]
+    pub with_shares: bool,
+
+    /// Write a JSON list of every output file produced (with sizes and SHA-256 hashes) to this path
+    #[clap(long, parse(from_os_str), value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub manifest: Option<PathBuf>,
+
+    /// Skip scenarios whose definition and input files haven't changed since they were last run (with the same --phase)
+    #[clap(long)]
+    pub only_changed: bool,
+
+    /// Profile the run and write a flamegraph SVG (or a pprof protobuf, if PATH ends in .pb) to this path
+    #[cfg(feature = "profile")]
+    #[clap(long, parse(from_os_str), value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub profile: Option<PathBuf>,
+
+    /// The label for the first column: ballots that expressed no preference among the configured groups
+    #[clap(long, value_name = "LABEL", default_value = booths::EXHAUSTED)]
+    pub exhausted_label: String,
+
+    /// Also write a report of how many booths contributed to each SA1 (alongside the SA1 prefs)
+    #[clap(long)]
+    pub coverage: bool,
+
+    /// The character encoding of the CSV outputs, e.g. windows-1252 for older spreadsheet software
+    #[clap(long, default_value = "utf-8", parse(try_from_str = output_encoding))]
+    pub encoding: &'static Encoding,
+
+    /// Fold preference combinations with fewer than this many votes (over all SA1s) into one "Other" column of the SA1 prefs
+    #[clap(long, value_name = "VOTES")]
+    pub min_column_votes: Option<f64>,
+
+    /// How to round the projected votes in the SA1 prefs
+    #[clap(long, arg_enum, default_value_t = Rounding::None)]
+    pub rounding: Rounding,
+
+    /// Scale the SA1 breakdown (which counts Reps voters) by this factor, or to each booth's distributed (Senate) votes with "booths"; without it, the SA1 prefs are in Reps votes
+    #[clap(long, value_name = "FACTOR|booths", parse(try_from_str = parse_turnout_scale))]
+    pub turnout_scale: Option<TurnoutScale>,
+
+    /// Write the SA1 breakdown entries whose booths aren't in the distribution output to this CSV (with the scenario name appended, if there are several)
+    #[clap(long, value_name = "PATH", parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub report_unmatched: Option<PathBuf>,
+
+    /// Write booths missing from the polling places file without their details, rather than stopping
+    #[clap(long)]
+    pub allow_missing_booths: bool,
+
+    /// Only treat booths as special votes (postals, absents, etc.) if they're missing from the polling places file, not just going by their names
+    #[clap(long)]
+    pub dedupe_specials_by_id: bool,
+
+    /// Write each division's booths as soon as they're done, to save memory (needs the preferences to be grouped by division, as the AEC's are)
+    #[clap(long)]
+    pub stream: bool,
+
+    /// Only warn (rather than stop) when a scenario's EXPECTED_FORMAL doesn't match the ballots distributed
+    #[clap(long)]
+    pub warn_formal_mismatch: bool,
+
+    /// Only distribute a random sample of the ballots, including each with this probability (e.g. 0.01 for 1%)
+    #[clap(long, value_name = "RATE")]
+    pub sample_rate: Option<f64>,
+
+    /// The seed for --sample-rate's random choices: the same seed gives the same sample
+    #[clap(long, default_value_t = 0, requires = "sample-rate")]
+    pub seed: u64,
+
+    /// Scale a sample's counts up by the inverse of --sample-rate, to approximate a full run
+    #[clap(long, requires = "sample-rate")]
+    pub scale_sample: bool,
+
+    /// Use the preferences file's header as-is, rather than repairing unquoted commas in candidate names (only for files known to be properly quoted)
+    #[clap(long)]
+    pub no_header_repair: bool,
+
+    /// Debugging: key booths by their names directly, rather than interning them (slower, but fine for small inputs)
+    #[clap(long)]
+    pub no_intern: bool,
+
+    /// Save memory by storing each booth's counts sparsely if there are more than this many combinations (e.g. 300 for five or more groups)
+    #[clap(long, value_name = "COMBINATIONS")]
+    pub sparse_above: Option<usize>,
+
+    /// Classify ballots on this many threads (default: as many as are available); the output is the same either way
+    #[clap(long, value_name = "N")]
+    pub threads: Option<usize>,
+
+    /// Write a column for each unordered set of groups (e.g. Alp+Grn) rather than for each preference order; only with --phase distribute
+    #[clap(long)]
+    pub unordered: bool,
+
+    /// Count informal ballots (no first preference anywhere) in an extra Informal column, rather than as Exhausted; only with --phase distribute
+    #[clap(long, conflicts_with = "unordered")]
+    pub informal_column: bool,
+
+    /// Write one row per polling place and combination (ID, Division, Booth, Combination, Count), leaving out zero counts; only with --phase distribute
+    #[clap(long)]
+    pub flat_combinations: bool,
+
+    /// The format of the preferences file(s)
+    #[clap(long, arg_enum, default_value_t = InputFormat::AecCsv)]
+    pub input_format: InputFormat,
+
+    /// The format of the distribution output; JSON goes next to the CSV's path (e.g. NPP_Booths.json), for web tooling. Only with --phase distribute
+    #[clap(long, arg_enum, default_value_t = OutputFormat::Csv)]
+    pub format: OutputFormat,
+
+    /// Only include polling places of this type in the distribution output
+    #[clap(long, arg_enum, default_value_t = BoothType::All)]
+    pub booth_type: BoothType,
+
+    /// Only write ordinary booths (not special votes) in the distribution output
+    #[clap(long, conflicts_with = "specials-only")]
+    pub booths_only: bool,
+
+    /// Only write special votes (absents, postals, etc.) in the distribution output; not with --booth-type
+    #[clap(long)]
+    pub specials_only: bool,
+
+    /// Warn about ballots with preferences greater than the number of candidates (slower)
+    #[clap(long)]
+    pub check_prefs: bool,
+
+    /// Audit: explain why some ballots weren't formal below the line (alongside the distribution output)
+    #[clap(long)]
+    pub explain_formality: bool,
+
+    /// With --explain-formality, explain one in this many of those ballots
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 1000,
+        requires = "explain-formality"
+    )]
+    pub explain_every: usize,
+
+    /// Error on any ballot with more than this many fields [default: 4 times the header length]
+    #[clap(long, value_name = "FIELDS")]
+    pub max_record_length: Option<usize>,
+
+    /// Write outputs under this directory instead of each scenario's OUTPUT_DIR
+    #[clap(long, parse(from_os_str), value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Run a SPECIFIC scenario from the configuration file, or those matching a glob pattern like '*_2PP_AlpLnp' (can be given multiple times to run several scenarios)
     #[clap(long, short)]
     pub scenario: Option<Vec<String>>,
 
-    /// The configuration file to run
+    /// Run the scenarios listed in this file (one name per line) first, in that order, then any others
+    #[clap(long, value_name = "FILE", parse(from_os_str), value_hint = ValueHint::FilePath)]
+    pub order: Option<PathBuf>,
+
+    /// The configuration file to run (`-` for standard input)
     #[clap(parse(from_os_str), value_hint = ValueHint::FilePath)]
     pub configfile: PathBuf,
 }
@@ -254,16 +598,158 @@ pub enum CliRunPhase {
     Combine,
 }
 
+/// The `run` options that can change what a scenario's phases write, for
+/// [`manifest::scenario_fingerprint`]. (Options that only affect speed, memory use, or
+/// warnings are left out.)
+#[derive(Debug, Serialize)]
+#[allow(clippy::struct_excessive_bools)] // as for CliRun
+struct OutputOptions {
+    js: bool,
+    json_pretty: bool,
+    js_only: bool,
+    renormalise_districts: bool,
+    split_fallback: SplitFallback,
+    district_rounding: Rounding,
+    with_shares: bool,
+    exhausted_label: String,
+    coverage: bool,
+    encoding: &'static str,
+    min_column_votes: Option<f64>,
+    rounding: Rounding,
+    turnout_scale: Option<TurnoutScale>,
+    report_unmatched: Option<PathBuf>,
+    allow_missing_booths: bool,
+    dedupe_specials_by_id: bool,
+    sample_rate: Option<f64>,
+    seed: u64,
+    scale_sample: bool,
+    no_header_repair: bool,
+    unordered: bool,
+    informal_column: bool,
+    flat_combinations: bool,
+    input_format: InputFormat,
+    format: OutputFormat,
+    booth_type: BoothType,
+    booths_only: bool,
+    specials_only: bool,
+    explain_formality: bool,
+    explain_every: usize,
+}
+
+impl From<&CliRun> for OutputOptions {
+    fn from(args: &CliRun) -> Self {
+        Self {
+            js: args.js,
+            json_pretty: args.json_pretty,
+            js_only: args.js_only,
+            renormalise_districts: args.renormalise_districts,
+            split_fallback: args.split_fallback,
+            district_rounding: args.district_rounding,
+            with_shares: args.with_shares,
+            exhausted_label: args.exhausted_label.clone(),
+            coverage: args.coverage,
+            encoding: args.encoding.name(),
+            min_column_votes: args.min_column_votes,
+            rounding: args.rounding,
+            turnout_scale: args.turnout_scale,
+            report_unmatched: args.report_unmatched.clone(),
+            allow_missing_booths: args.allow_missing_booths,
+            dedupe_specials_by_id: args.dedupe_specials_by_id,
+            sample_rate: args.sample_rate,
+            seed: args.seed,
+            scale_sample: args.scale_sample,
+            no_header_repair: args.no_header_repair,
+            unordered: args.unordered,
+            informal_column: args.informal_column,
+            flat_combinations: args.flat_combinations,
+            input_format: args.input_format,
+            format: args.format,
+            booth_type: args.booth_type,
+            booths_only: args.booths_only,
+            specials_only: args.specials_only,
+            explain_formality: args.explain_formality,
+            explain_every: args.explain_every,
+        }
+    }
+}
+
+/// How far (as a fraction) the projected total may stray from the distributed total
+/// before `--check-totals` complains.
+const TOTALS_TOLERANCE: f64 = 0.05;
+
 /// Performs the `run` subcommand.
 pub fn run(args: CliRun) -> color_eyre::eyre::Result<()> {
+    let output_options = OutputOptions::from(&args);
     let cfgpath = args.configfile;
 
     // Get data out of config
-    let cfg = config::get_scenarios(&config::get_cfg_doc_from_path(&cfgpath)?)?;
+    let mut cfg = config::get_scenarios(&config::get_cfg_doc_from_path(&cfgpath)?)?;
+    if let Some(output_dir) = &args.output_dir {
+        for scenario in cfg.values_mut() {
+            scenario.set_output_dir(output_dir);
+        }
+    }
+
+    let mut scenario_names: Vec<String> = match &args.scenario {
+        Some(requested) => {
+            config::select_scenarios(requested, cfg.keys()).with_suggestion(|| {
+                format!(
+                    "For a table of available scenarios, try running\n\tnparty list {:?}",
+                    &cfgpath
+                )
+            })?
+        }
+        None => cfg.keys().cloned().collect(),
+    };
+    if let Some(order) = &args.order {
+        let order = config::read_scenario_order(order)?;
+        for name in order.iter().filter(|n| !cfg.contains_key(*n)) {
+            warn!(
+                "Scenario {} (in --order) isn't in the configuration file",
+                name
+            );
+        }
+        config::order_scenarios(&mut scenario_names, &order);
+    }
+
+    let sample = args
+        .sample_rate
+        .map(|rate| Sample::new(rate, args.seed, args.scale_sample))
+        .transpose()
+        .context("Invalid --sample-rate")?;
+
+    if args.explain_every == 0 {
+        bail!("--explain-every must be at least 1");
+    }
+    if args.unordered && args.phase != CliRunPhase::Distribute {
+        bail!("--unordered output can't be projected or combined, so it needs --phase distribute");
+    }
+    if args.informal_column && args.phase != CliRunPhase::Distribute {
+        bail!("--informal-column output can't be projected or combined, so it needs --phase distribute");
+    }
+    if args.flat_combinations && args.phase != CliRunPhase::Distribute {
+        bail!("--flat-combinations output can't be projected or combined, so it needs --phase distribute");
+    }
+    if args.specials_only && args.booth_type != BoothType::All {
+        bail!("--specials-only can't be combined with --booth-type, as special votes have no polling place");
+    }
+    if args.format == OutputFormat::Json {
+        if args.phase != CliRunPhase::Distribute {
+            bail!("--format json output can't be projected or combined, so it needs --phase distribute");
+        }
+        if args.flat_combinations {
+            bail!("--flat-combinations is only for CSV output");
+        }
+    }
+
+    #[cfg(feature = "profile")]
+    let profiler = args
+        .profile
+        .as_deref()
+        .map(crate::profile::Profiler::start)
+        .transpose()?;
 
-    let scenario_names: Vec<String> = args
-        .scenario
-        .unwrap_or_else(|| cfg.keys().cloned().collect());
+    let mut manifest = Manifest::default();
 
     for scen_name in &scenario_names {
         let scenario = cfg
@@ -277,6 +763,17 @@ pub fn run(args: CliRun) -> color_eyre::eyre::Result<()> {
                     &cfgpath
                 )
             })?;
+        let phase = args.phase.to_possible_value().unwrap().get_name();
+        let fingerprint = if args.only_changed {
+            let fingerprint = manifest::scenario_fingerprint(scenario, &output_options)?;
+            if manifest::is_unchanged(scenario, phase, &fingerprint) {
+                info!("Skipping Scenario {} (unchanged)", scen_name);
+                continue;
+            }
+            Some(fingerprint)
+        } else {
+            None
+        };
         info!("Running Scenario {}", scen_name);
         // eprintln!("{:#?}", scenario);
 
@@ -284,6 +781,8 @@ pub fn run(args: CliRun) -> color_eyre::eyre::Result<()> {
         let sa1s_prefs = scenario.sa1s_prefs.as_ref();
         let sa1s_dists = scenario.sa1s_dists.as_ref();
         let npp_dists = scenario.npp_dists.as_ref();
+        // combination labels use any aliases
+        let groups = scenario.labelled_groups();
         let can_project = sa1s_prefs.is_some()
             && sa1s_breakdown.is_some()
             && (args.phase == CliRunPhase::All || args.phase == CliRunPhase::Project);
@@ -295,36 +794,164 @@ pub fn run(args: CliRun) -> color_eyre::eyre::Result<()> {
             args.phase == CliRunPhase::All || args.phase == CliRunPhase::Distribute;
 
         if can_distribute {
+            let audit_path = booths::formality_audit_path(&scenario.npp_booths);
+            let npp_booths = match args.format {
+                OutputFormat::Csv => scenario.npp_booths.clone(),
+                OutputFormat::Json => scenario.npp_booths.with_extension("json"),
+            };
+            let options = DistributionOptions {
+                filter: OutputFilter {
+                    booth_type: args.booth_type,
+                    booths: !args.specials_only,
+                    specials: !args.booths_only,
+                },
+                input_format: args.input_format,
+                check_prefs: args.check_prefs,
+                max_fields: args.max_record_length,
+                no_intern: args.no_intern,
+                no_header_repair: args.no_header_repair,
+                encoding: args.encoding,
+                explain_formality: args.explain_formality.then_some(FormalityAudit {
+                    path: &audit_path,
+                    every: args.explain_every,
+                }),
+                exhausted_label: &args.exhausted_label,
+                expected_formal: scenario.expected_formal,
+                warn_formal_mismatch: args.warn_formal_mismatch,
+                allow_missing_booths: args.allow_missing_booths,
+                specials_by_id: args.dedupe_specials_by_id,
+                stream: args.stream,
+                sample,
+                sparse_above: args.sparse_above,
+                unordered: args.unordered,
+                informal_column: args.informal_column,
+                long_format: args.flat_combinations,
+                output_format: args.format,
+                special_votes: scenario.special_votes.as_ref(),
+                threads: args.threads,
+            };
             booths::booth_npps(
-                &scenario.groups,
+                &groups,
                 scenario.state,
                 &scenario.prefs_path,
                 &scenario.polling_places,
-                &scenario.npp_booths,
+                &npp_booths,
+                options,
             )
             .context("Could not perform distribution step; stopping.")?;
+            if booths::was_interrupted() {
+                bail!(
+                    "Distribution was interrupted; partial results were written to {}",
+                    npp_booths.display()
+                );
+            }
+            manifest.add(scen_name, "distribute", &npp_booths);
+            if args.explain_formality {
+                manifest.add(scen_name, "distribute", &audit_path);
+            }
         }
         if can_project {
+            let report_unmatched = args.report_unmatched.as_ref().map(|path| {
+                if scenario_names.len() > 1 {
+                    let mut name = path.file_stem().unwrap_or_default().to_owned();
+                    name.push(format!("_{scen_name}.csv"));
+                    path.with_file_name(name)
+                } else {
+                    path.clone()
+                }
+            });
             multiplier::project(
-                &scenario.groups,
+                &groups,
                 scenario.state,
                 &scenario.year,
                 &scenario.npp_booths,
                 sa1s_breakdown.unwrap(),
                 sa1s_prefs.unwrap(),
+                ProjectionOptions {
+                    exhausted_label: &args.exhausted_label,
+                    coverage: args.coverage,
+                    min_column_votes: args.min_column_votes,
+                    senate_votes: scenario.senate_votes.as_deref(),
+                    turnout_scale: args.turnout_scale.unwrap_or_default(),
+                    report_unmatched: report_unmatched.as_deref(),
+                    encoding: args.encoding,
+                    rounding: args.rounding,
+                },
             )
             .context("Could not perform projection phase; stopping.")?;
+            manifest.add(scen_name, "project", sa1s_prefs.unwrap());
+            if args.coverage {
+                manifest.add(
+                    scen_name,
+                    "project",
+                    &multiplier::coverage_path(sa1s_prefs.unwrap()),
+                );
+            }
+            if let Some(path) = &report_unmatched {
+                manifest.add(scen_name, "project", path);
+            }
+            if args.check_totals && can_distribute {
+                multiplier::check_totals(
+                    &scenario.npp_booths,
+                    sa1s_prefs.unwrap(),
+                    TOTALS_TOLERANCE,
+                    args.encoding,
+                )
+                .context("Could not compare distribution and projection totals")?;
+            }
         }
         if can_combine {
+            let write_js = args.js
+                || args.json_pretty
+                || args.js_only
+                || scenario.write_js
+                || scenario.npp_dists_json.is_some();
+            let js_path = write_js.then(|| {
+                scenario
+                    .npp_dists_json
+                    .clone()
+                    .unwrap_or_else(|| npp_dists.unwrap().with_extension("json"))
+            });
             aggregator::aggregate(
                 sa1s_prefs.unwrap(),
                 sa1s_dists.unwrap(),
                 npp_dists.unwrap(),
-                args.js,
-                &scenario.groups,
+                &groups,
+                AggregationOptions {
+                    write_csv: !args.js_only,
+                    js_path: js_path.as_deref(),
+                    pretty_js: args.json_pretty,
+                    renormalise: args.renormalise_districts,
+                    fallback: args.split_fallback,
+                    rounding: args.district_rounding,
+                    with_shares: args.with_shares,
+                    encoding: args.encoding,
+                },
             )
             .context("Could not perform combination phase; stopping.")?;
+            if !args.js_only {
+                manifest.add(scen_name, "combine", npp_dists.unwrap());
+            }
+            if let Some(js_path) = &js_path {
+                manifest.add(scen_name, "combine", js_path);
+            }
         }
+        // a sample can't stand in for a full run (and an interrupted run has already stopped)
+        if let Some(fingerprint) = fingerprint.filter(|_| sample.is_none()) {
+            manifest::record_fingerprint(
+                scenario,
+                phase,
+                &fingerprint,
+                manifest.outputs(scen_name),
+            )?;
+        }
+    }
+    #[cfg(feature = "profile")]
+    if let Some(profiler) = profiler {
+        profiler.finish()?;
+    }
+    if let Some(path) = args.manifest {
+        manifest.write(&path)?;
     }
     info!("Done!");
     Ok(())
@@ -343,7 +970,11 @@ pub fn do_configure(args: CliConfigure) -> color_eyre::eyre::Result<()> {
     let polling_places = args.polling_places;
     let sa1s_breakdown = args.sa1s_breakdown;
     let year = args.year;
-    let state = args.state.map(|x| x.to_state_ab());
+    let state = args
+        .state
+        .map(|x| x.as_str().to_state_ab())
+        .transpose()
+        .context("Invalid --state")?;
 
     let kco = KnownConfigOptions {
         sa1s_dists: None,
@@ -356,22 +987,56 @@ pub fn do_configure(args: CliConfigure) -> color_eyre::eyre::Result<()> {
         state,
     };
 
-    let existings: BTreeMap<String, Scenario> = match from_scen {
-        Some(p) => config::get_scenarios(&config::get_cfg_doc_from_path(&p)?)?,
+    if from_scen.as_deref() == Some(config::STDIN_PATH.as_ref()) {
+        bail!("--from can't be standard input, because that's where the answers to configure's questions come from");
+    }
+    // read once, up front: the output file might be the same file
+    let from_doc = from_scen
+        .as_deref()
+        .map(config::get_cfg_doc_from_path)
+        .transpose()?;
+    let existings: BTreeMap<String, Scenario> = match &from_doc {
+        Some(doc) => config::get_scenarios(doc)?,
         None => BTreeMap::new(),
     };
 
     let existing = existings.values().next();
 
-    let candsfile = File::open(candspath)?;
+    let candsfile = File::open(&candspath)?;
     let candidates = utils::read_candidates(candsfile)?;
 
-    let out = config::cli_scenarios(existing, &candidates, &kco)
+    // Offer to pick up where an unfinished session left off
+    let progress = config::progress_path(&outpath);
+    let mut resumed = BTreeMap::new();
+    if progress.is_file() {
+        match config::read_progress(&progress) {
+            Ok(saved) if !saved.is_empty() => {
+                println!(
+                    "Found {} saved Scenario(s) from an unfinished session: {}",
+                    saved.len(),
+                    saved.keys().join(", ")
+                );
+                let resume = input("Resume from them? [Y]/n: ")?.to_uppercase();
+                if resume.starts_with('Y') || resume.is_empty() {
+                    resumed = saved;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Ignoring saved progress: {e:#}"),
+        }
+    }
+
+    let out = config::cli_scenarios(existing, &candidates, &kco, resumed, &progress)
         .context("Configuration could not be created.")?;
     // eprintln!("{:#?}", out);
 
-    let mut outfile = File::create(outpath)?;
-    config::write_scenarios(&out, &mut outfile)?;
+    let header = config::provenance_comment(&candspath, from_scen.as_deref());
+    // keeping the --from file's comments, layout and [DEFAULT] section
+    config::write_configuration(&outpath, &header, &out, from_doc)?;
+    // finished, so there's nothing left to resume
+    if progress.is_file() {
+        std::fs::remove_file(&progress)?;
+    }
     Ok(())
 }
 
@@ -384,22 +1049,117 @@ pub fn print_license() {
     println!(include_str!("dependencies.tsv"));
 }
 
+/// Performs the `combinations` subcommand.
+pub fn do_combinations(args: &CliCombinations) -> color_eyre::eyre::Result<()> {
+    // The distribution phase sorts the groups, so we do too
+    let mut groups: Vec<&str> = args.groups.iter().map(String::as_str).collect();
+    groups.sort_unstable();
+    if let Some([dup, _]) = groups.windows(2).find(|w| w[0] == w[1]) {
+        bail!("Group {} was given more than once", dup);
+    }
+    let combinations = booths::group_combos(&groups);
+    debug!(
+        "{} groups make {} combinations",
+        groups.len(),
+        combinations.len()
+    );
+    for (i, label) in combinations.iter().enumerate() {
+        println!("{i}\t{label}");
+    }
+    Ok(())
+}
+
+/// Performs the `project-booth` subcommand.
+pub fn do_project_booth(args: CliProjectBooth) -> color_eyre::eyre::Result<()> {
+    let mut cfg = config::get_scenarios(&config::get_cfg_doc_from_path(&args.configfile)?)?;
+    let scenario = cfg
+        .remove(&args.scenario)
+        .with_context(|| {
+            format!(
+                "Requested scenario {} not found in configuration file.",
+                args.scenario
+            )
+        })
+        .with_suggestion(|| {
+            format!(
+                "For a table of available scenarios, try running\n\tnparty list {:?}",
+                &args.configfile
+            )
+        })?;
+    let sa1s_breakdown = scenario
+        .sa1s_breakdown
+        .as_ref()
+        .with_context(|| format!("Scenario {} has no SA1S_BREAKDOWN_PATH", args.scenario))?;
+    multiplier::project_booth(
+        &scenario.labelled_groups(),
+        scenario.state,
+        &scenario.year,
+        &scenario.npp_booths,
+        sa1s_breakdown,
+        scenario.senate_votes.as_deref(),
+        args.encoding,
+        &(args.division, args.booth),
+    )
+}
+
 /// Does the top-level command.
 pub fn actual(m: CliCommands) -> color_eyre::eyre::Result<()> {
-    use CliCommands::{Configure, Data, Example, License, List, Readme, Run, Upgrade};
+    use CliCommands::{
+        Combinations, Compare, Configure, Data, Doctor, Example, License, List, ProjectBooth,
+        Readme, Run, Show, Upgrade, Validate,
+    };
     match m {
+        Combinations(sm) => do_combinations(&sm)?,
+        Compare(sm) => compare::do_compare(&sm)?,
         Configure(sm) => do_configure(sm)?,
         Data(sm) => match sm {
-            CliData::Download { DL_FOLDER } => data::download(&DL_FOLDER)?,
-            CliData::Examine { FILE } => {
-                FILE.map_or_else(data::examine_txt, |x| data::examine_html(&x));
+            CliData::Download {
+                force,
+                flat,
+                no_convert,
+                concurrency,
+                catalogue,
+                catalogue_only,
+                year,
+                state,
+                DL_FOLDER,
+            } => {
+                let selection = data::Selection {
+                    catalogue: catalogue.as_deref(),
+                    catalogue_only,
+                    years: &year,
+                    states: &state,
+                };
+                data::download(&DL_FOLDER, force, flat, no_convert, concurrency, &selection)?;
+            }
+            CliData::Examine {
+                catalogue,
+                catalogue_only,
+                year,
+                state,
+                FILE,
+            } => {
+                let selection = data::Selection {
+                    catalogue: catalogue.as_deref(),
+                    catalogue_only,
+                    years: &year,
+                    states: &state,
+                };
+                match FILE {
+                    Some(x) => data::examine_html(&x, &selection)?,
+                    None => data::examine_txt(&selection)?,
+                }
             }
         },
+        Doctor(sm) => doctor::do_doctor(&sm)?,
+        Validate(sm) => doctor::do_validate(&sm)?,
         Example(sm) => println!("{sm}"),
         License => print_license(),
-        List(sm) => config::list_scenarios(&sm.configfile)?,
+        List(sm) => config::list_scenarios(&sm.configfile, sm.party_details.as_deref())?,
+        ProjectBooth(sm) => do_project_booth(sm)?,
         Readme => println!("{}", include_str!("../README.md")),
         Run(sm) => run(sm)?,
+        Show(sm) => config::show_scenario(&sm.configfile, &sm.scenario)?,
         Upgrade(sm) => match sm {
             CliUpgrade::Prefs(ssm) => upgrades::do_upgrade_prefs(ssm)?,
             CliUpgrade::Sa1s(ssm) => upgrades::do_upgrade_sa1s(ssm)?,
@@ -408,3 +1168,158 @@ pub fn actual(m: CliCommands) -> color_eyre::eyre::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    /// Every bundled example configuration (`nparty example`) loads.
+    #[test]
+    fn examples_parse() {
+        for year in CliExampleYear::value_variants() {
+            let example = CliExample { year: year.clone() }.to_string();
+            let doc = example.parse::<toml_edit::Document>().unwrap();
+            let scenarios = config::get_scenarios(&doc)
+                .unwrap_or_else(|e| panic!("The {year:?} example doesn't load: {e:?}"));
+            assert!(!scenarios.is_empty());
+        }
+    }
+
+    /// Changing an option that affects the outputs changes a scenario's `--only-changed`
+    /// fingerprint; running it again the same way doesn't.
+    #[test]
+    fn options_change_fingerprint() {
+        let example = CliExample {
+            year: CliExampleYear::value_variants()[0].clone(),
+        }
+        .to_string();
+        let scenarios = config::get_scenarios(&example.parse().unwrap()).unwrap();
+        let scenario = scenarios.values().next().unwrap();
+        let fingerprint = |args: &[&str]| {
+            let args = CliRun::parse_from(std::iter::once(&"run").chain(args).chain(&["cfg.toml"]));
+            manifest::scenario_fingerprint(scenario, &OutputOptions::from(&args)).unwrap()
+        };
+
+        let plain = fingerprint(&[]);
+        assert_eq!(plain, fingerprint(&["--threads", "2"]));
+        for changed in [
+            &["--rounding", "largest-remainder"][..],
+            &["--district-rounding", "largest-remainder"],
+            &["--format", "json"],
+            &["--booth-type", "ordinary"],
+            &["--informal-column"],
+            &["--exhausted-label", "None"],
+            &["--encoding", "windows-1252"],
+        ] {
+            assert_ne!(plain, fingerprint(changed), "{changed:?}");
+        }
+    }
+
+    /// Groups configured out of alphabetical order get the same columns in every phase.
+    #[test]
+    fn phases_agree_on_columns() {
+        let dir = std::env::temp_dir().join(format!("nparty-phases-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name);
+        std::fs::write(
+            path("pp.csv"),
+            "\
+2019 Federal Election polling places
+State,DivisionID,DivisionNm,PollingPlaceID,PollingPlaceTypeID,PollingPlaceNm,PremisesNm,PremisesAddress1,PremisesAddress2,PremisesAddress3,PremisesSuburb,PremisesStateAb,PremisesPostCode,Latitude,Longitude
+QLD,1,Alpha,11,1,Town Hall,Hall,1 Main St,,,Alpha,QLD,4000,-27.5,153.0
+",
+        )
+        .unwrap();
+        // two votes for Lnp alone, one for Grn then Alp
+        std::fs::write(
+            path("prefs.csv"),
+            "\
+State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Grn,C:Lnp,A:SMITH John,B:BROWN Bob,C:GREY Tom
+QLD,Alpha,Town Hall,11,1,1,,,1,,,
+QLD,Alpha,Town Hall,11,1,2,,,1,,,
+QLD,Alpha,Town Hall,11,1,3,2,1,,,,
+",
+        )
+        .unwrap();
+        std::fs::write(
+            path("sa1s.csv"),
+            "year,state_ab,div_nm,SA1_id,pp_id,pp_nm,votes\n2019,QLD,Alpha,301,11,Town Hall,3\n",
+        )
+        .unwrap();
+        std::fs::write(path("dists.csv"), "301,North\n").unwrap();
+
+        let mut groups = booths::Parties::new();
+        for (name, ticket) in [("Lnp", "C:Lnp"), ("Grn", "B:Grn"), ("Alp", "A:Alp")] {
+            groups.insert(name.to_owned(), vec![ticket.to_owned()]);
+        }
+        booths::booth_npps(
+            &groups,
+            StateAb::QLD,
+            &[path("prefs.csv")],
+            &path("pp.csv"),
+            &path("NPP_Booths.csv"),
+            DistributionOptions::default(),
+        )
+        .unwrap();
+        multiplier::project(
+            &groups,
+            StateAb::QLD,
+            "2019",
+            &path("NPP_Booths.csv"),
+            &path("sa1s.csv"),
+            &path("SA1_Prefs.csv"),
+            ProjectionOptions {
+                exhausted_label: "Exhausted",
+                coverage: false,
+                min_column_votes: None,
+                senate_votes: None,
+                turnout_scale: TurnoutScale::None,
+                report_unmatched: None,
+                encoding: encoding_rs::UTF_8,
+                rounding: Rounding::None,
+            },
+        )
+        .unwrap();
+        aggregator::aggregate(
+            &path("SA1_Prefs.csv"),
+            &path("dists.csv"),
+            &path("NPP_Dists.csv"),
+            &groups,
+            AggregationOptions {
+                write_csv: true,
+                js_path: None,
+                pretty_js: false,
+                renormalise: false,
+                fallback: SplitFallback::Even,
+                rounding: Rounding::None,
+                with_shares: false,
+                encoding: encoding_rs::UTF_8,
+            },
+        )
+        .unwrap();
+
+        // each file's votes for each combination, by column name
+        let columns = |name: &str| -> BTreeMap<String, f64> {
+            let mut rdr = csv::Reader::from_path(path(name)).unwrap();
+            let header = rdr.headers().unwrap().clone();
+            let row = rdr.records().next().unwrap().unwrap();
+            header
+                .iter()
+                .zip(&row)
+                .filter_map(|(h, v)| Some((h.to_owned(), v.parse().ok()?)))
+                .filter(|(h, _)| h.chars().next().is_some_and(char::is_uppercase))
+                .collect()
+        };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        let booths = columns("NPP_Booths.csv");
+        assert!(close(booths["Lnp"], 2.0));
+        assert!(close(booths["GrnAlp"], 1.0));
+        // all of the booth's votes are from the one SA1, in the one district
+        for name in ["SA1_Prefs.csv", "NPP_Dists.csv"] {
+            let later = columns(name);
+            assert!(close(later["Lnp"], 2.0), "{name}");
+            assert!(close(later["GrnAlp"], 1.0), "{name}");
+            assert!(close(later["Alp"], 0.0), "{name}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 //! The n-party-preferred *distribution* phase.
 use super::term;
-use super::utils::{fix_prefs_headers, open_csvz_from_path, StateAb};
+use super::utils::{
+    create_encoded, encode_writer, fix_prefs_headers, local_input, open_csvz_from_path, StateAb,
+};
 /// We want to reduce each unique preference sequence to some ordering
 ///    of each of the parties. For example, for four parties there are 65 orderings:
 ///   `(0!) + (4 * 1!) + (6 * 2!) + (4 * 3!) + (4!)`
@@ -8,27 +10,35 @@ use super::utils::{fix_prefs_headers, open_csvz_from_path, StateAb};
 /// In fact, there are even more orderings (voters might interleave candidates)
 /// but we will consider the most-preferred candidate from each party as
 /// representing it (e.g. a vote `A1 > B1 > B2 > B3 > A2 > A3` as `A > B`).
-use color_eyre::eyre::{eyre, Context, ContextCompat, Result};
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat, Result};
 use color_eyre::Section;
+use encoding_rs::{Encoding, UTF_8};
 use factorial::Factorial;
 use indexmap::IndexMap;
 use itertools::Itertools;
+use rayon::prelude::*;
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
-use std::fs::create_dir_all;
-use std::path::Path;
+use std::fs::{create_dir_all, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 use string_interner::{backend::StringBackend, symbol::SymbolU16, StringInterner};
-use tracing::{info, trace};
+use tracing::{debug, info, trace, warn};
 
 /// The output file will start with these five columns:
 /// Booth ID, division name, booth name, latitude and longitude.
 const NPP_FIELD_NAMES: [&str; 5] = ["ID", "Division", "Booth", "Latitude", "Longitude"];
+/// The columns of the long-format output; see [`DistributionOptions::long_format`].
+const NPP_LONG_FIELD_NAMES: [&str; 5] = ["ID", "Division", "Booth", "Combination", "Count"];
 // for the output
 // const BOOTH_FIELD_NAMES :[&str; 15] = ["State", "DivisionID", "DivisionNm", "PollingPlaceID", "PollingPlaceTypeID", "PollingPlaceNm",
 //                               "PremisesNm", "PremisesAddress1", "PremisesAddress2", "PremisesAddress3", "PremisesSuburb",
 //                               "PremisesStateAb", "PremisesPostCode", "Latitude", "Longitude"];
 
 /// Preferences files in the 2019+ format begin with these six columns.
-const PREFS_FIELD_NAMES: [&str; 6] = [
+pub const PREFS_FIELD_NAMES: [&str; 6] = [
     "State",
     "Division",
     "Vote Collection Point Name",
@@ -37,30 +47,79 @@ const PREFS_FIELD_NAMES: [&str; 6] = [
     "Paper No",
 ];
 
-/// Special votes will contain one of these strings in the booth name
-const NON_BOOTH_CONVERT: [&str; 4] = ["ABSENT", "POSTAL", "PRE_POLL", "PROVISIONAL"];
+/// Special votes will contain one of these strings in the booth name,
+/// and are aggregated under the corresponding display name (unless configured otherwise;
+/// see [`SpecialVotes`])
+const NON_BOOTH_CONVERT: [(&str, &str); 4] = [
+    ("ABSENT", "Absent"),
+    ("POSTAL", "Postal"),
+    ("PRE_POLL", "Pre-Poll"),
+    ("PROVISIONAL", "Provisional"),
+];
 
-/// Convert the name of a "special" vote
-fn non_booth_convert(input: &str) -> &str {
-    match input {
-        "ABSENT" => "Absent",
-        "POSTAL" => "Postal",
-        "PRE_POLL" => "Pre-Poll",
-        "PROVISIONAL" => "Provisional",
-        _ => "Other",
-    }
+/// Substrings of special votes' booth names, each with the display name to aggregate them under.
+///
+/// A booth counts as the first kind whose substring its name contains.
+pub type SpecialVotes = IndexMap<String, String>;
+
+/// The usual [`SpecialVotes`], from the AEC's naming of them.
+pub fn default_special_votes() -> SpecialVotes {
+    NON_BOOTH_CONVERT
+        .iter()
+        .map(|(substring, name)| ((*substring).to_owned(), (*name).to_owned()))
+        .collect()
 }
 
 // `let` can only be used in a function
 fn ttyjump() -> &'static str {
-    use std::io::IsTerminal;
-    if std::io::stderr().is_terminal() {
+    if term::use_color(&std::io::stderr()) {
         term::TTYJUMP
     } else {
         ""
     }
 }
 
+/// Set when the user interrupts (Ctrl-C) the main distribution loop.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Was the last distribution interrupted (by Ctrl-C)? If so, its output is only partial.
+pub fn was_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Set while the main distribution loop is running.
+static DISTRIBUTING: AtomicBool = AtomicBool::new(false);
+
+/// Clears [`DISTRIBUTING`] however the main loop is left.
+struct DistributingGuard;
+
+impl Drop for DistributingGuard {
+    fn drop(&mut self) {
+        DISTRIBUTING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Install a Ctrl-C handler, once per process.
+///
+/// The first interrupt during the main distribution loop stops reading ballots,
+/// so that partial results can still be written. A second interrupt, or any
+/// interrupt outside of the loop, quits immediately.
+fn install_interrupt_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let rez = ctrlc::set_handler(|| {
+            if DISTRIBUTING.load(Ordering::SeqCst) && !INTERRUPTED.swap(true, Ordering::SeqCst) {
+                eprintln!("\nInterrupted! Writing partial results; press Ctrl-C again to quit.");
+            } else {
+                std::process::exit(130);
+            }
+        });
+        if let Err(e) = rez {
+            warn!("Could not install Ctrl-C handler: {}", e);
+        }
+    });
+}
+
 /// Returns the sum of (1!, ..., input!)
 pub fn factsum(input: usize) -> usize {
     let mut output: usize = 0;
@@ -70,13 +129,21 @@ pub fn factsum(input: usize) -> usize {
     output
 }
 
+/// The default label for the length-0 ordering: ballots that expressed no preference
+/// among the configured groups at all.
+pub const EXHAUSTED: &str = "Exhausted";
+
+/// The label for informal ballots, if they get a column of their own;
+/// see [`DistributionOptions::informal_column`].
+pub const INFORMAL: &str = "Informal";
+
 /// Construct all the orderings of the specified groups.
 ///
 /// (i.e. the sequence of permutations of the groups,
-/// from length 0 to length N)
+/// from length 0 to length N; the first is labelled [`EXHAUSTED`])
 pub fn group_combos(groups: &[&str]) -> Combinations {
     let mut combinations = Vec::with_capacity(factsum(groups.len()));
-    combinations.push(String::from("None"));
+    combinations.push(String::from(EXHAUSTED));
 
     for r in 1..=groups.len() {
         for i in groups.iter().permutations(r) {
@@ -88,6 +155,33 @@ pub fn group_combos(groups: &[&str]) -> Combinations {
     combinations
 }
 
+/// The group labels of `parties` in the order that every phase builds its combinations from:
+/// sorted, whatever order they were configured in.
+///
+/// Pass the result to [`group_combos`] or [`group_orders`], so that the distribution's columns
+/// line up with the projection's and the combination's.
+pub fn sorted_groups(parties: &Parties) -> Vec<&str> {
+    let mut groups: Vec<&str> = parties.keys().map(String::as_str).collect();
+    groups.sort_unstable();
+    groups
+}
+
+/// Like [`group_combos`], but with each ordering given as a list of groups rather than as a label.
+///
+/// The orderings are in the same sequence, so the two can be zipped together.
+pub fn group_orders(groups: &[&str]) -> Vec<Vec<String>> {
+    let mut orders = Vec::with_capacity(factsum(groups.len()) + 1);
+    orders.push(Vec::new());
+
+    for r in 1..=groups.len() {
+        for i in groups.iter().permutations(r) {
+            orders.push(i.iter().map(|x| (**x).to_string()).collect());
+        }
+    }
+
+    orders
+}
+
 /// This represents a row in the `polling_places` file
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)] // look, this isn't aesthetic but it matches the file
@@ -110,15 +204,363 @@ pub struct BoothRecord {
     Longitude: String, // and now we don't have to care about deserialising them either
 }
 
-/// A (Division, Booth) combination
+/// Types of polling place, per the `PollingPlaceTypeID` column of the polling places file.
+///
+/// Special votes (absents, postals, etc.) have no polling place and so are only
+/// included by [`BoothType::All`].
+#[derive(clap::ArgEnum, Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+pub enum BoothType {
+    /// All booths, plus the aggregated special votes (default)
+    All,
+    /// Ordinary (election day) polling places only
+    Ordinary,
+    /// Pre-poll voting centres only
+    PrePoll,
+    /// Special hospital, remote and other mobile teams only
+    Mobile,
+}
+
+impl BoothType {
+    /// Does this filter admit a polling place with the given `PollingPlaceTypeID`?
+    pub const fn includes(self, type_id: usize) -> bool {
+        match self {
+            Self::All => true,
+            Self::Ordinary => type_id == 1,
+            Self::PrePoll => type_id == 5,
+            Self::Mobile => matches!(type_id, 2..=4),
+        }
+    }
+}
+
+/// Which rows of the distribution output to write.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct OutputFilter {
+    /// Which types of polling place to write
+    pub booth_type: BoothType,
+    /// Write the polling places (booths) section
+    pub booths: bool,
+    /// Write the aggregated special votes section
+    pub specials: bool,
+}
+
+impl Default for OutputFilter {
+    fn default() -> Self {
+        Self {
+            booth_type: BoothType::All,
+            booths: true,
+            specials: true,
+        }
+    }
+}
+
+impl OutputFilter {
+    /// Should a polling place with the given `PollingPlaceTypeID` be written?
+    pub const fn includes_booth(self, type_id: usize) -> bool {
+        self.booths && self.booth_type.includes(type_id)
+    }
+
+    /// Should the specials be written?
+    ///
+    /// Special votes have no polling place type, so they only belong with [`BoothType::All`].
+    pub fn includes_specials(self) -> bool {
+        self.specials && self.booth_type == BoothType::All
+    }
+}
+
+/// The format of a preferences file.
+#[derive(clap::ArgEnum, Debug, PartialEq, Eq, Clone, Copy, Default, Serialize)]
+pub enum InputFormat {
+    /// The AEC's formal preferences CSV, in the 2019+ format (default)
+    #[default]
+    AecCsv,
+    /// JSON lines: see [`JsonLinesPrefs`]
+    Jsonl,
+}
+
+/// The format of the distribution output.
+#[derive(clap::ArgEnum, Debug, PartialEq, Eq, Clone, Copy, Default, Serialize)]
+pub enum OutputFormat {
+    /// A CSV with a row for each booth, then the specials (default)
+    #[default]
+    Csv,
+    /// A JSON object for web tooling: see [`BoothsJs`]
+    Json,
+}
+
+/// Distribute only a random sample of the ballots; see [`DistributionOptions::sample`].
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Sample {
+    /// The probability of including each ballot
+    rate: f64,
+    /// The seed for the random choices, so that a sample can be reproduced
+    seed: u64,
+    /// Scale the counts up by `1 / rate`, to approximate the full results
+    scale: bool,
+}
+
+impl Sample {
+    /// A sample at `rate`, which must be more than 0 and no more than 1.
+    pub fn new(rate: f64, seed: u64, scale: bool) -> Result<Self> {
+        if !(rate > 0.0 && rate <= 1.0) {
+            return Err(eyre!("Invalid sampling rate {}", rate))
+                .suggestion("The sampling rate is a probability, e.g. 0.01 for 1% of ballots.");
+        }
+        Ok(Self { rate, seed, scale })
+    }
+
+    /// Scale each booth's counts (without the raw ballot count) up to the full results,
+    /// if asked to, rounding to whole ballots.
+    fn scale_counts<Key>(&self, booth_counts: &mut BoothCounts<Key>) {
+        if !self.scale {
+            return;
+        }
+        let factor = 1.0 / self.rate;
+        for counts in booth_counts.values_mut() {
+            #[allow(clippy::cast_precision_loss, clippy::cast_sign_loss)]
+            // counts are far below 2^52, and non-negative
+            counts.map_counts(|count| (count as f64 * factor).round() as usize);
+        }
+    }
+}
+
+/// Decides which ballots are in a [`Sample`].
+///
+/// This is `SplitMix64`: statistically fine for sampling, and simple enough that
+/// the same seed will always give the same sample.
+struct Sampler {
+    state: u64,
+    /// Ballots are included when the next random number is below this
+    threshold: u64,
+}
+
+impl Sampler {
+    fn new(sample: Sample) -> Self {
+        #[allow(clippy::cast_precision_loss, clippy::cast_sign_loss)] // saturates at a rate of 1
+        let threshold = (sample.rate * u64::MAX as f64) as u64;
+        Self {
+            state: sample.seed,
+            threshold,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Is the next ballot in the sample?
+    fn include(&mut self) -> bool {
+        self.threshold == u64::MAX || self.next_u64() < self.threshold
+    }
+}
+
+/// Where (and how often) to explain why ballots weren't BTL-formal;
+/// see [`DistributionOptions::explain_formality`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FormalityAudit<'a> {
+    /// The CSV file to write the explanations to
+    pub path: &'a Path,
+    /// Explain one in this many of the ballots that weren't BTL-formal
+    pub every: usize,
+}
+
+/// Where the formality audit goes: alongside `npp_booths_path`, e.g. `NPP_Booths_Formality.csv`.
+pub fn formality_audit_path(npp_booths_path: &Path) -> PathBuf {
+    let mut audit_fn = npp_booths_path.file_stem().unwrap_or_default().to_owned();
+    audit_fn.push("_Formality.csv");
+    npp_booths_path.with_file_name(audit_fn)
+}
+
+/// Options for [`booth_npps`], beyond its inputs and outputs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(clippy::struct_excessive_bools)] // these are all independent options
+pub struct DistributionOptions<'a> {
+    /// Which rows to include in the output
+    pub filter: OutputFilter,
+    /// The format of the preferences file(s)
+    pub input_format: InputFormat,
+    /// Warn about preferences greater than the number of candidates
+    pub check_prefs: bool,
+    /// The most fields a ballot may have (default: four times the header length)
+    pub max_fields: Option<usize>,
+    /// Use [`PlainKeys`] rather than interning division and booth names
+    pub no_intern: bool,
+    /// Use the preferences header as-is, rather than repairing it with [`fix_prefs_headers`]
+    pub no_header_repair: bool,
+    /// The label for the first column; see [`EXHAUSTED`]
+    pub exhausted_label: &'a str,
+    /// How many formal ballots there should be, if known (e.g. from the AEC's published totals)
+    pub expected_formal: Option<usize>,
+    /// Only warn if `expected_formal` doesn't match, rather than failing
+    pub warn_formal_mismatch: bool,
+    /// Write booths that aren't in the polling places file (without their details), rather than failing
+    pub allow_missing_booths: bool,
+    /// Decide which booths are special votes by whether they're in the polling places file
+    /// (and so have a `PollingPlaceTypeID`), rather than by their names; see [`aggregate_specials`]
+    pub specials_by_id: bool,
+    /// Write each division's booths as soon as it's done, rather than keeping every booth in memory.
+    ///
+    /// This needs the ballots to be grouped by division, with the divisions in alphabetical order
+    /// (as AEC files are). If they aren't, distribution starts again without streaming.
+    pub stream: bool,
+    /// The encoding of the output; see [`crate::utils::output_encoding`]
+    pub encoding: &'static Encoding,
+    /// Write an explanation of why some of the ballots weren't BTL-formal, for auditing
+    pub explain_formality: Option<FormalityAudit<'a>>,
+    /// Only distribute a random sample of the ballots, e.g. to quickly check a full run's proportions.
+    ///
+    /// `expected_formal` isn't checked for a sample.
+    pub sample: Option<Sample>,
+    /// Store each booth's counts sparsely (see [`ComboCounts`]) if there are more combinations than this.
+    ///
+    /// This saves a lot of memory for scenarios with five or more groups, at some cost in speed.
+    pub sparse_above: Option<usize>,
+    /// Write a column for each unordered set of groups (see [`UnorderedFold`]),
+    /// rather than for each ordering
+    pub unordered: bool,
+    /// Write one row per booth and (non-zero) combination, as `NPP_LONG_FIELD_NAMES`,
+    /// rather than one row per booth with a column for each combination.
+    ///
+    /// The projection phase needs the usual wide format.
+    pub long_format: bool,
+    /// The format of the output. JSON is always UTF-8, and has no long format.
+    pub output_format: OutputFormat,
+    /// Count informal ballots (see [`distribute_preference`]) in an extra last column,
+    /// rather than with the ballots that preferenced none of the groups.
+    ///
+    /// Not with `unordered`, and the projection phase can't use the extra column.
+    pub informal_column: bool,
+    /// The kinds of special votes, if not the [`default_special_votes`]
+    pub special_votes: Option<&'a SpecialVotes>,
+    /// Classify ballots on this many threads, or as many as are available if `None`;
+    /// see [`BallotReader::set_threads`]. The output is the same either way.
+    pub threads: Option<usize>,
+}
+
+impl Default for DistributionOptions<'_> {
+    fn default() -> Self {
+        Self {
+            filter: OutputFilter::default(),
+            input_format: InputFormat::default(),
+            check_prefs: false,
+            max_fields: None,
+            no_intern: false,
+            no_header_repair: false,
+            exhausted_label: EXHAUSTED,
+            expected_formal: None,
+            warn_formal_mismatch: false,
+            allow_missing_booths: false,
+            specials_by_id: false,
+            stream: false,
+            encoding: UTF_8,
+            explain_formality: None,
+            sample: None,
+            sparse_above: None,
+            unordered: false,
+            long_format: false,
+            output_format: OutputFormat::Csv,
+            informal_column: false,
+            special_votes: None,
+            threads: None,
+        }
+    }
+}
+
+/// An interned (Division, Booth) combination
 type DivBooth = (SymbolU16, SymbolU16);
 
+/// The string interner used for [`DivBooth`] keys
+type Interner = StringInterner<StringBackend<SymbolU16>>;
+
+/// How (Division, Booth) names become map keys during distribution.
+///
+/// Interning is the fast path: u16s are much cheaper keys than strings are.
+/// [`PlainKeys`] are slower, but can be inspected directly (in tests or a debugger).
+pub trait BoothKeys {
+    type Key: Clone + Eq + std::hash::Hash + std::fmt::Debug;
+    /// Get the key for a (Division, Booth)
+    fn key(&mut self, division: &str, booth: &str) -> Self::Key;
+    /// Get the (Division, Booth) back from a key
+    fn resolve<'a>(&'a self, key: &'a Self::Key) -> (&'a str, &'a str);
+}
+
+impl BoothKeys for Interner {
+    type Key = DivBooth;
+    fn key(&mut self, division: &str, booth: &str) -> DivBooth {
+        (self.get_or_intern(division), self.get_or_intern(booth))
+    }
+    fn resolve<'a>(&'a self, key: &'a DivBooth) -> (&'a str, &'a str) {
+        // (`Self::resolve` is the inherent method.) Keys only ever come from `key()`, so they always resolve
+        (
+            Self::resolve(self, key.0).unwrap(),
+            Self::resolve(self, key.1).unwrap(),
+        )
+    }
+}
+
+/// Plain `(String, String)` keys, with no interning.
+#[derive(Debug, Default)]
+pub struct PlainKeys;
+
+impl BoothKeys for PlainKeys {
+    type Key = (String, String);
+    fn key(&mut self, division: &str, booth: &str) -> Self::Key {
+        (division.to_owned(), booth.to_owned())
+    }
+    fn resolve<'a>(&'a self, key: &'a Self::Key) -> (&'a str, &'a str) {
+        (&key.0, &key.1)
+    }
+}
+
 /// A map from the party name to a list of (pseudo)candidates of that party.
 pub type Parties = IndexMap<String, Vec<String>>;
 
 /// The headers, essentially
 pub type Combinations = Vec<String>;
 
+/// Going from an ordering of groups to its label in [`Combinations`].
+pub trait CombinationLabels {
+    /// The label for `order` (of group indexes, as for [`calculate_index`]),
+    /// e.g. `"AlpGrn"` for `[0, 1]` when the groups are `[Alp, Grn, ...]`.
+    ///
+    /// `None` if `order` isn't a valid ordering, or if these aren't the complete
+    /// [`group_combos`] of some number of groups.
+    fn label_for(&self, order: &[usize]) -> Option<&str>;
+}
+
+/// How many groups there are, given how many [`Combinations`] there are.
+/// `None` if that isn't the number of combinations of any number of groups.
+fn combos_groups_count(combos_count: usize) -> Option<usize> {
+    // there are N!/(N-k)! orderings of each length k, so find the N that adds up to our length
+    let mut groups_count = 0;
+    loop {
+        let count: usize = (0..=groups_count)
+            .map(|k| (groups_count - k + 1..=groups_count).product::<usize>())
+            .sum();
+        match count.cmp(&combos_count) {
+            std::cmp::Ordering::Less => groups_count += 1,
+            std::cmp::Ordering::Equal => return Some(groups_count),
+            std::cmp::Ordering::Greater => return None,
+        }
+    }
+}
+
+impl CombinationLabels for [String] {
+    fn label_for(&self, order: &[usize]) -> Option<&str> {
+        let groups_count = combos_groups_count(self.len())?;
+        if order.len() > groups_count
+            || !order.iter().all_unique()
+            || order.iter().any(|&x| x >= groups_count)
+        {
+            return None;
+        }
+        Some(&self[calculate_index(order, groups_count)])
+    }
+}
+
 /// A mapping between a party ID and a (pseudo)candidate number
 /// (such numbers are relative column indexes)
 type Groups = HashMap<usize, Vec<usize>>;
@@ -147,201 +589,1348 @@ fn make_combo_tree(groups_count: usize) -> ComboTree {
     output
 }
 
+/// The separator between groups in the labels of [`UnorderedFold`] columns, e.g. `Alp+Grn`.
+pub const UNORDERED_SEPARATOR: &str = "+";
+
+/// A precomputed folding of [`Combinations`] into unordered sets of groups, so that e.g.
+/// `AlpGrn` and `GrnAlp` are both counted as `Alp+Grn`.
+///
+/// The sets are in the same sort of order as the combinations: by size, then by group.
+/// See [`DistributionOptions::unordered`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnorderedFold {
+    /// The label of each set; the first (the empty set) is the same as the first combination
+    pub labels: Vec<String>,
+    /// For each combination index, the index of its set in `labels`
+    pub map: Vec<usize>,
+}
+
+impl UnorderedFold {
+    /// Work out the folding for `combinations`, as from [`group_combos`].
+    pub fn new(combinations: &[String]) -> Result<Self> {
+        let groups_count = combos_groups_count(combinations.len()).with_context(|| {
+            format!(
+                "{} isn't the number of combinations of any number of groups",
+                combinations.len()
+            )
+        })?;
+        let mut orders: Vec<(Vec<usize>, usize)> =
+            make_combo_tree(groups_count).into_iter().collect();
+        orders.sort_unstable_by_key(|(_, idx)| *idx);
+
+        // each set is first seen in its sorted order, so sets come out sorted too
+        let mut sets: IndexMap<Vec<usize>, usize> = IndexMap::new();
+        let mut map = Vec::with_capacity(orders.len());
+        for (mut order, _) in orders {
+            order.sort_unstable();
+            let next = sets.len();
+            map.push(*sets.entry(order).or_insert(next));
+        }
+        let labels = sets
+            .keys()
+            .map(|set| match set.as_slice() {
+                [] => combinations[0].clone(),
+                set => set
+                    .iter()
+                    .filter_map(|&g| combinations.label_for(&[g]))
+                    .join(UNORDERED_SEPARATOR),
+            })
+            .collect();
+        Ok(Self { labels, map })
+    }
+
+    /// Sum `counts` (one for each combination) into one for each set.
+    pub fn fold(&self, counts: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut out = vec![0; self.labels.len()];
+        for (idx, count) in counts.enumerate() {
+            out[self.map[idx]] += count;
+        }
+        out
+    }
+}
+
 /// Perform the distribution over a specified set of parties.
 ///
-/// * `formal_prefs_path`: the input preferences (one row per ballot)
+/// * `formal_prefs_paths`: the input preferences (one row per ballot), possibly split into several parts.
+///   Any of these may be an `http(s)://` URL, which is downloaded first (see [`local_input`]).
 /// * `polling_places_path`: the input info on polling places
 /// * `npp_booths_path`: where to write the output.
+/// * `options`: see [`DistributionOptions`].
+///
+/// Apart from streaming, this opens the files for [`booth_npps_from_readers`].
 pub fn booth_npps(
     parties: &Parties,
     state: StateAb,
-    formal_prefs_path: &Path,
+    formal_prefs_paths: &[PathBuf],
     polling_places_path: &Path,
     npp_booths_path: &Path,
-) -> Result<()> {
-    // TODO: make this take Read objects instead of paths.
-    //       otherwise it'll never work in WASM.
+    options: DistributionOptions,
+) -> Result<DistributionSummary> {
+    // any parts given as URLs are downloaded first (or found from an earlier download)
+    let formal_prefs_paths: Vec<PathBuf> = formal_prefs_paths
+        .iter()
+        .map(|p| local_input(p))
+        .collect::<Result<_>>()?;
+    if options.input_format == InputFormat::AecCsv {
+        for part in &formal_prefs_paths {
+            check_prefs_state(part, state)?;
+        }
+    }
+
+    if options.stream {
+        let streamed = if options.no_intern {
+            stream_booth_npps(
+                &mut PlainKeys,
+                parties,
+                state,
+                &formal_prefs_paths,
+                polling_places_path,
+                npp_booths_path,
+                options,
+            )?
+        } else {
+            stream_booth_npps(
+                &mut Interner::new(),
+                parties,
+                state,
+                &formal_prefs_paths,
+                polling_places_path,
+                npp_booths_path,
+                options,
+            )?
+        };
+        if let Some(summary) = streamed {
+            return Ok(summary);
+        }
+        warn!("The ballots aren't grouped by division in alphabetical order, so can't be streamed. Starting again without streaming.");
+    }
+
+    let formal_prefs = formal_prefs_paths
+        .iter()
+        .map(|p| Ok((p.display().to_string(), open_csvz_from_path(p)?)))
+        .collect::<Result<_>>()?;
+    booth_npps_from_readers(
+        parties,
+        state,
+        formal_prefs,
+        open_csvz_from_path(polling_places_path)?,
+        create_output(npp_booths_path)?,
+        DistributionOptions {
+            stream: false,
+            ..options
+        },
+    )
+}
+
+/// [`booth_npps`], but reading from and writing to anything at all rather than paths:
+/// each part of the preferences in `formal_prefs` (not zipped, and with a name for messages),
+/// the polling places (including their title line) and the output.
+///
+/// There's no streaming (see [`DistributionOptions::stream`]), since if the ballots turn out
+/// not to be grouped by division, the preferences would have to be read again.
+pub fn booth_npps_from_readers(
+    parties: &Parties,
+    state: StateAb,
+    formal_prefs: Vec<(String, Box<dyn Read>)>,
+    polling_places: impl Read,
+    npp_booths: impl Write,
+    options: DistributionOptions,
+) -> Result<DistributionSummary> {
+    if options.stream {
+        bail!("Streaming needs the preferences as paths, as it might need to read them twice");
+    }
+    if options.no_intern {
+        return booth_npps_from_readers_with(
+            &mut PlainKeys,
+            parties,
+            state,
+            formal_prefs,
+            polling_places,
+            npp_booths,
+            options,
+        );
+    }
 
     // String Interning: because u16s are much cheaper keys than strings are
-    let mut interner = StringInterner::<StringBackend<SymbolU16>>::new();
+    let mut interner = Interner::new();
+    let summary = booth_npps_from_readers_with(
+        &mut interner,
+        parties,
+        state,
+        formal_prefs,
+        polling_places,
+        npp_booths,
+        options,
+    )?;
+    trace!(
+        "Interned {} strings, with capacity for {}.",
+        interner.len(),
+        u16::MAX
+    );
+    Ok(summary)
+}
 
+/// [`booth_npps_from_readers`], with the given way of keying booths.
+fn booth_npps_from_readers_with<K: BoothKeys>(
+    keys: &mut K,
+    parties: &Parties,
+    state: StateAb,
+    formal_prefs: Vec<(String, Box<dyn Read>)>,
+    polling_places: impl Read,
+    npp_booths: impl Write,
+    options: DistributionOptions,
+) -> Result<DistributionSummary> {
     info!("\tLoading polling places and candidates");
-    let booths = load_polling_places(state, polling_places_path, &mut interner)?;
+    let booths = read_polling_places(state, polling_places, keys)?;
+    let parts = Box::new(formal_prefs.into_iter().map(Ok));
 
-    // The 2019 format is that there are a few fixed headers ... and then a field for each [pseudo]candidate
-    let mut prefs_rdr = csv::ReaderBuilder::new()
-        .flexible(true)
-        .escape(Some(b'\\'))
-        // .trim(csv::Trim::Fields) // Trimming at this stage more than doubles run time
-        .from_reader(open_csvz_from_path(formal_prefs_path)?);
+    let Tally::InMemory(mut summary, mut booth_counts) =
+        tally(keys, parties, parts, options, None)?
+    else {
+        unreachable!("only streaming can stream");
+    };
+    let division_specials = settle_counts(&mut booth_counts, &mut summary, &booths, keys, options)?;
+
+    info!("\t\tWriting File");
+    let mut wtr = BoothWriter::new(npp_booths, &summary.combinations, &booths, options)?;
+    wtr.write_booths(&booth_counts, keys)?;
+    wtr.finish(division_specials)?;
+    Ok(summary)
+}
+
+/// [`booth_npps`] with streaming (see [`DistributionOptions::stream`]), with the given way of keying booths.
+///
+/// `None` if the ballots turned out not to be grouped by division, so couldn't be streamed.
+fn stream_booth_npps<K: BoothKeys>(
+    keys: &mut K,
+    parties: &Parties,
+    state: StateAb,
+    formal_prefs_paths: &[PathBuf],
+    polling_places_path: &Path,
+    npp_booths_path: &Path,
+    options: DistributionOptions,
+) -> Result<Option<DistributionSummary>> {
+    info!("\tLoading polling places and candidates");
+    let booths = load_polling_places(state, polling_places_path, keys)?;
+    let output: Box<dyn Write> = Box::new(create_output(npp_booths_path)?);
+    let tallied = tally(
+        keys,
+        parties,
+        open_parts(formal_prefs_paths),
+        options,
+        Some((output, &booths)),
+    )?;
+    match tallied {
+        Tally::Streamed(summary) => Ok(Some(summary)),
+        _ => Ok(None),
+    }
+}
+
+/// What [`booth_npps`] distributed, for callers that want more than the file it writes.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DistributionSummary {
+    /// The combinations, in the order of the output's columns (before any relabelling or folding),
+    /// then [`INFORMAL`] if it has a column
+    pub combinations: Combinations,
+    /// How many ballots were distributed (if sampling, just the ones in the sample)
+    pub ballots: usize,
+    /// How many of those were formal below the line
+    pub btl_count: usize,
+    /// How many of those were informal (see [`distribute_preference`])
+    pub informal: usize,
+    /// How many ballots were distributed in each division, special votes included
+    pub division_totals: BTreeMap<String, usize>,
+}
+
+/// Check and (if sampling) scale the tallied `booth_counts`, totalling them by division in the `summary`,
+/// then take out the specials, returning them aggregated by division.
+fn settle_counts<K: BoothKeys>(
+    booth_counts: &mut BoothCounts<K::Key>,
+    summary: &mut DistributionSummary,
+    booths: &HashMap<K::Key, BoothRecord>,
+    keys: &K,
+    options: DistributionOptions,
+) -> Result<BTreeMap<(String, String), Vec<usize>>> {
+    check_booth_totals(booth_counts, keys, &mut summary.division_totals)?;
+    if let Some(sample) = options.sample {
+        sample.scale_counts(booth_counts);
+    }
+
+    info!("\t\tAggregating Absents, Postals, Prepolls & Provisionals");
+    Ok(aggregate_specials(
+        booth_counts,
+        &summary.combinations,
+        keys,
+        options.specials_by_id.then_some(booths),
+        options.special_votes,
+    ))
+}
+
+/// The parts of a preferences file, each with a name for messages, opened only as they're needed.
+type PrefsParts<'a> = Box<dyn Iterator<Item = Result<(String, Box<dyn Read>)>> + 'a>;
+
+/// The [`PrefsParts`] at `paths`, each opened with [`open_csvz_from_path`].
+fn open_parts(paths: &[PathBuf]) -> PrefsParts<'_> {
+    Box::new(
+        paths
+            .iter()
+            .map(|p| Ok((p.display().to_string(), open_csvz_from_path(p)?))),
+    )
+}
+
+/// An output to stream to, and the polling places; see [`tally_ballots`].
+type StreamTo<'a, Key> = (Box<dyn Write>, &'a HashMap<Key, BoothRecord>);
+
+/// The number of ballots for each preference combination, by booth
+type BoothCounts<Key> = HashMap<Key, ComboCounts>;
+
+/// One booth's number of ballots for each preference combination.
+///
+/// Scenarios with many groups have thousands of combinations, most of which get no ballots
+/// at any given booth, so the sparse form only stores the ones that do.
+/// See [`DistributionOptions::sparse_above`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComboCounts {
+    /// A count for every combination
+    Dense(Vec<usize>),
+    /// Only the non-zero counts, by combination index; the rest are zero
+    Sparse {
+        len: usize,
+        counts: HashMap<usize, usize>,
+    },
+}
+
+impl ComboCounts {
+    /// `len` zero counts, stored sparsely if `sparse`.
+    pub fn zeroed(len: usize, sparse: bool) -> Self {
+        if sparse {
+            Self::Sparse {
+                len,
+                counts: HashMap::new(),
+            }
+        } else {
+            Self::Dense(vec![0; len])
+        }
+    }
+
+    /// Add one to the count at `idx`.
+    #[inline]
+    pub fn increment(&mut self, idx: usize) {
+        match self {
+            Self::Dense(v) => v[idx] += 1,
+            Self::Sparse { counts, .. } => *counts.entry(idx).or_default() += 1,
+        }
+    }
+
+    /// The number of counts (including zeroes).
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Dense(v) => v.len(),
+            Self::Sparse { len, .. } => *len,
+        }
+    }
+
+    /// Remove and return the last count.
+    pub fn pop(&mut self) -> Option<usize> {
+        match self {
+            Self::Dense(v) => v.pop(),
+            Self::Sparse { len, counts } => {
+                *len = len.checked_sub(1)?;
+                Some(counts.remove(len).unwrap_or_default())
+            }
+        }
+    }
+
+    /// The sum of the counts.
+    pub fn sum(&self) -> usize {
+        match self {
+            Self::Dense(v) => v.iter().sum(),
+            Self::Sparse { counts, .. } => counts.values().sum(),
+        }
+    }
 
-    let prefs_headers = prefs_rdr.headers()?.clone();
-    trace!("\nNo actual preferences processed yet, but we successfully opened the zipfile and the raw headers look like this:\n{:#?}", prefs_headers);
+    /// Every count in order, including zeroes.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len()).map(move |i| match self {
+            Self::Dense(v) => v[i],
+            Self::Sparse { counts, .. } => counts.get(&i).copied().unwrap_or_default(),
+        })
+    }
+
+    /// Every count in order, as a dense vector.
+    #[cfg(test)]
+    pub fn to_vec(&self) -> Vec<usize> {
+        self.iter().collect()
+    }
+
+    /// Replace each (non-zero) count with `f` of it.
+    fn map_counts(&mut self, f: impl Fn(usize) -> usize) {
+        match self {
+            Self::Dense(v) => v.iter_mut().for_each(|x| *x = f(*x)),
+            Self::Sparse { counts, .. } => counts.values_mut().for_each(|x| *x = f(*x)),
+        }
+    }
+}
 
-    let above_start = PREFS_FIELD_NAMES.len();
-    // 2022 lack-of-quoting problems
-    let prefs_headers_fixed = fix_prefs_headers(&prefs_headers, above_start);
+impl From<Vec<usize>> for ComboCounts {
+    fn from(counts: Vec<usize>) -> Self {
+        Self::Dense(counts)
+    }
+}
+
+/// The result of [`tally_ballots`].
+enum Tally<Key> {
+    /// Every booth's counts, ready to write (the summary's division totals are still to come)
+    InMemory(DistributionSummary, BoothCounts<Key>),
+    /// Every booth was written as it was completed
+    Streamed(DistributionSummary),
+    /// Streaming was asked for, but the ballots weren't grouped by division
+    NotGrouped,
+}
+
+/// Check that an AEC preferences file is for `state`, going by its first ballot's `State`.
+///
+/// Otherwise the first sign of a mix-up is that none of the groups' candidates can be found.
+pub fn check_prefs_state(prefs_path: &Path, state: StateAb) -> Result<()> {
+    let mut rdr = csv_prefs_reader(open_csvz_from_path(prefs_path)?);
+    let mut record = csv::ByteRecord::new();
+    if !rdr.read_byte_record(&mut record)? {
+        return Ok(());
+    }
+    let found = String::from_utf8_lossy(record.get(0).unwrap_or_default());
+    // Anything that isn't a state (like 2016's `---` line) is left for later checks
+    match found.trim().parse::<StateAb>() {
+        Ok(found) if found != state => Err(eyre!(
+            "{} is a preferences file for {}, but the scenario is for {}",
+            prefs_path.display(),
+            found,
+            state
+        ))
+        .suggestion("Check the scenario's STATE and PREFS_PATH."),
+        _ => Ok(()),
+    }
+}
 
-    /* ***** Get candidate/party/group info ***** */
-    let (combinations, below_start, groups_above, groups_below) =
-        make_candidate_info(parties, &prefs_headers_fixed, above_start)?;
+/// [`tally_ballots`] for whichever of the [`InputFormat`]s is in `options`.
+fn tally<K: BoothKeys>(
+    keys: &mut K,
+    parties: &Parties,
+    parts: PrefsParts,
+    options: DistributionOptions,
+    stream_to: Option<StreamTo<K::Key>>,
+) -> Result<Tally<K::Key>> {
+    match options.input_format {
+        InputFormat::AecCsv => tally_ballots(
+            keys,
+            parties,
+            parts,
+            options,
+            |r| Ok(csv_prefs_reader(r)),
+            stream_to,
+        ),
+        InputFormat::Jsonl => tally_ballots(
+            keys,
+            parties,
+            parts,
+            options,
+            JsonLinesPrefs::new,
+            stream_to,
+        ),
+    }
+}
 
-    let mut below_groups: Vec<usize> = vec![usize::MAX; prefs_headers_fixed.len()];
-    for (g, v) in &groups_below {
-        for c in v {
-            below_groups[*c + above_start - 1] = *g;
+/// Read every ballot from the preferences file (in each of its parts), tallying them by booth.
+///
+/// Each part is made into a [`PrefsSource`] by `source`.
+///
+/// If `stream_to` is given (an output and the polling places), each division's booths
+/// are written out as soon as the next division starts; see [`DistributionOptions::stream`].
+fn tally_ballots<K: BoothKeys, S: PrefsSource>(
+    keys: &mut K,
+    parties: &Parties,
+    mut parts: PrefsParts,
+    options: DistributionOptions,
+    source: impl Fn(Box<dyn Read>) -> Result<S>,
+    stream_to: Option<StreamTo<K::Key>>,
+) -> Result<Tally<K::Key>> {
+    // Large states' preferences may come in several parts, which we read in sequence
+    let (_, first_part) = parts.next().context("No preferences file was given")??;
+    let mut ballots =
+        BallotReader::from_source(parties, source(first_part)?, !options.no_header_repair)?;
+    ballots.set_range_check(options.check_prefs);
+    if let Some(m) = options.max_fields {
+        ballots.set_max_fields(m);
+    }
+    let threads = options.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    debug!("\tClassifying ballots on {} threads", threads);
+    ballots.set_threads(threads)?;
+    if let Some(sample) = options.sample {
+        ballots.set_sample(sample);
+    }
+    let mut combinations = ballots.combos().clone();
+    if options.informal_column {
+        if options.unordered {
+            bail!("An informal column can't be folded into unordered sets");
         }
+        combinations.push(INFORMAL.to_owned());
     }
-    // trace!("groups_below: {:?}", groups_below);
-    // trace!("below_groups: {:?}", below_groups);
+
+    let mut audit = match options.explain_formality {
+        Some(FormalityAudit { path, every }) => {
+            ballots.set_explain_every(every);
+            let mut wtr = csv::Writer::from_writer(create_encoded(path, options.encoding)?);
+            wtr.write_record([
+                "Division",
+                "Booth",
+                "Batch No",
+                "Paper No",
+                "Combination",
+                "Reason",
+            ])
+            .context("error writing formality audit header")?;
+            Some(wtr)
+        }
+        None => None,
+    };
 
     /* ***** Start of main iteration ***** */
     info!("\tDistributing Preferences");
     eprintln!(); // still a normal eprintln for progress-jump reasons
 
+    install_interrupt_handler();
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    DISTRIBUTING.store(true, Ordering::SeqCst);
+    let guard = DistributingGuard;
+
     // Store all the things! DivBooth : rest of the derived columns
-    let mut booth_counts: HashMap<DivBooth, Vec<usize>> = HashMap::new();
+    let mut booth_counts: BoothCounts<K::Key> = HashMap::new();
+    let sparse = options
+        .sparse_above
+        .is_some_and(|threshold| combinations.len() > threshold);
+    if sparse {
+        debug!(
+            "\tStoring counts sparsely, as there are {} combinations",
+            combinations.len()
+        );
+    }
     let mut progress: usize = 0; // Diagnostics
-    let mut btl_count: usize = 0; // Diagnostics
 
-    // Hoists
-    let mut bests: Vec<(usize, usize)> =
-        Vec::with_capacity(groups_below.len().max(groups_above.len()));
-    let mut order: Vec<usize> = Vec::with_capacity(bests.len());
-    // let mut record = csv::StringRecord::new(); // Performance: <https://blog.burntsushi.net/csv/#amortizing-allocations>
-    let mut record =
-        csv::ByteRecord::with_capacity(prefs_headers_fixed.capacity(), prefs_headers_fixed.len());
-    // while prefs_rdr.read_record(&mut record)? {
-    while prefs_rdr.read_byte_record(&mut record)? {
-        // String interning in action
-        // let divnm = interner.get_or_intern(&record[1]);
-        // let boothnm = interner.get_or_intern(&record[2]);
-        let divnm = interner.get_or_intern(std::str::from_utf8(&record[1])?);
-        let boothnm = interner.get_or_intern(std::str::from_utf8(&record[2])?);
-
-        if (record[1]).starts_with(b"---") {
-            // ^^ This conditional might be inverted for testing; 2019+ files do NOT contain a `---` line.
-            return Result::Err(eyre!("Preferences file is in the 2016 format."))
-                .suggestion("Upgrade the file to the 2019+ format with:\n\tnparty upgrade prefs");
-        }
-        /* // Saving for reference
-        // First we must determine if it's ATL or BTL, then select appropriate candidates.
-        let is_btl: bool = check_btl(&record, below_start);
-        btl_count += if is_btl { 1 } else { 0 };
-        let groups_which = if is_btl { &groups_below } else { &groups_above };
-
-        // Next, actually distribute the preference.
-        let pref_idx_old = distribute_preference(
-            &record,
-            groups_which,
-            &combo_tree,
-            above_start,
-            prefs_headers_fixed.len() - above_start,
-            &mut bests,
-            &mut order,
+    // When streaming, `booth_counts` only holds the current division
+    let mut stream = stream_to
+        .map(|(output, booths)| BoothWriter::new(output, &combinations, booths, options))
+        .transpose()?;
+    let mut division: Vec<u8> = Vec::new();
+    let mut division_specials = BTreeMap::new();
+    let mut division_totals = BTreeMap::new();
+
+    let mut batch = BallotBatch::default();
+    'parts: loop {
+        while ballots.next_batch(&mut batch)? {
+            for (record, pref_idx, informal, explanation) in batch.iter() {
+                let pref_idx = if informal && options.informal_column {
+                    combinations.len() - 1
+                } else {
+                    pref_idx
+                };
+                if INTERRUPTED.load(Ordering::Relaxed) {
+                    break 'parts;
+                }
+                if let (Some(wtr), Some(reason)) = (audit.as_mut(), explanation) {
+                    let combination = match pref_idx {
+                        0 => options.exhausted_label,
+                        i => &combinations[i],
+                    };
+                    wtr.write_record([
+                        &record[1],
+                        &record[2],
+                        &record[4],
+                        &record[5],
+                        combination.as_bytes(),
+                        reason.as_bytes(),
+                    ])
+                    .context("error writing formality audit line")?;
+                }
+                if let Some(wtr) = stream.as_mut() {
+                    if record[1] != division[..] {
+                        if record[1] < division[..] {
+                            return Ok(Tally::NotGrouped);
+                        }
+                        flush_division(
+                            wtr,
+                            &mut booth_counts,
+                            &mut division_specials,
+                            &mut division_totals,
+                            &combinations,
+                            keys,
+                        )?;
+                        division = record[1].to_vec();
+                    }
+                }
+                // String interning in action (usually)
+                let divbooth = keys.key(
+                    std::str::from_utf8(&record[1])?,
+                    std::str::from_utf8(&record[2])?,
+                );
+
+                // ... and store. The extra last entry is a raw ballot count, for `check_booth_totals`
+                let booth = booth_counts
+                    .entry(divbooth)
+                    .or_insert_with(|| ComboCounts::zeroed(combinations.len() + 1, sparse));
+                booth.increment(pref_idx);
+                booth.increment(combinations.len());
+
+                progress += 1;
+                if progress % 100_000 == 0 {
+                    trace!("{:?}", record);
+                    info!(
+                        "{}\t\tPreferencing progress: {} ballots",
+                        ttyjump(),
+                        progress
+                    );
+                }
+            }
+        }
+        match parts.next() {
+            Some(part) => {
+                let (name, part) = part?;
+                debug!("Continuing with {}", name);
+                ballots
+                    .next_source(source(part)?)
+                    .with_context(|| format!("Could not continue with {name}"))?;
+            }
+            None => break,
+        }
+    }
+
+    drop(guard);
+    if let Some(mut wtr) = audit {
+        wtr.flush().context("error finalising formality audit")?;
+    }
+
+    info!(
+        "{}\t\tPreferencing complete: {} ballots ({} were BTL)",
+        ttyjump(),
+        progress,
+        ballots.btl_count()
+    );
+    if ballots.informal_count() > 0 {
+        info!(
+            "\t\t{} ballots were informal, with no first preference above the line (nor a formal vote below it)",
+            ballots.informal_count()
+        );
+    }
+    if options.sample.is_some() {
+        info!(
+            "\t\tThat was a sample: {} more ballots were left out",
+            ballots.unsampled_count()
+        );
+    }
+    if ballots.out_of_range_count() > 0 {
+        warn!(
+            "{} ballots had a preference greater than the number of candidates; is the preferences file malformed?",
+            ballots.out_of_range_count()
+        );
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        warn!(
+            "Distribution was interrupted: output will only reflect the first {} ballots.",
+            progress
         );
-        */
-
-        let pref_idx = handle_below(
-            &record,
-            below_start,
-            &below_groups,
-            &mut bests,
-            &mut order,
-            groups_below.len(),
+    }
+    /* ***** End of main iteration ***** */
+
+    if let Some(expected) = options.expected_formal {
+        // An interrupted run (or a sample) is already known to be short
+        if !INTERRUPTED.load(Ordering::SeqCst) && options.sample.is_none() {
+            check_expected_formal(progress, expected, options.warn_formal_mismatch)?;
+        }
+    }
+
+    let summary = DistributionSummary {
+        combinations,
+        ballots: progress,
+        btl_count: ballots.btl_count(),
+        informal: ballots.informal_count(),
+        division_totals,
+    };
+    match stream {
+        Some(mut wtr) => {
+            let mut summary = summary;
+            flush_division(
+                &mut wtr,
+                &mut booth_counts,
+                &mut division_specials,
+                &mut summary.division_totals,
+                &summary.combinations,
+                keys,
+            )?;
+            wtr.finish(division_specials)?;
+            Ok(Tally::Streamed(summary))
+        }
+        None => Ok(Tally::InMemory(summary, booth_counts)),
+    }
+}
+
+/// When streaming, write out (and forget) the booths of the division just completed,
+/// keeping its specials to write at the end.
+fn flush_division<K: BoothKeys>(
+    wtr: &mut BoothWriter<K>,
+    booth_counts: &mut BoothCounts<K::Key>,
+    division_specials: &mut BTreeMap<(String, String), Vec<usize>>,
+    division_totals: &mut BTreeMap<String, usize>,
+    combinations: &[String],
+    keys: &K,
+) -> Result<()> {
+    check_booth_totals(booth_counts, keys, division_totals)?;
+    if let Some(sample) = wtr.options.sample {
+        sample.scale_counts(booth_counts);
+    }
+    let polling_places = wtr.options.specials_by_id.then_some(wtr.booths);
+    division_specials.append(&mut aggregate_specials(
+        booth_counts,
+        combinations,
+        keys,
+        polling_places,
+        wtr.options.special_votes,
+    ));
+    wtr.write_booths(booth_counts, keys)?;
+    booth_counts.clear();
+    Ok(())
+}
+
+/// Reads ballots from a preferences file, reducing each to an index into its [`Combinations`].
+///
+/// This takes care of all the ATL/BTL handling, so that the results can be accumulated
+/// however you like: [`booth_npps`] tallies them by booth, for example.
+///
+/// For performance, [`BallotReader::next_ballot`] reuses a single record.
+/// The [`Iterator`] implementation is more convenient but has to clone each record.
+pub struct BallotReader<S: PrefsSource> {
+    source: S,
+    /// The raw header of the first part, which any later parts must match
+    prefs_headers: csv::StringRecord,
+    combinations: Combinations,
+    classifier: Classifier,
+    // Hoists
+    bests: Vec<(usize, usize)>,
+    order: Vec<usize>,
+    record: csv::ByteRecord,
+    classified: Vec<Classified>,
+    stats: BallotStats,
+    /// Ballots with more fields than this are an error
+    max_fields: usize,
+    /// Why the current ballot wasn't BTL-formal, if it's one to explain
+    explanation: Option<String>,
+    /// For classifying batches in parallel; see [`BallotReader::set_threads`]
+    pool: Option<rayon::ThreadPool>,
+    /// Which ballots to read, if only a sample; see [`BallotReader::set_sample`]
+    sampler: Option<Sampler>,
+}
+
+/// How many ballots [`BallotReader::next_batch`] reads at a time
+const BATCH_SIZE: usize = 4096;
+
+/// The ATL/BTL handling of a [`BallotReader`]: unlike the reading, this can be shared between threads.
+struct Classifier {
+    below_start: usize,
+    cands_count: usize,
+    groups_above: Groups,
+    groups_below_count: usize,
+    /// The BTL group of each field, or `usize::MAX`
+    below_groups: Vec<usize>,
+    /// Whether to check for out-of-range preferences
+    check_ranges: bool,
+}
+
+/// A ballot, as classified by [`Classifier::classify`].
+#[derive(Debug, Clone, Copy)]
+struct Classified {
+    /// The index into the [`Combinations`]
+    pref_idx: usize,
+    /// Whether it was formal below the line
+    btl: bool,
+    /// Whether it had a preference greater than the number of candidates (if checking)
+    out_of_range: bool,
+    /// Whether it was informal (and so has the `pref_idx` of no preferences)
+    informal: bool,
+}
+
+impl Classifier {
+    /// Reduce a `record` to its preference index, with `bests` and `order` as scratch space.
+    fn classify(
+        &self,
+        record: &csv::ByteRecord,
+        bests: &mut Vec<(usize, usize)>,
+        order: &mut Vec<usize>,
+    ) -> Classified {
+        let out_of_range = self.check_ranges
+            && record
+                .iter()
+                .skip(PREFS_FIELD_NAMES.len())
+                .any(|x| parse_u8_b10(x) > self.cands_count);
+
+        // BTL-formality takes priority; otherwise fall back to ATL
+        let mut btl_count = 0;
+        let below = handle_below(
+            record,
+            self.below_start,
+            &self.below_groups,
+            bests,
+            order,
+            self.groups_below_count,
             &mut btl_count,
-        )
-        .unwrap_or_else(|| {
-            distribute_preference(
-                &record,
-                &groups_above,
-                // &combo_tree,
-                above_start,
-                prefs_headers_fixed.len() - above_start,
-                &mut bests,
-                &mut order,
+        );
+        let (pref_idx, informal) = below.map_or_else(
+            || {
+                distribute_preference(
+                    record,
+                    &self.groups_above,
+                    PREFS_FIELD_NAMES.len(),
+                    self.below_start,
+                    self.cands_count,
+                    bests,
+                    order,
+                )
+                .map_or((0, true), |pref_idx| (pref_idx, false))
+            },
+            |pref_idx| (pref_idx, false),
+        );
+        Classified {
+            pref_idx,
+            btl: btl_count > 0,
+            out_of_range,
+            informal,
+        }
+    }
+}
+
+/// What a [`BallotReader`] has counted of the ballots read so far.
+#[derive(Debug, Default)]
+struct BallotStats {
+    btl_count: usize,
+    informal_count: usize,
+    out_of_range_count: usize,
+    /// Explain every so many ballots that aren't BTL-formal (or none, if zero)
+    explain_every: usize,
+    /// How many ballots weren't BTL-formal, while explaining
+    not_btl_count: usize,
+    /// How many ballots were left out of the sample (and so not counted otherwise)
+    unsampled_count: usize,
+}
+
+impl BallotStats {
+    /// Count a `classified` ballot, returning an explanation if it's one to explain.
+    fn count(
+        &mut self,
+        record: &csv::ByteRecord,
+        classified: Classified,
+        below_start: usize,
+    ) -> Option<String> {
+        if classified.out_of_range {
+            self.out_of_range_count += 1;
+        }
+        if classified.btl {
+            self.btl_count += 1;
+            return None;
+        }
+        if classified.informal {
+            self.informal_count += 1;
+        }
+        if self.explain_every > 0 {
+            self.not_btl_count += 1;
+            if self.not_btl_count % self.explain_every == 0 {
+                return Some(explain_not_btl(
+                    record,
+                    below_start,
+                    classified.pref_idx,
+                    classified.informal,
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Ballots read together by [`BallotReader::next_batch`].
+///
+/// The records are reused from batch to batch, so keep one of these around.
+#[derive(Debug, Default)]
+pub struct BallotBatch {
+    records: Vec<csv::ByteRecord>,
+    len: usize,
+    pref_idxs: Vec<usize>,
+    informal: Vec<bool>,
+    explanations: Vec<Option<String>>,
+}
+
+impl BallotBatch {
+    /// Each ballot in the batch, in order, as for [`BallotReader::next_ballot_explained`]
+    /// but also with whether it was informal.
+    pub fn iter(&self) -> impl Iterator<Item = (&csv::ByteRecord, usize, bool, Option<&str>)> {
+        self.records[..self.len]
+            .iter()
+            .zip(&self.pref_idxs)
+            .zip(&self.informal)
+            .zip(&self.explanations)
+            .map(|(((record, &pref_idx), &informal), explanation)| {
+                (record, pref_idx, informal, explanation.as_deref())
+            })
+    }
+}
+
+/// Check that a `record` isn't malformed (or from a 2016 file).
+fn check_ballot_record(record: &csv::ByteRecord, max_fields: usize) -> Result<()> {
+    if record.len() > max_fields {
+        return Result::Err(eyre!(
+            "Preferences file has a malformed ballot on line {}: {} fields, but expected at most {}.",
+            record.position().map_or(0, csv::Position::line),
+            record.len(),
+            max_fields
+        ))
+        .suggestion("If the file is fine, raise the limit with --max-record-length");
+    }
+
+    if (record[1]).starts_with(b"---") {
+        // ^^ This conditional might be inverted for testing; 2019+ files do NOT contain a `---` line.
+        return Result::Err(eyre!("Preferences file is in the 2016 format."))
+            .suggestion("Upgrade the file to the 2019+ format with:\n\tnparty upgrade prefs");
+    }
+    Ok(())
+}
+
+impl<S: PrefsSource> BallotReader<S> {
+    /// Set up to read ballots from `source`, distributing them over `parties`,
+    /// first repairing its header with [`fix_prefs_headers`] if `repair_headers`.
+    ///
+    /// Only skip the repair for files known to be properly quoted: a header that
+    /// really was split by unquoted commas won't match up with the candidates.
+    pub fn from_source(parties: &Parties, mut source: S, repair_headers: bool) -> Result<Self> {
+        // The 2019 format is that there are a few fixed headers ... and then a field for each [pseudo]candidate
+        let prefs_headers = source.headers()?;
+        trace!("\nNo actual preferences processed yet, but we successfully opened the zipfile and the raw headers look like this:\n{:#?}", prefs_headers);
+
+        let above_start = PREFS_FIELD_NAMES.len();
+        // 2022 lack-of-quoting problems
+        let prefs_headers_fixed = if repair_headers {
+            fix_prefs_headers(&prefs_headers, above_start)
+        } else {
+            prefs_headers.iter().map(String::from).collect()
+        };
+
+        /* ***** Get candidate/party/group info ***** */
+        let (combinations, below_start, groups_above, groups_below) =
+            make_candidate_info(parties, &prefs_headers_fixed, above_start)?;
+
+        let mut below_groups: Vec<usize> = vec![usize::MAX; prefs_headers_fixed.len()];
+        for (g, v) in &groups_below {
+            for c in v {
+                below_groups[*c + above_start - 1] = *g;
+            }
+        }
+        // trace!("groups_below: {:?}", groups_below);
+        // trace!("below_groups: {:?}", below_groups);
+
+        let bests = Vec::with_capacity(groups_below.len().max(groups_above.len()));
+        let order = Vec::with_capacity(bests.capacity());
+        // let mut record = csv::StringRecord::new(); // Performance: <https://blog.burntsushi.net/csv/#amortizing-allocations>
+        let record = csv::ByteRecord::with_capacity(
+            prefs_headers_fixed.capacity(),
+            prefs_headers_fixed.len(),
+        );
+
+        Ok(Self {
+            source,
+            prefs_headers,
+            combinations,
+            classifier: Classifier {
+                below_start,
+                cands_count: prefs_headers_fixed.len() - above_start,
+                groups_above,
+                groups_below_count: groups_below.len(),
+                below_groups,
+                check_ranges: false,
+            },
+            bests,
+            order,
+            record,
+            classified: Vec::new(),
+            stats: BallotStats::default(),
+            max_fields: 4 * prefs_headers_fixed.len(),
+            explanation: None,
+            pool: None,
+            sampler: None,
+        })
+    }
+
+    /// Continue reading ballots from `source`, the next part of a split preferences file.
+    ///
+    /// Its header must match the first part's, as the candidate info is derived from that.
+    pub fn next_source(&mut self, mut source: S) -> Result<()> {
+        if source.headers()? != self.prefs_headers {
+            bail!("This preferences file's header doesn't match the first file's header.");
+        }
+        self.source = source;
+        Ok(())
+    }
+
+    /// Set the most fields a ballot may have before it's considered malformed.
+    ///
+    /// The default is four times the number of header fields.
+    pub fn set_max_fields(&mut self, max_fields: usize) {
+        self.max_fields = max_fields;
+    }
+
+    /// Explain why every `every`th ballot that isn't BTL-formal was counted above the line
+    /// (or none, if `every` is zero). See [`BallotReader::next_ballot_explained`].
+    pub fn set_explain_every(&mut self, every: usize) {
+        self.stats.explain_every = every;
+    }
+
+    /// Enable or disable counting ballots with preferences greater than the number of candidates.
+    ///
+    /// For clean data that count should be zero. Checking costs an extra pass over each ballot.
+    pub fn set_range_check(&mut self, enabled: bool) {
+        self.classifier.check_ranges = enabled;
+    }
+
+    /// Classify each batch of ballots on this many threads (see [`BallotReader::next_batch`]).
+    ///
+    /// The ballots are still read in order on the calling thread, and come out in that order.
+    pub fn set_threads(&mut self, threads: usize) -> Result<()> {
+        self.pool = if threads > 1 {
+            Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .context("Could not start the distribution threads")?,
             )
-        });
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Only read a random `sample` of the ballots: the rest are skipped (before they're
+    /// classified or counted), except for [`BallotReader::unsampled_count`].
+    pub fn set_sample(&mut self, sample: Sample) {
+        self.sampler = Some(Sampler::new(sample));
+    }
+
+    /// Is the next ballot to be skipped, as it's not in the sample (if any)?
+    fn skip_unsampled(&mut self) -> bool {
+        let skip = self.sampler.as_mut().is_some_and(|s| !s.include());
+        if skip {
+            self.stats.unsampled_count += 1;
+        }
+        skip
+    }
+
+    /// How many of the ballots read so far were left out of the sample (if sampling).
+    pub const fn unsampled_count(&self) -> usize {
+        self.stats.unsampled_count
+    }
+
+    /// How many of the ballots read so far had an out-of-range preference (if checking).
+    pub const fn out_of_range_count(&self) -> usize {
+        self.stats.out_of_range_count
+    }
+
+    /// The [`Combinations`] that preference indexes refer to.
+    pub const fn combos(&self) -> &Combinations {
+        &self.combinations
+    }
+
+    /// How many of the ballots read so far were formal below the line.
+    pub const fn btl_count(&self) -> usize {
+        self.stats.btl_count
+    }
+
+    /// How many of the ballots read so far were informal; see [`distribute_preference`].
+    ///
+    /// These have the preference index of no preferences at all (zero).
+    pub const fn informal_count(&self) -> usize {
+        self.stats.informal_count
+    }
+
+    /// Read the next ballot, returning its raw record and its index into [`BallotReader::combos`].
+    ///
+    /// Returns `None` at the end of the file (or part; see [`BallotReader::next_source`]).
+    pub fn next_ballot(&mut self) -> Result<Option<(&csv::ByteRecord, usize)>> {
+        Ok(self
+            .next_ballot_explained()?
+            .map(|(record, pref_idx, _)| (record, pref_idx)))
+    }
+
+    /// As for [`BallotReader::next_ballot`], but also with an explanation of why
+    /// the ballot wasn't BTL-formal, if it's one of those to explain (see [`BallotReader::set_explain_every`]).
+    pub fn next_ballot_explained(
+        &mut self,
+    ) -> Result<Option<(&csv::ByteRecord, usize, Option<&str>)>> {
+        loop {
+            if !self.source.read_record(&mut self.record)? {
+                return Ok(None);
+            }
+            check_ballot_record(&self.record, self.max_fields)?;
+            if !self.skip_unsampled() {
+                break;
+            }
+        }
+
+        let classified = self
+            .classifier
+            .classify(&self.record, &mut self.bests, &mut self.order);
+        self.explanation = self
+            .stats
+            .count(&self.record, classified, self.classifier.below_start);
+
+        Ok(Some((
+            &self.record,
+            classified.pref_idx,
+            self.explanation.as_deref(),
+        )))
+    }
+
+    /// Read up to `BATCH_SIZE` ballots into `batch`, classifying them in parallel
+    /// if there's more than one thread (see [`BallotReader::set_threads`]).
+    ///
+    /// Returns `false` at the end of the file (or part), when the batch is empty.
+    pub fn next_batch(&mut self, batch: &mut BallotBatch) -> Result<bool> {
+        batch.len = 0;
+        while batch.len < BATCH_SIZE {
+            if batch.len == batch.records.len() {
+                batch.records.push(csv::ByteRecord::new());
+            }
+            let record = &mut batch.records[batch.len];
+            if !self.source.read_record(record)? {
+                break;
+            }
+            check_ballot_record(record, self.max_fields)?;
+            if self.skip_unsampled() {
+                continue;
+            }
+            batch.len += 1;
+        }
+
+        let records = &batch.records[..batch.len];
+        let classifier = &self.classifier;
+        if let Some(pool) = &self.pool {
+            pool.install(|| {
+                records
+                    .par_iter()
+                    .map_init(
+                        || (Vec::new(), Vec::new()),
+                        |(bests, order), record| classifier.classify(record, bests, order),
+                    )
+                    .collect_into_vec(&mut self.classified);
+            });
+        } else {
+            self.classified.clear();
+            self.classified.extend(
+                records
+                    .iter()
+                    .map(|record| classifier.classify(record, &mut self.bests, &mut self.order)),
+            );
+        }
+
+        // counting (and explaining) in order, so that it's the same however many threads there are
+        batch.pref_idxs.clear();
+        batch.informal.clear();
+        batch.explanations.clear();
+        for (record, &classified) in records.iter().zip(&self.classified) {
+            batch.pref_idxs.push(classified.pref_idx);
+            batch.informal.push(classified.informal);
+            batch
+                .explanations
+                .push(self.stats.count(record, classified, classifier.below_start));
+        }
+        Ok(batch.len > 0)
+    }
+}
+
+impl<S: PrefsSource> Iterator for BallotReader<S> {
+    type Item = Result<(csv::ByteRecord, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_ballot()
+            .map(|o| o.map(|(record, pref_idx)| (record.clone(), pref_idx)))
+            .transpose()
+    }
+}
+
+/// Where a [`BallotReader`] gets its ballots from.
+///
+/// Whatever the underlying format, each record is laid out like a line of an AEC
+/// preferences file (2019+ format), so that the same distribution logic applies.
+pub trait PrefsSource {
+    /// The header: the six [`PREFS_FIELD_NAMES`], then a label for each ticket (ATL)
+    /// and each candidate (BTL), in ballot-paper order.
+    fn headers(&mut self) -> Result<csv::StringRecord>;
+    /// Read the next ballot into `record`, returning `false` at the end of the input.
+    fn read_record(&mut self, record: &mut csv::ByteRecord) -> Result<bool>;
+}
+
+impl<R: Read> PrefsSource for csv::Reader<R> {
+    fn headers(&mut self) -> Result<csv::StringRecord> {
+        Ok(Self::headers(self)?.clone())
+    }
+    fn read_record(&mut self, record: &mut csv::ByteRecord) -> Result<bool> {
+        Ok(self.read_byte_record(record)?)
+    }
+}
+
+/// A CSV reader set up for AEC preferences files.
+pub fn csv_prefs_reader<R: Read>(prefs: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .escape(Some(b'\\'))
+        // .trim(csv::Trim::Fields) // Trimming at this stage more than doubles run time
+        .from_reader(prefs)
+}
+
+/// The header line of a JSON-lines preferences file.
+#[derive(Debug, Deserialize)]
+struct JsonPrefsHeader {
+    labels: Vec<String>,
+}
+
+/// A ballot in a JSON-lines preferences file.
+#[derive(Debug, Deserialize)]
+struct JsonBallot {
+    division: String,
+    booth: String,
+    prefs: HashMap<String, usize>,
+}
+
+/// Preferences as JSON lines, for data that doesn't come from the AEC.
+///
+/// The first line is a header listing every label in ballot-paper order, as in
+/// the header of an AEC preferences file: all the tickets (ATL), then all the
+/// candidates (BTL). For example:
+///
+/// ```text
+/// {"labels": ["A:Red Party", "B:Blue Party", "A:RED Alice", "A:RED Bob", "B:BLUE Carol", "B:BLUE Dan"]}
+/// ```
+///
+/// (The order can't be inferred from the ballots themselves, as ATL and BTL labels look alike.)
+/// Each following line is a ballot, giving the number written against each marked label:
+///
+/// ```text
+/// {"division": "Alpha", "booth": "Town Hall", "prefs": {"B:Blue Party": 1, "A:Red Party": 2}}
+/// ```
+pub struct JsonLinesPrefs<R: Read> {
+    lines: std::io::Lines<std::io::BufReader<R>>,
+    line_number: usize,
+    headers: csv::StringRecord,
+    /// Each label's field index
+    label_index: HashMap<String, usize>,
+}
+
+impl<R: Read> JsonLinesPrefs<R> {
+    /// Read the header line from `input`.
+    pub fn new(input: R) -> Result<Self> {
+        use std::io::BufRead;
+        let mut lines = std::io::BufReader::new(input).lines();
+        let header: JsonPrefsHeader = serde_json::from_str(
+            &lines
+                .next()
+                .context("The JSON-lines preferences file is empty")??,
+        )
+        .context("Could not read the header line of the JSON-lines preferences file")
+        .suggestion(
+            r#"The first line should list the labels, like {"labels": ["A:Party", ...]}"#,
+        )?;
+
+        let mut headers = csv::StringRecord::from(PREFS_FIELD_NAMES.to_vec());
+        let mut label_index = HashMap::with_capacity(header.labels.len());
+        for label in header.labels {
+            label_index.insert(label.clone(), headers.len());
+            headers.push_field(&label);
+        }
+
+        Ok(Self {
+            lines,
+            line_number: 1,
+            headers,
+            label_index,
+        })
+    }
+}
 
-        /* // Saving for reference
-        // if pref_idx != pref_idx_old {
-        //     panic!(
-        //         "Difference in result: old was {} but new is {} on iteration{}\n{}\nbests: {:?}",
-        //         combinations[pref_idx_old],
-        //         combinations[pref_idx],
-        //         progress,
-        //         record
-        //             .iter()
-        //             .zip(prefs_headers_fixed)
-        //             .filter(|(v, _)| !v.is_empty())
-        //             .map(|(v, k)| format!("{}\t{}\n", k, v))
-        //             .collect::<String>(),
-        //         bests
-        //     );
-        // } */
-
-        // ... and store.
-        let divbooth: DivBooth = (divnm, boothnm);
-        let booth = booth_counts
-            .entry(divbooth)
-            .or_insert_with(|| vec![0_usize; combinations.len()]);
-        booth[pref_idx] += 1;
-
-        progress += 1;
-        if progress % 100_000 == 0 {
-            trace!("{:?}", record);
-            info!(
-                "{}\t\tPreferencing progress: {} ballots",
-                ttyjump(),
-                progress
-            );
-        }
+impl<R: Read> PrefsSource for JsonLinesPrefs<R> {
+    fn headers(&mut self) -> Result<csv::StringRecord> {
+        Ok(self.headers.clone())
     }
 
-    info!(
-        "{}\t\tPreferencing complete: {} ballots ({} were BTL)",
-        ttyjump(),
-        progress,
-        btl_count
-    );
-    trace!(
-        "Interned {} strings, with capacity for {}.",
-        interner.len(),
-        u16::MAX
-    );
-    /* ***** End of main iteration ***** */
+    fn read_record(&mut self, record: &mut csv::ByteRecord) -> Result<bool> {
+        let line = loop {
+            match self.lines.next() {
+                None => return Ok(false),
+                Some(line) => {
+                    self.line_number += 1;
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        break line;
+                    }
+                }
+            }
+        };
+        let ballot: JsonBallot = serde_json::from_str(&line)
+            .with_context(|| format!("Could not read the ballot on line {}", self.line_number))?;
+
+        let mut fields = vec![None; self.headers.len()];
+        for (label, pref) in ballot.prefs {
+            let idx = self.label_index.get(&label).with_context(|| {
+                format!(
+                    "The ballot on line {} has a preference for {:?}, which isn't in the header",
+                    self.line_number, label
+                )
+            })?;
+            fields[*idx] = Some(pref);
+        }
 
-    info!("\t\tAggregating Absents, Postals, Prepolls & Provisionals");
-    let division_specials = aggregate_specials(&mut booth_counts, &combinations, &interner);
+        record.clear();
+        // State, Division, Vote Collection Point Name, ID, Batch No, Paper No
+        for f in ["", &ballot.division, &ballot.booth, "", "", ""] {
+            record.push_field(f.as_bytes());
+        }
+        for f in fields.iter().skip(PREFS_FIELD_NAMES.len()) {
+            match f {
+                Some(pref) => record.push_field(pref.to_string().as_bytes()),
+                None => record.push_field(b""),
+            }
+        }
+        Ok(true)
+    }
+}
 
-    info!("\t\tWriting File");
-    write_output(
-        npp_booths_path,
-        &combinations,
-        &booth_counts,
-        division_specials,
-        &booths,
-        &interner,
-    )
+/// Load the polling places data from a path (which may be zipped; see [`open_csvz_from_path`])
+pub fn load_polling_places<K: BoothKeys>(
+    state: StateAb,
+    polling_places_path: &Path,
+    keys: &mut K,
+) -> Result<HashMap<K::Key, BoothRecord>> {
+    read_polling_places(state, open_csvz_from_path(polling_places_path)?, keys)
 }
 
-/// Load the polling places data from a path
+/// Read the polling places data (as a CSV with a title line before its header)
 #[inline(never)]
-pub fn load_polling_places(
+pub fn read_polling_places<K: BoothKeys>(
     state: StateAb,
-    polling_places_path: &Path,
-    interner: &mut StringInterner<StringBackend<SymbolU16>>,
-) -> Result<HashMap<DivBooth, BoothRecord>> {
+    polling_places: impl Read,
+    keys: &mut K,
+) -> Result<HashMap<K::Key, BoothRecord>> {
     // this is now just for actual booth data
     // For some gods-forsaken reason, the PollingPlaceID is not the Vote Collection Point ID
     // The only consistent identifier is ({Division}, {Booth})
-    let mut booths: HashMap<DivBooth, BoothRecord> = HashMap::new();
+    let mut booths: HashMap<K::Key, BoothRecord> = HashMap::new();
 
     // OK, let's figure out polling places
     let mut pp_rdr = csv::ReaderBuilder::new()
         .flexible(true)
         .has_headers(false)
-        .from_path(polling_places_path)?;
+        .from_reader(polling_places);
     // 2019 problems: there's a pre-header line
     // we need to skip it, and we're going to do so manually.
 
@@ -354,9 +1943,7 @@ pub fn load_polling_places(
         if record.State != state {
             continue;
         }
-        let division_nm = interner.get_or_intern(record.DivisionNm.clone());
-        let booth_nm = interner.get_or_intern(record.PollingPlaceNm.clone());
-        let dvb = (division_nm, booth_nm);
+        let dvb = keys.key(&record.DivisionNm, &record.PollingPlaceNm);
         booths.insert(dvb, record);
     }
     trace!("Loaded {} polling places", row_count - 2);
@@ -376,12 +1963,7 @@ pub fn make_candidate_info(
     prefs_headers_fixed: &[String],
     above_start: usize,
 ) -> Result<(Combinations, usize, Groups, Groups)> {
-    let mut partykeys = Vec::with_capacity(parties.len());
-    for i in parties.keys() {
-        partykeys.push(i.as_str());
-    }
-    partykeys.sort_unstable();
-    let partykeys = partykeys;
+    let partykeys = sorted_groups(parties);
 
     let mut party_indices: HashMap<&str, usize> = HashMap::new();
     for (i, val) in partykeys.iter().enumerate() {
@@ -517,6 +2099,8 @@ pub fn check_btl(record: &csv::StringRecord, below_start: usize) -> bool {
 /// at least `[1]` through `[6]` marked BTL (and BTL-formality takes priority).
 /// (If there are fewer than 6 candidates, all squares must be marked)
 /// <http://classic.austlii.edu.au/au/legis/cth/consol_act/cea1918233/s268a.html>
+///
+/// A tick or cross is taken as a `[1]`, as under the savings provisions of section 269.
 // NOTE 2022-07-14: I am quite confident this ATL-vs-BTL code is correct.
 // It produces the correct number of BTLs and it has been fairly exhaustively checked against
 // the previous version of the code.
@@ -542,7 +2126,10 @@ pub fn handle_below(
             .skip(below_start)
             .filter(|(_, s)| !s.is_empty())
             // .map(|(i, s)| (i, s.parse::<usize>().unwrap()))
-            .map(|(i, s)| (i, parse_u8_b10(s)))
+            // A tick or cross parses as zero, but it means 1.
+            // Left as zero it would outrank the real 1 (or tie with another: the 0/0)
+            // without failing the formality check below.
+            .map(|(i, s)| (i, parse_u8_b10(s).max(1)))
         {
             match v {
                 // Can we really rely on the lack of whitespace? We may need to trim() again
@@ -562,8 +2149,8 @@ pub fn handle_below(
                 // g can be greater than order.len()
                 // (specifically because order was empty)
                 // fixed an issue where candidates weren't allocated correctly
-                // but we can still get a 0/0 here
                 // also changed the call to order.resize() above
+                // (the 0/0 from ticks and crosses is fixed by the max(1) above)
                 order[g] = v;
             }
         }
@@ -573,6 +2160,13 @@ pub fn handle_below(
         if btl_counts.iter().all(|c| *c == 1) {
             *count += 1;
 
+            if groups_count == 2 {
+                return Some(two_party_index(
+                    (order[0] < usize::MAX).then_some(order[0]),
+                    (order[1] < usize::MAX).then_some(order[1]),
+                ));
+            }
+
             for (i, v) in order.iter().enumerate() {
                 if *v < usize::MAX {
                     bests.push((*v, i));
@@ -596,22 +2190,82 @@ pub fn handle_below(
     }
 }
 
+/// Why a ballot wasn't BTL-formal (and so was counted above the line, as `pref_idx`, unless `informal`).
+///
+/// Gives how many times each of the preferences 1 to 6 was marked below the line:
+/// it's only BTL-formal if each was marked exactly once (see [`handle_below`]).
+/// Only for auditing, so this isn't at all optimised.
+fn explain_not_btl(
+    record: &csv::ByteRecord,
+    below_start: usize,
+    pref_idx: usize,
+    informal: bool,
+) -> String {
+    let mut btl_counts = [0_usize; 6];
+    for v in record
+        .iter()
+        .skip(below_start)
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_u8_b10(s).max(1))
+    {
+        if (1..=6).contains(&v) {
+            btl_counts[v - 1] += 1;
+        }
+    }
+    let mut reason = if btl_counts.iter().all(|c| *c == 0) {
+        String::from("no BTL preferences; fell through to ATL")
+    } else {
+        format!(
+            "BTL-informal (counts {}); fell through to ATL",
+            btl_counts.iter().join(",")
+        )
+    };
+    if informal {
+        reason += "; ATL-informal too (no first preference)";
+    } else if pref_idx == 0 {
+        reason += "; no target party marked";
+    }
+    reason
+}
+
 /// Distribute the preference of a single ballot to an ordering of the specified [`Groups`].
 ///
 /// Having determined the ballot's ATL/BTL status we determine the "best" preference
 /// (if any) for each party we care about. (Candidates may be interleaved.)  
 /// Then sort and determine the index into the relevant [`Combinations`].
 /// For performance reasons, `bests` is hoisted.
+///
+/// Returns `None` if the ballot is informal above the line: there's no first preference
+/// among the fields from `above_start` up to `below_start`, where the candidates start.
+/// A tick or cross (anything that parses as zero) counts as a first preference.
 #[inline(never)]
+#[allow(clippy::too_many_arguments)] // the hoists are for performance
 pub fn distribute_preference(
     record: &csv::ByteRecord,
     groups: &Groups,
     // combo_tree: &ComboTree,
     above_start: usize,
+    below_start: usize,
     cands_count: usize,
     bests: &mut Vec<(usize, usize)>,
     order: &mut Vec<usize>,
-) -> usize {
+) -> Option<usize> {
+    let has_first = record
+        .iter()
+        .take(below_start)
+        .skip(above_start)
+        .any(|x| !x.is_empty() && parse_u8_b10(x) <= 1);
+    if !has_first {
+        return None;
+    }
+    if groups.len() == 2 {
+        return Some(distribute_two_party(
+            record,
+            groups,
+            above_start,
+            cands_count,
+        ));
+    }
     bests.clear();
     order.clear();
     for (group_num, candidate_nums) in groups {
@@ -642,42 +2296,181 @@ pub fn distribute_preference(
     // (Unstable sort is in-place and there shouldn't be any equal elements anyway)
     bests.sort_unstable();
     order.extend(bests.iter().map(|x| x.1));
-    calculate_index(order, groups.len())
+    Some(calculate_index(order, groups.len()))
     // panic!(
     //     "{:?}\nbests: {:?}\torder: {:?}\tindex: {}",
     //     record, bests, order, idx
     // );
 }
 
+/// The best (lowest) preference for any of `candidate_nums`, if it's below `cands_count`.
+fn best_preference(
+    record: &csv::ByteRecord,
+    candidate_nums: &[usize],
+    above_start: usize,
+    cands_count: usize,
+) -> Option<usize> {
+    candidate_nums
+        .iter()
+        .filter_map(|i| record.get(i + above_start - 1))
+        .filter(|x| !x.is_empty())
+        .map(parse_u8_b10)
+        .min()
+        .filter(|best| *best < cands_count)
+}
+
+/// [`distribute_preference`] specialised for exactly two groups, which is by far the most common case.
+/// This avoids sorting and the general [`calculate_index`].
+fn distribute_two_party(
+    record: &csv::ByteRecord,
+    groups: &Groups,
+    above_start: usize,
+    cands_count: usize,
+) -> usize {
+    let best = |g: usize| {
+        groups
+            .get(&g)
+            .and_then(|c| best_preference(record, c, above_start, cands_count))
+    };
+    two_party_index(best(0), best(1))
+}
+
+/// The index into the two-group [`Combinations`], `[Exhausted, A, B, AB, BA]`,
+/// given the best preference (if any) for each group.
+///
+/// Ties go to the first group, as in the general path.
+const fn two_party_index(a: Option<usize>, b: Option<usize>) -> usize {
+    match (a, b) {
+        (None, None) => 0,
+        (Some(_), None) => 1,
+        (None, Some(_)) => 2,
+        (Some(a), Some(b)) => {
+            if a <= b {
+                3
+            } else {
+                4
+            }
+        }
+    }
+}
+
+/// Check the number of ballots distributed against the number there should have been,
+/// which catches a wrong or truncated preferences file.
+fn check_expected_formal(distributed: usize, expected: usize, warn_only: bool) -> Result<()> {
+    if distributed == expected {
+        debug!(
+            "\t\tDistributed exactly the {} expected formal ballots",
+            expected
+        );
+        return Ok(());
+    }
+    let msg = format!(
+        "Distributed {} ballots, but EXPECTED_FORMAL is {} ({} {})",
+        distributed,
+        expected,
+        distributed.abs_diff(expected),
+        if distributed > expected {
+            "too many"
+        } else {
+            "too few"
+        }
+    );
+    if warn_only {
+        warn!("{}", msg);
+        Ok(())
+    } else {
+        Err(eyre!(msg)).suggestion(
+            "Check that PREFS_PATH is the right (and complete) file for this election, \
+            or pass --warn-formal-mismatch to continue anyway.",
+        )
+    }
+}
+
+/// Check that each booth's combination counts sum to the number of ballots seen there,
+/// which catches any indexing bug that drops or double-counts a ballot.
+///
+/// Each entry of `booth_counts` must have that raw ballot count as an extra last element,
+/// which is removed (and added to its division's in `division_totals`).
+fn check_booth_totals<K: BoothKeys>(
+    booth_counts: &mut BoothCounts<K::Key>,
+    keys: &K,
+    division_totals: &mut BTreeMap<String, usize>,
+) -> Result<()> {
+    for (bk, bv) in booth_counts.iter_mut() {
+        let raw = bv.pop().context("missing raw ballot count")?;
+        let (division, _) = keys.resolve(bk);
+        *division_totals.entry(division.to_owned()).or_default() += raw;
+        let total = bv.sum();
+        if total != raw {
+            bail!(
+                "Internal error: {:?} had {} ballots, but its preference counts total {}.",
+                keys.resolve(bk),
+                raw,
+                total
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check that each row of counts has one entry per combination, to match the header.
+///
+/// A mismatch would silently shift every later column of that row, so it's an error.
+fn check_row_lengths<B: std::fmt::Debug>(
+    rows: impl IntoIterator<Item = (B, usize)>,
+    columns: usize,
+) -> Result<()> {
+    for (booth, len) in rows {
+        if len != columns {
+            bail!(
+                "Internal error: {:?} has {} preference counts, but there are {} combinations.",
+                booth,
+                len,
+                columns
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Aggregate the "special" booths by Division, removing them from the main structure
 /// Initially, the special votes are split up into e.g. `POSTAL_1` through `POSTAL_8`
 /// (For backwards compatibility we'd like to print them at the end of the file)
+///
+/// A booth is special if its name says so. Given the `polling_places`, it also mustn't be one of them;
+/// a booth that's in neither is left for the usual missing-booth handling.
+///
+/// The kinds are the `special_votes`, or by default the [`default_special_votes`].
 #[inline(never)]
-pub fn aggregate_specials(
-    booth_counts: &mut HashMap<DivBooth, Vec<usize>>,
+pub fn aggregate_specials<K: BoothKeys>(
+    booth_counts: &mut BoothCounts<K::Key>,
     combinations: &[String],
-    interner: &StringInterner<StringBackend<SymbolU16>>,
+    keys: &K,
+    polling_places: Option<&HashMap<K::Key, BoothRecord>>,
+    special_votes: Option<&SpecialVotes>,
 ) -> BTreeMap<(String, String), Vec<usize>> {
+    let special_votes =
+        special_votes.map_or_else(|| Cow::Owned(default_special_votes()), Cow::Borrowed);
     let mut division_specials: BTreeMap<(String, String), Vec<usize>> = BTreeMap::new();
 
     let mut to_remove = Vec::new();
 
     for (bk, bv) in &*booth_counts {
-        for w in &NON_BOOTH_CONVERT {
+        let (division, booth) = keys.resolve(bk);
+        // If we're given the polling places, a polling place is never special, whatever its name.
+        let polling_place = polling_places.is_some_and(|pp| pp.contains_key(bk));
+        for (w, name) in special_votes.iter() {
             // hoisting for file order
-            let divbooth = (
-                interner.resolve(bk.0).unwrap().to_string(),
-                non_booth_convert(w).to_string(),
-            );
+            let divbooth = (division.to_string(), name.clone());
             let db = division_specials
                 .entry(divbooth)
                 .or_insert_with(|| vec![0_usize; bv.len()]);
-            if interner.resolve(bk.1).unwrap().contains(w) {
-                for j in 0..combinations.len() {
-                    db[j] += bv[j];
+            if !polling_place && booth.contains(w.as_str()) {
+                for (d, b) in db.iter_mut().zip(bv.iter()).take(combinations.len()) {
+                    *d += b;
                 }
                 // ^^ Still not sure I like this version. We didn't need to do the addition on new entries before.
-                to_remove.push(*bk);
+                to_remove.push(bk.clone());
                 break;
             }
         }
@@ -689,96 +2482,256 @@ pub fn aggregate_specials(
     division_specials
 }
 
-/// Write the output CSV for the distribution stage.
-/// Format: `{NPP_FIELD_NAMES} + {combinations} + Total`
-#[inline(never)]
-pub fn write_output(
-    npp_booths_path: &Path,
-    combinations: &[String],
-    booth_counts: &HashMap<DivBooth, Vec<usize>>,
-    division_specials: BTreeMap<(String, String), Vec<usize>>,
-    booths: &HashMap<DivBooth, BoothRecord>,
-    interner: &StringInterner<StringBackend<SymbolU16>>,
-) -> Result<()> {
-    // first create directory if needed
+/// Create the output file at `npp_booths_path` (and its directory, if need be).
+fn create_output(npp_booths_path: &Path) -> Result<File> {
     create_dir_all(
         npp_booths_path
             .parent()
             .with_context(|| format!("{} has no parent", npp_booths_path.display()))?,
     )?;
-    let mut wtr = csv::WriterBuilder::new()
-        .terminator(csv::Terminator::CRLF)
-        .has_headers(false)
-        .from_path(npp_booths_path)?;
+    File::create(npp_booths_path)
+        .with_context(|| format!("Could not create {}", npp_booths_path.display()))
+}
+
+/// The distribution output in the JSON format, mirroring the combination phase's JSON.
+///
+/// Keys are serialised in a fixed order (fields alphabetically, maps sorted)
+/// so that the same inputs always give byte-identical output.
+#[derive(Debug, Default, Serialize)]
+pub struct BoothsJs {
+    /// The label of each count: a combination, or `Total`
+    field_names: Vec<String>,
+    /// The counts of each ordinary booth, keyed by `{Division}_{Booth}`
+    booths: BTreeMap<String, Vec<usize>>,
+    /// The counts of each division's special votes, keyed by `{Division}_{Absent}` and so on
+    specials: BTreeMap<String, Vec<usize>>,
+}
+
+/// Where a [`BoothWriter`] writes to.
+enum BoothSink<'a> {
+    Csv(Box<csv::Writer<Box<dyn Write + 'a>>>),
+    /// Collected, and only written by [`BoothWriter::finish`]
+    Json(Box<dyn Write + 'a>, BoothsJs),
+}
+
+/// Writes the distribution output: the header, then booths (in as many batches as you like),
+/// then the specials.
+///
+/// Format: `{NPP_FIELD_NAMES} + {combinations} + Total`,
+/// with the first combination relabelled as `options.exhausted_label`
+/// (or in the long format; see [`DistributionOptions::long_format`]).
+/// Only the booths and specials admitted by `options.filter` are written.
+struct BoothWriter<'a, K: BoothKeys> {
+    sink: BoothSink<'a>,
+    booths: &'a HashMap<K::Key, BoothRecord>,
+    options: DistributionOptions<'a>,
+    /// How many booths weren't in `booths` (see [`DistributionOptions::allow_missing_booths`])
+    missing_booths: usize,
+    /// How many combinations there are, and so how many counts each row must have
+    columns: usize,
+    /// If the combinations are written as unordered sets, how to fold them
+    fold: Option<UnorderedFold>,
+    /// The label of each column (after any folding)
+    labels: Vec<String>,
+}
+
+impl<'a, K: BoothKeys> BoothWriter<'a, K> {
+    /// Write the header to `output`, in `options.encoding` (or for JSON, get ready to).
+    fn new(
+        output: impl Write + 'a,
+        combinations: &[String],
+        booths: &'a HashMap<K::Key, BoothRecord>,
+        options: DistributionOptions<'a>,
+    ) -> Result<Self> {
+        let fold = options
+            .unordered
+            .then(|| UnorderedFold::new(combinations))
+            .transpose()?;
+        let mut labels = fold
+            .as_ref()
+            .map_or(combinations, |f| f.labels.as_slice())
+            .to_vec();
+        options.exhausted_label.clone_into(&mut labels[0]);
+
+        let sink = if options.output_format == OutputFormat::Json {
+            let field_names = labels.iter().cloned().chain(["Total".into()]).collect();
+            BoothSink::Json(
+                Box::new(output),
+                BoothsJs {
+                    field_names,
+                    ..Default::default()
+                },
+            )
+        } else {
+            let mut wtr = csv::WriterBuilder::new()
+                .terminator(csv::Terminator::CRLF)
+                .has_headers(false)
+                .from_writer(encode_writer(output, options.encoding));
+            if options.long_format {
+                wtr.write_record(NPP_LONG_FIELD_NAMES)
+                    .context("error writing booths header")?;
+            } else {
+                let npp_header = &mut NPP_FIELD_NAMES.to_vec();
+                for i in &labels {
+                    npp_header.push(i.as_str());
+                }
+                npp_header.push("Total");
+
+                wtr.write_record(npp_header)
+                    .context("error writing booths header")?;
+            }
+            BoothSink::Csv(Box::new(wtr))
+        };
+
+        Ok(Self {
+            sink,
+            booths,
+            options,
+            missing_booths: 0,
+            columns: combinations.len(),
+            fold,
+            labels,
+        })
+    }
 
-    let npp_header = &mut NPP_FIELD_NAMES.to_vec();
-    for i in combinations {
-        npp_header.push(i.as_str());
+    /// Write a row (or in the long format, rows) for a booth's details `bdeets`
+    /// (as `NPP_FIELD_NAMES`) and its `counts`, folded if need be.
+    ///
+    /// For JSON, `special` says which of [`BoothsJs`]'s maps the booth belongs in.
+    fn write_row(
+        &mut self,
+        mut bdeets: Vec<String>,
+        counts: impl Iterator<Item = usize>,
+        special: bool,
+    ) -> Result<()> {
+        let mut counts = match &self.fold {
+            Some(fold) => fold.fold(counts),
+            None => counts.collect(),
+        };
+        match &mut self.sink {
+            BoothSink::Json(_, js) => {
+                counts.push(counts.iter().sum());
+                let key = format!("{}_{}", bdeets[1], bdeets[2]);
+                if special {
+                    js.specials.insert(key, counts);
+                } else {
+                    js.booths.insert(key, counts);
+                }
+            }
+            BoothSink::Csv(wtr) if self.options.long_format => {
+                bdeets.truncate(3);
+                for (label, count) in self.labels.iter().zip(counts) {
+                    if count > 0 {
+                        wtr.write_record(bdeets.iter().chain([label, &count.to_string()]))
+                            .context("error writing booths")?;
+                    }
+                }
+            }
+            BoothSink::Csv(wtr) => {
+                bdeets.extend(counts.iter().map(ToString::to_string));
+                bdeets.push(counts.iter().sum::<usize>().to_string());
+                wtr.write_record(&bdeets).context("error writing booths")?;
+            }
+        }
+        Ok(())
     }
-    npp_header.push("Total");
 
-    wtr.write_record(npp_header)
-        .context("error writing booths header")?;
+    /// Write the (ordinary) booths of `booth_counts`, sorted by division and then name.
+    fn write_booths(&mut self, booth_counts: &BoothCounts<K::Key>, keys: &K) -> Result<()> {
+        let filter = self.options.filter;
+        check_row_lengths(
+            booth_counts
+                .iter()
+                .map(|(bk, bv)| (keys.resolve(bk), bv.len())),
+            self.columns,
+        )?;
+        // Switching to string interning messed up the file order a little bit.
+        // We'd like it to be sorted by ({division name}, {polling place name})
+        // for all ordinary divisions, then the specials separately after ---
+        // this matches previous behaviour.
+        // (when sorted, old and new files have identical hashes,
+        //    so we can be confident in the rest of everything)
+
+        let mut sorted_booths: Vec<&K::Key> = booth_counts.keys().collect();
+        sorted_booths.sort_by_cached_key(|bk| keys.resolve(bk));
+
+        for bk in sorted_booths {
+            let bv = booth_counts
+                .get(bk)
+                .context("missing entry in `booth_counts`")?;
+            let bdeets = if let Some(br) = self.booths.get(bk) {
+                if !filter.includes_booth(br.PollingPlaceTypeID) {
+                    continue;
+                }
+                vec![
+                    br.PollingPlaceID.to_string(),
+                    br.DivisionNm.clone(),
+                    br.PollingPlaceNm.clone(),
+                    br.Latitude.clone(),
+                    br.Longitude.clone(),
+                ]
+            } else if self.options.allow_missing_booths {
+                // Without a polling place type, only an unfiltered output can include it
+                if !(filter.booths && filter.booth_type == BoothType::All) {
+                    continue;
+                }
+                let (division, booth) = keys.resolve(bk);
+                debug!("{} ({}) isn't in the polling places file", booth, division);
+                self.missing_booths += 1;
+                vec![
+                    String::new(),
+                    division.to_owned(),
+                    booth.to_owned(),
+                    String::new(),
+                    String::new(),
+                ]
+            } else {
+                return Err(eyre!(
+                    "{:?} has preferences, but isn't in the polling places file.",
+                    keys.resolve(bk)
+                ))
+                .suggestion("Check that the polling places file is for the same election, or pass --allow-missing-booths to write such booths without their details.");
+            };
+            // (densified here, a row at a time, if need be)
+            self.write_row(bdeets, bv.iter(), false)?;
+        }
 
-    // Switching to string interning messed up the file order a little bit.
-    // We'd like it to be sorted by ({division name}, {polling place name})
-    // for all ordinary divisions, then the specials separately after ---
-    // this matches previous behaviour.
-    // (when sorted, old and new files have identical hashes,
-    //    so we can be confident in the rest of everything)
+        if let BoothSink::Csv(wtr) = &mut self.sink {
+            wtr.flush().context("error writing booths")?;
+        }
+        Ok(())
+    }
 
-    let mut sorted_booths: Vec<&(SymbolU16, SymbolU16)> = booth_counts.keys().collect();
-    sorted_booths.sort_by_cached_key(|(div_id, booth_id)| {
-        (
-            interner.resolve(*div_id).unwrap(),
-            interner.resolve(*booth_id).unwrap(),
-        )
-    });
+    /// Write the specials (if the filter allows) and finish up.
+    fn finish(mut self, division_specials: BTreeMap<(String, String), Vec<usize>>) -> Result<()> {
+        if self.missing_booths > 0 {
+            warn!(
+                "{} booths weren't in the polling places file, so were written without their ID, latitude or longitude.",
+                self.missing_booths
+            );
+        }
 
-    for bk in sorted_booths {
-        let bv = booth_counts
-            .get(bk)
-            .context("missing entry in `booth_counts`")?;
-        let br = booths.get(bk).with_context(|| {
-            eyre!(
-                "It's really weird, but {:?} (actually {:?}) isn't in `booths`.",
-                bk,
-                (interner.resolve(bk.0), interner.resolve(bk.1))
-            )
-        })?;
-        let mut bdeets = vec![
-            br.PollingPlaceID.to_string(),
-            br.DivisionNm.clone(),
-            br.PollingPlaceNm.clone(),
-            br.Latitude.clone(),
-            br.Longitude.clone(),
-        ];
-        let mut total = 0;
-        for i in bv {
-            bdeets.push(i.to_string());
-            total += *i;
-        }
-        bdeets.push(total.to_string());
-        let bdeets = bdeets;
-        wtr.write_record(&bdeets).context("error writing booths")?;
-    }
-
-    wtr.flush().context("error writing booths")?;
-
-    for (bk, bv) in division_specials {
-        let mut bdeets: Vec<String> = vec![String::new(), bk.0, bk.1, String::new(), String::new()];
-
-        let mut total = 0;
-        for i in bv {
-            bdeets.push(i.to_string());
-            total += i;
-        }
-        bdeets.push(total.to_string());
-        let bdeets = bdeets;
-        wtr.write_record(&bdeets).context("error writing booths")?;
-    }
-    wtr.flush().context("Failed to finalise writing booths")?;
-    Ok(())
+        if self.options.filter.includes_specials() {
+            check_row_lengths(
+                division_specials.iter().map(|(bk, bv)| (bk, bv.len())),
+                self.columns,
+            )?;
+            for (bk, bv) in division_specials {
+                let bdeets: Vec<String> =
+                    vec![String::new(), bk.0, bk.1, String::new(), String::new()];
+                self.write_row(bdeets, bv.into_iter(), true)?;
+            }
+        }
+        match self.sink {
+            BoothSink::Csv(mut wtr) => wtr.flush(),
+            BoothSink::Json(mut out, js) => {
+                serde_json::to_writer(&mut out, &js)?;
+                out.flush()
+            }
+        }
+        .context("Failed to finalise writing booths")?;
+        Ok(())
+    }
 }
 
 /// Calculate a preference index given an ordering
@@ -837,6 +2790,9 @@ pub fn calculate_index(order: &[usize], groups_count: usize) -> usize {
 // it was buggy because you used hex constants
 /// Parse a `&[u8]` as though it were an ASCII base-10 string
 /// skipping over any bytes not corresponding to ascii 1-10
+///
+/// Saturates at `usize::MAX` rather than overflowing on a long run of digits,
+/// so that a garbage cell is just more than any number of candidates.
 #[inline(never)]
 pub fn parse_u8_b10(input: &[u8]) -> usize {
     // eprintln!("{input:?}");
@@ -846,9 +2802,9 @@ pub fn parse_u8_b10(input: &[u8]) -> usize {
         match k {
             48 => {
                 // ascii 0
-                acc *= 10;
+                acc = acc.saturating_mul(10);
             }
-            49..=57 => acc = acc * 10 + ((*k - 48) as usize),
+            49..=57 => acc = acc.saturating_mul(10).saturating_add((*k - 48) as usize),
             _ => continue,
         }
         // eprintln!("\t{k} {acc}");
@@ -860,6 +2816,47 @@ pub fn parse_u8_b10(input: &[u8]) -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fmt::Write as _;
+    #[test]
+    fn combination_labels() {
+        let groups = ["Alp", "Grn", "Lnp"];
+        let combinations = group_combos(&groups);
+        assert_eq!(combinations.label_for(&[0, 1]), Some("AlpGrn"));
+        assert_eq!(combinations.label_for(&[]), Some(EXHAUSTED));
+        assert_eq!(combinations.label_for(&[1, 1]), None);
+        assert_eq!(combinations.label_for(&[3]), None);
+
+        // every ordering has its own label, and it's the one at its index
+        let mut seen = std::collections::BTreeSet::new();
+        for len in 0..=groups.len() {
+            for order in (0..groups.len()).permutations(len) {
+                let label = combinations.label_for(&order).unwrap();
+                let expected: String = order.iter().map(|&x| groups[x]).collect();
+                assert_eq!(label, if len == 0 { EXHAUSTED } else { &expected });
+                assert_eq!(label, combinations[calculate_index(&order, groups.len())]);
+                seen.insert(calculate_index(&order, groups.len()));
+            }
+        }
+        assert_eq!(seen.len(), combinations.len());
+    }
+
+    #[test]
+    fn unordered_fold() {
+        let combinations = group_combos(&["Alp", "Grn"]);
+        let fold = UnorderedFold::new(&combinations).unwrap();
+        assert_eq!(fold.labels, [EXHAUSTED, "Alp", "Grn", "Alp+Grn"]);
+        // Exhausted, Alp, Grn, AlpGrn, GrnAlp
+        assert_eq!(fold.map, [0, 1, 2, 3, 3]);
+        assert_eq!(fold.fold([5, 4, 3, 2, 1].into_iter()), [5, 4, 3, 3]);
+
+        let fold = UnorderedFold::new(&group_combos(&["A", "B", "C"])).unwrap();
+        assert_eq!(
+            fold.labels,
+            [EXHAUSTED, "A", "B", "C", "A+B", "A+C", "B+C", "A+B+C"]
+        );
+        assert!(UnorderedFold::new(&combinations[..4]).is_err());
+    }
+
     #[test]
     fn test_calculate_index() {
         // *** First-of-the-length ***
@@ -899,6 +2896,572 @@ mod tests {
         }
     }
 
+    #[test]
+    fn two_party_matches_general() {
+        let prefs = [None, Some(1), Some(2), Some(5)];
+        for a in prefs {
+            for b in prefs {
+                let mut bests: Vec<(usize, usize)> = [a.map(|p| (p, 0)), b.map(|p| (p, 1))]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                bests.sort_unstable();
+                let order: Vec<usize> = bests.iter().map(|x| x.1).collect();
+                assert_eq!(
+                    two_party_index(a, b),
+                    calculate_index(&order, 2),
+                    "{a:?} {b:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn group_orders_match_combos() {
+        let groups = ["Alp", "Grn", "Lnp", "Phn"];
+        let combos = group_combos(&groups);
+        let orders = group_orders(&groups);
+        assert_eq!(combos.len(), orders.len());
+        assert!(orders[0].is_empty());
+        for (combo, order) in combos.iter().zip(&orders).skip(1) {
+            assert_eq!(*combo, order.concat());
+        }
+    }
+
+    /// BTL-formality takes priority over ATL: check all four combinations.
+    #[test]
+    fn atl_btl_priority() {
+        let mut parties = Parties::new();
+        for (k, v) in [
+            ("Alp", ["A:Alp", "A:SMITH John", "A:JONES Amy"]),
+            ("Grn", ["B:Grn", "B:BROWN Bob", "B:WHITE Sue"]),
+            ("Lnp", ["C:Lnp", "C:GREY Tom", "C:BLACK Jim"]),
+        ] {
+            parties.insert(k.to_string(), v.iter().map(ToString::to_string).collect());
+        }
+        let prefs = "\
+State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Grn,C:Lnp,A:SMITH John,A:JONES Amy,B:BROWN Bob,B:WHITE Sue,C:GREY Tom,C:BLACK Jim,UG:SOLO Han
+QLD,Alpha,Town Hall,1,1,1,,2,1,1,2,3,4,5,6,
+QLD,Alpha,Town Hall,1,1,2,,1,,,,,,1,2,3
+QLD,Alpha,Town Hall,1,1,3,,,,6,5,4,3,2,1,
+QLD,Alpha,Town Hall,1,1,4,,,,,,1,2,,,
+";
+        let mut ballots =
+            BallotReader::from_source(&parties, csv_prefs_reader(prefs.as_bytes()), true).unwrap();
+        let combos = ballots.combos().clone();
+        let mut results = Vec::new();
+        while let Some((_, idx)) = ballots.next_ballot().unwrap() {
+            results.push(combos[idx].as_str());
+        }
+        assert_eq!(
+            results,
+            [
+                // ATL-formal and BTL-formal: BTL wins
+                "AlpGrnLnp",
+                // ATL-formal but BTL-informal: ATL
+                "Grn",
+                // ATL-informal but BTL-formal: BTL
+                "LnpGrnAlp",
+                // Neither: informal, counted with no preferences
+                EXHAUSTED,
+            ]
+        );
+        assert_eq!(ballots.btl_count(), 2);
+        assert_eq!(ballots.informal_count(), 1);
+
+        let mut ballots =
+            BallotReader::from_source(&parties, csv_prefs_reader(prefs.as_bytes()), true).unwrap();
+        ballots.set_explain_every(1);
+        let mut explanations = Vec::new();
+        while let Some((_, _, explanation)) = ballots.next_ballot_explained().unwrap() {
+            explanations.push(explanation.map(String::from));
+        }
+        assert_eq!(
+            explanations,
+            [
+                None,
+                Some("BTL-informal (counts 1,1,1,0,0,0); fell through to ATL".into()),
+                None,
+                Some(
+                    "BTL-informal (counts 1,1,0,0,0,0); fell through to ATL; ATL-informal too (no first preference)"
+                        .into()
+                ),
+            ]
+        );
+    }
+
+    /// The shape of 2022's `ACT_3CP`: few BTL candidates, with the groups' candidates interleaved.
+    /// Ticks and crosses BTL used to parse as preference 0, ahead of the real 1.
+    #[test]
+    fn btl_ticks_and_crosses() {
+        let mut parties = Parties::new();
+        for (k, v) in [
+            ("Alp", ["A:ALP", "A:GALLAGHER Katy", "A:SMITH David"]),
+            ("Lib", ["B:LIB", "B:SESELJA Zed", "B:CAMPBELL Kacey"]),
+            ("Ind", ["C:IND", "C:POCOCK David", "C:HIATT Kim"]),
+        ] {
+            parties.insert(k.to_string(), v.iter().map(ToString::to_string).collect());
+        }
+        let prefs = "\
+State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:ALP,B:LIB,C:IND,A:GALLAGHER Katy,B:SESELJA Zed,C:POCOCK David,A:SMITH David,B:CAMPBELL Kacey,C:HIATT Kim,UG:LEE Ann,UG:WU Bo
+ACT,Canberra,Civic,1,1,1,,,,3,2,/,4,6,5,,
+ACT,Canberra,Civic,1,1,2,2,,1,4,*,2,5,,6,1,3
+ACT,Canberra,Civic,1,1,3,1,,,3,/,*,4,5,6,1,2
+";
+        let mut ballots =
+            BallotReader::from_source(&parties, csv_prefs_reader(prefs.as_bytes()), true).unwrap();
+        let combos = ballots.combos().clone();
+        let mut idxs = Vec::new();
+        while let Some((_, idx)) = ballots.next_ballot().unwrap() {
+            idxs.push(idx);
+        }
+        let labels: Vec<&str> = idxs.iter().map(|&i| combos[i].as_str()).collect();
+        assert_eq!(
+            labels,
+            [
+                // a tick for the 1: BTL-formal
+                "IndLibAlp",
+                // a cross as well as a 1 is two 1s: BTL-informal, so ATL
+                "IndAlp",
+                // the 0/0: a tick and a cross as well as a 1, so ATL
+                "Alp",
+            ]
+        );
+        assert_eq!(ballots.btl_count(), 1);
+
+        // groups are numbered in the order of the single-group combinations
+        let group = |name: &str| combos.iter().position(|c| c == name).unwrap() - 1;
+        assert_eq!(
+            idxs[0],
+            calculate_index(&[group("Ind"), group("Lib"), group("Alp")], 3)
+        );
+    }
+
+    /// A non-AEC header where a group has no BTL candidates, which the repair would mangle.
+    #[test]
+    fn unrepaired_header() {
+        let mut parties = Parties::new();
+        parties.insert("Alp".into(), vec!["A:Alp".into(), "A:SMITH John".into()]);
+        parties.insert("Lnp".into(), vec!["C:Lnp".into(), "C:GREY Tom".into()]);
+        let prefs = "\
+State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Grn,C:Lnp,A:SMITH John,C:GREY Tom
+QLD,Alpha,Town Hall,1,1,1,2,,1,,
+";
+        // the repair joins up "A:SMITH John,C:GREY Tom", so C:GREY Tom can't be found
+        assert!(
+            BallotReader::from_source(&parties, csv_prefs_reader(prefs.as_bytes()), true).is_err()
+        );
+        let mut raw =
+            BallotReader::from_source(&parties, csv_prefs_reader(prefs.as_bytes()), false).unwrap();
+        let combos = raw.combos().clone();
+        let (_, idx) = raw.next_ballot().unwrap().unwrap();
+        assert_eq!(combos[idx], "LnpAlp");
+    }
+
+    /// A header with a trailing comma, and ballots to match.
+    #[test]
+    fn trailing_empty_header() {
+        let mut parties = Parties::new();
+        parties.insert("Alp".into(), vec!["A:Alp".into(), "A:SMITH John".into()]);
+        parties.insert("Lnp".into(), vec!["B:Lnp".into(), "B:BLACK Jim".into()]);
+        let prefs = "\
+State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Lnp,A:SMITH John,A:JONES Amy,A:WHITE Sue,B:GREY Tom,B:BROWN Bob,B:BLACK Jim,
+QLD,Alpha,Town Hall,1,1,1,,,2,3,4,5,6,1,
+QLD,Alpha,Town Hall,1,1,2,1,2,,,,,,,
+";
+        let mut ballots =
+            BallotReader::from_source(&parties, csv_prefs_reader(prefs.as_bytes()), true).unwrap();
+        let combos = ballots.combos().clone();
+        // the last column is still B:BLACK Jim
+        let (_, idx) = ballots.next_ballot().unwrap().unwrap();
+        assert_eq!(combos[idx], "LnpAlp");
+        let (_, idx) = ballots.next_ballot().unwrap().unwrap();
+        assert_eq!(combos[idx], "AlpLnp");
+    }
+
+    #[test]
+    fn jsonl_prefs() {
+        let mut parties = Parties::new();
+        parties.insert("Alp".into(), vec!["A:Alp".into(), "A:SMITH John".into()]);
+        parties.insert("Grn".into(), vec!["B:Grn".into(), "B:BROWN Bob".into()]);
+        let prefs = r#"{"labels": ["A:Alp", "B:Grn", "A:SMITH John", "B:BROWN Bob"]}
+{"division": "Alpha", "booth": "Town Hall", "prefs": {"B:Grn": 1, "A:Alp": 2}}
+
+{"division": "Alpha", "booth": "School", "prefs": {"A:Alp": 1}}
+{"division": "Alpha", "booth": "School", "prefs": {"C:Lnp": 1}}
+"#;
+        let mut ballots = BallotReader::from_source(
+            &parties,
+            JsonLinesPrefs::new(prefs.as_bytes()).unwrap(),
+            true,
+        )
+        .unwrap();
+        let combos = ballots.combos().clone();
+        let (record, idx) = ballots.next_ballot().unwrap().unwrap();
+        assert_eq!((&record[1], &record[2]), (&b"Alpha"[..], &b"Town Hall"[..]));
+        assert_eq!(combos[idx], "GrnAlp");
+        let (_, idx) = ballots.next_ballot().unwrap().unwrap();
+        assert_eq!(combos[idx], "Alp");
+        // labels must be in the header
+        assert!(ballots.next_ballot().is_err());
+    }
+
+    #[test]
+    fn specials_aggregate_by_division() {
+        let combinations = [EXHAUSTED, "Alp"].map(String::from);
+        let mut keys = PlainKeys;
+        let mut booth_counts = HashMap::new();
+        booth_counts.insert(keys.key("Alpha", "Town Hall"), vec![1, 2].into());
+        booth_counts.insert(keys.key("Alpha", "POSTAL 1"), vec![3, 4].into());
+        booth_counts.insert(keys.key("Alpha", "POSTAL 2"), vec![5, 6].into());
+
+        let specials =
+            aggregate_specials(&mut booth_counts.clone(), &combinations, &keys, None, None);
+        assert_eq!(specials[&keys.key("Alpha", "Postal")], [8, 10]);
+        assert_eq!(specials[&keys.key("Alpha", "Absent")], [0, 0]);
+
+        // configured kinds: the first that matches wins, and anything else stays a booth
+        let mut special_votes = SpecialVotes::new();
+        special_votes.insert("POSTAL 1".into(), "Early Postal".into());
+        special_votes.insert("POSTAL".into(), "Postal".into());
+        booth_counts.insert(keys.key("Alpha", "ABSENT 1"), vec![7, 8].into());
+        let specials = aggregate_specials(
+            &mut booth_counts,
+            &combinations,
+            &keys,
+            None,
+            Some(&special_votes),
+        );
+        assert_eq!(booth_counts.len(), 2);
+        assert_eq!(
+            booth_counts[&keys.key("Alpha", "Town Hall")].to_vec(),
+            [1, 2]
+        );
+        assert_eq!(specials[&keys.key("Alpha", "Early Postal")], [3, 4]);
+        assert_eq!(specials[&keys.key("Alpha", "Postal")], [5, 6]);
+        assert_eq!(specials.len(), 2);
+    }
+
+    #[test]
+    fn specials_by_polling_place() {
+        let combinations = [EXHAUSTED, "Alp"].map(String::from);
+        let mut keys = PlainKeys;
+        let polling_places = "\
+State,DivisionID,DivisionNm,PollingPlaceID,PollingPlaceTypeID,PollingPlaceNm,PremisesNm,PremisesAddress1,PremisesAddress2,PremisesAddress3,PremisesSuburb,PremisesStateAb,PremisesPostCode,Latitude,Longitude
+QLD,1,Alpha,11,1,POSTAL STREET,Hall,1 Postal St,,,Alpha,QLD,4000,-27.5,153.0
+";
+        let mut booths = HashMap::new();
+        for row in csv::Reader::from_reader(polling_places.as_bytes()).deserialize() {
+            let br: BoothRecord = row.unwrap();
+            booths.insert(keys.key(&br.DivisionNm, &br.PollingPlaceNm), br);
+        }
+        let mut booth_counts = HashMap::new();
+        // an ordinary booth that happens to be named like a special
+        booth_counts.insert(keys.key("Alpha", "POSTAL STREET"), vec![1, 2].into());
+        booth_counts.insert(keys.key("Alpha", "POSTAL 1"), vec![3, 4].into());
+        // neither a polling place nor a special: left for the missing-booth handling
+        booth_counts.insert(keys.key("Alpha", "Declaration Votes"), vec![5, 6].into());
+
+        let specials =
+            aggregate_specials(&mut booth_counts, &combinations, &keys, Some(&booths), None);
+
+        assert_eq!(booth_counts.len(), 2);
+        assert_eq!(
+            booth_counts[&keys.key("Alpha", "POSTAL STREET")].to_vec(),
+            [1, 2]
+        );
+        assert_eq!(
+            booth_counts[&keys.key("Alpha", "Declaration Votes")].to_vec(),
+            [5, 6]
+        );
+        assert_eq!(specials[&keys.key("Alpha", "Postal")], [3, 4]);
+    }
+
+    #[test]
+    fn distribute_from_readers() {
+        let mut parties = Parties::new();
+        for (k, v) in [
+            ("Alp", ["A:Alp", "A:SMITH John"]),
+            ("Lnp", ["B:Lnp", "B:GREY Tom"]),
+        ] {
+            parties.insert(k.to_string(), v.iter().map(ToString::to_string).collect());
+        }
+        let prefs: &[u8] = b"\
+State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Lnp,A:SMITH John,A:JONES Amy,B:GREY Tom,B:BLACK Jim
+QLD,Alpha,Town Hall,1,1,1,1,2,,,,
+QLD,Alpha,Town Hall,1,1,2,2,1,,,,
+QLD,Alpha,Town Hall,1,1,3,,,,,,
+QLD,Alpha,POSTAL 1,2,1,1,1,,,,,
+";
+        let polling_places: &[u8] = b"\
+2019 Federal Election polling places
+State,DivisionID,DivisionNm,PollingPlaceID,PollingPlaceTypeID,PollingPlaceNm,PremisesNm,PremisesAddress1,PremisesAddress2,PremisesAddress3,PremisesSuburb,PremisesStateAb,PremisesPostCode,Latitude,Longitude
+QLD,1,Alpha,11,1,Town Hall,Hall,1 Main St,,,Alpha,QLD,4000,-27.5,153.0
+";
+        let mut output = Vec::new();
+        let summary = booth_npps_from_readers(
+            &parties,
+            StateAb::QLD,
+            vec![("prefs".to_owned(), Box::new(prefs))],
+            polling_places,
+            &mut output,
+            DistributionOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            summary.combinations,
+            [EXHAUSTED, "Alp", "Lnp", "AlpLnp", "LnpAlp"]
+        );
+        assert_eq!(summary.ballots, 4);
+        assert_eq!(summary.btl_count, 0);
+        assert_eq!(
+            summary.division_totals,
+            BTreeMap::from([("Alpha".to_owned(), 4)])
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+ID,Division,Booth,Latitude,Longitude,Exhausted,Alp,Lnp,AlpLnp,LnpAlp,Total\r
+11,Alpha,Town Hall,-27.5,153.0,1,0,0,1,1,3\r
+,Alpha,Absent,,,0,0,0,0,0,0\r
+,Alpha,Postal,,,0,1,0,0,0,1\r
+,Alpha,Pre-Poll,,,0,0,0,0,0,0\r
+,Alpha,Provisional,,,0,0,0,0,0,0\r
+"
+        );
+
+        let mut output = Vec::new();
+        booth_npps_from_readers(
+            &parties,
+            StateAb::QLD,
+            vec![("prefs".to_owned(), Box::new(prefs))],
+            polling_places,
+            &mut output,
+            DistributionOptions {
+                output_format: OutputFormat::Json,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"{"field_names":["Exhausted","Alp","Lnp","AlpLnp","LnpAlp","Total"],"booths":{"Alpha_Town Hall":[1,0,0,1,1,3]},"specials":{"Alpha_Absent":[0,0,0,0,0,0],"Alpha_Postal":[0,1,0,0,0,1],"Alpha_Pre-Poll":[0,0,0,0,0,0],"Alpha_Provisional":[0,0,0,0,0,0]}}"#
+        );
+    }
+
+    #[test]
+    fn streamed_matches_in_memory() {
+        let dir = std::env::temp_dir().join(format!("nparty-stream-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let mut parties = Parties::new();
+        parties.insert("Alp".into(), vec!["A:Alp".into()]);
+        parties.insert("Lnp".into(), vec!["B:Lnp".into()]);
+        let polling_places = dir.join("pp.csv");
+        std::fs::write(
+            &polling_places,
+            "\
+2019 Federal Election polling places
+State,DivisionID,DivisionNm,PollingPlaceID,PollingPlaceTypeID,PollingPlaceNm,PremisesNm,PremisesAddress1,PremisesAddress2,PremisesAddress3,PremisesSuburb,PremisesStateAb,PremisesPostCode,Latitude,Longitude
+QLD,1,Alpha,11,1,Town Hall,Hall,1 Main St,,,Alpha,QLD,4000,-27.5,153.0
+QLD,2,Beta,21,1,Library,Library,2 Main St,,,Beta,QLD,4000,-27.6,153.1
+",
+        )
+        .unwrap();
+        let header = "State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Lnp\n";
+        let alpha = "QLD,Alpha,Town Hall,11,1,1,1,2\nQLD,Alpha,POSTAL 1,2,1,2,2,1\n";
+        let beta = "QLD,Beta,Library,21,1,1,,1\nQLD,Beta,Library,21,1,2,1,\n";
+
+        let booths = load_polling_places(StateAb::QLD, &polling_places, &mut PlainKeys).unwrap();
+        // Beta before Alpha isn't grouped in alphabetical order, so can't be streamed
+        for (name, body, grouped) in [
+            ("grouped", [alpha, beta], true),
+            ("ungrouped", [beta, alpha], false),
+        ] {
+            let prefs = dir.join(format!("{name}.csv"));
+            std::fs::write(&prefs, [header, body[0], body[1]].concat()).unwrap();
+            let distributed = |stream: bool| {
+                let output = dir.join(format!("{name}_{stream}.csv"));
+                booth_npps(
+                    &parties,
+                    StateAb::QLD,
+                    std::slice::from_ref(&prefs),
+                    &polling_places,
+                    &output,
+                    DistributionOptions {
+                        stream,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+                std::fs::read(output).unwrap()
+            };
+            assert_eq!(distributed(true), distributed(false), "{name}");
+
+            let tallied = tally(
+                &mut PlainKeys,
+                &parties,
+                open_parts(std::slice::from_ref(&prefs)),
+                DistributionOptions::default(),
+                Some((Box::new(std::io::sink()), &booths)),
+            )
+            .unwrap();
+            if grouped {
+                assert!(matches!(tallied, Tally::Streamed(_)));
+            } else {
+                assert!(matches!(tallied, Tally::NotGrouped));
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn threads_match_serial() {
+        use std::fmt::Write as _;
+        let mut parties = Parties::new();
+        for (k, v) in [
+            ("Alp", ["A:Alp", "A:SMITH John", "A:JONES Amy"]),
+            ("Grn", ["B:Grn", "B:BROWN Bob", "B:WHITE Sue"]),
+            ("Lnp", ["C:Lnp", "C:GREY Tom", "C:BLACK Jim"]),
+        ] {
+            parties.insert(k.to_string(), v.iter().map(ToString::to_string).collect());
+        }
+        // enough ballots for a few batches, some formal above the line and some below
+        let mut prefs = String::from("State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Grn,C:Lnp,A:SMITH John,A:JONES Amy,B:BROWN Bob,B:WHITE Sue,C:GREY Tom,C:BLACK Jim\n");
+        let mut seed: u64 = 1;
+        for paper in 0..3 * BATCH_SIZE {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            let pick = (seed >> 33) as usize;
+            let atl = ["1,2,3", "3,,1", ",1,", ",,"][pick % 4];
+            let btl = ["1,2,3,4,5,6", "6,5,4,3,2,1", "1,,2,,,", ",,,,,"][(pick / 4) % 4];
+            let booth = ["Town Hall", "School", "POSTAL 1"][(pick / 16) % 3];
+            writeln!(prefs, "QLD,Alpha,{booth},1,1,{paper},{atl},{btl}").unwrap();
+        }
+        let polling_places = "\
+2019 Federal Election polling places
+State,DivisionID,DivisionNm,PollingPlaceID,PollingPlaceTypeID,PollingPlaceNm,PremisesNm,PremisesAddress1,PremisesAddress2,PremisesAddress3,PremisesSuburb,PremisesStateAb,PremisesPostCode,Latitude,Longitude
+QLD,1,Alpha,11,1,Town Hall,Hall,1 Main St,,,Alpha,QLD,4000,-27.5,153.0
+QLD,1,Alpha,12,1,School,School,2 Main St,,,Alpha,QLD,4000,-27.6,153.1
+";
+        let distribute = |threads| {
+            let mut output = Vec::new();
+            booth_npps_from_readers(
+                &parties,
+                StateAb::QLD,
+                vec![(
+                    "prefs".to_owned(),
+                    Box::new(std::io::Cursor::new(prefs.clone())),
+                )],
+                polling_places.as_bytes(),
+                &mut output,
+                DistributionOptions {
+                    threads: Some(threads),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            output
+        };
+        assert_eq!(distribute(1), distribute(4));
+    }
+
+    #[test]
+    fn booth_totals_must_match() {
+        let mut keys = PlainKeys;
+        let mut booth_counts = HashMap::new();
+        booth_counts.insert(keys.key("Alpha", "Town Hall"), vec![1, 2, 3].into());
+        check_booth_totals(&mut booth_counts, &keys, &mut BTreeMap::new()).unwrap();
+        assert_eq!(
+            booth_counts[&keys.key("Alpha", "Town Hall")].to_vec(),
+            [1, 2]
+        );
+
+        booth_counts.insert(keys.key("Alpha", "School"), vec![1, 2, 4].into());
+        booth_counts.insert(keys.key("Alpha", "Town Hall"), vec![1, 2, 3].into());
+        assert!(check_booth_totals(&mut booth_counts, &keys, &mut BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn row_lengths_must_match() {
+        let mut rows = BTreeMap::new();
+        rows.insert(("Alpha", "Postal"), vec![1, 2, 3, 4, 5]);
+        let lengths = |rows: &BTreeMap<_, Vec<usize>>| {
+            rows.iter().map(|(k, v)| (*k, v.len())).collect::<Vec<_>>()
+        };
+        assert!(check_row_lengths(lengths(&rows), 5).is_ok());
+        rows.insert(("Alpha", "Absent"), vec![1, 2, 3, 4]);
+        assert!(check_row_lengths(lengths(&rows), 5).is_err());
+    }
+
+    #[test]
+    fn sparse_counts() {
+        let mut dense = ComboCounts::zeroed(6, false);
+        let mut sparse = ComboCounts::zeroed(6, true);
+        for idx in [1, 4, 4, 5, 5, 5] {
+            dense.increment(idx);
+            sparse.increment(idx);
+        }
+        assert_eq!(sparse.to_vec(), dense.to_vec());
+        assert_eq!((sparse.len(), sparse.sum()), (6, 6));
+        assert_eq!((sparse.pop(), dense.pop()), (Some(3), Some(3)));
+        assert_eq!(sparse.to_vec(), [0, 1, 0, 0, 2]);
+        assert_eq!(sparse.to_vec(), dense.to_vec());
+    }
+
+    #[test]
+    fn expected_formal_mismatch() {
+        assert!(check_expected_formal(100, 100, false).is_ok());
+        assert!(check_expected_formal(99, 100, false).is_err());
+        assert!(check_expected_formal(99, 100, true).is_ok());
+    }
+
+    #[test]
+    fn seeded_sample() {
+        assert!(Sample::new(0.0, 0, false).is_err());
+        assert!(Sample::new(1.5, 0, false).is_err());
+
+        let picks = |seed| {
+            let mut sampler = Sampler::new(Sample::new(0.1, seed, false).unwrap());
+            (0..10_000).filter(|_| sampler.include()).count()
+        };
+        assert_eq!(picks(1), picks(1));
+        assert_ne!(picks(1), picks(2));
+        assert!((900..1100).contains(&picks(1)));
+
+        let mut all = Sampler::new(Sample::new(1.0, 0, false).unwrap());
+        assert!((0..1000).all(|_| all.include()));
+
+        // ballots left out of the sample aren't counted as informal (or anything else)
+        let mut parties = Parties::new();
+        for (k, v) in [
+            ("Alp", ["A:Alp", "A:SMITH John"]),
+            ("Lnp", ["B:Lnp", "B:GREY Tom"]),
+        ] {
+            parties.insert(k.to_string(), v.iter().map(ToString::to_string).collect());
+        }
+        let mut prefs = String::from(
+            "State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Alp,B:Lnp,A:SMITH John,B:GREY Tom\n",
+        );
+        for paper in 1..=200 {
+            writeln!(prefs, "QLD,Alpha,Town Hall,1,1,{paper},,,,").unwrap();
+        }
+        let mut ballots =
+            BallotReader::from_source(&parties, csv_prefs_reader(prefs.as_bytes()), true).unwrap();
+        ballots.set_sample(Sample::new(0.25, 0, false).unwrap());
+        let mut read = 0;
+        while ballots.next_ballot().unwrap().is_some() {
+            read += 1;
+        }
+        assert!((25..75).contains(&read));
+        assert_eq!(ballots.informal_count(), read);
+        assert_eq!(ballots.unsampled_count(), 200 - read);
+
+        let mut counts: BoothCounts<&str> = HashMap::from([("Town Hall", vec![3, 0, 1].into())]);
+        Sample::new(0.25, 0, true)
+            .unwrap()
+            .scale_counts(&mut counts);
+        assert_eq!(counts["Town Hall"].to_vec(), [12, 0, 4]);
+    }
+
     #[test]
     fn u8_b10_test() {
         assert_eq!(0, parse_u8_b10(b""));
@@ -911,4 +3474,11 @@ mod tests {
             assert_eq!(i, parse_u8_b10(i.to_string().as_bytes()));
         }
     }
+
+    #[test]
+    fn u8_b10_saturates() {
+        assert_eq!(usize::MAX, parse_u8_b10(b"123456789012345678901234567890"));
+        assert_eq!(usize::MAX, parse_u8_b10(&[b'9'; 40]));
+        assert_eq!(usize::MAX, parse_u8_b10(b"100000000000000000000000000000"));
+    }
 }
@@ -0,0 +1,143 @@
+//! Approximate comparison of output files, for regression testing.
+//!
+//! The distribution phase is exact, but the projection and combination phases
+//! do floating-point arithmetic whose last few digits can drift whenever the
+//! order of summation changes. So: text cells must match exactly, and numeric
+//! cells must match to within a tolerance.
+
+use crate::app::CliCompare;
+use crate::utils::open_csvz_from_path;
+use color_eyre::eyre::{bail, Context, Result};
+use std::io::Read;
+
+/// A single cell that differs between two files.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CellDifference {
+    /// The line number in the files (the header is line 1)
+    pub line: u64,
+    /// The column header
+    pub column: String,
+    pub left: String,
+    pub right: String,
+}
+
+/// Are two cells close enough?
+///
+/// If both parse as numbers, they are compared to within `tolerance`;
+/// otherwise they must be identical.
+fn cells_match(left: &str, right: &str, tolerance: f64) -> bool {
+    if left == right {
+        return true;
+    }
+    match (left.parse::<f64>(), right.parse::<f64>()) {
+        (Ok(l), Ok(r)) => (l - r).abs() <= tolerance,
+        _ => false,
+    }
+}
+
+/// Compare two CSVs of the same structure, row by row and cell by cell.
+///
+/// Errors if the headers or the number of rows differ, as then there's no sensible
+/// cell-by-cell comparison to make.
+pub fn compare_csvs<L: Read, R: Read>(
+    left: L,
+    right: R,
+    tolerance: f64,
+) -> Result<Vec<CellDifference>> {
+    let mut left_rdr = csv::ReaderBuilder::new().flexible(true).from_reader(left);
+    let mut right_rdr = csv::ReaderBuilder::new().flexible(true).from_reader(right);
+
+    let header = left_rdr.headers()?.clone();
+    if &header != right_rdr.headers()? {
+        bail!(
+            "Headers differ:\n\t{:?}\n\t{:?}",
+            header,
+            right_rdr.headers()?
+        );
+    }
+
+    let mut out = Vec::new();
+    let mut left_rows = left_rdr.records();
+    let mut right_rows = right_rdr.records();
+    let mut line = 1;
+    loop {
+        line += 1;
+        let (l, r) = match (left_rows.next(), right_rows.next()) {
+            (None, None) => break,
+            (Some(l), Some(r)) => (l?, r?),
+            _ => bail!("Files have different numbers of rows (from line {})", line),
+        };
+        if l.len() != r.len() {
+            bail!(
+                "Line {} has {} fields in one file and {} in the other",
+                line,
+                l.len(),
+                r.len()
+            );
+        }
+        for (i, (lc, rc)) in l.iter().zip(r.iter()).enumerate() {
+            if !cells_match(lc, rc, tolerance) {
+                out.push(CellDifference {
+                    line,
+                    column: header.get(i).unwrap_or_default().to_owned(),
+                    left: lc.to_owned(),
+                    right: rc.to_owned(),
+                });
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Performs the `compare` subcommand.
+pub fn do_compare(args: &CliCompare) -> Result<()> {
+    let left = open_csvz_from_path(&args.left)
+        .with_context(|| format!("Could not open {}", args.left.display()))?;
+    let right = open_csvz_from_path(&args.right)
+        .with_context(|| format!("Could not open {}", args.right.display()))?;
+
+    let diffs = compare_csvs(left, right, args.tolerance)?;
+    for d in &diffs {
+        println!("line {}\t{}\t{}\t{}", d.line, d.column, d.left, d.right);
+    }
+    if !diffs.is_empty() {
+        bail!(
+            "{} cells differ by more than {}",
+            diffs.len(),
+            args.tolerance
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn approximate_comparison() {
+        let a = "SA1_id,Alp,Lnp,Total\n3000001,0.6666666666666666,1.3333333333333333,2\n3000002,1,1,2\n";
+        let b = "SA1_id,Alp,Lnp,Total\n3000001,0.6666666666666667,1.3333333333333333,2\n3000002,1,1.5,2.5\n";
+        let diffs = compare_csvs(a.as_bytes(), b.as_bytes(), 1e-6).unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                CellDifference {
+                    line: 3,
+                    column: "Lnp".into(),
+                    left: "1".into(),
+                    right: "1.5".into()
+                },
+                CellDifference {
+                    line: 3,
+                    column: "Total".into(),
+                    left: "2".into(),
+                    right: "2.5".into()
+                },
+            ]
+        );
+        // key columns are compared exactly
+        assert!(!cells_match("Alpha_Town Hall", "Alpha_Town  Hall", 1e-6));
+        // structural mismatches are errors
+        assert!(compare_csvs(a.as_bytes(), &b"SA1_id,Alp\n"[..], 1e-6).is_err());
+    }
+}
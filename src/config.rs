@@ -1,34 +1,29 @@
 //! Generation and loading of configuration files.
 
-use crate::booths::Parties;
-use crate::term::{BOLD, END};
+use crate::booths::{Parties, SpecialVotes};
+use crate::term::{self, print_table};
 use crate::utils::{
-    filter_candidates, input, open_csvz_from_path, read_party_abbrvs, CandsData, FilteredCandidate,
-    StateAb,
+    filter_candidates, input, open_csvz_from_path, party_names, read_party_abbrvs,
+    read_party_abbrvs_checked, utc_timestamp, CandsData, FilteredCandidate, StateAb, ToStateAb,
 };
-use color_eyre::eyre::{bail, Context, ContextCompat, Result};
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat, Result};
+use color_eyre::Section;
 use indexmap::{IndexMap, IndexSet};
 use itertools::Itertools;
 use std::collections::{BTreeMap, HashMap};
-use std::fs::read_to_string;
-use std::io::Write;
+use std::fs::{read_to_string, File};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use tabwriter::TabWriter;
-use toml_edit::{ser, Document, Item, TableLike};
+use toml_edit::{table, value, Document, Item, Table, TableLike, Value};
+use tracing::warn;
 
-// TODO: long term goals to get back to Python equivalent functionality
-// We will support a TOML setup that's otherwise consistent with Python's ConfigParser's
+// We support a TOML setup that's otherwise consistent with Python's ConfigParser's
 // "basic interpolation" mode. This means there's a special [DEFAULT] section, and then
 // other, arbitrarily-named sections after that.
-// Interpolation will pull from other keys in that section and then from [DEFAULT] if needed.
-// To have an interpolation reference loop is a runtime error.
-
-// But for now, interpolation is way too much effort.
-// Let's step back and add that back in once TOML supports it down the line.
-// see https://github.com/toml-lang/toml/issues/445
-// Or at least put it in a separate crate
-
-// We're keeping defaults though.
+// Interpolation (`${KEY}`, as TOML itself doesn't have it: see
+// https://github.com/toml-lang/toml/issues/445) pulls from other keys in that section
+// and then from [DEFAULT] if needed. To have an interpolation reference loop is a runtime error.
 
 /// Quickly dump a configuration from a file
 // pub fn cfgdump(cfgpath: &Path) -> Result<()> {
@@ -38,12 +33,29 @@ use toml_edit::{ser, Document, Item, TableLike};
 //     Ok(())
 // }
 
+/// The configuration file "path" that means standard input.
+pub const STDIN_PATH: &str = "-";
+
+/// Read a configuration file's TOML from `reader`.
+pub fn get_cfg_doc(mut reader: impl Read) -> Result<Document> {
+    let mut text = String::new();
+    reader
+        .read_to_string(&mut text)
+        .context("Config file could not be read")?;
+    text.parse::<Document>()
+        .context("Config file could not be parsed")
+}
+
 /// Does what it says on the tin (or at least, the function signature).
+///
+/// A `cfgpath` of [`STDIN_PATH`] reads standard input. Either way, relative paths in the
+/// configuration resolve against the current directory, not the configuration file's.
 pub fn get_cfg_doc_from_path(cfgpath: &Path) -> Result<Document> {
-    read_to_string(cfgpath)
-        .context("Config file could not be read")?
-        .parse::<Document>()
-        .context("Config file could not be parsed")
+    if cfgpath == Path::new(STDIN_PATH) {
+        get_cfg_doc(std::io::stdin().lock())
+    } else {
+        get_cfg_doc(File::open(cfgpath).context("Config file could not be read")?)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -56,6 +68,9 @@ pub struct Scenario {
     pub polling_places: PathBuf,
     #[serde(rename = "SA1S_BREAKDOWN_PATH")]
     pub sa1s_breakdown: Option<PathBuf>,
+    /// The Senate votes at each booth, to scale the SA1 breakdown by (see [`crate::multiplier::ProjectionOptions`])
+    #[serde(rename = "SENATE_VOTES_PATH")]
+    pub senate_votes: Option<PathBuf>,
     #[serde(rename = "OUTPUT_DIR")]
     pub output_dir: PathBuf,
     #[serde(rename = "NPP_BOOTHS_FN")]
@@ -64,18 +79,198 @@ pub struct Scenario {
     pub sa1s_prefs: Option<PathBuf>,
     #[serde(rename = "NPP_DISTS_FN")]
     pub npp_dists: Option<PathBuf>,
+    /// Where the combination phase's JSON goes, if not next to `npp_dists`
+    /// (and if set, the JSON is always written)
+    #[serde(rename = "NPP_DISTS_JSON_FN")]
+    pub npp_dists_json: Option<PathBuf>,
+    /// One or more parts of the preferences file, to be read in sequence
     #[serde(rename = "PREFS_PATH")]
-    pub prefs_path: PathBuf,
+    pub prefs_path: Vec<PathBuf>,
     #[serde(rename = "SA1S_DISTS_PATH")]
     pub sa1s_dists: Option<PathBuf>,
     #[serde(rename = "STATE")]
     pub state: StateAb,
+    /// Whether the combination phase should also output JSON (`--js` forces it on)
+    #[serde(rename = "WRITE_JS", default)]
+    pub write_js: bool,
+    /// The official number of formal votes, to check the distribution against
+    #[serde(rename = "EXPECTED_FORMAL")]
+    pub expected_formal: Option<usize>,
     #[serde(rename = "GROUPS")]
     #[serde(with = "indexmap::serde_seq")]
     pub groups: Parties,
+    /// Short labels for some of the `groups`, used in combination column headers
+    /// instead of the (full) group names
+    #[serde(skip)]
+    pub aliases: IndexMap<String, String>,
+    /// The kinds of special votes, if not the usual ones (see [`crate::booths::SpecialVotes`])
+    #[serde(
+        rename = "SPECIAL_VOTES",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub special_votes: Option<SpecialVotes>,
     // Optional paths are those for the latter two phases
 }
 
+impl Scenario {
+    /// Construct a Scenario with everything needed for the distribution phase.
+    ///
+    /// As in a configuration file, `npp_booths_fn` is a file name relative to
+    /// `output_dir/name`, whereas `prefs_path` (one or more parts) and `polling_places` are used as-is.
+    /// Use [`Scenario::with_projection`] and [`Scenario::with_combination`] to enable the later phases.
+    #[allow(clippy::too_many_arguments)] // mirrors the required config keys
+    pub fn new(
+        name: &str,
+        year: &str,
+        state: StateAb,
+        groups: Parties,
+        prefs_path: Vec<PathBuf>,
+        polling_places: impl Into<PathBuf>,
+        output_dir: impl Into<PathBuf>,
+        npp_booths_fn: impl AsRef<Path>,
+    ) -> Self {
+        let output_dir = output_dir.into();
+        let npp_booths = output_dir.join(name).join(npp_booths_fn);
+        Self {
+            name: name.to_owned(),
+            year: year.to_owned(),
+            polling_places: polling_places.into(),
+            sa1s_breakdown: None,
+            senate_votes: None,
+            output_dir,
+            npp_booths,
+            sa1s_prefs: None,
+            npp_dists: None,
+            npp_dists_json: None,
+            prefs_path,
+            sa1s_dists: None,
+            state,
+            write_js: false,
+            expected_formal: None,
+            groups,
+            aliases: IndexMap::new(),
+            special_votes: None,
+        }
+    }
+
+    /// The `groups`, keyed by their aliases (where they have one) rather than their full names.
+    ///
+    /// The group names only matter as labels, so this is what the phases should be given.
+    pub fn labelled_groups(&self) -> Parties {
+        self.groups
+            .iter()
+            .map(|(name, cands)| {
+                let label = self.aliases.get(name).unwrap_or(name);
+                (label.clone(), cands.clone())
+            })
+            .collect()
+    }
+
+    /// Where an output file called `filename` goes: `output_dir/name/filename`.
+    pub fn output_path(&self, filename: impl AsRef<Path>) -> PathBuf {
+        self.output_dir.join(&self.name).join(filename)
+    }
+
+    /// Move the outputs to `output_dir/name`, keeping their file names.
+    pub fn set_output_dir(&mut self, output_dir: impl Into<PathBuf>) {
+        self.output_dir = output_dir.into();
+        self.npp_booths = self.relocated(&self.npp_booths);
+        self.sa1s_prefs = self.sa1s_prefs.as_deref().map(|p| self.relocated(p));
+        self.npp_dists = self.npp_dists.as_deref().map(|p| self.relocated(p));
+        self.npp_dists_json = self.npp_dists_json.as_deref().map(|p| self.relocated(p));
+    }
+
+    /// An output path moved to the current `output_dir/name`
+    fn relocated(&self, path: &Path) -> PathBuf {
+        path.file_name()
+            .map_or_else(|| path.to_owned(), |f| self.output_path(f))
+    }
+
+    /// Enable the projection phase, reading `sa1s_breakdown` and writing
+    /// `sa1s_prefs_fn` (relative to `output_dir/name`).
+    #[must_use]
+    pub fn with_projection(
+        mut self,
+        sa1s_breakdown: impl Into<PathBuf>,
+        sa1s_prefs_fn: impl AsRef<Path>,
+    ) -> Self {
+        self.sa1s_breakdown = Some(sa1s_breakdown.into());
+        self.sa1s_prefs = Some(self.output_path(sa1s_prefs_fn));
+        self
+    }
+
+    /// Enable the combination phase, reading `sa1s_dists` and writing
+    /// `npp_dists_fn` (relative to `output_dir/name`).
+    #[must_use]
+    pub fn with_combination(
+        mut self,
+        sa1s_dists: impl Into<PathBuf>,
+        npp_dists_fn: impl AsRef<Path>,
+    ) -> Self {
+        self.sa1s_dists = Some(sa1s_dists.into());
+        self.npp_dists = Some(self.output_path(npp_dists_fn));
+        self
+    }
+}
+
+/// Resolve the `${KEY}` references in `value`, the setting for `key` in `scenario`.
+///
+/// As in `ConfigParser`'s basic interpolation, each `KEY` is looked up in the `scenario`
+/// and then in the `defaults`, and any references in its value are resolved in turn.
+/// It's an error to refer to a key that isn't set (or isn't a string), or to loop back.
+fn interpolate(
+    key: &str,
+    value: &str,
+    scenario: &dyn TableLike,
+    defaults: &HashMap<&str, &Item>,
+) -> Result<String> {
+    fn resolve(
+        value: &str,
+        scenario: &dyn TableLike,
+        defaults: &HashMap<&str, &Item>,
+        seen: &mut Vec<String>,
+    ) -> Result<String> {
+        let mut out = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after
+                .find('}')
+                .with_context(|| format!("Unclosed ${{ in {value:?}"))?;
+            let referenced = &after[..end];
+            if seen.iter().any(|k| k == referenced) {
+                bail!(
+                    "Interpolation loop: {} -> {}",
+                    seen.join(" -> "),
+                    referenced
+                );
+            }
+            let item = scenario
+                .get(referenced)
+                .or_else(|| defaults.get(referenced).copied())
+                .with_context(|| format!("{value:?} refers to {referenced}, which isn't set"))?
+                .as_str()
+                .with_context(|| {
+                    format!("{value:?} refers to {referenced}, which isn't a string")
+                })?;
+            seen.push(referenced.to_owned());
+            out.push_str(&resolve(item, scenario, defaults, seen)?);
+            seen.pop();
+            rest = &after[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+    resolve(value, scenario, defaults, &mut vec![key.to_owned()])
+        .with_context(|| format!("Could not interpolate {key}"))
+}
+
+/// How many groups a scenario needs, unless it sets `MIN_GROUPS`:
+/// an "N-party-preferred" count of fewer than two parties isn't very meaningful.
+pub const DEFAULT_MIN_GROUPS: usize = 2;
+
 /// Get all the Scenarios, with defaults suitably propogated and paths ready to use!
 /// This function can panic (but shouldn't).
 pub fn get_scenarios(cfg: &Document) -> Result<BTreeMap<String, Scenario>> {
@@ -113,102 +308,184 @@ pub fn get_scenarios(cfg: &Document) -> Result<BTreeMap<String, Scenario>> {
 
         #[allow(clippy::items_after_statements)]
         /// We are able to abstract out much of the logic into this...
-        fn get_attribute<'a, T, F>(
-            key: &'a str,
-            scenario: &'a dyn TableLike,
-            defaults: &'a HashMap<&str, &Item>,
+        /// (including [`interpolate`]-ing the value)
+        fn get_attribute<T, F>(
+            key: &str,
+            scenario: &dyn TableLike,
+            defaults: &HashMap<&str, &Item>,
             conversion_fn: F,
-        ) -> Option<T>
+        ) -> Result<Option<T>>
         where
-            F: FnOnce(&'a str) -> T,
+            F: FnOnce(String) -> T,
         {
             scenario
                 .get(key)
                 .or_else(|| defaults.get(key).copied())
                 .and_then(toml_edit::Item::as_str)
-                .map(conversion_fn)
+                .map(|x| interpolate(key, x, scenario, defaults).map(conversion_fn))
+                .transpose()
         }
 
         // Non-Optional: YEAR
         let year =
-            get_attribute("YEAR", scenario, &defaults, String::from).context("Missing YEAR")?;
+            get_attribute("YEAR", scenario, &defaults, String::from)?.context("Missing YEAR")?;
 
         // Non-Optional paths: POLLING_PLACES_PATH, OUTPUT_DIR, NPP_BOOTHS_FN, PREFS_PATH
 
         let polling_places =
-            get_attribute("POLLING_PLACES_PATH", scenario, &defaults, PathBuf::from)
+            get_attribute("POLLING_PLACES_PATH", scenario, &defaults, PathBuf::from)?
                 .context("Missing POLLING_PLACES_PATH")?;
 
-        let output_dir = get_attribute("OUTPUT_DIR", scenario, &defaults, PathBuf::from)
+        let output_dir = get_attribute("OUTPUT_DIR", scenario, &defaults, PathBuf::from)?
             .context("Missing OUTPUT_DIR")?;
 
-        let npp_booths = get_attribute("NPP_BOOTHS_FN", scenario, &defaults, PathBuf::from)
-            .map(|x| output_dir.clone().join(&name).join(x))
+        let npp_booths = get_attribute("NPP_BOOTHS_FN", scenario, &defaults, PathBuf::from)?
             .context("Missing NPP_BOOTHS_FN")?;
 
-        let prefs_path = get_attribute("PREFS_PATH", scenario, &defaults, PathBuf::from)
-            .context("Missing PREFS_PATH")?;
-
-        // Optional Paths: SA1S_BREAKDOWN_PATH, SA1S_PREFS_FN, NPP_DISTS_FN, SA1S_DISTS_PATH
-
-        let sa1s_breakdown =
-            get_attribute("SA1S_BREAKDOWN_PATH", scenario, &defaults, PathBuf::from);
+        // PREFS_PATH may be a single path, or an array of parts
+        let prefs_path: Vec<PathBuf> = match scenario
+            .get("PREFS_PATH")
+            .or_else(|| defaults.get("PREFS_PATH").copied())
+            .context("Missing PREFS_PATH")?
+        {
+            x if x.is_str() => vec![PathBuf::from(interpolate(
+                "PREFS_PATH",
+                x.as_str().unwrap(),
+                scenario,
+                &defaults,
+            )?)],
+            x => x
+                .as_array()
+                .context("PREFS_PATH must be a path or an array of paths")?
+                .iter()
+                .map(|p| {
+                    let p = p
+                        .as_str()
+                        .context("PREFS_PATH must be a path or an array of paths")?;
+                    interpolate("PREFS_PATH", p, scenario, &defaults).map(PathBuf::from)
+                })
+                .collect::<Result<_>>()?,
+        };
+        if prefs_path.is_empty() {
+            bail!("PREFS_PATH is empty");
+        }
 
-        let sa1s_prefs = get_attribute("SA1S_PREFS_FN", scenario, &defaults, PathBuf::from)
-            .map(|x| output_dir.clone().join(&name).join(x));
+        // Not optional: STATE
+        let state: StateAb =
+            get_attribute("STATE", scenario, &defaults, |x| x.as_str().to_state_ab())?
+                .context("Missing STATE")?
+                .context("Invalid STATE")?;
 
-        let npp_dists = get_attribute("NPP_DISTS_FN", scenario, &defaults, PathBuf::from)
-            .map(|x| output_dir.clone().join(&name).join(x));
+        // Optional: WRITE_JS, defaulting to false
+        let write_js = scenario
+            .get("WRITE_JS")
+            .or_else(|| defaults.get("WRITE_JS").copied())
+            .map(|x| x.as_bool().context("WRITE_JS must be true or false"))
+            .transpose()?
+            .unwrap_or(false);
 
-        let sa1s_dists = get_attribute("SA1S_DISTS_PATH", scenario, &defaults, PathBuf::from);
+        // Optional: EXPECTED_FORMAL
+        let expected_formal = scenario
+            .get("EXPECTED_FORMAL")
+            .or_else(|| defaults.get("EXPECTED_FORMAL").copied())
+            .map(|x| {
+                x.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .context("EXPECTED_FORMAL must be a non-negative whole number")
+            })
+            .transpose()?;
 
-        // Not optional: STATE
-        let state: StateAb =
-            get_attribute("STATE", scenario, &defaults, StateAb::from).context("Missing STATE")?;
+        // Optional: MIN_GROUPS
+        let min_groups = scenario
+            .get("MIN_GROUPS")
+            .or_else(|| defaults.get("MIN_GROUPS").copied())
+            .map(|x| {
+                x.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .context("MIN_GROUPS must be a non-negative whole number")
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_MIN_GROUPS);
 
         // Really the only complicated parse is the GROUPS.
-        let mut groups: Parties = IndexMap::new();
-        if scenario.contains_key("GROUPS") {
-            for (group_name, group) in scenario.get("GROUPS").unwrap().as_table().unwrap() {
-                let groupvec = group
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .map(|x| String::from(x.as_str().unwrap()))
-                    .collect::<Vec<String>>();
-                groups.insert(String::from(group_name), groupvec);
-            }
-        } else if defaults.contains_key("GROUPS") {
-            for (group_name, group) in defaults.get("GROUPS").unwrap().as_table().unwrap() {
-                let groupvec = group
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .map(|x| String::from(x.as_str().unwrap()))
-                    .collect::<Vec<String>>();
-                groups.insert(String::from(group_name), groupvec);
-            }
-        } else {
-            bail!("Missing GROUPS");
+        let (groups, aliases) = match scenario
+            .get("GROUPS")
+            .or_else(|| defaults.get("GROUPS").copied())
+        {
+            Some(x) => parse_groups(x.as_table_like().context("GROUPS must be a table")?)
+                .with_context(|| format!("Invalid GROUPS for scenario {name}"))?,
+            None => bail!("Missing GROUPS"),
+        };
+        if groups.len() < min_groups {
+            return Err(eyre!(
+                "Scenario {} has {} groups, but needs at least {}",
+                name,
+                groups.len(),
+                min_groups
+            ))
+            .suggestion(
+                "Add groups to GROUPS, or (if you really do want so few) lower MIN_GROUPS.",
+            );
         }
 
-        out.insert(
-            String::from(&name),
-            Scenario {
-                name,
-                year,
-                polling_places,
-                sa1s_breakdown,
-                output_dir,
-                npp_booths,
-                sa1s_prefs,
-                npp_dists,
-                prefs_path,
-                sa1s_dists,
-                state,
-                groups,
-            },
+        let mut out_scenario = Scenario::new(
+            &name,
+            &year,
+            state,
+            groups,
+            prefs_path,
+            polling_places,
+            output_dir,
+            npp_booths,
         );
+        out_scenario.write_js = write_js;
+        out_scenario.expected_formal = expected_formal;
+        out_scenario.aliases = aliases;
+
+        // Optional: SPECIAL_VOTES, a table of booth name substrings to display names
+        out_scenario.special_votes = scenario
+            .get("SPECIAL_VOTES")
+            .or_else(|| defaults.get("SPECIAL_VOTES").copied())
+            .map(|x| {
+                x.as_table_like()
+                    .context("SPECIAL_VOTES must be a table")?
+                    .iter()
+                    .map(|(substring, name)| {
+                        let name = name.as_str().with_context(|| {
+                            format!("The SPECIAL_VOTES name for {substring} must be a string")
+                        })?;
+                        Ok((substring.to_owned(), name.to_owned()))
+                    })
+                    .collect::<Result<SpecialVotes>>()
+            })
+            .transpose()?;
+
+        // Optional Paths: SA1S_BREAKDOWN_PATH, SENATE_VOTES_PATH, SA1S_PREFS_FN, NPP_DISTS_FN,
+        // NPP_DISTS_JSON_FN, SA1S_DISTS_PATH
+        // These are set individually (rather than by `with_projection` etc.)
+        // so that a partially-specified later phase still loads.
+
+        out_scenario.sa1s_breakdown =
+            get_attribute("SA1S_BREAKDOWN_PATH", scenario, &defaults, PathBuf::from)?;
+
+        out_scenario.senate_votes =
+            get_attribute("SENATE_VOTES_PATH", scenario, &defaults, PathBuf::from)?;
+
+        out_scenario.sa1s_prefs =
+            get_attribute("SA1S_PREFS_FN", scenario, &defaults, PathBuf::from)?
+                .map(|x| out_scenario.output_path(x));
+
+        out_scenario.npp_dists = get_attribute("NPP_DISTS_FN", scenario, &defaults, PathBuf::from)?
+            .map(|x| out_scenario.output_path(x));
+
+        out_scenario.npp_dists_json =
+            get_attribute("NPP_DISTS_JSON_FN", scenario, &defaults, PathBuf::from)?
+                .map(|x| out_scenario.output_path(x));
+
+        out_scenario.sa1s_dists =
+            get_attribute("SA1S_DISTS_PATH", scenario, &defaults, PathBuf::from)?;
+
+        out.insert(name, out_scenario);
     }
 
     Ok(out)
@@ -220,40 +497,220 @@ pub fn get_scenarios(cfg: &Document) -> Result<BTreeMap<String, Scenario>> {
 //     pub dist_dir: Option<PathBuf>,
 // }
 
+/// Parse a GROUPS table.
+///
+/// Each group is either a list of candidates, or a table of those `candidates`
+/// and an optional short `alias` to use in column headers.
+fn parse_groups(table: &dyn TableLike) -> Result<(Parties, IndexMap<String, String>)> {
+    let mut groups = Parties::new();
+    let mut aliases = IndexMap::new();
+    for (group_name, group) in table.iter() {
+        let candidates = match group.as_table_like() {
+            Some(t) => {
+                if let Some(alias) = t.get("alias") {
+                    let alias = alias
+                        .as_str()
+                        .filter(|a| !a.is_empty())
+                        .with_context(|| format!("The alias for {group_name} must be a string"))?;
+                    aliases.insert(group_name.to_owned(), alias.to_owned());
+                }
+                t.get("candidates")
+            }
+            None => Some(group),
+        }
+        .and_then(Item::as_array)
+        .with_context(|| format!("{group_name} must be a list of candidates"))?;
+        let groupvec = candidates
+            .iter()
+            .map(|x| x.as_str().map(String::from))
+            .collect::<Option<Vec<String>>>()
+            .with_context(|| format!("The candidates for {group_name} must be strings"))?;
+        groups.insert(group_name.to_owned(), groupvec);
+    }
+
+    let labels = groups.keys().map(|name| aliases.get(name).unwrap_or(name));
+    if let Some(dup) = labels.duplicates().next() {
+        return Err(eyre!("More than one group is labelled {}", dup)).suggestion(
+            "Each group's alias must be distinct from every other group's name or alias.",
+        );
+    }
+    Ok((groups, aliases))
+}
+
 /// this function handles `nparty list`
-pub fn list_scenarios(cfgpath: &Path) -> Result<()> {
+///
+/// With `party_details`, groups named for a party's abbreviation also show the party's name.
+pub fn list_scenarios(cfgpath: &Path, party_details: Option<&Path>) -> Result<()> {
     let headers = "Scenario\tPreferred Parties\tPlace\tYear";
     let mut output = Vec::new();
     let doc = get_cfg_doc_from_path(cfgpath)?;
     let scenarios = get_scenarios(&doc)?;
+    let names = party_details
+        .map(|p| -> Result<_> {
+            // the names are only for display, so an unreadable row just goes without
+            Ok(party_names(&read_party_abbrvs(open_csvz_from_path(p)?)))
+        })
+        .transpose()?
+        .unwrap_or_default();
     for (name, scenario) in scenarios {
         let state = scenario.state.to_string();
-        let groups = scenario.groups.keys().join(" v. ");
+        let groups = scenario
+            .groups
+            .keys()
+            .map(|g| match names.get(&g.to_lowercase()) {
+                Some(full) if full != g => format!("{g} ({full})"),
+                _ => g.clone(),
+            })
+            .join(" v. ");
         let year = scenario.year;
         output.push(format!("{name}\t{groups}\t{state}\t{year}"));
     }
 
-    if std::io::IsTerminal::is_terminal(&std::io::stdout()) {
-        let mut tw = TabWriter::new(vec![]);
-        writeln!(&mut tw, "{headers}")?;
-        for i in output {
-            writeln!(&mut tw, "{i}")?;
+    print_table(headers, &output)
+}
+
+/// Select scenarios from `available` by name or by glob pattern (e.g. `*_2PP_AlpLnp`).
+///
+/// An exact name is taken as-is; otherwise every scenario matching the pattern is selected,
+/// in `available`'s order. Anything selected twice is only run once.
+/// Errors if a pattern matches nothing (or isn't a valid pattern).
+pub fn select_scenarios<'a>(
+    requested: &[String],
+    available: impl IntoIterator<Item = &'a String>,
+) -> Result<Vec<String>> {
+    let available: Vec<&String> = available.into_iter().collect();
+    let mut selected: IndexSet<String> = IndexSet::new();
+    for req in requested {
+        if available.contains(&req) {
+            selected.insert(req.clone());
+            continue;
         }
-        tw.flush()?;
-        let output = String::from_utf8(tw.into_inner()?)?;
-        let firstnewline = output.find('\n').unwrap();
-        let head = &output[0..firstnewline];
-        let body = &output[firstnewline..output.len()];
-        println!("{BOLD}{head}{END}{body}");
-    } else {
-        println!("{headers}");
-        for i in output {
-            println!("{i}");
+        let pattern = glob::Pattern::new(req)
+            .with_context(|| format!("Requested scenario {req} is not a valid pattern."))?;
+        let mut matched = false;
+        for &name in &available {
+            if pattern.matches(name) {
+                matched = true;
+                selected.insert(name.clone());
+            }
+        }
+        if !matched {
+            bail!("Requested scenario {req} not found in configuration file.");
         }
     }
+    Ok(selected.into_iter().collect())
+}
+
+/// Read a scenario ordering file for `nparty run --order`: one scenario name per line,
+/// ignoring blank lines and `#` comments.
+pub fn read_scenario_order(path: &Path) -> Result<Vec<String>> {
+    let text = read_to_string(path)
+        .with_context(|| format!("Could not read scenario order file {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Put `names` in the given `order`: the scenarios listed there first (in that order),
+/// then the rest as they were. Listed scenarios that aren't in `names` are ignored.
+pub fn order_scenarios(names: &mut [String], order: &[String]) {
+    // a stable sort, so the unlisted scenarios keep their places relative to each other
+    names.sort_by_key(|n| order.iter().position(|o| o == n).unwrap_or(order.len()));
+}
+
+/// this function handles `nparty show`
+pub fn show_scenario(cfgpath: &Path, name: &str) -> Result<()> {
+    let doc = get_cfg_doc_from_path(cfgpath)?;
+    let scenario = get_scenarios(&doc)?
+        .remove(name)
+        .with_context(|| format!("Requested scenario {name} not found in configuration file."))
+        .with_suggestion(|| {
+            format!(
+                "For a table of available scenarios, try running\n\tnparty list {}",
+                cfgpath.display()
+            )
+        })?;
+    print!(
+        "{}",
+        describe_scenario(&scenario, term::use_color(&std::io::stdout()))?
+    );
     Ok(())
 }
 
+/// A readable description of a `scenario`: its settings, then each group with its candidates.
+///
+/// If `color`, the headings are bold.
+fn describe_scenario(scenario: &Scenario, color: bool) -> Result<String> {
+    let bold = |x: &str| {
+        if color {
+            term::decorate(x, term::BOLD)
+        } else {
+            x.to_owned()
+        }
+    };
+    let path_or_none = |p: &Option<PathBuf>| {
+        p.as_ref()
+            .map_or_else(|| String::from("(none)"), |p| p.display().to_string())
+    };
+
+    let mut settings = vec![
+        ("Year", scenario.year.clone()),
+        ("State", scenario.state.to_string()),
+        (
+            "Preferences",
+            scenario.prefs_path.iter().map(|p| p.display()).join("\n"),
+        ),
+        (
+            "Polling places",
+            scenario.polling_places.display().to_string(),
+        ),
+        ("SA1s breakdown", path_or_none(&scenario.sa1s_breakdown)),
+        ("Senate votes", path_or_none(&scenario.senate_votes)),
+        ("SA1s to districts", path_or_none(&scenario.sa1s_dists)),
+        (
+            "Distribution output",
+            scenario.npp_booths.display().to_string(),
+        ),
+        ("Projection output", path_or_none(&scenario.sa1s_prefs)),
+        ("Combination output", path_or_none(&scenario.npp_dists)),
+        (
+            "Combination JSON output",
+            path_or_none(&scenario.npp_dists_json),
+        ),
+        ("Write JS", scenario.write_js.to_string()),
+    ];
+    if let Some(expected) = scenario.expected_formal {
+        settings.push(("Expected formal", expected.to_string()));
+    }
+
+    // Elastic tabstops for the settings, so that the values line up
+    let mut tw = TabWriter::new(vec![]);
+    for (k, v) in settings {
+        // continuation lines (of several preferences files) have an empty key
+        let v = v.replace('\n', "\n  \t");
+        writeln!(&mut tw, "  {k}:\t{v}")?;
+    }
+    let settings = String::from_utf8(tw.into_inner()?)?;
+
+    let mut groups = Vec::new();
+    for (group, cands) in &scenario.groups {
+        match scenario.aliases.get(group) {
+            Some(alias) => groups.push(format!("  {} (as {alias})\n", bold(group))),
+            None => groups.push(format!("  {}\n", bold(group))),
+        }
+        groups.extend(cands.iter().map(|c| format!("    {c}\n")));
+    }
+    Ok(format!(
+        "{}\n{settings}{}\n{}",
+        bold(&scenario.name),
+        bold("Groups"),
+        groups.concat()
+    ))
+}
+
 pub struct KnownConfigOptions {
     pub sa1s_dists: Option<PathBuf>,
     pub prefs_path: Option<PathBuf>,
@@ -306,19 +763,24 @@ where
 // Then we have three main functions:
 // [x] Turn a Document into Scenarios
 // [-] Create a new Scenario from CLI input
-// [ ] Update a Document from Scenarios
+// [x] Update a Document from Scenarios
 
 // `cli_scenarios()` is about creating one or more Scenarios interactively
 // Previously with `get_scenarios()` and `get_defaults()` we read them from a toml_edit::Document
 // Then with `patch_scenarios()` we shall incorporate the new scenarios into an existing toml_edit::Document
 // (and factor out a Defaults section)
 
+// Scenarios are saved to `progress` (see `progress_path()`) as each one is completed,
+// so that a session cut short can be resumed from `resumed`.
+
 pub fn cli_scenarios(
     existing: Option<&Scenario>,
     candidates: &CandsData,
     known_options: &KnownConfigOptions,
+    resumed: BTreeMap<String, Scenario>,
+    progress: &Path,
 ) -> Result<BTreeMap<String, Scenario>> {
-    let mut out = BTreeMap::new();
+    let mut out = resumed;
     let mut new_scen: String = input("Define a new Scenario? [Y]/n: ")?.to_uppercase();
     while new_scen.starts_with('Y') || new_scen.is_empty() {
         let year = get_option_cli(
@@ -363,7 +825,7 @@ pub fn cli_scenarios(
         let prefs_path = get_option_cli(
             "preferences file path",
             &known_options.prefs_path,
-            existing.map(|x| &x.prefs_path),
+            existing.and_then(|x| x.prefs_path.first()),
             false,
         )
         .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
@@ -376,7 +838,8 @@ pub fn cli_scenarios(
         )
         .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
         let party_details_file = open_csvz_from_path(&party_details)?;
-        let party_abbrvs = read_party_abbrvs(party_details_file);
+        let party_abbrvs = read_party_abbrvs_checked(party_details_file)
+            .context("Could not read the party-details file")?;
 
         let state = get_option_cli(
             "state or territory",
@@ -388,9 +851,29 @@ pub fn cli_scenarios(
 
         // now for the tricky bit
         let mut groups = IndexMap::new();
+        let mut aliases = IndexMap::new();
+
+        // Offer the existing scenario's groups as a starting point (if it's for the same place)
+        if let Some(ex) = existing.filter(|x| x.state == state) {
+            for (group_name, group_cands) in &ex.groups {
+                println!("Existing group {group_name}: {}", group_cands.join(", "));
+                let keep = input(&format!("Keep group {group_name}? [Y]/n: "))?.to_uppercase();
+                if keep.starts_with('Y') || keep.is_empty() {
+                    groups.insert(group_name.clone(), group_cands.clone());
+                    // and its alias, if it has one
+                    if let Some(alias) = ex.aliases.get(group_name) {
+                        aliases.insert(group_name.clone(), alias.clone());
+                    }
+                }
+            }
+        }
 
         // Add a Group
-        let mut add_group = String::from("Y");
+        let mut add_group = if groups.is_empty() {
+            String::from("Y")
+        } else {
+            input("Add a new group? [Y]/n: ")?.to_uppercase()
+        };
 
         while add_group.starts_with('Y') || add_group.is_empty() {
             // what is a group but a list of candidates?
@@ -471,32 +954,32 @@ pub fn cli_scenarios(
         }
 
         // I see no reason to go to the CLI on these. Generator == Defaults Are Fine Here
-        let npp_booths = PathBuf::from(&output_dir)
-            .join(&name)
-            .join("NPP_Booths.csv");
-        let sa1s_prefs = Some(
-            PathBuf::from(&output_dir)
-                .join(&name)
-                .join("SA1s_Prefs.csv"),
-        );
-        let npp_dists = Some(PathBuf::from(&output_dir).join(&name).join("NPP_Dists.csv"));
-
-        let scenario = Scenario {
-            name: name.clone(),
-            year,
-            polling_places,
-            sa1s_breakdown,
-            output_dir,
-            npp_booths,
-            sa1s_prefs,
-            npp_dists,
-            prefs_path,
-            sa1s_dists,
+        let mut scenario = Scenario::new(
+            &name,
+            &year,
             state,
             groups,
-        };
+            vec![prefs_path],
+            polling_places,
+            output_dir,
+            "NPP_Booths.csv",
+        );
+        scenario.aliases = aliases;
+        if let Some(sa1s_breakdown) = sa1s_breakdown {
+            scenario = scenario.with_projection(sa1s_breakdown, "SA1s_Prefs.csv");
+        }
+        if let Some(sa1s_dists) = sa1s_dists {
+            scenario = scenario.with_combination(sa1s_dists, "NPP_Dists.csv");
+        }
 
         out.insert(name.clone(), scenario);
+        // save our progress, but don't give up over it
+        if let Err(e) = std::fs::File::create(progress)
+            .map_err(Into::into)
+            .and_then(|mut f| write_scenarios(&out, "", &mut f))
+        {
+            warn!("Could not save progress to {}: {e}", progress.display());
+        }
         // go again?
         new_scen = input("Define another new Scenario? [Y]/n: ")?.to_uppercase();
     }
@@ -504,14 +987,682 @@ pub fn cli_scenarios(
     Ok(out)
 }
 
-// TODO: function to write scenarios back out
+/// Where `nparty configure` saves its progress towards `configfile`: in the system's temporary
+/// directory, named for a hash of `configfile`'s path.
+pub fn progress_path(configfile: &Path) -> PathBuf {
+    use sha2::{Digest, Sha256};
+    let full =
+        std::env::current_dir().map_or_else(|_| configfile.to_owned(), |d| d.join(configfile));
+    let hash = format!("{:x}", Sha256::digest(full.to_string_lossy().as_bytes()));
+    std::env::temp_dir().join(format!("nparty-configure-{}.toml", &hash[..16]))
+}
+
+/// Read back the Scenarios saved by [`write_scenarios`] during an unfinished `nparty configure`.
+pub fn read_progress(path: &Path) -> Result<BTreeMap<String, Scenario>> {
+    let text = read_to_string(path)
+        .with_context(|| format!("Could not read saved progress {}", path.display()))?;
+    text.parse::<Document>()
+        .map_err(Into::into)
+        .and_then(|doc| get_scenarios(&doc))
+        .with_context(|| format!("Could not parse saved progress {}", path.display()))
+}
+
+/// A comment block for the top of a generated configuration file, recording how it came to be.
+pub fn provenance_comment(candidates: &Path, from: Option<&Path>) -> String {
+    let mut out = format!(
+        "# Generated by nparty {} (nparty configure) at {}\n# Candidates: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        utc_timestamp(std::time::SystemTime::now()),
+        candidates.display()
+    );
+    if let Some(from) = from {
+        out += "# Based on: ";
+        out += &from.display().to_string();
+        out += "\n";
+    }
+    out + "\n"
+}
 
-/// Write an entire `BTreeMap` of `Scenarios` back out to TOML
-pub fn write_scenarios(input: &BTreeMap<String, Scenario>, outfile: &mut dyn Write) -> Result<()> {
-    // we want the top-level tables in the doc to use [key] formatting and for groups to use [key.groups] formatting
-    // so the "pretty" formatting gives us that
-    // (this is important, because non-pretty results in inline tables)
-    let outstring = ser::to_string_pretty(&input).context("Error converting Scenario to TOML")?;
-    outfile.write_all(outstring.as_bytes())?;
+/// Write an entire `BTreeMap` of `Scenarios` back out to TOML,
+/// after a `header` (e.g. from [`provenance_comment`]).
+///
+/// They're written just as a configuration file has them (see [`patch_scenarios`]),
+/// group aliases and all, so [`get_scenarios`] reads them back the same.
+pub fn write_scenarios(
+    input: &BTreeMap<String, Scenario>,
+    header: &str,
+    outfile: &mut dyn Write,
+) -> Result<()> {
+    let mut doc = Document::new();
+    patch_scenarios(&mut doc, input)?;
+    outfile.write_all(header.as_bytes())?;
+    outfile.write_all(doc.to_string().as_bytes())?;
     Ok(())
 }
+
+/// Write a configuration file of `scenarios` to `path`, after a `header` (see [`provenance_comment`]).
+///
+/// If there's a `from` document (as read by `nparty configure --from`), the scenarios are patched
+/// into it (see [`patch_scenarios`]), keeping its header if it already has one.
+/// The file is written under a temporary name and then renamed, so that `path` can be the file
+/// `from` was read from, and so that it's never left half-written.
+pub fn write_configuration(
+    path: &Path,
+    header: &str,
+    scenarios: &BTreeMap<String, Scenario>,
+    from: Option<Document>,
+) -> Result<()> {
+    let patched = from
+        .map(|mut doc| -> Result<String> {
+            patch_scenarios(&mut doc, scenarios)?;
+            Ok(doc.to_string())
+        })
+        .transpose()?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let write = || -> Result<()> {
+        let mut outfile = File::create(&partial)?;
+        match &patched {
+            Some(doc) => {
+                if !doc.starts_with("# Generated by nparty") {
+                    outfile.write_all(header.as_bytes())?;
+                }
+                outfile.write_all(doc.as_bytes())?;
+            }
+            None => write_scenarios(scenarios, header, &mut outfile)?,
+        }
+        outfile.sync_all()?;
+        Ok(std::fs::rename(&partial, path)?)
+    };
+    write().map_err(|e| {
+        std::fs::remove_file(&partial).ok();
+        e.wrap_err(format!("Could not write {}", path.display()))
+    })
+}
+
+/// A group's candidates as a configuration value: a list, or a table if it has an `alias`.
+fn group_value(candidates: &[String], alias: Option<&String>) -> Value {
+    let candidates: Value = candidates.iter().collect();
+    match alias {
+        Some(alias) => [("candidates", candidates), ("alias", alias.into())]
+            .into_iter()
+            .collect(),
+        None => candidates,
+    }
+}
+
+/// A Scenario's settings as they'd appear in its table in a configuration file
+/// (so output files are given by name alone), in order. Unset settings are `None`.
+fn scenario_items(scenario: &Scenario) -> Vec<(&'static str, Option<Item>)> {
+    let path = |p: &Path| value(p.to_string_lossy().as_ref());
+    let file_name = |p: &Path| path(p.file_name().map_or(p, Path::new));
+
+    let mut groups = Table::new();
+    for (name, candidates) in &scenario.groups {
+        groups.insert(
+            name,
+            Item::Value(group_value(candidates, scenario.aliases.get(name))),
+        );
+    }
+    let special_votes = scenario.special_votes.as_ref().map(|special| {
+        let mut t = Table::new();
+        for (substring, name) in special {
+            t.insert(substring, value(name));
+        }
+        Item::Table(t)
+    });
+    let prefs_path = match scenario.prefs_path.as_slice() {
+        [part] => path(part),
+        parts => value(
+            parts
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect::<Value>(),
+        ),
+    };
+
+    vec![
+        ("YEAR", Some(value(&scenario.year))),
+        ("STATE", Some(value(scenario.state.to_string()))),
+        ("PREFS_PATH", Some(prefs_path)),
+        ("POLLING_PLACES_PATH", Some(path(&scenario.polling_places))),
+        (
+            "SA1S_BREAKDOWN_PATH",
+            scenario.sa1s_breakdown.as_deref().map(path),
+        ),
+        (
+            "SENATE_VOTES_PATH",
+            scenario.senate_votes.as_deref().map(path),
+        ),
+        ("SA1S_DISTS_PATH", scenario.sa1s_dists.as_deref().map(path)),
+        ("OUTPUT_DIR", Some(path(&scenario.output_dir))),
+        ("NPP_BOOTHS_FN", Some(file_name(&scenario.npp_booths))),
+        (
+            "SA1S_PREFS_FN",
+            scenario.sa1s_prefs.as_deref().map(file_name),
+        ),
+        ("NPP_DISTS_FN", scenario.npp_dists.as_deref().map(file_name)),
+        (
+            "NPP_DISTS_JSON_FN",
+            scenario.npp_dists_json.as_deref().map(file_name),
+        ),
+        ("WRITE_JS", scenario.write_js.then(|| value(true))),
+        (
+            "EXPECTED_FORMAL",
+            scenario
+                .expected_formal
+                .and_then(|x| i64::try_from(x).ok())
+                .map(value),
+        ),
+        ("GROUPS", Some(Item::Table(groups))),
+        ("SPECIAL_VOTES", special_votes),
+    ]
+}
+
+/// An item's value without any surrounding whitespace or comments, for comparisons.
+fn plain(item: &Item) -> String {
+    match item {
+        Item::Value(v) => {
+            let mut v = v.clone();
+            v.decor_mut().clear();
+            v.to_string()
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Set `key` to `item`, keeping any comment that follows an existing value.
+fn replace_item(table: &mut dyn TableLike, key: &str, item: Item) {
+    match (table.get_mut(key), item) {
+        (Some(Item::Value(old)), Item::Value(mut new)) => {
+            *new.decor_mut() = old.decor().clone();
+            *old = new;
+        }
+        (Some(old), new) => *old = new,
+        (None, new) => {
+            table.insert(key, new);
+        }
+    }
+}
+
+/// Update an existing GROUPS table to `groups`, group by group,
+/// keeping any aliases (and comments) the groups already have.
+fn patch_groups(table: &mut dyn TableLike, groups: &Parties, aliases: &IndexMap<String, String>) {
+    let gone: Vec<String> = table
+        .iter()
+        .map(|(name, _)| name.to_owned())
+        .filter(|name| !groups.contains_key(name))
+        .collect();
+    for name in gone {
+        table.remove(&name);
+    }
+    for (name, candidates) in groups {
+        if let Some(group) = table
+            .get_mut(name)
+            .and_then(Item::as_table_like_mut)
+            .filter(|t| t.contains_key("candidates"))
+        {
+            replace_item(
+                group,
+                "candidates",
+                value(candidates.iter().collect::<Value>()),
+            );
+        } else if table.contains_key(name) {
+            replace_item(table, name, value(candidates.iter().collect::<Value>()));
+        } else {
+            table.insert(
+                name,
+                Item::Value(group_value(candidates, aliases.get(name))),
+            );
+        }
+    }
+}
+
+/// Incorporate `scenarios` into an existing configuration `doc`: new scenarios are added,
+/// and existing ones are overwritten in place, so that comments, key order,
+/// and the `[DEFAULT]` section all survive.
+///
+/// Only settings that have changed are touched, so a setting that still comes out the same
+/// (from `[DEFAULT]`, or by interpolation) is left as it was. A new scenario leaves out
+/// any setting that `[DEFAULT]` already has. A scenario can't leave out a setting that
+/// `[DEFAULT]` has, though, because it would just inherit it again: that's an error.
+pub fn patch_scenarios(doc: &mut Document, scenarios: &BTreeMap<String, Scenario>) -> Result<()> {
+    let existing = get_scenarios(doc).context("Could not read the existing scenarios")?;
+    let defaults: HashMap<String, String> = doc
+        .get("DEFAULT")
+        .and_then(Item::as_table_like)
+        .map(|t| t.iter().map(|(k, v)| (k.to_owned(), plain(v))).collect())
+        .unwrap_or_default();
+
+    for (name, scenario) in scenarios {
+        let old = existing.get(name).map(scenario_items);
+        let table = doc
+            .entry(name)
+            .or_insert_with(table)
+            .as_table_like_mut()
+            .with_context(|| format!("{name} must be a table"))?;
+        for (i, (key, item)) in scenario_items(scenario).into_iter().enumerate() {
+            let unchanged = old.as_ref().map_or_else(
+                || item.as_ref().map(plain).as_ref() == defaults.get(key),
+                |old| old[i].1.as_ref().map(plain) == item.as_ref().map(plain),
+            );
+            if unchanged {
+                continue;
+            }
+            match item {
+                None if defaults.contains_key(key) => {
+                    return Err(eyre!(
+                        "{name} has no {key}, but it would inherit one from [DEFAULT]"
+                    ))
+                    .suggestion(format!(
+                        "Remove {key} from [DEFAULT], or give {name} a {key} of its own."
+                    ));
+                }
+                None => {
+                    table.remove(key);
+                }
+                Some(item) => {
+                    if key == "GROUPS" {
+                        if let Some(groups) = table.get_mut(key).and_then(Item::as_table_like_mut) {
+                            patch_groups(groups, &scenario.groups, &scenario.aliases);
+                            continue;
+                        }
+                    }
+                    replace_item(table, key, item);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn constructed_scenario_matches_config() {
+        let doc = r#"
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+POLLING_PLACES_PATH = "pp.csv"
+SA1S_BREAKDOWN_PATH = "sa1s.csv"
+OUTPUT_DIR = "out"
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+SA1S_PREFS_FN = "SA1_Prefs.csv"
+NPP_DISTS_FN = "NPP_Dists.csv"
+PREFS_PATH = "prefs.csv"
+SA1S_DISTS_PATH = "dists.csv"
+
+[QLD_2PP]
+GROUPS.Alp = ["A:Alp"]
+GROUPS.Lnp = ["B:Lnp"]
+"#
+        .parse::<Document>()
+        .unwrap();
+        let loaded = get_scenarios(&doc).unwrap().remove("QLD_2PP").unwrap();
+
+        let mut groups = Parties::new();
+        groups.insert("Alp".to_string(), vec!["A:Alp".to_string()]);
+        groups.insert("Lnp".to_string(), vec!["B:Lnp".to_string()]);
+        let built = Scenario::new(
+            "QLD_2PP",
+            "2019",
+            StateAb::QLD,
+            groups,
+            vec!["prefs.csv".into()],
+            "pp.csv",
+            "out",
+            "NPP_Booths.csv",
+        )
+        .with_projection("sa1s.csv", "SA1_Prefs.csv")
+        .with_combination("dists.csv", "NPP_Dists.csv");
+
+        assert_eq!(format!("{loaded:?}"), format!("{built:?}"));
+        assert_eq!(built.npp_booths, Path::new("out/QLD_2PP/NPP_Booths.csv"));
+
+        let mut moved = built;
+        moved.set_output_dir("scratch");
+        assert_eq!(
+            moved.npp_booths,
+            Path::new("scratch/QLD_2PP/NPP_Booths.csv")
+        );
+        assert_eq!(
+            moved.npp_dists.unwrap(),
+            Path::new("scratch/QLD_2PP/NPP_Dists.csv")
+        );
+        // inputs stay put
+        assert_eq!(moved.prefs_path, [Path::new("prefs.csv")]);
+    }
+
+    #[test]
+    fn too_few_groups() {
+        let doc = r#"
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+POLLING_PLACES_PATH = "pp.csv"
+OUTPUT_DIR = "out"
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+PREFS_PATH = "prefs.csv"
+
+[QLD_1PP]
+GROUPS.Alp = ["A:Alp"]
+"#;
+        assert!(get_scenarios(&doc.parse::<Document>().unwrap()).is_err());
+        let doc = doc.replace("[QLD_1PP]", "[QLD_1PP]\nMIN_GROUPS = 1");
+        assert!(get_scenarios(&doc.parse::<Document>().unwrap()).is_ok());
+        let doc = doc.replace("GROUPS.Alp = [\"A:Alp\"]", "GROUPS = {}");
+        assert!(get_scenarios(&doc.parse::<Document>().unwrap()).is_err());
+    }
+
+    #[test]
+    fn group_aliases() {
+        let doc = r#"
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+POLLING_PLACES_PATH = "pp.csv"
+OUTPUT_DIR = "out"
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+PREFS_PATH = "prefs.csv"
+
+[QLD_2PP]
+GROUPS.AustralianLaborParty = { alias = "Alp", candidates = ["A:Alp"] }
+GROUPS.Lnp = ["B:Lnp"]
+"#;
+        let loaded = get_scenarios(&doc.parse::<Document>().unwrap())
+            .unwrap()
+            .remove("QLD_2PP")
+            .unwrap();
+        assert_eq!(
+            loaded.groups.keys().collect::<Vec<_>>(),
+            ["AustralianLaborParty", "Lnp"]
+        );
+        let labelled = loaded.labelled_groups();
+        assert_eq!(labelled.keys().collect::<Vec<_>>(), ["Alp", "Lnp"]);
+        assert_eq!(labelled["Alp"], ["A:Alp"]);
+
+        let shown = describe_scenario(&loaded, false).unwrap();
+        assert!(shown.starts_with("QLD_2PP\n  Year:"));
+        assert!(shown
+            .ends_with("Groups\n  AustralianLaborParty (as Alp)\n    A:Alp\n  Lnp\n    B:Lnp\n"));
+
+        // written out and read back in, the aliases are kept
+        let mut written = Vec::new();
+        let scenarios = BTreeMap::from([("QLD_2PP".to_string(), loaded)]);
+        write_scenarios(&scenarios, "", &mut written).unwrap();
+        let reread = get_scenarios(&String::from_utf8(written).unwrap().parse().unwrap()).unwrap();
+        assert_eq!(format!("{reread:?}"), format!("{scenarios:?}"));
+
+        // columns are sorted by label, so an alias changes the order
+        let doc = doc.replace(
+            "GROUPS.Lnp",
+            "GROUPS.Ind = { alias = \"Aaa\", candidates = [\"C:Ind\"] }\nGROUPS.Lnp",
+        );
+        let loaded = get_scenarios(&doc.parse::<Document>().unwrap()).unwrap();
+        let labelled = loaded["QLD_2PP"].labelled_groups();
+        assert_eq!(
+            crate::booths::sorted_groups(&labelled),
+            ["Aaa", "Alp", "Lnp"]
+        );
+
+        // an alias can't clash with another group
+        let doc = doc.replace("alias = \"Alp\"", "alias = \"Lnp\"");
+        assert!(get_scenarios(&doc.parse::<Document>().unwrap()).is_err());
+    }
+
+    #[test]
+    fn special_votes_table() {
+        let doc = r#"
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+POLLING_PLACES_PATH = "pp.csv"
+OUTPUT_DIR = "out"
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+PREFS_PATH = "prefs.csv"
+GROUPS.Alp = ["A:Alp"]
+GROUPS.Lnp = ["B:Lnp"]
+
+[QLD_2PP]
+
+[QLD_2PP_DECLARATION]
+SPECIAL_VOTES = { POSTAL = "Postal", ABSENT = "Declaration", PROVISIONAL = "Declaration" }
+"#;
+        let mut loaded = get_scenarios(&doc.parse::<Document>().unwrap()).unwrap();
+        assert_eq!(loaded["QLD_2PP"].special_votes, None);
+        let special_votes = loaded
+            .remove("QLD_2PP_DECLARATION")
+            .unwrap()
+            .special_votes
+            .unwrap();
+        assert_eq!(
+            special_votes.into_iter().collect::<Vec<_>>(),
+            [
+                ("POSTAL", "Postal"),
+                ("ABSENT", "Declaration"),
+                ("PROVISIONAL", "Declaration")
+            ]
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        );
+
+        let doc = doc.replace("POSTAL = \"Postal\"", "POSTAL = 1");
+        assert!(get_scenarios(&doc.parse::<Document>().unwrap()).is_err());
+    }
+
+    #[test]
+    fn interpolation() {
+        let doc = r#"
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+DATA = "data/${YEAR}"
+POLLING_PLACES_PATH = "${DATA}/pp.csv"
+OUTPUT_DIR = "out"
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+PREFS_PATH = "${DATA}/prefs-${STATE}.csv"
+GROUPS.Alp = ["A:Alp"]
+GROUPS.Lnp = ["B:Lnp"]
+
+[QLD_2PP]
+OUTPUT_DIR = "${DATA}/out"
+
+[NSW_2PP]
+STATE = "NSW"
+PREFS_PATH = ["${DATA}/a.csv", "${DATA}/b.csv"]
+"#;
+        let loaded = get_scenarios(&doc.parse::<Document>().unwrap()).unwrap();
+        // from the scenario and then [DEFAULT], through more than one reference
+        let qld = &loaded["QLD_2PP"];
+        assert_eq!(qld.polling_places, Path::new("data/2019/pp.csv"));
+        assert_eq!(qld.prefs_path, [Path::new("data/2019/prefs-QLD.csv")]);
+        assert_eq!(
+            qld.npp_booths,
+            Path::new("data/2019/out/QLD_2PP/NPP_Booths.csv")
+        );
+        // a [DEFAULT] value referring to a key the scenario overrides
+        let nsw = &loaded["NSW_2PP"];
+        assert_eq!(
+            nsw.prefs_path,
+            [Path::new("data/2019/a.csv"), Path::new("data/2019/b.csv")]
+        );
+
+        let looped = doc.replace("DATA = \"data/${YEAR}\"", "DATA = \"${OUTPUT_DIR}/data\"");
+        let err = get_scenarios(&looped.parse::<Document>().unwrap()).unwrap_err();
+        assert!(format!("{err:?}").contains("Interpolation loop"));
+        let missing = doc.replace("${YEAR}", "${ELECTION}");
+        assert!(get_scenarios(&missing.parse::<Document>().unwrap()).is_err());
+    }
+
+    #[test]
+    fn scenario_order() {
+        let mut names = ["NSW_2PP", "QLD_3PP", "TAS_2PP", "VIC_2PP"].map(String::from);
+        order_scenarios(
+            &mut names,
+            &["VIC_2PP", "WA_2PP", "NSW_2PP"].map(String::from),
+        );
+        assert_eq!(names, ["VIC_2PP", "NSW_2PP", "QLD_3PP", "TAS_2PP"]);
+    }
+
+    #[test]
+    fn scenario_patterns() {
+        let available = ["NSW_2PP_AlpLnp", "QLD_3PP", "VIC_2PP_AlpLnp"].map(String::from);
+        let req = |x: &[&str]| x.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
+        assert_eq!(
+            select_scenarios(&req(&["QLD_3PP", "*_2PP_AlpLnp", "NSW*"]), &available).unwrap(),
+            ["QLD_3PP", "NSW_2PP_AlpLnp", "VIC_2PP_AlpLnp"]
+        );
+        assert!(select_scenarios(&req(&["*_4PP"]), &available).is_err());
+    }
+
+    #[test]
+    fn patching_keeps_comments() {
+        let original = r#"# My scenarios
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+PREFS_PATH = "prefs.csv"
+POLLING_PLACES_PATH = "pp.csv"
+OUTPUT_DIR = "out" # everything goes here
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+
+# Labor vs the Coalition
+[QLD_2PP]
+GROUPS.Alp = { candidates = ["A:Alp"], alias = "A" }
+GROUPS.Lnp = ["B:Lnp"] # the Coalition
+"#;
+        let mut doc = original.parse::<Document>().unwrap();
+        let mut scenarios = get_scenarios(&doc).unwrap();
+
+        // unchanged scenarios leave the document untouched
+        patch_scenarios(&mut doc, &scenarios).unwrap();
+        assert_eq!(doc.to_string(), original);
+
+        let qld_2pp = scenarios.get_mut("QLD_2PP").unwrap();
+        qld_2pp
+            .groups
+            .insert("Alp".into(), vec!["A:Alp".into(), "A:Two".into()]);
+        qld_2pp.groups.insert("Grn".into(), vec!["C:Grn".into()]);
+        let mut nsw_3pp = qld_2pp.clone();
+        nsw_3pp.name = "NSW_3PP".into();
+        nsw_3pp.npp_booths = nsw_3pp.output_path("NPP_Booths.csv");
+        nsw_3pp.state = StateAb::NSW;
+        scenarios.insert("NSW_3PP".into(), nsw_3pp);
+        patch_scenarios(&mut doc, &scenarios).unwrap();
+
+        let patched = doc.to_string();
+        assert!(patched.starts_with("# My scenarios\n[DEFAULT]"));
+        assert!(patched.contains("OUTPUT_DIR = \"out\" # everything goes here"));
+        assert!(patched.contains("# Labor vs the Coalition\n[QLD_2PP]"));
+        assert!(patched.contains("GROUPS.Lnp = [\"B:Lnp\"] # the Coalition"));
+        assert!(patched.contains("alias = \"A\""));
+        // the new scenario only has what differs from [DEFAULT]
+        let keys: Vec<&str> = doc["NSW_3PP"]
+            .as_table()
+            .unwrap()
+            .iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["STATE", "GROUPS"]);
+
+        let reread = get_scenarios(&patched.parse::<Document>().unwrap()).unwrap();
+        assert_eq!(format!("{reread:?}"), format!("{scenarios:?}"));
+    }
+
+    #[test]
+    fn cfg_doc_from_reader() {
+        let doc = get_cfg_doc(&b"[DEFAULT]\nYEAR = \"2019\"\n"[..]).unwrap();
+        assert_eq!(doc["DEFAULT"]["YEAR"].as_str(), Some("2019"));
+        let e = get_cfg_doc(&b"[DEFAULT\nYEAR = 2019"[..]).unwrap_err();
+        assert_eq!(e.to_string(), "Config file could not be parsed");
+        // nor can it be read if it isn't text
+        assert!(get_cfg_doc(&b"\xff\xfe"[..]).is_err());
+    }
+
+    #[test]
+    fn patching_cant_unset_defaults() {
+        let mut doc = r#"
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+PREFS_PATH = "prefs.csv"
+POLLING_PLACES_PATH = "pp.csv"
+SA1S_BREAKDOWN_PATH = "sa1s.csv"
+SA1S_PREFS_FN = "SA1_Prefs.csv"
+OUTPUT_DIR = "out"
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+
+[QLD_2PP]
+GROUPS.Alp = ["A:Alp"]
+GROUPS.Lnp = ["B:Lnp"]
+"#
+        .parse::<Document>()
+        .unwrap();
+        let mut scenarios = get_scenarios(&doc).unwrap();
+        scenarios.get_mut("QLD_2PP").unwrap().sa1s_breakdown = None;
+        let e = patch_scenarios(&mut doc, &scenarios).unwrap_err();
+        assert!(e.to_string().contains("SA1S_BREAKDOWN_PATH"));
+    }
+
+    #[test]
+    fn configure_over_from() {
+        let path = std::env::temp_dir().join(format!(
+            "nparty-configure-over-from-{}.toml",
+            std::process::id()
+        ));
+        let original = r#"# Mine
+[DEFAULT]
+YEAR = "2019"
+STATE = "QLD"
+PREFS_PATH = "prefs.csv"
+POLLING_PLACES_PATH = "pp.csv"
+OUTPUT_DIR = "out"
+NPP_BOOTHS_FN = "NPP_Booths.csv"
+
+[QLD_2PP]
+GROUPS.Alp = ["A:Alp"]
+GROUPS.Lnp = ["B:Lnp"]
+"#;
+        std::fs::write(&path, original).unwrap();
+        // as `nparty configure --from x.toml x.toml` does
+        let from = get_cfg_doc_from_path(&path).unwrap();
+        let mut scenarios = get_scenarios(&from).unwrap();
+        let qld_2pp = scenarios.get_mut("QLD_2PP").unwrap();
+        qld_2pp.groups.insert("Grn".into(), vec!["C:Grn".into()]);
+        write_configuration(&path, "# Header\n", &scenarios, Some(from)).unwrap();
+
+        let written = read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(written.starts_with("# Header\n# Mine\n[DEFAULT]"));
+        let reread = get_scenarios(&written.parse::<Document>().unwrap()).unwrap();
+        assert_eq!(format!("{reread:?}"), format!("{scenarios:?}"));
+    }
+
+    #[test]
+    fn progress_round_trip() {
+        let mut groups = Parties::new();
+        groups.insert("Alp".to_string(), vec!["A:Alp".to_string()]);
+        groups.insert("Lnp".to_string(), vec!["B:Lnp".to_string()]);
+        let mut scenario = Scenario::new(
+            "QLD_2PP",
+            "2019",
+            StateAb::QLD,
+            groups,
+            vec!["prefs.csv".into()],
+            "pp.csv",
+            "out",
+            "NPP_Booths.csv",
+        )
+        .with_projection("sa1s.csv", "SA1_Prefs.csv");
+        scenario.aliases.insert("Alp".into(), "Labor".into());
+        let saved = BTreeMap::from([("QLD_2PP".to_string(), scenario)]);
+
+        let path = progress_path(Path::new("progress_round_trip.toml"));
+        write_scenarios(&saved, "", &mut std::fs::File::create(&path).unwrap()).unwrap();
+        let resumed = read_progress(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(format!("{resumed:?}"), format!("{saved:?}"));
+    }
+}
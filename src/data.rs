@@ -1,15 +1,22 @@
 //! Functions to download preference data or print corresponding URLs.
 
-use std::collections::BTreeMap;
-use std::fs::{create_dir_all, write, File};
-use std::io::Write;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, File};
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use color_eyre::eyre::Context;
+use calamine::{open_workbook, Reader, Xlsx};
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat, Result};
+use color_eyre::Section;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use tracing::{debug, info, warn};
 
-use crate::utils::fetch_blocking;
-
-// TODO: calamine for conversions...
+use crate::manifest::ManifestEntry;
+use crate::utils::{fetch_to_file, StateAb};
 
 // const STATES: [&str; 8] = ["ACT", "NT", "NSW", "QLD", "SA", "TAS", "VIC", "WA"];
 
@@ -25,14 +32,127 @@ pub struct DlItems {
     candidates: String,
     /// state/territory : URL
     formal_prefs: BTreeMap<String, String>,
+    /// file name : hex-encoded SHA-256, for the files whose contents are known
+    #[serde(default)]
+    sha256: BTreeMap<String, String>,
+    /// file name : size in bytes, for the files whose size is known
+    #[serde(default)]
+    sizes: BTreeMap<String, u64>,
 }
 
 /// Returns `data_files/downloads.ron` as a `BTreeMap`
-pub fn make_map() -> BTreeMap<String, DlItems> {
+fn builtin_map() -> BTreeMap<String, DlItems> {
     ron::de::from_str::<BTreeMap<String, DlItems>>(include_str!("data_files/downloads.ron"))
         .unwrap()
 }
 
+/// Where a user's own catalogue of downloads is looked for, if none is given:
+/// `nparty/downloads.ron` in `$XDG_CONFIG_HOME` (or `~/.config`), or `%APPDATA%` on Windows.
+pub fn user_catalogue_path() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+    };
+    config_dir.map(|d| d.join("nparty").join("downloads.ron"))
+}
+
+/// Returns [`builtin_map`] merged with the user's own catalogue:
+/// `catalogue` if given, else [`user_catalogue_path`] if it exists.
+///
+/// The user's catalogue is in the same format. Its elections are added to the built-in ones,
+/// replacing any built-in election with the same name or year.
+/// If `only`, the built-in catalogue is left out altogether, and `catalogue` must be given.
+pub fn make_map(catalogue: Option<&Path>, only: bool) -> Result<BTreeMap<String, DlItems>> {
+    if only && catalogue.is_none() {
+        bail!("Only a downloads catalogue of your own was asked for, but none was given");
+    }
+    let mut map = if only { BTreeMap::new() } else { builtin_map() };
+    let catalogue = catalogue.map_or_else(
+        || user_catalogue_path().filter(|c| c.is_file()),
+        |c| Some(c.to_path_buf()),
+    );
+    if let Some(path) = catalogue {
+        info!("Adding downloads from {}", path.display());
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read downloads catalogue {}", path.display()))?;
+        let extra = ron::de::from_str::<BTreeMap<String, DlItems>>(&text)
+            .with_context(|| format!("Could not parse downloads catalogue {}", path.display()))
+            .suggestion(
+                "Each election needs a year, id, polling_places, political_parties, sa1s_pps, \
+                candidates and formal_prefs, as in nparty's own src/data_files/downloads.ron.",
+            )?;
+        merge_catalogue(&mut map, extra);
+    }
+    Ok(map)
+}
+
+/// The year of each election in [`make_map`]'s catalogue.
+pub fn election_years(catalogue: Option<&Path>) -> Result<BTreeSet<String>> {
+    Ok(make_map(catalogue, false)?
+        .into_values()
+        .map(|item| item.year)
+        .collect())
+}
+
+/// Which elections to download or examine, and which states' preferences files.
+#[derive(Debug, Default)]
+pub struct Selection<'a> {
+    /// An extra catalogue for [`make_map`]
+    pub catalogue: Option<&'a Path>,
+    /// Use only the extra catalogue, not the built-in one
+    pub catalogue_only: bool,
+    /// Only the elections in these years (or all, if empty)
+    pub years: &'a [String],
+    /// Only these states' preferences (or all, if empty)
+    pub states: &'a [StateAb],
+}
+
+impl Selection<'_> {
+    /// [`Selection::select`] from the elections in [`make_map`].
+    fn elections(&self) -> Result<BTreeMap<String, DlItems>> {
+        self.select(make_map(self.catalogue, self.catalogue_only)?)
+    }
+
+    /// The selected elections from `map`, each with only the selected states' preferences.
+    fn select(&self, mut map: BTreeMap<String, DlItems>) -> Result<BTreeMap<String, DlItems>> {
+        if !self.years.is_empty() {
+            map.retain(|_, item| self.years.contains(&item.year));
+            if map.is_empty() {
+                return Err(eyre!(
+                    "There are no elections in {} in the catalogue",
+                    self.years.join(" or ")
+                ))
+                .suggestion("`nparty data examine` lists the elections in the catalogue.");
+            }
+        }
+        if !self.states.is_empty() {
+            for item in map.values_mut() {
+                item.formal_prefs
+                    .retain(|state, _| state.parse().is_ok_and(|s| self.states.contains(&s)));
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Add the `extra` elections to `map`, replacing any with the same name or year.
+fn merge_catalogue(map: &mut BTreeMap<String, DlItems>, extra: BTreeMap<String, DlItems>) {
+    for (name, item) in extra {
+        map.retain(|k, v| {
+            let keep = *k != name && v.year != item.year;
+            if !keep {
+                debug!("{} replaces {}", name, k);
+            }
+            keep
+        });
+        map.insert(name, item);
+    }
+}
+
 /// Output a formatted HTML page detailing the downloads
 fn make_html(texts: &BTreeMap<String, DlItems>) -> String {
     use std::fmt::Write as _; // import without risk of name clashing
@@ -67,18 +187,19 @@ fn make_html(texts: &BTreeMap<String, DlItems>) -> String {
     String::from(template_html).replace("CONTENT", &content)
 }
 
-/// Print the HTML of the download links
-pub fn examine_html(path: &Path) {
-    let sacred_texts = make_map();
-    let mut output = File::create(path).expect("Error creating file");
+/// Print the HTML of the download links for the `selection`
+pub fn examine_html(path: &Path, selection: &Selection) -> Result<()> {
+    let sacred_texts = selection.elections()?;
+    let mut output =
+        File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
     output
         .write_all(make_html(&sacred_texts).as_bytes())
-        .expect("Error writing file");
+        .with_context(|| format!("Could not write {}", path.display()))
 }
 
-/// Print the download links as plain text
-pub fn examine_txt() {
-    let sacred_texts = make_map();
+/// Print the download links for the `selection` as plain text
+pub fn examine_txt(selection: &Selection) -> Result<()> {
+    let sacred_texts = selection.elections()?;
     // eprintln!("{:#?}", sacred_texts);
     for (_, item) in sacred_texts {
         println!(
@@ -89,11 +210,272 @@ pub fn examine_txt() {
             println!("{url}");
         }
     }
+    Ok(())
 }
 
-/// Download all the links to `dldir`.
-pub fn download(dldir: &Path) -> color_eyre::eyre::Result<()> {
-    let sacred_texts = make_map();
+/// Write the first non-empty worksheet of the XLSX spreadsheet at `xlsx` out as a CSV at `csv`.
+fn xlsx_to_csv(xlsx: &Path, csv: &Path) -> Result<()> {
+    let mut workbook: Xlsx<_> = open_workbook(xlsx)
+        .with_context(|| format!("Could not open spreadsheet {}", xlsx.display()))?;
+    let names = workbook.sheet_names();
+    let mut chosen = None;
+    for name in &names {
+        let range = workbook
+            .worksheet_range(name)
+            .with_context(|| format!("Could not read worksheet {name} of {}", xlsx.display()))?;
+        if !range.is_empty() {
+            chosen = Some((name, range));
+            break;
+        }
+    }
+    let (name, range) =
+        chosen.with_context(|| format!("Spreadsheet {} has nothing in it", xlsx.display()))?;
+    if names.len() > 1 {
+        info!(
+            "{} has {} worksheets; converting {name}",
+            xlsx.display(),
+            names.len()
+        );
+    }
+
+    let mut wtr = csv::Writer::from_path(csv)
+        .with_context(|| format!("Could not create {}", csv.display()))?;
+    for row in range.rows() {
+        wtr.write_record(row.iter().map(ToString::to_string))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// What's wrong with the file at `path`, if it doesn't have the expected `sha256` or `size`.
+/// Files with neither expected aren't read.
+fn verify(path: &Path, sha256: Option<&String>, size: Option<&u64>) -> Result<Option<String>> {
+    if sha256.is_none() && size.is_none() {
+        return Ok(None);
+    }
+    let actual = ManifestEntry::from_path(path)?;
+    if let Some(&size) = size.filter(|&&s| s != actual.size) {
+        return Ok(Some(format!(
+            "it's {} bytes, not the expected {size}",
+            actual.size
+        )));
+    }
+    if let Some(sha256) = sha256.filter(|s| !s.eq_ignore_ascii_case(&actual.sha256)) {
+        return Ok(Some(format!(
+            "its SHA-256 is {}, not the expected {sha256}",
+            actual.sha256
+        )));
+    }
+    Ok(None)
+}
+
+/// One file for [`download`] to fetch.
+struct DlJob {
+    link: String,
+    dlto: PathBuf,
+    sha256: Option<String>,
+    size: Option<u64>,
+}
+
+/// How [`download`] shows what it's doing: a spinner for each file in progress if standard error
+/// is a terminal, or else a line as each file starts.
+struct Progress(Option<MultiProgress>);
+
+impl Progress {
+    fn new() -> Self {
+        Self(std::io::stderr().is_terminal().then(MultiProgress::new))
+    }
+
+    /// Print a line (above any spinners).
+    fn println(&self, msg: &str) {
+        match &self.0 {
+            Some(bars) => bars.println(msg).unwrap_or_else(|_| eprintln!("{msg}")),
+            None => eprintln!("{msg}"),
+        }
+    }
+
+    /// Show that `msg` is underway, until [`Progress::finish`].
+    fn start(&self, msg: String) -> Option<ProgressBar> {
+        let Some(bars) = &self.0 else {
+            eprintln!("{msg}");
+            return None;
+        };
+        let bar = bars.add(ProgressBar::new_spinner().with_message(msg));
+        #[allow(clippy::literal_string_with_formatting_args)] // indicatif's template
+        if let Ok(style) = ProgressStyle::with_template("{spinner} {msg} ({elapsed})") {
+            bar.set_style(style.tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏✓"));
+        }
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Some(bar)
+    }
+
+    /// Show that `bytes` (of `total`, if known) have been downloaded so far.
+    fn received(bar: Option<&ProgressBar>, bytes: u64, total: Option<u64>) {
+        let Some(bar) = bar else { return };
+        // the first we hear is the response, before any data
+        if bytes == 0 {
+            #[allow(clippy::literal_string_with_formatting_args)] // indicatif's template
+            let template = if total.is_some() {
+                "{spinner} {msg} ({bytes}/{total_bytes}, {elapsed})"
+            } else {
+                "{spinner} {msg} ({bytes}, {elapsed})"
+            };
+            if let Ok(style) = ProgressStyle::with_template(template) {
+                bar.set_style(style.tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏✓"));
+            }
+            if let Some(total) = total {
+                bar.set_length(total);
+            }
+        }
+        bar.set_position(bytes);
+    }
+
+    /// Replace a spinner with `msg`, or remove it if there's no `msg`.
+    fn finish(bar: Option<ProgressBar>, msg: Option<String>) {
+        match (bar, msg) {
+            (Some(bar), Some(msg)) => bar.finish_with_message(msg),
+            (Some(bar), None) => bar.finish_and_clear(),
+            (None, _) => {}
+        }
+    }
+}
+
+/// What became of one file in [`download`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Downloaded,
+    /// Already present (and not damaged).
+    Skipped,
+    /// Couldn't be downloaded (or was, but was wrong, and has been deleted).
+    Failed,
+    /// Already present but damaged, and couldn't be downloaded again, so it's been left as it was.
+    Damaged,
+    /// Not (fully) downloaded, because the downloads were cancelled.
+    Cancelled,
+}
+
+/// Fetch (or skip) one file for [`download`].
+///
+/// URLs might be incorrectly specified (or not available yet), so failed downloads are
+/// reported but not errors; but if we can't write one file then chances are we can't write any.
+fn download_one(
+    job: &DlJob,
+    force: bool,
+    no_convert: bool,
+    cancel: &Arc<AtomicBool>,
+    progress: &Progress,
+) -> Result<Outcome> {
+    let DlJob {
+        link,
+        dlto,
+        sha256,
+        size,
+    } = job;
+    let (sha256, size) = (sha256.as_ref(), size.as_ref());
+    let name = dlto.file_name().unwrap_or_default().to_string_lossy();
+
+    let mut fetch = force || !dlto.is_file();
+    let mut damaged = false;
+    if !fetch {
+        if let Some(problem) = verify(dlto, sha256, size)? {
+            progress.println(&format!("{} is damaged ({problem})", dlto.display()));
+            (fetch, damaged) = (true, true);
+        }
+    }
+    if fetch && cancel.load(Ordering::SeqCst) {
+        return Ok(Outcome::Cancelled);
+    }
+    let mut outcome = Outcome::Skipped;
+    if fetch {
+        let bar = progress.start(format!("Downloading: {}", dlto.display()));
+        match fetch_to_file(link, dlto, cancel, |bytes, total| {
+            Progress::received(bar.as_ref(), bytes, total);
+        }) {
+            Ok(()) => {
+                if let Some(problem) = verify(dlto, sha256, size)? {
+                    std::fs::remove_file(dlto).context("Error deleting file")?;
+                    Progress::finish(bar, None);
+                    progress.println(&format!(
+                        "Error downloading {name:?}: {problem}, so it's been deleted"
+                    ));
+                    outcome = Outcome::Failed;
+                } else {
+                    Progress::finish(bar, Some(format!("Downloaded: {}", dlto.display())));
+                    outcome = Outcome::Downloaded;
+                }
+            }
+            // the network's problems are per-file, but the disk's aren't
+            Err(e) if e.root_cause().is::<std::io::Error>() => {
+                Progress::finish(bar, None);
+                return Err(e).context("Error writing file");
+            }
+            Err(_) if cancel.load(Ordering::SeqCst) => {
+                Progress::finish(bar, None);
+                outcome = Outcome::Cancelled;
+            }
+            Err(e) => {
+                Progress::finish(bar, None);
+                progress.println(&format!("Error downloading {name:?}:\n{e:#}"));
+                outcome = if damaged {
+                    Outcome::Damaged
+                } else {
+                    Outcome::Failed
+                };
+            }
+        }
+    }
+
+    let is_xlsx = dlto
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("xlsx"));
+    let csv = dlto.with_extension("csv");
+    if !no_convert && is_xlsx && dlto.is_file() && (fetch || !csv.is_file()) {
+        let bar = progress.start(format!("Converting to CSV: {}", csv.display()));
+        // like a failed download, a failed conversion only affects the one file
+        if let Err(e) = xlsx_to_csv(dlto, &csv) {
+            Progress::finish(bar, None);
+            progress.println(&format!("Error converting {}:\n{e:#}", dlto.display()));
+        } else {
+            Progress::finish(bar, Some(format!("Converted: {}", csv.display())));
+        }
+    }
+    Ok(outcome)
+}
+
+/// Cancel the downloads on Ctrl-C; or if they've already been cancelled, quit.
+fn cancel_on_interrupt(cancel: &Arc<AtomicBool>) {
+    let cancel = Arc::clone(cancel);
+    let rez = ctrlc::set_handler(move || {
+        if cancel.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("\nInterrupted! Cancelling downloads; press Ctrl-C again to quit.");
+    });
+    if let Err(e) = rez {
+        warn!("Could not install Ctrl-C handler: {}", e);
+    }
+}
+
+/// Download all the links to `dldir`, up to `concurrency` at a time.
+///
+/// Files go in a subdirectory for each election year, unless `flat`.
+/// Files that are already present are skipped, unless `force`.
+/// Files with a known SHA-256 or size are checked after downloading (and deleted if they're wrong),
+/// and before skipping (and downloaded again if they're wrong).
+/// Unless `no_convert`, XLSX spreadsheets are also written out as CSVs alongside.
+/// Only the `selection` is downloaded. Ctrl-C cancels the downloads in progress.
+pub fn download(
+    dldir: &Path,
+    force: bool,
+    flat: bool,
+    no_convert: bool,
+    concurrency: usize,
+    selection: &Selection,
+) -> color_eyre::eyre::Result<()> {
+    if concurrency == 0 {
+        bail!("--concurrency must be at least 1");
+    }
+    let sacred_texts = selection.elections()?;
 
     let mut dldir = dldir;
 
@@ -103,10 +485,13 @@ pub fn download(dldir: &Path) -> color_eyre::eyre::Result<()> {
         create_dir_all(dldir).unwrap();
     }
 
-    let mut skips = 0;
-
+    let mut jobs = Vec::new();
     for (_, item) in sacred_texts {
-        let year_dir = dldir.join(item.year);
+        let year_dir = if flat {
+            dldir.to_path_buf()
+        } else {
+            dldir.join(item.year)
+        };
         create_dir_all(&year_dir).unwrap();
         let mut all_urls: Vec<String> = vec![
             item.polling_places,
@@ -123,33 +508,116 @@ pub fn download(dldir: &Path) -> color_eyre::eyre::Result<()> {
                 let aspath = PathBuf::from(linkpath.path());
                 let mut dlto = PathBuf::from(&year_dir);
                 dlto.push(aspath.file_name().unwrap());
+                let file_name = dlto.file_name().unwrap().to_string_lossy().into_owned();
                 // globfn omitted for now
-                if !dlto.is_file() {
-                    eprintln!("Downloading: {}", &dlto.display());
-                    // URLs might be incorrectly specified (or not available yet) and are skippable individually
-                    // but if we can't write one file then chances are we can't write any
-                    match fetch_blocking(&link) {
-                        Ok(response) => {
-                            write(&dlto, response.bytes).context("Error writing file")?;
-                        }
-                        Err(e) => eprintln!(
-                            "Error downloading {:#?}:\n{}",
-                            &aspath.file_name().unwrap(),
-                            e
-                        ),
-                    };
-                } else if dlto.is_file() {
-                    skips += 1;
-                }
+                jobs.push(DlJob {
+                    link,
+                    sha256: item.sha256.get(&file_name).cloned(),
+                    size: item.sizes.get(&file_name).copied(),
+                    dlto,
+                });
             } else {
                 eprintln!("Error parsing URL `{}`; skipping.", &link);
             }
         }
     }
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    cancel_on_interrupt(&cancel);
+    let progress = Progress::new();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(concurrency)
+        .build()
+        .context("Could not start the downloads")?;
+    let outcomes: Vec<Outcome> = pool.install(|| {
+        jobs.par_iter()
+            .map(|job| download_one(job, force, no_convert, &cancel, &progress))
+            .collect::<Result<_>>()
+    })?;
+    let count = |o| outcomes.iter().filter(|&&x| x == o).count();
+    let skips = count(Outcome::Skipped);
     if skips == 0 {
         eprintln!("Done!");
     } else {
         eprintln!("Done! Skipped {skips} already-downloaded files.");
     }
+    let failures = count(Outcome::Failed);
+    if failures > 0 {
+        eprintln!("Could not download {failures} files (see above).");
+    }
+    let damages = count(Outcome::Damaged);
+    if damages > 0 {
+        eprintln!(
+            "{damages} damaged files could not be downloaded again, so they're still damaged (see above)."
+        );
+    }
+    let cancellations = count(Outcome::Cancelled);
+    if cancellations > 0 {
+        bail!("The downloads were cancelled, leaving {cancellations} files not downloaded");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_catalogue() {
+        let mut map = builtin_map();
+        let extra = r#"{
+            "2019 Federal Election (corrected)": DlItems(
+                year: "2019", id: "24310", polling_places: "pp", political_parties: "parties",
+                sa1s_pps: "sa1s", candidates: "cands", formal_prefs: {},
+            ),
+            "2099 Federal Election": DlItems(
+                year: "2099", id: "99999", polling_places: "pp", political_parties: "parties",
+                sa1s_pps: "sa1s", candidates: "cands", formal_prefs: { "QLD": "prefs" },
+            ),
+        }"#;
+        let before = map.len();
+        merge_catalogue(&mut map, ron::de::from_str(extra).unwrap());
+        assert_eq!(map.len(), before + 1);
+        assert!(!map.contains_key("2019 Federal Election"));
+        assert_eq!(map["2019 Federal Election (corrected)"].candidates, "cands");
+        assert_eq!(map["2099 Federal Election"].formal_prefs["QLD"], "prefs");
+        assert!(make_map(Some(Path::new("no-such-catalogue.ron")), false).is_err());
+        assert!(make_map(None, true).is_err());
+        // checksums are optional
+        assert!(map["2099 Federal Election"].sha256.is_empty());
+    }
+
+    #[test]
+    fn selection() {
+        let selection = Selection {
+            catalogue: None,
+            catalogue_only: false,
+            years: &["2019".into()],
+            states: &[StateAb::QLD, StateAb::TAS],
+        };
+        // from the built-in catalogue only, not the user's own, which might have anything in it
+        let elections = selection.select(builtin_map()).unwrap();
+        assert_eq!(elections.len(), 1);
+        let item = elections.values().next().unwrap();
+        assert_eq!(item.year, "2019");
+        assert_eq!(item.formal_prefs.keys().collect::<Vec<_>>(), ["QLD", "TAS"]);
+    }
+
+    #[test]
+    fn verification() {
+        let path =
+            std::env::temp_dir().join(format!("nparty-verification-{}.txt", std::process::id()));
+        std::fs::write(&path, "abc").unwrap();
+        let sha256 = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_string();
+        assert_eq!(verify(&path, Some(&sha256), Some(&3)).unwrap(), None);
+        assert_eq!(verify(&path, None, None).unwrap(), None);
+        assert!(verify(&path, None, Some(&4))
+            .unwrap()
+            .unwrap()
+            .contains("3 bytes"));
+        assert!(verify(&path, Some(&"00".repeat(32)), None)
+            .unwrap()
+            .is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,590 @@
+//! `nparty doctor` and `nparty validate`: check that a configuration file's scenarios are ready
+//! to run.
+//!
+//! Every check is read-only (apart from a probe file in each output directory),
+//! and they all run even if some fail, so that everything can be fixed in one go.
+
+use crate::app::{CliDoctor, CliValidate};
+use crate::booths::{
+    check_prefs_state, csv_prefs_reader, load_polling_places, PlainKeys, PREFS_FIELD_NAMES,
+};
+use crate::config::{get_cfg_doc_from_path, get_scenarios, Scenario};
+use crate::data::election_years;
+use crate::multiplier::{breakdown_booth_totals, read_senate_votes};
+use crate::term::{self, print_table, BOLD};
+use crate::utils::{download_cache_path, fix_prefs_headers, is_url, is_zip, open_csvz_from_path};
+use color_eyre::eyre::{bail, eyre, Result};
+use color_eyre::Section;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+/// How a check went.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Status {
+    Pass,
+    /// Not fatal, but probably not what was intended
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "pass",
+            Self::Warn => "warn",
+            Self::Fail => "FAIL",
+        })
+    }
+}
+
+/// The outcome of one check.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Check {
+    pub status: Status,
+    /// What was checked, and what was found
+    pub message: String,
+    /// How to fix it (if it needs fixing)
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(message: impl Into<String>) -> Self {
+        Self {
+            status: Status::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+    fn warn(message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            status: Status::Warn,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+    fn fail(message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            status: Status::Fail,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Check that an input file exists and isn't an (unconverted) spreadsheet.
+///
+/// `zip_ok` is for files that are read with [`open_csvz_from_path`], which unzips them.
+fn check_input_exists(what: &str, path: &Path, zip_ok: bool) -> Option<Check> {
+    if !path.is_file() {
+        return Some(Check::fail(
+            format!("{what} {} not found", path.display()),
+            "Check the path in the configuration file; `nparty data download` fetches the AEC's files.",
+        ));
+    }
+    let is_spreadsheet = path
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("xlsx") || x.eq_ignore_ascii_case("xls"));
+    let zipped = File::open(path)
+        .map_err(color_eyre::Report::from)
+        .and_then(|mut f| is_zip(&mut f));
+    match zipped {
+        Err(e) => Some(Check::fail(
+            format!("{what} {} can't be opened: {e}", path.display()),
+            "Check the file's permissions.",
+        )),
+        Ok(zipped) if is_spreadsheet || (zipped && !zip_ok) => Some(Check::fail(
+            format!(
+                "{what} {} looks like a spreadsheet or zip file, not a CSV",
+                path.display()
+            ),
+            "Open it in a spreadsheet program and save it as CSV.",
+        )),
+        Ok(_) => None,
+    }
+}
+
+/// Check each part of the preferences file: that it opens, is in the 2019+ format,
+/// is for the right state, and has all the groups' candidates in its header.
+fn check_prefs(scenario: &Scenario, path: &Path) -> Check {
+    let cached;
+    let path = if is_url(path) {
+        // only check an earlier download; doctoring shouldn't take the time to download anything
+        cached = download_cache_path(&path.to_string_lossy());
+        if !cached.is_file() {
+            return Check::warn(
+                format!(
+                    "Preferences file {} hasn't been downloaded yet",
+                    path.display()
+                ),
+                "It will be downloaded by `nparty run`; check again after that.",
+            );
+        }
+        &cached
+    } else {
+        path
+    };
+    if let Some(check) = check_input_exists("Preferences file", path, true) {
+        return check;
+    }
+    let mut rdr =
+        match open_csvz_from_path(path) {
+            Ok(r) => csv_prefs_reader(r),
+            Err(e) => return Check::fail(
+                format!("Preferences file {} can't be read: {e}", path.display()),
+                "If it's zipped, check that the zip file isn't damaged; re-download it if need be.",
+            ),
+        };
+    let header = match rdr.headers() {
+        Ok(h) => h.clone(),
+        Err(e) => {
+            return Check::fail(
+                format!(
+                    "Preferences file {} has no readable header: {e}",
+                    path.display()
+                ),
+                "Re-download it.",
+            )
+        }
+    };
+    let mut first = csv::StringRecord::new();
+    if rdr.read_record(&mut first).unwrap_or(false)
+        && first.get(1).is_some_and(|x| x.starts_with("---"))
+    {
+        return Check::fail(
+            format!("Preferences file {} is in the 2016 format", path.display()),
+            "Upgrade it to the 2019+ format with `nparty upgrade prefs`.",
+        );
+    }
+    if let Err(e) = check_prefs_state(path, scenario.state) {
+        return Check::fail(e.to_string(), "Check the scenario's STATE and PREFS_PATH.");
+    }
+
+    let candidates = fix_prefs_headers(&header, PREFS_FIELD_NAMES.len());
+    let missing: Vec<&str> = scenario
+        .groups
+        .values()
+        .flatten()
+        .filter(|c| !candidates.contains(c))
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() {
+        Check::pass(format!(
+            "Preferences file {} has all the groups' candidates",
+            path.display()
+        ))
+    } else {
+        let suggestions: Vec<String> = missing
+            .iter()
+            .filter_map(|m| suggest_candidate(m, &candidates).map(|c| format!("{m} -> {c}")))
+            .collect();
+        let hint = "Check the scenario's GROUPS (and YEAR); `nparty configure` makes groups from a candidates file.";
+        Check::fail(
+            format!(
+                "Preferences file {} lacks some of the groups' candidates: {}",
+                path.display(),
+                missing.join(", ")
+            ),
+            if suggestions.is_empty() {
+                hint.to_owned()
+            } else {
+                format!("{hint} Perhaps: {}", suggestions.join("; "))
+            },
+        )
+    }
+}
+
+/// A header entry that `missing` might have been meant to be:
+/// the same name on another ticket, or in other letter case, or else the same surname.
+///
+/// Header entries look like `B:SMITH John`.
+fn suggest_candidate<'a>(missing: &str, candidates: &'a [String]) -> Option<&'a str> {
+    let name = |x: &str| {
+        x.split_once(':')
+            .map_or(x, |(_, n)| n)
+            .trim()
+            .to_lowercase()
+    };
+    let surname = |x: &str| {
+        name(x)
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let (m_name, m_surname) = (name(missing), surname(missing));
+    let tickets = candidates.iter().filter(|c| c.contains(':'));
+    tickets
+        .clone()
+        .find(|c| name(c) == m_name)
+        .or_else(|| {
+            tickets
+                .clone()
+                .find(|c| !m_surname.is_empty() && surname(c) == m_surname)
+        })
+        .map(String::as_str)
+}
+
+/// The years of the elections in the downloads catalogue, or why they couldn't be read.
+pub type KnownYears = std::result::Result<BTreeSet<String>, String>;
+
+/// The [`KnownYears`] from the built-in and user's downloads catalogues.
+pub fn known_years() -> KnownYears {
+    election_years(None).map_err(|e| e.to_string())
+}
+
+/// Check that the scenario's year looks like a federal election with preference files:
+/// 2016 onwards, and preferably one of the `known` elections.
+fn check_year(scenario: &Scenario, known: &KnownYears) -> Check {
+    let year = &scenario.year;
+    let hint = "Set the scenario's YEAR to the election's year, e.g. \"2019\".";
+    if year.len() != 4 || !year.bytes().all(|b| b.is_ascii_digit()) {
+        return Check::fail(format!("YEAR \"{year}\" isn't a year"), hint);
+    }
+    if year.as_str() < "2016" {
+        return Check::fail(
+            format!("YEAR {year} is before the AEC's preferences files began (2016)"),
+            hint,
+        );
+    }
+    match known {
+        Err(e) => Check::warn(
+            format!("YEAR {year} couldn't be checked against the downloads catalogue: {e}"),
+            "Fix or remove the downloads catalogue.",
+        ),
+        Ok(years) if !years.contains(year) => Check::warn(
+            format!("YEAR {year} isn't an election in the downloads catalogue"),
+            "Check the scenario's YEAR, or add the election to your downloads catalogue (see `nparty data download --help`).",
+        ),
+        Ok(_) => Check::pass(format!("YEAR {year} is a known election")),
+    }
+}
+
+/// Check that the polling places file has some polling places for the scenario's state.
+fn check_polling_places(scenario: &Scenario) -> Check {
+    let path = &scenario.polling_places;
+    if let Some(check) = check_input_exists("Polling places file", path, true) {
+        return check;
+    }
+    match load_polling_places(scenario.state, path, &mut PlainKeys) {
+        Err(e) => Check::fail(
+            format!("Polling places file {} can't be read: {e}", path.display()),
+            "It should be the AEC's polling places CSV, including its title line.",
+        ),
+        Ok(booths) if booths.is_empty() => Check::warn(
+            format!(
+                "Polling places file {} has no polling places in {}",
+                path.display(),
+                scenario.state
+            ),
+            "Check the scenario's STATE and POLLING_PLACES_PATH.",
+        ),
+        Ok(booths) => Check::pass(format!(
+            "Polling places file {} has {} polling places in {}",
+            path.display(),
+            booths.len(),
+            scenario.state
+        )),
+    }
+}
+
+/// Check that the SA1s breakdown has booths for the scenario's state and year.
+fn check_sa1s_breakdown(scenario: &Scenario, path: &Path) -> Check {
+    if let Some(check) = check_input_exists("SA1s breakdown", path, false) {
+        return check;
+    }
+    match breakdown_booth_totals(path, scenario.state, &scenario.year) {
+        Err(e) => Check::fail(
+            format!("SA1s breakdown {} can't be read: {e}", path.display()),
+            "It should be the AEC's polling place by SA1 spreadsheet, saved as CSV.",
+        ),
+        Ok(booths) if booths.is_empty() => Check::fail(
+            format!(
+                "SA1s breakdown {} has nothing for {} in {}",
+                path.display(),
+                scenario.state,
+                scenario.year
+            ),
+            "Check the scenario's STATE and YEAR: each election has its own breakdown.",
+        ),
+        Ok(booths) => Check::pass(format!(
+            "SA1s breakdown {} has {} polling places for {} in {}",
+            path.display(),
+            booths.len(),
+            scenario.state,
+            scenario.year
+        )),
+    }
+}
+
+/// Check that the Senate votes file has some polling places for the scenario's state.
+fn check_senate_votes(scenario: &Scenario, path: &Path) -> Check {
+    if let Some(check) = check_input_exists("Senate votes file", path, false) {
+        return check;
+    }
+    match File::open(path)
+        .map_err(color_eyre::Report::from)
+        .and_then(|f| read_senate_votes(f, scenario.state))
+    {
+        Err(e) => Check::fail(
+            format!("Senate votes file {} can't be read: {e}", path.display()),
+            "It needs a header naming the division, polling place and votes columns.",
+        ),
+        Ok(votes) if votes.is_empty() => Check::warn(
+            format!(
+                "Senate votes file {} has no polling places in {}",
+                path.display(),
+                scenario.state
+            ),
+            "Check the scenario's STATE and SENATE_VOTES_PATH.",
+        ),
+        Ok(votes) => Check::pass(format!(
+            "Senate votes file {} has {} polling places",
+            path.display(),
+            votes.len()
+        )),
+    }
+}
+
+/// Check that the SA1s-to-districts file opens.
+fn check_sa1s_dists(path: &Path) -> Check {
+    if let Some(check) = check_input_exists("SA1s-to-districts file", path, true) {
+        return check;
+    }
+    match open_csvz_from_path(path).and_then(|r| Ok(csv::Reader::from_reader(r).headers()?.len())) {
+        Ok(_) => Check::pass(format!("SA1s-to-districts file {} opens", path.display())),
+        Err(e) => Check::fail(
+            format!(
+                "SA1s-to-districts file {} can't be read: {e}",
+                path.display()
+            ),
+            "It should be a CSV of SA1 IDs and districts (and populations).",
+        ),
+    }
+}
+
+/// Check that the scenario's outputs can be written, without creating any directories:
+/// the nearest existing one must accept a (removed) probe file.
+fn check_output_dir(scenario: &Scenario) -> Check {
+    let dir = scenario.output_dir.join(&scenario.name);
+    let Some(existing) = dir
+        .ancestors()
+        .map(|d| {
+            if d.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                d
+            }
+        })
+        .find(|d| d.is_dir())
+    else {
+        return Check::fail(
+            format!("Output directory {} can't be created", dir.display()),
+            "Check the scenario's OUTPUT_DIR.",
+        );
+    };
+    let probe = existing.join(".nparty-doctor-probe");
+    match File::create(&probe) {
+        Ok(_) => {
+            std::fs::remove_file(&probe).ok();
+            Check::pass(format!("Output directory {} is writable", dir.display()))
+        }
+        Err(e) => Check::fail(
+            format!("Output directory {} isn't writable: {e}", dir.display()),
+            "Check the scenario's OUTPUT_DIR, or pass `--output-dir` to `nparty run`.",
+        ),
+    }
+}
+
+/// Run every check on a `scenario`, whose year should be one of the `known` elections.
+pub fn check_scenario(scenario: &Scenario, known: &KnownYears) -> Vec<Check> {
+    let mut checks = vec![check_year(scenario, known)];
+    if scenario.prefs_path.is_empty() {
+        checks.push(Check::fail(
+            "No preferences file",
+            "Set the scenario's PREFS_PATH.",
+        ));
+    }
+    for path in &scenario.prefs_path {
+        checks.push(check_prefs(scenario, path));
+    }
+    checks.push(check_polling_places(scenario));
+    if let Some(path) = &scenario.sa1s_breakdown {
+        checks.push(check_sa1s_breakdown(scenario, path));
+    }
+    if let Some(path) = &scenario.senate_votes {
+        checks.push(check_senate_votes(scenario, path));
+    }
+    if let Some(path) = &scenario.sa1s_dists {
+        checks.push(check_sa1s_dists(path));
+    }
+    checks.push(check_output_dir(scenario));
+    checks
+}
+
+/// Print a checklist of `checks` under a `heading`.
+fn print_checks(heading: &str, checks: &[Check], color: bool) {
+    let bold = |x: &str| {
+        if color {
+            term::decorate(x, BOLD)
+        } else {
+            x.to_owned()
+        }
+    };
+    println!("{}", bold(heading));
+    for check in checks {
+        let status = format!("[{}]", check.status);
+        let status = if check.status == Status::Pass {
+            status
+        } else {
+            bold(&status)
+        };
+        println!("  {status} {}", check.message);
+        if let Some(hint) = &check.hint {
+            println!("         {hint}");
+        }
+    }
+}
+
+/// this function handles `nparty doctor`
+pub fn do_doctor(args: &CliDoctor) -> Result<()> {
+    let color = term::use_color(&std::io::stdout());
+    let cfgpath = &args.configfile;
+    let scenarios = match get_cfg_doc_from_path(cfgpath).and_then(|doc| get_scenarios(&doc)) {
+        Ok(s) => s,
+        Err(e) => {
+            let check = Check::fail(
+                format!(
+                    "Configuration file {} can't be used: {e}",
+                    cfgpath.display()
+                ),
+                "`nparty example` prints an example configuration file.",
+            );
+            print_checks(&cfgpath.display().to_string(), &[check], color);
+            bail!("The configuration file has problems");
+        }
+    };
+    print_checks(
+        &cfgpath.display().to_string(),
+        &[Check::pass(format!(
+            "Configuration file parses, with {} scenario(s)",
+            scenarios.len()
+        ))],
+        color,
+    );
+
+    let known = known_years();
+    let mut failed = 0;
+    let mut warned = 0;
+    for (name, scenario) in &scenarios {
+        let checks = check_scenario(scenario, &known);
+        failed += checks.iter().filter(|c| c.status == Status::Fail).count();
+        warned += checks.iter().filter(|c| c.status == Status::Warn).count();
+        print_checks(name, &checks, color);
+    }
+    if failed > 0 {
+        bail!("{failed} checks failed (and {warned} warned)");
+    }
+    Ok(())
+}
+
+/// One row of the `nparty validate` table: the scenario's `name`, its worst status,
+/// how many `checks` passed, and the first problem found (if any).
+fn summary_row(name: &str, checks: &[Check]) -> String {
+    let count = |s| checks.iter().filter(|c| c.status == s).count();
+    let worst = [Status::Fail, Status::Warn]
+        .into_iter()
+        .find(|&s| count(s) > 0)
+        .unwrap_or(Status::Pass);
+    let problem = checks
+        .iter()
+        .find(|c| c.status == worst && worst != Status::Pass)
+        .map_or("", |c| c.message.as_str());
+    format!(
+        "{name}\t{worst}\t{}/{}\t{problem}",
+        count(Status::Pass),
+        checks.len()
+    )
+}
+
+/// this function handles `nparty validate`
+pub fn do_validate(args: &CliValidate) -> Result<()> {
+    let cfgpath = &args.configfile;
+    let doc = get_cfg_doc_from_path(cfgpath)?;
+    let scenarios = get_scenarios(&doc)?;
+    let known = known_years();
+    let mut rows = Vec::new();
+    let mut failed = 0;
+    for (name, scenario) in &scenarios {
+        let checks = check_scenario(scenario, &known);
+        if checks.iter().any(|c| c.status == Status::Fail) {
+            failed += 1;
+        }
+        rows.push(summary_row(name, &checks));
+    }
+    print_table("Scenario\tResult\tPassed\tFirst problem", &rows)?;
+    if failed > 0 {
+        return Err(eyre!("{failed} of {} scenarios failed", scenarios.len())).suggestion(format!(
+            "`nparty doctor {}` lists every problem, with hints for fixing them.",
+            cfgpath.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn missing_inputs() {
+        let scenario = Scenario::new(
+            "QLD_2PP",
+            "2019",
+            crate::utils::StateAb::QLD,
+            crate::booths::Parties::new(),
+            vec!["no-such-prefs.csv".into()],
+            "no-such-polling-places.csv",
+            "out",
+            "NPP_Booths.csv",
+        );
+        // not the user's own catalogue, which might have anything in it
+        let known = Ok(BTreeSet::from(["2019".to_owned()]));
+        let checks = check_scenario(&scenario, &known);
+        assert_eq!(checks[0].status, Status::Pass);
+        assert_eq!(checks[1].status, Status::Fail);
+        assert!(checks[1].message.contains("no-such-prefs.csv not found"));
+        assert_eq!(checks[2].status, Status::Fail);
+        assert!(checks[2]
+            .message
+            .contains("no-such-polling-places.csv not found"));
+
+        let checks = check_scenario(&scenario, &Ok(BTreeSet::new()));
+        assert_eq!(checks[0].status, Status::Warn);
+        assert_eq!(
+            summary_row("QLD_2PP", &checks),
+            format!(
+                "QLD_2PP\tFAIL\t{}/{}\tPreferences file no-such-prefs.csv not found",
+                checks.iter().filter(|c| c.status == Status::Pass).count(),
+                checks.len()
+            )
+        );
+    }
+
+    #[test]
+    fn candidate_suggestions() {
+        let candidates: Vec<String> = ["Ticket", "A:SMITH John", "B:JONES Mary", "B:BROWN Ann"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            suggest_candidate("C:JONES Mary", &candidates),
+            Some("B:JONES Mary")
+        );
+        assert_eq!(
+            suggest_candidate("A:Smith Jon", &candidates),
+            Some("A:SMITH John")
+        );
+        assert_eq!(suggest_candidate("A:GREEN Bob", &candidates), None);
+    }
+}
@@ -10,9 +10,14 @@ use klask::{run_derived, Settings};
 mod aggregator;
 mod app;
 mod booths;
+mod compare;
 mod config;
 mod data;
+mod doctor;
+mod manifest;
 mod multiplier;
+#[cfg(feature = "profile")]
+mod profile;
 mod term;
 mod upgrades;
 mod utils;
@@ -27,9 +27,14 @@ use clap::Parser;
 mod aggregator;
 mod app;
 mod booths;
+mod compare;
 mod config;
 mod data;
+mod doctor;
+mod manifest;
 mod multiplier;
+#[cfg(feature = "profile")]
+mod profile;
 mod term;
 mod upgrades;
 mod utils;
@@ -39,6 +44,7 @@ use app::Cli;
 fn main() -> color_eyre::eyre::Result<()> {
     // Parse command-line arguments...
     let cli = Cli::parse();
+    term::set_color_choice(cli.color);
 
     // ... So that we can set a verbosity level
     tracing_subscriber::fmt()
@@ -52,12 +58,15 @@ fn main() -> color_eyre::eyre::Result<()> {
         })
         .with_target(false)
         .without_time()
+        .with_ansi(term::use_color(&std::io::stdout()))
         .init();
 
-    // Initialise sweet coloured error messages
-    color_eyre::config::HookBuilder::new()
-        .display_env_section(false)
-        .install()?;
+    // Initialise sweet coloured error messages (or not)
+    let mut hook = color_eyre::config::HookBuilder::new().display_env_section(false);
+    if !term::use_color(&std::io::stderr()) {
+        hook = hook.theme(color_eyre::config::Theme::new());
+    }
+    hook.install()?;
     std::env::set_var("RUST_SPANTRACE", "0");
 
     // finally, run the app!
@@ -0,0 +1,170 @@
+//! A record of the files produced by `nparty run`, for downstream automation;
+//! and of the scenarios it's run, for `--only-changed`.
+
+use crate::config::Scenario;
+use color_eyre::eyre::{Context, Result};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// One output file.
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    /// Size in bytes
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+}
+
+impl ManifestEntry {
+    /// Describe the file at `path`, which must exist.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// Output files by scenario name, then by phase.
+///
+/// Files are only read (to size and hash them) when the manifest is written.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    scenarios: BTreeMap<String, BTreeMap<&'static str, Vec<PathBuf>>>,
+}
+
+impl Manifest {
+    /// Record that `scenario`'s `phase` produced the file at `path`.
+    pub fn add(&mut self, scenario: &str, phase: &'static str, path: &Path) {
+        self.scenarios
+            .entry(scenario.to_owned())
+            .or_default()
+            .entry(phase)
+            .or_default()
+            .push(path.to_owned());
+    }
+
+    /// Every file recorded for `scenario`, in any phase.
+    pub fn outputs(&self, scenario: &str) -> Vec<PathBuf> {
+        self.scenarios
+            .get(scenario)
+            .into_iter()
+            .flat_map(BTreeMap::values)
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Write the manifest to `path` as JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut out: BTreeMap<&str, BTreeMap<&str, Vec<ManifestEntry>>> = BTreeMap::new();
+        for (scenario, phases) in &self.scenarios {
+            for (phase, paths) in phases {
+                let entries = paths
+                    .iter()
+                    .map(|p| ManifestEntry::from_path(p))
+                    .collect::<Result<_>>()?;
+                out.entry(scenario).or_default().insert(phase, entries);
+            }
+        }
+        let file =
+            File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+        serde_json::to_writer_pretty(file, &json!({ "scenarios": out }))
+            .context("Could not write manifest")
+    }
+}
+
+/// The file (in each scenario's output directory) where `--only-changed` keeps the
+/// [`scenario_fingerprint`]s of the scenario's last successful runs, by phase.
+const FINGERPRINTS_FN: &str = ".nparty_fingerprints.json";
+
+/// A hash of everything that a scenario's outputs depend on: its definition (groups, paths,
+/// state, year, etc.), the `options` it's run with, and when its input files were last modified.
+pub fn scenario_fingerprint(
+    scenario: &Scenario,
+    options: &impl serde::Serialize,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(scenario).context("Could not serialise scenario")?);
+    hasher.update(serde_json::to_vec(&scenario.aliases).context("Could not serialise aliases")?);
+    hasher.update(serde_json::to_vec(options).context("Could not serialise run options")?);
+
+    let inputs = scenario
+        .prefs_path
+        .iter()
+        .chain([&scenario.polling_places])
+        .chain(&scenario.sa1s_breakdown)
+        .chain(&scenario.senate_votes)
+        .chain(&scenario.sa1s_dists);
+    for input in inputs {
+        // a URL is only downloaded once, so its cached copy is as good as unchanged
+        let modified = std::fs::metadata(input)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        hasher.update(input.to_string_lossy().as_bytes());
+        hasher.update(modified.to_le_bytes());
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// What `--only-changed` records about a phase's last complete run.
+#[derive(Debug, Serialize, Deserialize)]
+struct PhaseRecord {
+    fingerprint: String,
+    /// The files it wrote
+    outputs: Vec<PathBuf>,
+}
+
+/// The records for `scenario`, by phase (none, if they can't be read).
+fn read_fingerprints(scenario: &Scenario) -> BTreeMap<String, PhaseRecord> {
+    std::fs::read(scenario.output_path(FINGERPRINTS_FN))
+        .ok()
+        .and_then(|x| serde_json::from_slice(&x).ok())
+        .unwrap_or_default()
+}
+
+/// Was `scenario`'s `phase` last run (completely) with this `fingerprint`,
+/// and are the files it wrote still there?
+pub fn is_unchanged(scenario: &Scenario, phase: &str, fingerprint: &str) -> bool {
+    read_fingerprints(scenario)
+        .get(phase)
+        .is_some_and(|r| r.fingerprint == fingerprint && r.outputs.iter().all(|p| p.exists()))
+}
+
+/// Record that `scenario`'s `phase` was run completely with this `fingerprint`, writing `outputs`.
+///
+/// Only record a run that can stand in for a full one: not one that was sampled or interrupted.
+pub fn record_fingerprint(
+    scenario: &Scenario,
+    phase: &str,
+    fingerprint: &str,
+    outputs: Vec<PathBuf>,
+) -> Result<()> {
+    let mut fingerprints = read_fingerprints(scenario);
+    fingerprints.insert(
+        phase.to_owned(),
+        PhaseRecord {
+            fingerprint: fingerprint.to_owned(),
+            outputs,
+        },
+    );
+    let path = scenario.output_path(FINGERPRINTS_FN);
+    std::fs::create_dir_all(path.parent().unwrap())
+        .with_context(|| format!("Could not create {}", path.parent().unwrap().display()))?;
+    let file =
+        File::create(&path).with_context(|| format!("Could not create {}", path.display()))?;
+    serde_json::to_writer_pretty(file, &fingerprints)
+        .with_context(|| format!("Could not write {}", path.display()))
+}
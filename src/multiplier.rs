@@ -2,21 +2,27 @@
 //!   
 //! The AEC have given us a
 //! "this many people from this SA1 voted at this booth"
-//! spreadsheet. It doesn't exactly correspond to either Reps or Senate numbers
-//! but we shall simply scale slightly for Senate voter numbers.
+//! spreadsheet. It doesn't exactly correspond to either Reps or Senate numbers.
+//! If we're also given the Senate votes at each booth, each booth's SA1 breakdown
+//! is scaled to match (see [`SenateVotes`]), or it can be scaled by a [`TurnoutScale`];
+//! otherwise it's used as-is, and so the output is in Reps votes.
 //!   
 //! We are basically performing a matrix product:
 //! `[sa1s; booths] * [booths; orders] = [sa1s; orders]`
 
 // This file corresponds to `SA1s_Multiplier.py`
 
-use super::booths::{group_combos, Parties};
-use super::utils::{PrefsMap, StateAb};
+use super::booths::{group_combos, sorted_groups, Combinations, Parties};
+use super::term::print_table;
+use super::utils::{create_encoded, open_encoded, PrefsMap, PrefsRows, StateAb};
 use color_eyre::eyre::{bail, Context, ContextCompat, Result};
+use color_eyre::Section;
+use encoding_rs::Encoding;
+use itertools::Itertools;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::create_dir_all;
-use std::path::Path;
-use tracing::{debug, info, trace};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace, warn};
 
 /// Convert a header to a column index in the SA1-Booth file.
 #[allow(non_camel_case_types)]
@@ -32,29 +38,41 @@ enum sa1col {
     votes = 6,
 }
 
+/// A (Division, Booth) pair.
+///
+/// (Not a `Division_Booth` portmanteau, as either name may itself contain an underscore.)
+pub type DivBooth = (String, String);
+
+/// NPP Booth Data: the vote for each combination (and the total) by booth.
+type BoothPrefs = BTreeMap<DivBooth, Vec<f64>>;
+
 /// Load up NPP Booth Data from file.
 ///
 /// `combinations` is a list of group-code orderings, as output by [`group_combos`].  
 ///
 /// This is the equivalent to the `[booths; orders]` matrix.
-fn load_npp_booths(combinations: &[String], npp_booths_path: &Path) -> Result<PrefsMap> {
+fn load_npp_booths(
+    combinations: &[String],
+    npp_booths_path: &Path,
+    encoding: &'static Encoding,
+) -> Result<BoothPrefs> {
     // Five fixed fields at the start, plus all the combinations, plus a total at the end:
     // ID, Division, Booth, Latitude, Longitude, {combinations ...}, Total
     // we don't actually care about ID, Latitude or Longitude,
     // as IDs have been known to be inconsistent across files,
     // and Lat/Lon are for the booths, not the SA1s
 
-    let mut booths: PrefsMap = BTreeMap::new();
+    let mut booths: BoothPrefs = BTreeMap::new();
     let mut total_votes: f64 = 0.0;
 
     let mut booths_rdr = csv::ReaderBuilder::new()
         .flexible(true)
         .has_headers(true)
-        .from_path(npp_booths_path)?;
+        .from_reader(open_encoded(npp_booths_path, encoding)?);
 
     for record in booths_rdr.records() {
         let row = record?;
-        let divbooth = row[1].to_owned() + "_" + &row[2];
+        let divbooth = (row[1].to_owned(), row[2].to_owned());
 
         let mut boothvotes: Vec<f64> = Vec::with_capacity(combinations.len() + 1);
 
@@ -73,17 +91,215 @@ fn load_npp_booths(combinations: &[String], npp_booths_path: &Path) -> Result<Pr
     Ok(booths)
 }
 
+/// Senate votes by booth, for scaling the SA1 breakdown (which is for House votes).
+pub type SenateVotes = BTreeMap<DivBooth, f64>;
+
+/// Header names for the division, booth, votes and (optional) state columns of a Senate votes file.
+/// The AEC's names come first; each list is tried in order.
+const SENATE_DIVISION_NAMES: [&str; 3] = ["DivisionNm", "div_nm", "Division"];
+const SENATE_BOOTH_NAMES: [&str; 3] = ["PollingPlaceNm", "pp_nm", "Booth"];
+const SENATE_VOTES_NAMES: [&str; 3] = ["OrdinaryVotes", "votes", "Votes"];
+const SENATE_STATE_NAMES: [&str; 3] = ["StateAb", "state_ab", "State"];
+
+/// Read the Senate votes cast at each booth in `state`, summing any rows for the same booth
+/// (so that e.g. the AEC's first preferences by polling place can be used directly).
+///
+/// Columns are found by header name. The header needn't be the first row, as AEC downloads
+/// often start with a title line; and if there's no state column, every row is used.
+pub fn read_senate_votes<R: std::io::Read>(senate_votes: R, state: StateAb) -> Result<SenateVotes> {
+    let mut records = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(senate_votes)
+        .into_records();
+
+    let find = |header: &csv::StringRecord, names: &[&str]| {
+        names
+            .iter()
+            .find_map(|n| header.iter().position(|h| h.trim().eq_ignore_ascii_case(n)))
+    };
+    let (div_col, booth_col, votes_col, state_col) = loop {
+        let header = records
+            .next()
+            .transpose()?
+            .context("No header naming the division, booth and votes columns")?;
+        if let (Some(d), Some(b), Some(v)) = (
+            find(&header, &SENATE_DIVISION_NAMES),
+            find(&header, &SENATE_BOOTH_NAMES),
+            find(&header, &SENATE_VOTES_NAMES),
+        ) {
+            break (d, b, v, find(&header, &SENATE_STATE_NAMES));
+        }
+    };
+
+    let state = state.to_string();
+    let mut votes = SenateVotes::new();
+    for record in records {
+        let row = record?;
+        if state_col
+            .and_then(|i| row.get(i))
+            .is_some_and(|s| s != state)
+        {
+            continue;
+        }
+        let (Some(div), Some(booth)) = (row.get(div_col), row.get(booth_col)) else {
+            continue;
+        };
+        let v = row
+            .get(votes_col)
+            .and_then(|x| x.trim().parse::<f64>().ok())
+            .unwrap_or(0.0);
+        *votes
+            .entry((div.trim().to_owned(), booth.trim().to_owned()))
+            .or_default() += v;
+    }
+    Ok(votes)
+}
+
+/// The total votes at each booth in the SA1 breakdown, for `state` and `year`.
+pub fn breakdown_booth_totals(
+    sa1_breakdown_path: &Path,
+    state: StateAb,
+    year: &str,
+) -> Result<BTreeMap<DivBooth, f64>> {
+    let mut sa1_rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(true)
+        .from_path(sa1_breakdown_path)?;
+    let state = state.to_string();
+    let mut totals: BTreeMap<DivBooth, f64> = BTreeMap::new();
+    for record in sa1_rdr.records() {
+        let row = record?;
+        let field = |col: sa1col| row.get(col as usize).unwrap_or_default();
+        if field(sa1col::state_ab) != state || field(sa1col::year) != year {
+            continue;
+        }
+        let votes = field(sa1col::votes).parse::<f64>().unwrap_or(0.0);
+        *totals
+            .entry((
+                field(sa1col::div_nm).to_owned(),
+                field(sa1col::pp_nm).to_owned(),
+            ))
+            .or_default() += votes;
+    }
+    Ok(totals)
+}
+
+/// For each booth in the SA1 breakdown, the ratio of its Senate votes to its breakdown votes.
+///
+/// Booths with no Senate votes recorded (or no breakdown votes) are left out, and so aren't scaled;
+/// those, and any Senate booths missing from the breakdown, are listed at the `debug` level.
+fn senate_ratios(
+    senate: &SenateVotes,
+    breakdown_totals: &BTreeMap<DivBooth, f64>,
+) -> BTreeMap<DivBooth, f64> {
+    let mut ratios = BTreeMap::new();
+    for (divbooth, total) in breakdown_totals {
+        match senate.get(divbooth) {
+            Some(senate_votes) if *total > 0.0 => {
+                ratios.insert(divbooth.clone(), senate_votes / total);
+            }
+            Some(_) => {}
+            None => debug!(
+                "\tNo Senate votes for {:?}; leaving its {} breakdown votes unscaled",
+                divbooth, total
+            ),
+        }
+    }
+    for (divbooth, senate_votes) in senate {
+        if !breakdown_totals.contains_key(divbooth) {
+            debug!(
+                "\t{:?} has {} Senate votes, but isn't in the SA1 breakdown",
+                divbooth, senate_votes
+            );
+        }
+    }
+    ratios
+}
+
+/// The per-booth [`senate_ratios`] for the SA1 breakdown, taking the Senate votes
+/// to be each booth's total in the distribution output.
+fn booth_ratios(
+    booths: &BoothPrefs,
+    sa1_breakdown_path: &Path,
+    state: StateAb,
+    year: &str,
+) -> Result<BTreeMap<DivBooth, f64>> {
+    let senate: SenateVotes = booths
+        .iter()
+        .map(|(divbooth, votes)| (divbooth.clone(), votes.last().copied().unwrap_or_default()))
+        .collect();
+    let breakdown_totals = breakdown_booth_totals(sa1_breakdown_path, state, year)?;
+    let ratios = senate_ratios(&senate, &breakdown_totals);
+    debug!(
+        "\tScaling {} of {} booths to their distributed votes",
+        ratios.len(),
+        breakdown_totals.len()
+    );
+    Ok(ratios)
+}
+
+/// Load the Senate votes file at `senate_votes_path`
+/// and work out the per-booth [`senate_ratios`] for the SA1 breakdown.
+fn load_senate_ratios(
+    senate_votes_path: &Path,
+    sa1_breakdown_path: &Path,
+    state: StateAb,
+    year: &str,
+) -> Result<BTreeMap<DivBooth, f64>> {
+    let file = std::fs::File::open(senate_votes_path).with_context(|| {
+        format!(
+            "Could not open Senate votes file {}",
+            senate_votes_path.display()
+        )
+    })?;
+    let senate = read_senate_votes(file, state)
+        .with_context(|| format!("Could not read {}", senate_votes_path.display()))?;
+    let breakdown_totals = breakdown_booth_totals(sa1_breakdown_path, state, year)?;
+    let ratios = senate_ratios(&senate, &breakdown_totals);
+    debug!(
+        "\tScaling {} of {} booths to their Senate votes",
+        ratios.len(),
+        breakdown_totals.len()
+    );
+    Ok(ratios)
+}
+
+/// The combinations of `parties`, in the order of the distribution output's columns.
+fn party_combos(parties: &Parties) -> Combinations {
+    // in the same order as the distribution's columns
+    group_combos(&sorted_groups(parties))
+}
+
+/// A booth's contribution to an SA1: its results `boothvotes` (with the total last),
+/// scaled down to the `sa1_booth_votes` votes that came from that SA1.
+///
+/// The booth's total must be non-zero.
+fn scale_booth_votes(sa1_booth_votes: f64, boothvotes: &[f64]) -> impl Iterator<Item = f64> + '_ {
+    let boothtotal = boothvotes.last().copied().unwrap_or_default();
+    // w: the vote share for a preference combo
+    // doing it in one go produces slightly different results to the Python,
+    // which is concerning...
+    boothvotes
+        .iter()
+        .map(move |w| (sa1_booth_votes * w) / boothtotal)
+}
+
 /// Actually write the output (as a CSV).
 ///
 /// * `sa1_prefs_path`: the path to write it to
 /// * `combinations`: every possible combination of group codes (see [`group_combos`]), which will be most of the headers.
+/// * `exhausted_label`: the label for the first combination (see [`crate::booths::EXHAUSTED`])
 /// * `outputn`: a map of from SA1 IDs to preference results (in the corresponding order to `combinations`)
+/// * `encoding`: see [`crate::utils::output_encoding`]
 ///
 /// The column order is `SA1_ID`, then all of the `combinations`.
 fn write_sa1_prefs(
     sa1_prefs_path: &Path,
     combinations: &[String],
-    outputn: BTreeMap<String, Vec<f64>>,
+    exhausted_label: &str,
+    outputn: PrefsMap,
+    encoding: &'static Encoding,
 ) -> Result<()> {
     // having summed it all up...
     use std::iter::once;
@@ -93,10 +309,11 @@ fn write_sa1_prefs(
             .parent()
             .context("couldn't perform path conversion")?,
     )?;
-    let mut sa1_wtr = csv::Writer::from_path(sa1_prefs_path)?;
+    let mut sa1_wtr = csv::Writer::from_writer(create_encoded(sa1_prefs_path, encoding)?);
 
     let header = once("SA1_id")
-        .chain(combinations.iter().map(String::as_str))
+        .chain(once(exhausted_label))
+        .chain(combinations.iter().skip(1).map(String::as_str))
         .chain(once("Total"));
 
     sa1_wtr
@@ -114,6 +331,259 @@ fn write_sa1_prefs(
     Ok(())
 }
 
+/// Options for [`project`], beyond its inputs and outputs.
+#[derive(Debug, Clone, Copy)]
+pub struct ProjectionOptions<'a> {
+    /// The label for the first column (see [`crate::booths::EXHAUSTED`])
+    pub exhausted_label: &'a str,
+    /// Also write a coverage report; see [`write_coverage`]
+    pub coverage: bool,
+    /// Fold small combination columns into one; see [`fold_small_columns`]
+    pub min_column_votes: Option<f64>,
+    /// Scale each booth's SA1 breakdown to its Senate votes, as given in this file;
+    /// see [`read_senate_votes`]
+    pub senate_votes: Option<&'a Path>,
+    /// Otherwise, how to scale the SA1 breakdown (not at all by default)
+    pub turnout_scale: TurnoutScale,
+    /// Also write the SA1 breakdown entries that had no booth in the distribution output
+    /// to this file; see [`write_unmatched`]
+    pub report_unmatched: Option<&'a Path>,
+    /// The encoding of the distribution output (read) and of the SA1 prefs (written);
+    /// see [`crate::utils::output_encoding`]
+    pub encoding: &'static Encoding,
+    /// How to round the projected votes
+    pub rounding: Rounding,
+}
+
+/// How to scale the SA1 breakdown, which counts Reps voters, to Senate turnout.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize)]
+pub enum TurnoutScale {
+    /// Don't (unless there's a Senate votes file): the projection is in Reps votes
+    #[default]
+    None,
+    /// Scale every booth by the same factor
+    Factor(f64),
+    /// Scale each booth to its total in the distribution output (its formal Senate votes),
+    /// so that the projection adds up to the distribution
+    Booths,
+}
+
+/// The [`TurnoutScale`] for a `--turnout-scale` argument: a positive factor, or `booths`.
+pub fn parse_turnout_scale(arg: &str) -> std::result::Result<TurnoutScale, String> {
+    let arg = arg.trim();
+    if arg.eq_ignore_ascii_case("booths") {
+        return Ok(TurnoutScale::Booths);
+    }
+    match arg.parse::<f64>() {
+        Ok(x) if x.is_finite() && x > 0.0 => Ok(TurnoutScale::Factor(x)),
+        _ => Err(format!(
+            "expected a positive factor or \"booths\", not {arg:?}"
+        )),
+    }
+}
+
+/// How to round each SA1's projected votes.
+#[derive(clap::ArgEnum, Debug, PartialEq, Eq, Clone, Copy, Default, Serialize)]
+pub enum Rounding {
+    /// Don't round: leave fractional votes as they are
+    #[default]
+    None,
+    /// Round each value, including the total, to the nearest whole vote (ties to even)
+    HalfEven,
+    /// Round the total to the nearest whole vote (ties to even), and then apportion it
+    /// between the combinations by largest remainder, so that they add up to it
+    LargestRemainder,
+}
+
+/// Round `x` to the nearest whole number, with ties to the even one.
+fn round_half_even(x: f64) -> f64 {
+    let floor = x.floor();
+    let diff = x - floor;
+    if diff > 0.5 || (diff >= 0.5 && floor % 2.0 != 0.0) {
+        floor + 1.0
+    } else {
+        floor
+    }
+}
+
+/// Round a row of votes (with the total last) as per `rounding`.
+pub fn round_row(row: &mut [f64], rounding: Rounding) {
+    let Some((total, votes)) = row.split_last_mut() else {
+        return;
+    };
+    match rounding {
+        Rounding::None => {}
+        Rounding::HalfEven => {
+            for v in votes {
+                *v = round_half_even(*v);
+            }
+            *total = round_half_even(*total);
+        }
+        Rounding::LargestRemainder => {
+            // Hare: everyone gets their quota rounded down, then the remaining seats (votes)
+            // go to the largest remainders, earlier columns first in a tie
+            let target = round_half_even(*total);
+            let mut remainders: Vec<(usize, f64)> = Vec::with_capacity(votes.len());
+            let mut allocated = 0.0;
+            for (i, v) in votes.iter_mut().enumerate() {
+                let floor = v.floor();
+                remainders.push((i, *v - floor));
+                *v = floor;
+                allocated += floor;
+            }
+            remainders.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            #[allow(clippy::cast_sign_loss)] // the floors can't add up to more than the target
+            let leftover = (target - allocated).max(0.0) as usize;
+            for (i, _) in remainders.into_iter().take(leftover) {
+                votes[i] += 1.0;
+            }
+            *total = target;
+        }
+    }
+}
+
+/// The label for the combination columns merged by [`fold_small_columns`]
+pub const OTHER: &str = "Other";
+
+/// Merge the combination columns whose total across all SA1s is below `min_votes`
+/// into a single [`OTHER`] column (just before `Total`), returning the remaining combinations.
+///
+/// The first column, of exhausted ballots, is always kept.
+/// Many-party scenarios have hundreds of combinations, most of them near-empty, so this
+/// makes for a much more compact output.
+fn fold_small_columns(
+    combinations: &[String],
+    outputn: &mut PrefsMap,
+    min_votes: f64,
+) -> Vec<String> {
+    let mut totals = vec![0.0_f64; combinations.len()];
+    for row in outputn.values() {
+        for (total, votes) in totals.iter_mut().zip(row) {
+            *total += votes;
+        }
+    }
+    let keep: Vec<bool> = totals
+        .iter()
+        .enumerate()
+        .map(|(i, total)| i == 0 || *total >= min_votes)
+        .collect();
+    let folded = keep.iter().filter(|k| !**k).count();
+    if folded == 0 {
+        return combinations.to_vec();
+    }
+    debug!(
+        "\tFolding {} of {} combinations into {}",
+        folded,
+        combinations.len(),
+        OTHER
+    );
+
+    for row in outputn.values_mut() {
+        let mut other = 0.0;
+        let mut i = 0;
+        row.retain(|votes| {
+            // the Total (beyond the combinations) is kept too
+            let kept = keep.get(i).copied().unwrap_or(true);
+            if !kept {
+                other += votes;
+            }
+            i += 1;
+            kept
+        });
+        row.insert(row.len() - 1, other);
+    }
+    combinations
+        .iter()
+        .zip(&keep)
+        .filter(|(_, kept)| **kept)
+        .map(|(c, _)| c.clone())
+        .chain(std::iter::once(OTHER.to_owned()))
+        .collect()
+}
+
+/// How a single SA1's votes were put together from booths.
+#[derive(Debug, Default)]
+struct Coverage {
+    /// How many booths contributed votes
+    booths: usize,
+    /// The total votes attributed to this SA1
+    votes: f64,
+    /// The booth which contributed the most votes
+    dominant_booth: DivBooth,
+    /// ... and how many it contributed
+    dominant_votes: f64,
+}
+
+/// Where the coverage report goes: alongside `sa1_prefs_path`, e.g. `SA1_Prefs_Coverage.csv`.
+pub fn coverage_path(sa1_prefs_path: &Path) -> PathBuf {
+    let mut coverage_fn = sa1_prefs_path.file_stem().unwrap_or_default().to_owned();
+    coverage_fn.push("_Coverage.csv");
+    sa1_prefs_path.with_file_name(coverage_fn)
+}
+
+/// Write the coverage report: for each SA1, how many booths contributed
+/// and what fraction of its votes came from the dominant booth.
+///
+/// An SA1 whose votes nearly all came from one booth is mostly passed through from that booth's
+/// results; one spread over many booths is more of an interpolation.
+fn write_coverage(
+    coverage_path: &Path,
+    coverage: BTreeMap<String, Coverage>,
+    encoding: &'static Encoding,
+) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(create_encoded(coverage_path, encoding)?);
+    wtr.write_record([
+        "SA1_id",
+        "Booths",
+        "Votes",
+        "Dominant_Booth",
+        "Dominant_Share",
+    ])
+    .context("error writing coverage header")?;
+    for (id, c) in coverage {
+        let share = if c.votes > 0.0 {
+            c.dominant_votes / c.votes
+        } else {
+            0.0
+        };
+        wtr.write_record([
+            id,
+            c.booths.to_string(),
+            c.votes.to_string(),
+            format!("{}_{}", c.dominant_booth.0, c.dominant_booth.1),
+            share.to_string(),
+        ])
+        .context("error writing coverage line")?;
+    }
+    wtr.flush().context("error finalising coverage")?;
+    Ok(())
+}
+
+/// An SA1 breakdown entry whose booth isn't in the distribution output: SA1, booth and votes.
+type Unmatched = (String, DivBooth, f64);
+
+/// Write the SA1 breakdown entries that [`project`] couldn't match to a booth
+/// (as a CSV, in `encoding`), with columns `SA1_id`, `Division`, `Booth` and `Votes`.
+fn write_unmatched(
+    path: &Path,
+    unmatched: &[Unmatched],
+    encoding: &'static Encoding,
+) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(
+        create_encoded(path, encoding)
+            .with_context(|| format!("Could not create {}", path.display()))?,
+    );
+    wtr.write_record(["SA1_id", "Division", "Booth", "Votes"])
+        .context("error writing unmatched-entries header")?;
+    for (id, (div, booth), votes) in unmatched {
+        wtr.write_record([id, div, booth, &votes.to_string()])
+            .context("error writing unmatched-entries line")?;
+    }
+    wtr.flush().context("error finalising unmatched entries")?;
+    info!("\tWrote unmatched SA1 entries to {}", path.display());
+    Ok(())
+}
+
 /// Were there errors of correspondence between the SA1-Booth file and the NPP-Booth file?
 ///
 /// * `skipped`: map of booths : votes, where the booth was not found in the NPP-Booth file
@@ -125,11 +595,11 @@ fn write_sa1_prefs(
 /// This function effectively performs `booths.keys - divbooths_seen` to determine if any booths are missing from
 /// the SA1-Booth file, or spuriously generated by [`crate::booths::booth_npps`].
 fn print_diagnostics(
-    skipped: BTreeMap<String, f64>,
+    skipped: BTreeMap<DivBooth, f64>,
     sa1_booth_total: f64,
     output_total: f64,
-    booths: &PrefsMap,
-    divbooths_seen: &BTreeSet<String>,
+    booths: &BoothPrefs,
+    divbooths_seen: &BTreeSet<DivBooth>,
 ) {
     debug!("\tSA1 Booths votes:\t{:8}", sa1_booth_total);
     debug!("\tOutput SA1s votes:\t{:8.0}", output_total / 2.0); // div2 as total included in sum
@@ -137,29 +607,83 @@ fn print_diagnostics(
         debug!(
             "\tThe following booths had no corresponding NPP Preferences recorded. This has been known to happen.",
         );
-        for (k, v) in skipped {
-            let (div, booth) = k.split_once('_').unwrap();
+        for ((div, booth), v) in skipped {
             debug!("\t\t{:8}\t{:32}\t{:5}", div, booth, v);
         }
     }
     // Perform and report the set difference between `booths.keys` and `divbooths_seen`
-    let booth_keys = booths
-        .keys()
-        .map(String::clone)
-        .collect::<BTreeSet<String>>();
+    let booth_keys = booths.keys().cloned().collect::<BTreeSet<DivBooth>>();
     let whoopsie = booth_keys
         .difference(divbooths_seen)
-        .collect::<BTreeSet<&String>>();
+        .collect::<BTreeSet<&DivBooth>>();
     if !whoopsie.is_empty() {
         debug!("The following booths, if any, appear in the NPP Preferences file but not in the SA1-Booths file:");
         for k in whoopsie {
             let total = *(booths.get(k).and_then(|v| v.last()).unwrap_or(&0.0));
-            let (div, booth) = k.split_once('_').unwrap();
+            let (div, booth) = k;
             debug!("\t\t{:8}\t{:32}\t{:5}", div, booth, total);
         }
     }
 }
 
+/// Sum the `Total` column (the last column) of a CSV written by either the
+/// distribution or the projection phase (in `encoding`).
+fn sum_total_column(path: &Path, encoding: &'static Encoding) -> Result<f64> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(true)
+        .from_reader(
+            open_encoded(path, encoding)
+                .with_context(|| format!("Could not open {}", path.display()))?,
+        );
+    let mut total = 0.0;
+    for record in rdr.records() {
+        let row = record?;
+        total += row
+            .iter()
+            .next_back()
+            .and_then(|x| x.parse::<f64>().ok())
+            .unwrap_or(0.0);
+    }
+    Ok(total)
+}
+
+/// Check that the projection has (roughly) preserved the number of ballots.
+///
+/// Compares the grand total of `npp_booths_path` against the sum of the `Total` column of
+/// `sa1_prefs_path` and warns if they differ by more than `tolerance` (as a fraction).
+/// The projection reweights booths by the SA1 breakdown, so exact equality isn't expected;
+/// a large divergence usually means the breakdown file is for the wrong year or state.
+/// Both files are read in `encoding`.
+pub fn check_totals(
+    npp_booths_path: &Path,
+    sa1_prefs_path: &Path,
+    tolerance: f64,
+    encoding: &'static Encoding,
+) -> Result<()> {
+    let booths_total = sum_total_column(npp_booths_path, encoding)?;
+    let sa1s_total = sum_total_column(sa1_prefs_path, encoding)?;
+    debug!("\tDistributed votes:\t{:8.0}", booths_total);
+    debug!("\tProjected votes:\t{:8.0}", sa1s_total);
+
+    let divergence = if booths_total > 0.0 {
+        (booths_total - sa1s_total).abs() / booths_total
+    } else if sa1s_total > 0.0 {
+        1.0
+    } else {
+        0.0
+    };
+    if divergence > tolerance {
+        warn!(
+            "{:.0} votes were distributed but {:.0} were projected onto SA1s ({:.1}% apart). Is the SA1s breakdown file for the right year and state?",
+            booths_total,
+            sa1s_total,
+            divergence * 100.0
+        );
+    }
+    Ok(())
+}
+
 /// Perform the projection from booths to SA1s.
 ///
 /// * `parties`: the groups being considered on a `preferred` basis.
@@ -168,6 +692,8 @@ fn print_diagnostics(
 /// * `npp_booths_path`: the location of the file created by [`crate::booths::booth_npps`].
 /// * `sa1_breakdown_path`: the SA1-Booths correspondence.
 /// * `sa1_prefs_path`: the output file.
+/// * `options`: see [`ProjectionOptions`]. The coverage report (if any) is written
+///   alongside the output file, with `_Coverage` appended to its name.
 pub fn project(
     parties: &Parties,
     state: StateAb,
@@ -175,21 +701,31 @@ pub fn project(
     npp_booths_path: &Path,
     sa1_breakdown_path: &Path,
     sa1_prefs_path: &Path,
+    options: ProjectionOptions,
 ) -> Result<()> {
     info!("\tProjecting results onto SA1s");
 
-    let combinations = {
-        // potential soundness issue: is this going to work out the same order?
-        // BTreeMap for Parties in general should fix that
-        let mut partykeys: Vec<&str> = Vec::new();
-        for k in parties.keys() {
-            partykeys.push(k);
-        }
-        group_combos(&partykeys)
-    };
+    let combinations = party_combos(parties);
 
     // *** Load up NPP-Booth data ***
-    let booths = load_npp_booths(&combinations, npp_booths_path)?;
+    let booths = load_npp_booths(&combinations, npp_booths_path, options.encoding)?;
+
+    // *** Optionally, per-booth Senate scaling (or some other turnout scaling) ***
+    if options.senate_votes.is_some() && options.turnout_scale != TurnoutScale::None {
+        bail!("The SA1 breakdown can be scaled to a Senate votes file or by a turnout scale, but not both");
+    }
+    let (senate_ratios, default_ratio) = match options.turnout_scale {
+        TurnoutScale::None => (
+            options
+                .senate_votes
+                .map(|p| load_senate_ratios(p, sa1_breakdown_path, state, year))
+                .transpose()?
+                .unwrap_or_default(),
+            1.0,
+        ),
+        TurnoutScale::Factor(x) => (BTreeMap::new(), x),
+        TurnoutScale::Booths => (booth_ratios(&booths, sa1_breakdown_path, state, year)?, 1.0),
+    };
 
     // *** Load up SA1 data ***
     // This is the [sa1s; booths] matrix equivalent
@@ -200,13 +736,15 @@ pub fn project(
         .has_headers(true)
         .from_path(sa1_breakdown_path)?;
 
-    let mut outputn: PrefsMap = BTreeMap::new(); // Our numerical ultimate output. Indexed by SA1 ID
+    let mut outputn = PrefsMap::new(); // Our numerical ultimate output. Indexed by SA1 ID
 
     // Diagnostics
-    let mut skipped: BTreeMap<String, f64> = BTreeMap::new();
+    let mut skipped: BTreeMap<DivBooth, f64> = BTreeMap::new();
+    let mut unmatched: Vec<Unmatched> = Vec::new();
     let mut sa1_booth_total: f64 = 0.0;
     let mut output_total: f64 = 0.0;
-    let mut divbooths_seen: BTreeSet<String> = BTreeSet::new();
+    let mut divbooths_seen: BTreeSet<DivBooth> = BTreeSet::new();
+    let mut coverage: BTreeMap<String, Coverage> = BTreeMap::new();
 
     let mut row = csv::StringRecord::new();
     while sa1_rdr.read_record(&mut row)? {
@@ -236,14 +774,22 @@ pub fn project(
                 year
             );
         }
-        let divbooth = row[sa1col::div_nm as usize].to_owned() + "_" + &row[sa1col::pp_nm as usize];
+        let divbooth = (
+            row[sa1col::div_nm as usize].to_owned(),
+            row[sa1col::pp_nm as usize].to_owned(),
+        );
         divbooths_seen.insert(divbooth.clone());
 
         // The number of votes from a booth attributed to this SA1
+        // (scaled to the booth's Senate votes, if we have them)
         let sa1_booth_votes: f64 = row
             .get(sa1col::votes as usize)
             .and_then(|x| x.parse::<f64>().ok())
-            .unwrap_or(0.0_f64);
+            .unwrap_or(0.0_f64)
+            * senate_ratios
+                .get(&divbooth)
+                .copied()
+                .unwrap_or(default_ratio);
 
         sa1_booth_total += sa1_booth_votes;
 
@@ -253,36 +799,54 @@ pub fn project(
             // The total number of Senate votes cast at the booth
             let boothtotal = boothvotes
                 .last()
-                .with_context(|| format!("No vote records for {:?}", &divbooth.split_once('_')))?;
+                .with_context(|| format!("No vote records for {:?}", &divbooth))?;
 
-            let output_row = outputn
-                .entry(sa1_id)
-                .or_insert_with(|| vec![0.0_f64; combinations.len() + 1]);
+            if options.coverage && sa1_booth_votes > 0.0 {
+                let c = coverage.entry(sa1_id.clone()).or_default();
+                c.booths += 1;
+                c.votes += sa1_booth_votes;
+                if sa1_booth_votes > c.dominant_votes {
+                    c.dominant_votes = sa1_booth_votes;
+                    c.dominant_booth.clone_from(&divbooth);
+                }
+            }
 
             if (*boothtotal) > 0.0 {
-                for (i, w) in boothvotes.iter().enumerate() {
-                    // w: the vote share for a preference combo
-                    let val = (sa1_booth_votes * w) / boothtotal;
-                    // doing it in one go produces slightly different results to the Python,
-                    // which is concerning...
-                    *output_row.get_mut(i).unwrap() += val;
-                    output_total += val;
-                }
+                output_total +=
+                    outputn.add_row(&sa1_id, scale_booth_votes(sa1_booth_votes, boothvotes));
             } else {
-                trace!("Booth {} has an entry, but total of zero votes.", divbooth);
+                // the SA1 is still listed, if with no votes
+                outputn.add_row(&sa1_id, boothvotes.iter().map(|_| 0.0));
+                trace!(
+                    "Booth {:?} has an entry, but total of zero votes.",
+                    divbooth
+                );
             }
         } else {
             *skipped.entry(divbooth.clone()).or_insert(0.0) += sa1_booth_votes;
             trace!(
-                "No NPP prefs found for {}; skipping {} votes from {}",
+                "No NPP prefs found for {:?}; skipping {} votes from {}",
                 divbooth,
                 sa1_booth_votes,
                 sa1_id
             );
+            unmatched.push((sa1_id, divbooth, sa1_booth_votes));
         }
     }
 
     // Diagnostics
+    if !unmatched.is_empty() {
+        let unmatched_votes: f64 = unmatched.iter().map(|(_, _, v)| v).sum();
+        warn!(
+            "{} SA1 breakdown entries, at {} booths with no distributed votes, could not be projected, totalling {:.1} votes. This has been known to happen (e.g. for booths that took no formal Senate votes); they're listed at the debug level.",
+            unmatched.len(),
+            skipped.len(),
+            unmatched_votes
+        );
+    }
+    if let Some(path) = options.report_unmatched {
+        write_unmatched(path, &unmatched, options.encoding)?;
+    }
     print_diagnostics(
         skipped,
         sa1_booth_total,
@@ -291,8 +855,314 @@ pub fn project(
         &divbooths_seen,
     );
 
+    let combinations = match options.min_column_votes {
+        Some(min_votes) => fold_small_columns(&combinations, &mut outputn, min_votes),
+        None => combinations,
+    };
+    if options.rounding != Rounding::None {
+        for row in outputn.values_mut() {
+            round_row(row, options.rounding);
+        }
+    }
+
     // Actually write the output
-    write_sa1_prefs(sa1_prefs_path, &combinations, outputn)?;
+    write_sa1_prefs(
+        sa1_prefs_path,
+        &combinations,
+        options.exhausted_label,
+        outputn,
+        options.encoding,
+    )?;
+    if options.coverage {
+        write_coverage(&coverage_path(sa1_prefs_path), coverage, options.encoding)?;
+    }
     info!("\tProjection Done!");
     Ok(())
 }
+
+/// Show how [`project`] spreads a single booth's results across the SA1s that voted there.
+///
+/// Prints a row for each such SA1: its ID, the votes it cast at the booth, those votes as a share
+/// of the booth's (per the SA1 breakdown), and then its projected votes for each combination.
+/// As in [`ProjectionOptions`], `senate_votes` optionally scales the votes to the booth's Senate votes,
+/// and `encoding` is that of the distribution output.
+#[allow(clippy::too_many_arguments)] // mirrors `project` and its options
+pub fn project_booth(
+    parties: &Parties,
+    state: StateAb,
+    year: &str,
+    npp_booths_path: &Path,
+    sa1_breakdown_path: &Path,
+    senate_votes: Option<&Path>,
+    encoding: &'static Encoding,
+    divbooth: &DivBooth,
+) -> Result<()> {
+    let combinations = party_combos(parties);
+    let booths = load_npp_booths(&combinations, npp_booths_path, encoding)?;
+    let (division, booth) = divbooth;
+    let boothvotes = booths
+        .get(divbooth)
+        .with_context(|| {
+            format!(
+                "{} ({}) isn't in {}",
+                booth,
+                division,
+                npp_booths_path.display()
+            )
+        })
+        .suggestion("Use the division and booth names as they are in the distribution output.")?;
+    if boothvotes.last().copied().unwrap_or_default() <= 0.0 {
+        bail!("{} ({}) has no votes to project", booth, division);
+    }
+    let senate_ratio = senate_votes
+        .map(|p| load_senate_ratios(p, sa1_breakdown_path, state, year))
+        .transpose()?
+        .and_then(|ratios| ratios.get(divbooth).copied())
+        .unwrap_or(1.0);
+
+    let mut sa1_rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(true)
+        .from_path(sa1_breakdown_path)?;
+    let state = state.to_string();
+    let mut sa1s = Vec::new();
+    for record in sa1_rdr.records() {
+        let row = record?;
+        let field = |col: sa1col| row.get(col as usize).unwrap_or_default();
+        if field(sa1col::state_ab) == state
+            && field(sa1col::year) == year
+            && field(sa1col::div_nm) == division
+            && field(sa1col::pp_nm) == booth
+        {
+            let votes = field(sa1col::votes).parse::<f64>().unwrap_or(0.0) * senate_ratio;
+            sa1s.push((field(sa1col::SA1_id).to_owned(), votes));
+        }
+    }
+    if sa1s.is_empty() {
+        bail!(
+            "No SA1s in {} voted at {} ({})",
+            sa1_breakdown_path.display(),
+            booth,
+            division
+        );
+    }
+    let sa1s_total: f64 = sa1s.iter().map(|(_, votes)| votes).sum();
+
+    let header = ["SA1_id", "Votes", "Share"]
+        .into_iter()
+        .chain(combinations.iter().map(String::as_str))
+        .chain(std::iter::once("Total"))
+        .join("\t");
+    let rows: Vec<String> = sa1s
+        .iter()
+        .map(|(id, votes)| {
+            let share = if sa1s_total > 0.0 {
+                votes / sa1s_total
+            } else {
+                0.0
+            };
+            let projected = scale_booth_votes(*votes, boothvotes).map(|x| format!("{x:.2}"));
+            [id.clone(), votes.to_string(), format!("{share:.4}")]
+                .into_iter()
+                .chain(projected)
+                .join("\t")
+        })
+        .collect();
+    print_table(&header, &rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn small_columns_fold() {
+        let combinations = ["Exhausted", "Alp", "Lnp", "AlpLnp", "LnpAlp"].map(String::from);
+        let mut outputn = PrefsMap::new();
+        outputn.insert("1".into(), vec![0.5, 10.0, 0.5, 0.25, 8.0, 19.25]);
+        outputn.insert("2".into(), vec![0.0, 5.0, 0.5, 0.0, 6.0, 11.5]);
+        let kept = fold_small_columns(&combinations, &mut outputn, 1.5);
+        assert_eq!(kept, ["Exhausted", "Alp", "LnpAlp", OTHER]);
+        assert_eq!(outputn["1"], [0.5, 10.0, 8.0, 0.75, 19.25]);
+        assert_eq!(outputn["2"], [0.0, 5.0, 6.0, 0.5, 11.5]);
+    }
+
+    #[test]
+    fn rounding() {
+        let mut row = vec![2.5, 3.5, 0.4, 1.6, 8.0];
+        round_row(&mut row, Rounding::HalfEven);
+        assert_eq!(row, [2.0, 4.0, 0.0, 2.0, 8.0]);
+
+        let mut row = vec![1.3, 1.3, 1.4, 0.5, 4.5];
+        round_row(&mut row, Rounding::LargestRemainder);
+        assert_eq!(row, [1.0, 1.0, 1.0, 1.0, 4.0]);
+        let mut row = vec![0.7, 0.7, 0.6, 3.0, 5.0];
+        round_row(&mut row, Rounding::LargestRemainder);
+        assert_eq!(row, [1.0, 1.0, 0.0, 3.0, 5.0]);
+    }
+
+    #[test]
+    fn senate_votes_scaling() {
+        let senate = "\
+2019 Federal Election: Senate first preferences by polling place
+StateAb,DivisionNm,PollingPlaceNm,Surname,OrdinaryVotes
+QLD,Brisbane,Ascot,ONE,30
+QLD,Brisbane,Ascot,TWO,60
+QLD,Brisbane,Hamilton,ONE,50
+QLD,Ryan,Kenmore,ONE,40
+NSW,Sydney,Ascot,ONE,1000
+";
+        let votes = read_senate_votes(senate.as_bytes(), StateAb::QLD).unwrap();
+        let key = |d: &str, b: &str| (d.to_owned(), b.to_owned());
+        assert_eq!(
+            votes,
+            BTreeMap::from([
+                (key("Brisbane", "Ascot"), 90.0),
+                (key("Brisbane", "Hamilton"), 50.0),
+                (key("Ryan", "Kenmore"), 40.0),
+            ])
+        );
+
+        let breakdown = BTreeMap::from([
+            (key("Brisbane", "Ascot"), 100.0),
+            (key("Brisbane", "Hamilton"), 0.0),
+            (key("Ryan", "Indooroopilly"), 20.0),
+        ]);
+        let ratios = senate_ratios(&votes, &breakdown);
+        assert_eq!(ratios, BTreeMap::from([(key("Brisbane", "Ascot"), 0.9)]));
+
+        let no_header = "Division,Booth\nBrisbane,Ascot\n";
+        assert!(read_senate_votes(no_header.as_bytes(), StateAb::QLD).is_err());
+    }
+
+    #[test]
+    fn turnout_scaling() {
+        let dir = std::env::temp_dir().join(format!("nparty-turnout-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let npp_booths = dir.join("NPP_Booths.csv");
+        let breakdown = dir.join("SA1s.csv");
+        let sa1_prefs = dir.join("SA1_Prefs.csv");
+        std::fs::write(
+            &npp_booths,
+            "\
+ID,Division,Booth,Latitude,Longitude,Exhausted,Alp,Lnp,AlpLnp,LnpAlp,Total
+1,Brisbane,Ascot,0,0,10,20,30,20,10,90
+2,Brisbane,Hamilton,0,0,0,25,25,0,0,50
+",
+        )
+        .unwrap();
+        // 100 and 40 Reps votes at the booths, for 90 and 50 Senate votes
+        std::fs::write(
+            &breakdown,
+            "\
+year,state_ab,div_nm,SA1_id,pp_id,pp_nm,votes
+2019,QLD,Brisbane,301,1,Ascot,60
+2019,QLD,Brisbane,302,1,Ascot,40
+2019,QLD,Brisbane,302,2,Hamilton,40
+2019,QLD,Brisbane,303,3,Clayfield,7
+",
+        )
+        .unwrap();
+        let mut parties = Parties::new();
+        parties.insert("Alp".into(), vec!["A:Alp".into()]);
+        parties.insert("Lnp".into(), vec!["B:Lnp".into()]);
+
+        let projected = |turnout_scale| {
+            let options = ProjectionOptions {
+                exhausted_label: "Exhausted",
+                coverage: false,
+                min_column_votes: None,
+                senate_votes: None,
+                turnout_scale,
+                report_unmatched: None,
+                encoding: encoding_rs::UTF_8,
+                rounding: Rounding::None,
+            };
+            project(
+                &parties,
+                StateAb::QLD,
+                "2019",
+                &npp_booths,
+                &breakdown,
+                &sa1_prefs,
+                options,
+            )
+            .unwrap();
+            sum_total_column(&sa1_prefs, encoding_rs::UTF_8).unwrap()
+        };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        // Reps votes
+        assert!(close(projected(TurnoutScale::None), 140.0));
+        assert!(close(projected(TurnoutScale::Factor(0.5)), 70.0));
+        // the distribution's booth totals
+        assert!(close(projected(TurnoutScale::Booths), 140.0));
+        let mut rdr = csv::Reader::from_path(&sa1_prefs).unwrap();
+        let totals: Vec<f64> = rdr
+            .records()
+            .map(|r| r.unwrap().iter().next_back().unwrap().parse().unwrap())
+            .collect();
+        // 60/100 of Ascot's 90; then 40/100 of Ascot's 90 and all of Hamilton's 50
+        assert!(close(totals[0], 54.0));
+        assert!(close(totals[1], 86.0));
+
+        assert_eq!(parse_turnout_scale("booths"), Ok(TurnoutScale::Booths));
+        assert_eq!(parse_turnout_scale("0.95"), Ok(TurnoutScale::Factor(0.95)));
+        assert!(parse_turnout_scale("-1").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unmatched_report() {
+        let dir = std::env::temp_dir().join(format!("nparty-unmatched-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let npp_booths = dir.join("NPP_Booths.csv");
+        let breakdown = dir.join("SA1s.csv");
+        let sa1_prefs = dir.join("SA1_Prefs.csv");
+        let unmatched = dir.join("Unmatched.csv");
+        std::fs::write(
+            &npp_booths,
+            "\
+ID,Division,Booth,Latitude,Longitude,Exhausted,Alp,Lnp,AlpLnp,LnpAlp,Total
+1,Brisbane,Ascot,0,0,10,20,30,20,10,90
+",
+        )
+        .unwrap();
+        std::fs::write(
+            &breakdown,
+            "\
+year,state_ab,div_nm,SA1_id,pp_id,pp_nm,votes
+2019,QLD,Brisbane,301,1,Ascot,60
+2019,QLD,Brisbane,303,3,Clayfield,7
+",
+        )
+        .unwrap();
+        let mut parties = Parties::new();
+        parties.insert("Alp".into(), vec!["A:Alp".into()]);
+        parties.insert("Lnp".into(), vec!["B:Lnp".into()]);
+
+        project(
+            &parties,
+            StateAb::QLD,
+            "2019",
+            &npp_booths,
+            &breakdown,
+            &sa1_prefs,
+            ProjectionOptions {
+                exhausted_label: "Exhausted",
+                coverage: false,
+                min_column_votes: None,
+                senate_votes: None,
+                turnout_scale: TurnoutScale::None,
+                report_unmatched: Some(&unmatched),
+                encoding: encoding_rs::UTF_8,
+                rounding: Rounding::None,
+            },
+        )
+        .unwrap();
+        // Clayfield had no distributed votes
+        assert_eq!(
+            std::fs::read_to_string(&unmatched).unwrap(),
+            "SA1_id,Division,Booth,Votes\n303,Brisbane,Clayfield,7\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
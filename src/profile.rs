@@ -0,0 +1,62 @@
+//! Built-in sampling profiler for `nparty run --profile`, so that a release build can be
+//! profiled without `cargo flamegraph` (see `optimisation.md`).
+//!
+//! Only available with the `profile` feature, and only on platforms `pprof` supports.
+
+use color_eyre::eyre::{eyre, Context, Result};
+use pprof::protos::Message;
+use pprof::{ProfilerGuard, ProfilerGuardBuilder};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Samples per second
+const FREQUENCY: i32 = 997;
+
+/// A running profiler, which writes its report on [`Profiler::finish`].
+pub struct Profiler {
+    guard: ProfilerGuard<'static>,
+    path: PathBuf,
+}
+
+impl Profiler {
+    /// Start sampling. The report will go to `path`: a flamegraph SVG, unless `path` ends in
+    /// `.pb`, in which case it's a protobuf for `pprof` and friends.
+    pub fn start(path: &Path) -> Result<Self> {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| eyre!("Could not start the profiler: {e}"))?;
+        Ok(Self {
+            guard,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Stop sampling and write the report.
+    pub fn finish(self) -> Result<()> {
+        let report = self
+            .guard
+            .report()
+            .build()
+            .map_err(|e| eyre!("Could not build the profile: {e}"))?;
+        let mut file = File::create(&self.path)
+            .with_context(|| format!("Could not create {}", self.path.display()))?;
+        if self.path.extension().is_some_and(|x| x == "pb") {
+            let profile = report
+                .pprof()
+                .map_err(|e| eyre!("Could not build the profile: {e}"))?;
+            let content = profile.write_to_bytes()?;
+            file.write_all(&content)
+                .with_context(|| format!("Could not write {}", self.path.display()))?;
+        } else {
+            report
+                .flamegraph(file)
+                .map_err(|e| eyre!("Could not write {}: {e}", self.path.display()))?;
+        }
+        info!("Wrote profile to {}", self.path.display());
+        Ok(())
+    }
+}
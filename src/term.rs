@@ -2,8 +2,70 @@
 // See also
 // https://en.wikipedia.org/wiki/ANSI_escape_code#Escape_sequences
 // Just need to use `\u{1b}` rather than `\033` for the ESC
+use clap::ArgEnum;
+use color_eyre::eyre::Result;
+use std::io::{IsTerminal, Write};
 use std::ops::Range;
 use std::string::String;
+use std::sync::atomic::{AtomicU8, Ordering};
+use tabwriter::TabWriter;
+
+/// When to use ANSI colours and other escape codes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ArgEnum)]
+#[repr(u8)]
+pub enum ColorChoice {
+    /// Only when writing to a terminal, and `NO_COLOR` is not set
+    Auto,
+    /// Even when writing to a file or pipe, and even if `NO_COLOR` is set
+    Always,
+    /// Never
+    Never,
+}
+
+/// The global [`ColorChoice`], as set by [`set_color_choice`].
+static COLOR_CHOICE: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+/// Set whether to use escape codes everywhere. Call this before any output.
+pub fn set_color_choice(choice: ColorChoice) {
+    COLOR_CHOICE.store(choice as u8, Ordering::Relaxed);
+}
+
+/// Should we write escape codes to `stream`?
+///
+/// See <https://no-color.org> for `NO_COLOR`.
+pub fn use_color(stream: &impl IsTerminal) -> bool {
+    match COLOR_CHOICE.load(Ordering::Relaxed) {
+        x if x == ColorChoice::Always as u8 => true,
+        x if x == ColorChoice::Never as u8 => false,
+        _ => stream.is_terminal() && std::env::var_os("NO_COLOR").map_or(true, |x| x.is_empty()),
+    }
+}
+
+/// Print a table of tab-separated `rows` (and a `header`) to standard output.
+///
+/// If that's a terminal, it's pretty-printed with elastic tabstops and a bold header.
+/// Otherwise it's left tab-separated, to make further processing as straightforward as possible.
+pub fn print_table(header: &str, rows: &[String]) -> Result<()> {
+    if use_color(&std::io::stdout()) {
+        let mut tw = TabWriter::new(vec![]);
+        writeln!(&mut tw, "{header}")?;
+        for i in rows {
+            writeln!(&mut tw, "{i}")?;
+        }
+        tw.flush()?;
+        let output = String::from_utf8(tw.into_inner()?)?;
+        let firstnewline = output.find('\n').unwrap();
+        let head = &output[0..firstnewline];
+        let body = &output[firstnewline..output.len()];
+        println!("{BOLD}{head}{END}{body}");
+    } else {
+        println!("{header}");
+        for i in rows {
+            println!("{i}");
+        }
+    }
+    Ok(())
+}
 
 /// Cease all formatting
 pub const END: &str = "\u{1b}[0m";
@@ -6,8 +6,10 @@
 use color_eyre::eyre::{bail, Context, ContextCompat, Result};
 
 use crate::app::{CliUpgradeBooths, CliUpgradeSa1s};
+use crate::booths::PREFS_FIELD_NAMES;
 use crate::utils::{
-    get_zip_writer_to_path, open_csvz_from_path, read_candidates, CandsData, StateAb, ToTicket,
+    fix_prefs_headers, get_zip_writer_to_path, open_csvz_from_path, read_candidates, ticket_count,
+    CandsData, StateAb, ToTicket,
 };
 use std::collections::{BTreeMap, HashMap};
 use std::fs::{metadata, File};
@@ -47,13 +49,14 @@ where
     out
 }
 
-/// Upgrade a preferences file from 2016 to 2019 format
+/// Upgrade a preferences file from 2016 to 2019 format.
+/// Returns the number of ballot papers upgraded.
 pub fn upgrade_prefs_16_19(
     infile: &mut dyn Read,
     outfile: &mut dyn Write,
     candsdata: &CandsData,
     divstates: &HashMap<DivisionName, StateAb>,
-) {
+) -> usize {
     #[derive(Debug, Deserialize)]
     #[allow(non_snake_case)]
     struct OldRow {
@@ -104,7 +107,8 @@ pub fn upgrade_prefs_16_19(
             // and figure out who our candidates are
             // we have a CandsData, and thence a ...
             let ballot_paper = &candsdata[&state];
-            for tnum in 1..ballot_paper.len() {
+            // (there might not be any grouped tickets at all)
+            for tnum in 1..=ticket_count(ballot_paper) {
                 let tnum = tnum as u32;
                 let tstring = tnum.to_ticket();
                 let ticket = &ballot_paper[&tstring];
@@ -118,9 +122,8 @@ pub fn upgrade_prefs_16_19(
                 }
             }
 
-            {
+            if let Some(ticket) = ballot_paper.get("UG") {
                 // handle UGs
-                let ticket = &ballot_paper["UG"];
                 for cand_num in 1..=ticket.len() {
                     let cand_num = cand_num as u32;
                     belows.push(format!(
@@ -153,23 +156,64 @@ pub fn upgrade_prefs_16_19(
         progress += 1;
 
         if progress % 100_000 == 0 {
-            eprintln!("{}Upgrade progress... {}", crate::term::TTYJUMP, progress);
+            let jump = if crate::term::use_color(&std::io::stderr()) {
+                crate::term::TTYJUMP
+            } else {
+                ""
+            };
+            eprintln!("{jump}Upgrade progress... {progress}");
         }
     }
+    progress
+}
+
+/// Upgrade a 2022-era preferences file, which is in the 2019 format except that some candidates
+/// in the header weren't quoted, by quoting them properly (see [`fix_prefs_headers`]).
+/// The ballots are copied as-is.
+/// Returns the number of ballot papers copied.
+pub fn upgrade_prefs_19_22(infile: &mut dyn Read, outfile: &mut dyn Write) -> Result<usize> {
+    let mut inrdr = csv::ReaderBuilder::new().flexible(true).from_reader(infile);
+    let mut outwtr = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_writer(outfile);
+
+    let header = fix_prefs_headers(inrdr.headers()?, PREFS_FIELD_NAMES.len());
+    outwtr
+        .write_record(&header)
+        .context("Error writing output file")?;
+
+    let mut progress: usize = 0;
+    let mut record = csv::ByteRecord::new();
+    while inrdr.read_byte_record(&mut record)? {
+        outwtr
+            .write_byte_record(&record)
+            .context("Error writing output file")?;
+        progress += 1;
+    }
+    outwtr.flush()?;
+    Ok(progress)
 }
 
 /// Sniff the era of a CSV stream
 /// It's a stream, so be sure it's the start
+///
+/// A 2019-style header which needs [`fix_prefs_headers`] (because some candidates weren't
+/// quoted, as in 2022) is 2022.
 pub fn era_sniff(infile: &mut dyn Read) -> color_eyre::eyre::Result<usize> {
     let mut inrdr = csv::Reader::from_reader(infile);
-    let hdr: Vec<&str> = inrdr.headers()?.into_iter().collect();
+    let raw = inrdr.headers()?;
+    let hdr: Vec<&str> = raw.into_iter().collect();
 
     let rez = match hdr.get(0..6).context("Invalid headers.")? {
         ["ElectorateNm", "VoteCollectionPointNm", "VoteCollectionPointId", "BatchNo", "PaperNo", "Preferences"] => {
             2016
         }
         ["State", "Division", "Vote Collection Point Name", "Vote Collection Point ID", "Batch No", "Paper No"] => {
-            2019
+            if fix_prefs_headers(raw, PREFS_FIELD_NAMES.len()) == hdr {
+                2019
+            } else {
+                2022
+            }
         }
         _ => bail!("Invalid headers."),
     };
@@ -223,7 +267,11 @@ pub fn do_upgrade_sa1s(args: CliUpgradeSa1s) -> color_eyre::eyre::Result<()> {
     // Previously, we deserialised by position, not by header name
     //
 
+    let mut recordcount = 0;
+    let mut unmatchcount = 0;
+
     for record in oldf.records() {
+        recordcount += 1;
         let r = record?;
         // positional deserialisation because we may only have 2 columns
         let row = Sa1sDist {
@@ -244,9 +292,25 @@ pub fn do_upgrade_sa1s(args: CliUpgradeSa1s) -> color_eyre::eyre::Result<()> {
                 *e += row.Pop * ratio;
                 // we'll have to fill in PopShare later
             }
+        } else {
+            unmatchcount += 1;
         }
     }
 
+    if args.validate_only {
+        let rowcount: usize = converted.values().map(BTreeMap::len).sum();
+        eprintln!(
+            "{} correspondence entries; {recordcount} input rows, of which {unmatchcount} could not be matched.",
+            corrs.len()
+        );
+        eprintln!(
+            "Would write {rowcount} rows ({} new SA1s) to {}",
+            converted.len(),
+            args.output.display()
+        );
+        return Ok(());
+    }
+
     // 3. Finalise and write results
     let mut outf = csv::WriterBuilder::new()
         .has_headers(true)
@@ -333,7 +397,32 @@ pub fn do_upgrade_prefs(args: crate::app::CliUpgradePrefs) -> color_eyre::eyre::
         let era = era_sniff(&mut open_csvz_from_path(ipath)?)
             .context("Error determining era of input.")?;
 
-        if era == 2016 {
+        if args.validate_only {
+            eprintln!("{}: {era} format", ipath.display());
+            // run the upgrade for real, just without keeping the output
+            let count = match era {
+                2016 => Some(upgrade_prefs_16_19(
+                    &mut open_csvz_from_path(ipath)?,
+                    &mut std::io::sink(),
+                    &candsdata,
+                    &divstates,
+                )),
+                2022 => Some(upgrade_prefs_19_22(
+                    &mut open_csvz_from_path(ipath)?,
+                    &mut std::io::sink(),
+                )?),
+                _ => None,
+            };
+            match count {
+                Some(count) => {
+                    eprintln!("Would write {count} ballot papers to {}", opath.display());
+                }
+                None => eprintln!("No upgrade available - is it already the latest?"),
+            }
+            continue;
+        }
+
+        if era == 2016 || era == 2022 {
             // Test if upgrade already exists
             let im = metadata(ipath).context("In-path doesn't seem to exist?")?;
             let om = metadata(opath);
@@ -347,12 +436,19 @@ pub fn do_upgrade_prefs(args: crate::app::CliUpgradePrefs) -> color_eyre::eyre::
                 continue;
             }
             eprintln!("Upgrading...");
-            upgrade_prefs_16_19(
-                &mut open_csvz_from_path(ipath)?,
-                &mut get_zip_writer_to_path(opath, "csv")?,
-                &candsdata,
-                &divstates,
-            );
+            if era == 2016 {
+                upgrade_prefs_16_19(
+                    &mut open_csvz_from_path(ipath)?,
+                    &mut get_zip_writer_to_path(opath, "csv")?,
+                    &candsdata,
+                    &divstates,
+                );
+            } else {
+                upgrade_prefs_19_22(
+                    &mut open_csvz_from_path(ipath)?,
+                    &mut get_zip_writer_to_path(opath, "csv")?,
+                )?;
+            }
         } else {
             eprintln!("No upgrade available - is it already the latest?");
         }
@@ -510,13 +606,12 @@ pub fn do_upgrade_booths(args: CliUpgradeBooths) -> color_eyre::eyre::Result<()>
 
     // Previously, we deserialised by position, not by header name
 
-    // let mut recordcount = 0;
+    let mut recordcount = 0;
     // let mut desercount = 0;
-    let mut unmatchcount = 0;
-    let mut unmatchvote = 0_f64;
+    let mut unmatched: Vec<(String, f64)> = Vec::new();
 
     for record in oldf.records() {
-        // recordcount += 1;
+        recordcount += 1;
         let r = record?;
         let row: Sa1sBooth = r.deserialize(None)?;
 
@@ -555,14 +650,46 @@ pub fn do_upgrade_booths(args: CliUpgradeBooths) -> color_eyre::eyre::Result<()>
             }
         } else {
             // eprintln!("Could not find a match for:\n{row:?}");
-            unmatchcount += 1;
-            unmatchvote += row.votes;
+            unmatched.push((row.ccd_id, row.votes));
         }
     }
 
-    // eprintln!("{recordcount} old entries attempted conversion");
+    if args.validate_only {
+        eprintln!("{recordcount} old entries attempted conversion");
+    }
     // eprintln!("{desercount} old entries deserialized successfully");
+    let unmatchcount = unmatched.len();
+    let unmatchvote: f64 = unmatched.iter().map(|(_, v)| v).sum();
     eprintln!("{unmatchcount} old entries could not be matched, totalling {unmatchvote} votes. These are probably voters with no fixed address or similar, especially if the ratio of entries to voters is close to 1.");
+    if args.validate_only {
+        let rowcount: usize = converted.values().map(BTreeMap::len).sum();
+        eprintln!(
+            "Would write {rowcount} rows ({} new SA1s) to {}",
+            converted.len(),
+            args.output.display()
+        );
+        if let Some(path) = args.unmatched_out {
+            eprintln!(
+                "Would write {unmatchcount} unmatched entries to {}",
+                path.display()
+            );
+        }
+        return Ok(());
+    }
+    if let Some(path) = args.unmatched_out {
+        let mut unmf = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_path(&path)
+            .with_context(|| {
+                format!("Could not create unmatched-entries file {}", path.display())
+            })?;
+        unmf.write_record(["SA1_id", "votes"])?;
+        for (sa1, votes) in &unmatched {
+            unmf.write_record([sa1.as_str(), &votes.to_string()])?;
+        }
+        unmf.flush()?;
+        eprintln!("Wrote unmatched entries to {}", path.display());
+    }
     // eprintln!("{} entries in converted tree", converted.len());
 
     // 3. Finalise and write results
@@ -584,3 +711,100 @@ pub fn do_upgrade_booths(args: CliUpgradeBooths) -> color_eyre::eyre::Result<()>
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::booths::{BallotReader, Parties, EXHAUSTED};
+
+    /// A 2022 header with unquoted commas in some candidates' names.
+    #[test]
+    fn unquoted_header_upgrade() {
+        let old = "\
+State,Division,Vote Collection Point Name,Vote Collection Point ID,Batch No,Paper No,A:Shooters, Fishers and Farmers,B:Greens,A:SMITH John,B:JONES, Amy
+QLD,Alpha,Town Hall,1,1,1,1,2,,
+QLD,Alpha,Town Hall,1,1,2,,,2,1
+";
+        assert_eq!(era_sniff(&mut old.as_bytes()).unwrap(), 2022);
+
+        let mut upgraded = Vec::new();
+        let count = upgrade_prefs_19_22(&mut old.as_bytes(), &mut upgraded).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(era_sniff(&mut upgraded.as_slice()).unwrap(), 2019);
+
+        let mut rdr = csv::Reader::from_reader(upgraded.as_slice());
+        let candidates: Vec<&str> = rdr.headers().unwrap().iter().skip(6).collect();
+        assert_eq!(
+            candidates,
+            [
+                "A:Shooters, Fishers and Farmers",
+                "B:Greens",
+                "A:SMITH John",
+                "B:JONES, Amy"
+            ]
+        );
+        let rows: Vec<csv::StringRecord> = rdr.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[1].iter().skip(6).collect::<Vec<_>>(),
+            ["", "", "2", "1"]
+        );
+    }
+
+    /// A ballot paper with no tickets at all, only ungrouped candidates.
+    #[test]
+    fn ungrouped_only_paper() {
+        let cands = "\
+nom_ty,state_ab,div_nm,ticket,ballot_position,surname,ballot_given_nm,party_ballot_nm
+S,TAS,,UG,1,ONE,Ann,Independent
+S,TAS,,UG,2,TWO,Bob,Independent
+S,TAS,,UG,3,THREE,Cat,
+S,TAS,,UG,4,FOUR,Dan,
+S,TAS,,UG,5,FIVE,Eve,Independent
+S,TAS,,UG,6,SIX,Fay,
+";
+        let candsdata = read_candidates(cands.as_bytes()).unwrap();
+        let numbers: BTreeMap<_, _> = candsdata[&StateAb::TAS]["UG"]
+            .iter()
+            .map(|(pos, c)| (*pos, c.ballot_number))
+            .collect();
+        assert_eq!(numbers, (1..=6).map(|n| (n, n)).collect());
+
+        let old = "\
+ElectorateNm,VoteCollectionPointNm,VoteCollectionPointId,BatchNo,PaperNo,Preferences
+------------,-------,-------,-------,-------,-------
+Denison,Town Hall,1,1,1,\"1,2,3,4,5,6\"
+Denison,Town Hall,1,1,2,\"6,5,4,3,2,1\"
+Denison,Town Hall,1,1,3,\"1,,,,,\"
+";
+        let divstates = HashMap::from([("Denison".to_string(), StateAb::TAS)]);
+        let mut upgraded = Vec::new();
+        upgrade_prefs_16_19(&mut old.as_bytes(), &mut upgraded, &candsdata, &divstates);
+        let mut rdr = csv::Reader::from_reader(upgraded.as_slice());
+        let headers: Vec<&str> = rdr.headers().unwrap().iter().skip(6).collect();
+        assert_eq!(
+            headers,
+            [
+                "UG:ONE Ann",
+                "UG:TWO Bob",
+                "UG:THREE Cat",
+                "UG:FOUR Dan",
+                "UG:FIVE Eve",
+                "UG:SIX Fay"
+            ]
+        );
+
+        let mut parties = Parties::new();
+        parties.insert("First".into(), vec!["UG:ONE Ann".into()]);
+        parties.insert("Last".into(), vec!["UG:SIX Fay".into()]);
+        let mut ballots = BallotReader::from_source(&parties, rdr, true).unwrap();
+        let combos = ballots.combos().clone();
+        let mut results = Vec::new();
+        while let Some((_, idx)) = ballots.next_ballot().unwrap() {
+            results.push(combos[idx].as_str());
+        }
+        // with no tickets, a ballot is either formal BTL or exhausted
+        assert_eq!(results, ["FirstLast", "LastFirst", EXHAUSTED]);
+        assert_eq!(ballots.btl_count(), 2);
+    }
+}
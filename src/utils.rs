@@ -1,8 +1,10 @@
 //! Assorted utility structs and functions.
 
 use super::term;
-use color_eyre::eyre::{Context, ContextCompat, Result};
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat, Result};
 use csv::StringRecord;
+use ehttp::streaming::Part;
+use encoding_rs::{CoderResult, Decoder, Encoder, EncoderResult, Encoding, UTF_8};
 use inflector::cases::titlecase::to_title_case;
 use ouroboros::self_referencing;
 use std::char;
@@ -10,8 +12,13 @@ use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::io::{stdin, stdout, Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
 use std::path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
 use zip::{read::ZipFile, result::ZipResult, ZipArchive, ZipWriter};
 use SeekFrom::Start;
 
@@ -27,10 +34,65 @@ pub type TicketString = String; // is this really needed? eh
 
 /// A map with each entry representing a row of results.
 ///
-/// * the keys are typically either a `{division}_{booth}` portmanteau or an SA1 ID.
-/// * the values are a sequence of preference results in the same order that [`crate::booths::group_combos`] would give.
+/// * the keys are typically either an SA1 ID or a district name.
+/// * the values are a sequence of preference results in the same order that [`crate::booths::group_combos`] would give,
+///   usually with the total last.
+///
+/// See [`PrefsRows`] for the common operations.
 pub type PrefsMap = std::collections::BTreeMap<String, Vec<f64>>;
 
+/// Operations on the rows of a [`PrefsMap`], shared by the projection and combination phases.
+pub trait PrefsRows: Sized {
+    /// Read a CSV of an ID column followed by numeric columns, returning the rows
+    /// keyed by ID and the header. Blank or unparseable numbers are zero.
+    fn from_csv_reader<R: Read>(rdr: csv::Reader<R>) -> Result<(Self, StringRecord)>;
+    /// The last value (i.e. the total) of the row for `key`, if there is one.
+    fn row_total(&self, key: &str) -> Option<f64>;
+    /// Add `values` to the row for `key`, element by element, extending it as needed.
+    /// Returns the sum of the `values`.
+    fn add_row(&mut self, key: &str, values: impl IntoIterator<Item = f64>) -> f64;
+    /// Add `row`, multiplied by `factor`, to the row for `key`; see [`PrefsRows::add_row`].
+    fn add_scaled(&mut self, key: &str, row: &[f64], factor: f64) -> f64 {
+        self.add_row(key, row.iter().map(|x| x * factor))
+    }
+}
+
+impl PrefsRows for PrefsMap {
+    fn from_csv_reader<R: Read>(mut rdr: csv::Reader<R>) -> Result<(Self, StringRecord)> {
+        let mut out = Self::new();
+        for record in rdr.records() {
+            let row = record?;
+            let id = row.get(0).context("empty row")?;
+            let numbers = row
+                .iter()
+                .skip(1)
+                .map(|x| x.parse::<f64>().unwrap_or(0.0_f64))
+                .collect();
+            out.insert(id.to_string(), numbers);
+        }
+        let headers = rdr.headers()?.clone();
+        Ok((out, headers))
+    }
+
+    fn row_total(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(|row| row.last()).copied()
+    }
+
+    fn add_row(&mut self, key: &str, values: impl IntoIterator<Item = f64>) -> f64 {
+        let row = self.entry(key.to_owned()).or_default();
+        let mut sum = 0.0;
+        for (i, x) in values.into_iter().enumerate() {
+            if i < row.len() {
+                row[i] += x;
+            } else {
+                row.push(x);
+            }
+            sum += x;
+        }
+        sum
+    }
+}
+
 pub trait ToTicket {
     fn to_ticket(self) -> TicketString;
 }
@@ -126,6 +188,7 @@ impl PrettifyNumber for BallotNumber {
 // we might need several things
 
 #[derive(PartialEq, Eq, Hash, Debug, Serialize, Deserialize, Copy, Clone)]
+#[serde(try_from = "String")]
 #[allow(clippy::use_self)] // False positive: There's a bug here related to derives
 #[allow(clippy::upper_case_acronyms)] // It's usual for these to be capitalised and there aren't contiguity issues
 pub enum StateAb {
@@ -140,38 +203,43 @@ pub enum StateAb {
 }
 
 pub trait ToStateAb {
-    fn to_state_ab(self) -> StateAb;
+    fn to_state_ab(self) -> Result<StateAb>;
 }
 
 impl ToStateAb for &str {
-    fn to_state_ab(self) -> StateAb {
-        match StateAb::from_str(self) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        }
+    fn to_state_ab(self) -> Result<StateAb> {
+        StateAb::from_str(self).map_err(|e| eyre!("{:?}: {}", self, e))
     }
 }
 
 impl FromStr for StateAb {
     type Err = &'static str;
+    /// Accepts abbreviations or full names, in any case and with any dots or spaces:
+    /// `"ACT"`, `"A.C.T."` and `"Australian Capital Territory"` are all [`StateAb::ACT`].
     fn from_str(item: &str) -> std::result::Result<Self, Self::Err> {
-        match item.to_uppercase().as_str() {
-            "ACT" => Ok(Self::ACT),
-            "NSW" => Ok(Self::NSW),
-            "NT" => Ok(Self::NT),
-            "QLD" => Ok(Self::QLD),
-            "SA" => Ok(Self::SA),
-            "TAS" => Ok(Self::TAS),
-            "VIC" => Ok(Self::VIC),
-            "WA" => Ok(Self::WA),
-            _ => Err("Jurisdiction does not exist"),
+        let normalised: String = item
+            .chars()
+            .filter(|c| *c != '.' && !c.is_whitespace())
+            .collect::<String>()
+            .to_uppercase();
+        match normalised.as_str() {
+            "ACT" | "AUSTRALIANCAPITALTERRITORY" => Ok(Self::ACT),
+            "NSW" | "NEWSOUTHWALES" => Ok(Self::NSW),
+            "NT" | "NORTHERNTERRITORY" => Ok(Self::NT),
+            "QLD" | "QUEENSLAND" => Ok(Self::QLD),
+            "SA" | "SOUTHAUSTRALIA" => Ok(Self::SA),
+            "TAS" | "TASMANIA" => Ok(Self::TAS),
+            "VIC" | "VICTORIA" => Ok(Self::VIC),
+            "WA" | "WESTERNAUSTRALIA" => Ok(Self::WA),
+            _ => Err("not a state or territory; expected one of ACT, NSW, NT, QLD, SA, TAS, VIC or WA (or the full name)"),
         }
     }
 }
 
-impl std::convert::From<&str> for StateAb {
-    fn from(item: &str) -> Self {
-        item.to_state_ab()
+impl TryFrom<String> for StateAb {
+    type Error = &'static str;
+    fn try_from(item: String) -> std::result::Result<Self, Self::Error> {
+        item.parse()
     }
 }
 
@@ -226,6 +294,12 @@ pub type Ticket = HashMap<BallotPosition, Candidate>;
 pub type BallotPaper = HashMap<TicketString, Ticket>;
 pub type CandsData = HashMap<StateAb, BallotPaper>;
 
+/// The number of grouped tickets (A, B, ...) on a ballot paper.
+/// There may or may not also be ungrouped (UG) candidates; there may even be _only_ those.
+pub fn ticket_count(ballot_paper: &BallotPaper) -> usize {
+    ballot_paper.keys().filter(|t| *t != "UG").count()
+}
+
 // ^^ all this formalises the structure of the nested dicts
 
 /// This represents a row in the candidates csv file.
@@ -241,10 +315,89 @@ struct CandidateRecord {
     pub party_ballot_nm: String,
 }
 
+/// A row in a 2013-and-earlier candidates file (the AEC Tally Room's "Senate candidates" download).
+///
+/// These are Senate-only, so there's no nomination type.
+#[derive(Debug, Deserialize)]
+struct TallyRoomCandidateRecord {
+    #[serde(rename = "StateAb")]
+    pub state_ab: StateAb,
+    #[serde(rename = "Ticket")]
+    pub ticket: TicketString,
+    #[serde(rename = "BallotPosition")]
+    pub ballot_position: BallotPosition,
+    #[serde(rename = "Surname")]
+    pub surname: String,
+    #[serde(rename = "GivenNm")]
+    pub ballot_given_nm: String,
+    #[serde(rename = "PartyNm")]
+    pub party_ballot_nm: String,
+}
+
+impl From<TallyRoomCandidateRecord> for CandidateRecord {
+    fn from(r: TallyRoomCandidateRecord) -> Self {
+        Self {
+            nom_ty: NominationType::S,
+            state_ab: r.state_ab,
+            ticket: r.ticket,
+            ballot_position: r.ballot_position,
+            surname: r.surname,
+            ballot_given_nm: r.ballot_given_nm,
+            party_ballot_nm: r.party_ballot_nm,
+        }
+    }
+}
+
+/// The columns we need from a 2016+ candidates file.
+const CANDIDATE_COLUMNS: [&str; 7] = [
+    "nom_ty",
+    "state_ab",
+    "ticket",
+    "ballot_position",
+    "surname",
+    "ballot_given_nm",
+    "party_ballot_nm",
+];
+
+/// The columns we need from a 2013-and-earlier candidates file.
+const TALLY_ROOM_CANDIDATE_COLUMNS: [&str; 6] = [
+    "StateAb",
+    "Ticket",
+    "BallotPosition",
+    "Surname",
+    "GivenNm",
+    "PartyNm",
+];
+
+/// Sniff the era of a candidates file from its header
+/// (compare [`crate::upgrades::era_sniff`] for preferences).
+///
+/// Returns 2016 for the current format and 2013 for the older one.
+fn candidates_era_sniff(header: &StringRecord) -> Result<usize> {
+    let has_all = |cols: &[&str]| cols.iter().all(|c| header.iter().any(|h| h == *c));
+    if has_all(&CANDIDATE_COLUMNS) {
+        Ok(2016)
+    } else if has_all(&TALLY_ROOM_CANDIDATE_COLUMNS) {
+        Ok(2013)
+    } else {
+        bail!(
+            "Unrecognised candidates file. Expected columns {} (2016 onward) or {} (2013 and earlier).",
+            CANDIDATE_COLUMNS.join(", "),
+            TALLY_ROOM_CANDIDATE_COLUMNS.join(", ")
+        )
+    }
+}
+
 /// If a 2022 header is missing quotes around some values they'll be incorrectly split.
 /// This unsplits them in a semi-intelligent fashion.
+///
+/// Empty fields at the end (from a trailing comma) are dropped, rather than being joined onto the last candidate.
 pub fn fix_prefs_headers(prefs_headers_raw: &StringRecord, atl_start: usize) -> Vec<String> {
     let mut prefs_headers_fixed: Vec<String> = Vec::with_capacity(prefs_headers_raw.len());
+    let mut used_len = prefs_headers_raw.len();
+    while used_len > atl_start && prefs_headers_raw[used_len - 1].is_empty() {
+        used_len -= 1;
+    }
 
     // opening six are fine...
     for s in prefs_headers_raw.iter().take(atl_start) {
@@ -255,7 +408,7 @@ pub fn fix_prefs_headers(prefs_headers_raw: &StringRecord, atl_start: usize) ->
     // if they don't, then we assume their entry has been broken by lack of quoting
     // and join it back up to the previous entry
     let mut idx: BallotNumber = 0;
-    for s in prefs_headers_raw.iter().skip(atl_start) {
+    for s in prefs_headers_raw.iter().take(used_len).skip(atl_start) {
         if s.starts_with("A:") {
             // set/reset
             idx = 1;
@@ -293,7 +446,17 @@ where
 
     let mut rdr = csv::Reader::from_reader(candsfile);
 
-    for row in rdr.deserialize() {
+    let records: Box<dyn Iterator<Item = csv::Result<CandidateRecord>>> =
+        if candidates_era_sniff(rdr.headers()?)? == 2013 {
+            Box::new(
+                rdr.deserialize::<TallyRoomCandidateRecord>()
+                    .map(|r| r.map(CandidateRecord::from)),
+            )
+        } else {
+            Box::new(rdr.deserialize())
+        };
+
+    for row in records {
         let cand_record: CandidateRecord =
             row.context("Could not understand a row in the candidates file")?;
 
@@ -353,10 +516,10 @@ where
     // Now iterate by state to fill in the `ballot_number`s
 
     for state in bigdict.values_mut() {
-        let ticket_count = state.len();
-        let mut ballot_number = (ticket_count - 1) as BallotNumber;
+        let ticket_count = ticket_count(state);
+        let mut ballot_number = ticket_count as BallotNumber;
 
-        for tnum in 1..ticket_count {
+        for tnum in 1..=ticket_count {
             let ticket = (tnum as BallotNumber).to_ticket();
             let candidate_count = state.get(&ticket).context("TOCTOU")?.len();
             for cnum in 1..candidate_count {
@@ -370,13 +533,10 @@ where
                     .ballot_number = ballot_number;
             }
         }
-        if state.contains_key("UG") {
-            let candidate_count = state.get("UG").unwrap().len();
-            for cnum in 1..=candidate_count {
+        if let Some(ungrouped) = state.get_mut("UG") {
+            for cnum in 1..=ungrouped.len() {
                 ballot_number += 1;
-                state
-                    .get_mut("UG")
-                    .context("TOCTOU")?
+                ungrouped
                     .get_mut(&(cnum as BallotPosition))
                     .context("TOCTOU")?
                     .ballot_number = ballot_number;
@@ -404,6 +564,9 @@ pub type PartyData = HashMap<String, String>;
 
 /// Reads party abbreviations from the relevant file...
 /// -> {(party name on ballot | party abbreviation) : party abbreviation}
+///
+/// Rows that can't be understood (like the AEC file's title and header rows) are silently
+/// skipped; see [`read_party_abbrvs_checked`] where a wrong file should be an error.
 pub fn read_party_abbrvs<T>(partyfile: T) -> PartyData
 where
     T: Read,
@@ -426,6 +589,66 @@ where
     bigdict
 }
 
+/// As [`read_party_abbrvs`], but rows that can't be understood are reported.
+/// The AEC file's title and header rows don't parse as party rows either, so they're skipped too;
+/// they're only mentioned when debugging, while any other skipped row gets a warning.
+/// If *no* rows could be understood, the file is probably in the wrong format; that's an error.
+pub fn read_party_abbrvs_checked<T>(partyfile: T) -> Result<PartyData>
+where
+    T: Read,
+{
+    let mut bigdict = PartyData::new();
+    let mut valid: usize = 0;
+    let mut skipped: usize = 0;
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(partyfile);
+
+    for (i, row) in rdr.deserialize::<PartyRecord>().enumerate() {
+        match row {
+            Ok(pr) => {
+                valid += 1;
+                if !pr.registered_party_ab.is_empty() {
+                    bigdict.insert(pr.registered_party_ab, to_title_case(&pr.party_ab));
+                }
+                bigdict.insert(pr.party_nm, to_title_case(&pr.party_ab));
+            }
+            Err(e) => {
+                skipped += 1;
+                if i < 2 {
+                    debug!("Skipped row {} of the party details file: {}", i + 1, e);
+                } else {
+                    warn!("Skipped row {} of the party details file: {}", i + 1, e);
+                }
+            }
+        }
+    }
+
+    if valid == 0 {
+        bail!("No valid rows in the party details file ({skipped} rows skipped). Is it the AEC's 'Political Parties' CSV?");
+    }
+    info!("Read {valid} rows from the party details file ({skipped} skipped)");
+
+    Ok(bigdict)
+}
+
+/// Inverts [`read_party_abbrvs`]'s output, for display:
+/// -> {lowercased party abbreviation : party name}
+///
+/// Where several names share an abbreviation, the longest (usually the full name) is kept.
+pub fn party_names(abbrvs: &PartyData) -> HashMap<String, String> {
+    let mut out: HashMap<String, String> = HashMap::new();
+    for (name, ab) in abbrvs {
+        let e = out.entry(ab.to_lowercase()).or_default();
+        if name.len() > e.len() {
+            e.clone_from(name);
+        }
+    }
+    out
+}
+
 // next up is `filter_candidates`
 // So at this point in the thing we have a dilemma. For `read_candidates` we used a
 // Candidate struct that didn't use the ticket, since that was in the tree.
@@ -465,30 +688,32 @@ impl fmt::Display for FilteredCandidate {
 }
 
 impl FilteredCandidate {
+    /// Tab-separated, with the matches underlined (if using colour; see [`term::use_color`]).
     pub fn fmt_tty(&self) -> String {
+        let underline = term::use_color(&stdout());
         let mut surname = self.surname.clone();
         let mut ballot_given_nm = self.ballot_given_nm.clone();
         let mut ballot_number = format!("{:4}", self.ballot_number);
         let mut party = self.party.clone();
         let mut ticket = self.ticket.clone();
 
-        if self.cands_matches[0] {
+        if underline && self.cands_matches[0] {
             let s = self.filter.find(&self.surname).unwrap();
             surname = term::decorate_range(&surname, s.range(), term::UNDERLINE);
         }
-        if self.cands_matches[1] {
+        if underline && self.cands_matches[1] {
             let s = self.filter.find(&self.ballot_given_nm).unwrap();
             ballot_given_nm = term::decorate_range(&ballot_given_nm, s.range(), term::UNDERLINE);
         }
-        if self.cands_matches[2] {
+        if underline && self.cands_matches[2] {
             let s = self.filter.find(&ballot_number).unwrap();
             ballot_number = term::decorate_range(&ballot_number, s.range(), term::UNDERLINE);
         }
-        if self.cands_matches[3] {
+        if underline && self.cands_matches[3] {
             let s = self.filter.find(&self.party).unwrap();
             party = term::decorate_range(&party, s.range(), term::UNDERLINE);
         }
-        if self.cands_matches[4] {
+        if underline && self.cands_matches[4] {
             let s = self.filter.find(&self.ticket).unwrap();
             ticket = term::decorate_range(&ticket, s.range(), term::UNDERLINE);
         }
@@ -658,6 +883,173 @@ pub fn get_zip_writer_to_path(
     Ok(outfile)
 }
 
+/// The encoding for an `--encoding` label, such as `utf-8` or `windows-1252`.
+///
+/// It has to be one we can write, which rules out UTF-16.
+pub fn output_encoding(label: &str) -> std::result::Result<&'static Encoding, String> {
+    let encoding = Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("unknown encoding {label:?} (try e.g. windows-1252)"))?;
+    if encoding.output_encoding() != encoding {
+        return Err(format!(
+            "can't write {} (try e.g. windows-1252)",
+            encoding.name()
+        ));
+    }
+    Ok(encoding)
+}
+
+/// Transcodes the UTF-8 written to it into another encoding, on its way to `inner`.
+///
+/// A character the encoding doesn't have is an error, since any stand-in for it
+/// (such as `?`) would no longer match the name it came from when read back.
+pub struct EncodingWriter<W: Write> {
+    inner: W,
+    encoder: Encoder,
+    /// The start of a character that was split across writes
+    pending: Vec<u8>,
+}
+
+impl<W: Write> EncodingWriter<W> {
+    pub fn new(inner: W, encoding: &'static Encoding) -> Self {
+        Self {
+            inner,
+            encoder: encoding.new_encoder(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let complete = match std::str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            // an incomplete character at the end; the rest of it is yet to come
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        };
+        let mut input = std::str::from_utf8(&self.pending[..complete]).unwrap_or_default();
+        let mut output = Vec::with_capacity(
+            self.encoder
+                .max_buffer_length_from_utf8_if_no_unmappables(input.len())
+                .unwrap_or(input.len()),
+        );
+        loop {
+            let (result, read) =
+                self.encoder
+                    .encode_from_utf8_to_vec_without_replacement(input, &mut output, false);
+            input = &input[read..];
+            match result {
+                EncoderResult::InputEmpty => break,
+                EncoderResult::OutputFull => output.reserve(input.len() + 16),
+                EncoderResult::Unmappable(c) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "{:?} can't be written in {} (try --encoding utf-8)",
+                            c,
+                            self.encoder.encoding().name()
+                        ),
+                    ))
+                }
+            }
+        }
+        self.inner.write_all(&output)?;
+        self.pending.drain(..complete);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Create a file at `outpath` for writing text in `encoding` (see [`output_encoding`]).
+pub fn create_encoded(outpath: &path::Path, encoding: &'static Encoding) -> Result<Box<dyn Write>> {
+    let file =
+        File::create(outpath).with_context(|| format!("Could not create {}", outpath.display()))?;
+    Ok(encode_writer(file, encoding))
+}
+
+/// Wrap `inner` so that UTF-8 written to it comes out in `encoding`.
+pub fn encode_writer<'a>(
+    inner: impl Write + 'a,
+    encoding: &'static Encoding,
+) -> Box<dyn Write + 'a> {
+    if encoding == UTF_8 {
+        Box::new(inner)
+    } else {
+        Box::new(EncodingWriter::new(inner, encoding))
+    }
+}
+
+/// Decodes text in another encoding into UTF-8 as it's read from `inner`.
+pub struct DecodingReader<R: Read> {
+    inner: R,
+    decoder: Decoder,
+    /// Decoded text not yet read, from `start`
+    decoded: String,
+    start: usize,
+    /// Has `inner` run out?
+    done: bool,
+}
+
+impl<R: Read> DecodingReader<R> {
+    pub fn new(inner: R, encoding: &'static Encoding) -> Self {
+        Self {
+            inner,
+            decoder: encoding.new_decoder(),
+            decoded: String::new(),
+            start: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.start == self.decoded.len() && !self.done {
+            let mut raw = [0; 8192];
+            let n = self.inner.read(&mut raw)?;
+            self.done = n == 0;
+            self.decoded.clear();
+            self.start = 0;
+            let mut input = &raw[..n];
+            loop {
+                self.decoded.reserve(
+                    self.decoder
+                        .max_utf8_buffer_length(input.len())
+                        .unwrap_or(input.len() * 3 + 16),
+                );
+                let (result, read, _) =
+                    self.decoder
+                        .decode_to_string(input, &mut self.decoded, self.done);
+                input = &input[read..];
+                if result == CoderResult::InputEmpty {
+                    break;
+                }
+            }
+        }
+        let rest = &self.decoded.as_bytes()[self.start..];
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+/// Open a text file written in `encoding` (as by [`create_encoded`]), decoding it to UTF-8 as it's read.
+pub fn open_encoded(inpath: &path::Path, encoding: &'static Encoding) -> Result<Box<dyn Read>> {
+    let file = File::open(inpath)?;
+    if encoding == UTF_8 {
+        return Ok(Box::new(file));
+    }
+    Ok(Box::new(DecodingReader::new(
+        std::io::BufReader::new(file),
+        encoding,
+    )))
+}
+
 /// Get user input live, given a prompt, like the Python function of the same name.
 ///  
 /// Credit to /u/Ophekkis
@@ -671,13 +1063,183 @@ pub fn input(prompt: &str) -> std::io::Result<String> {
     Ok(response.trim().to_string())
 }
 
-/// Fetch a URL in a blocking fashion despite async interface of `ehttp`.
-/// Uses a `sync::mpsc::channel` under the hood.
-pub fn fetch_blocking(url: impl ToString) -> Result<ehttp::Response, String> {
-    let (sender, receiver) = std::sync::mpsc::channel();
-    let req = ehttp::Request::get(url);
-    ehttp::fetch(req, move |r| sender.send(r).unwrap());
-    receiver.recv().unwrap()
+/// Format a time as an ISO 8601 UTC timestamp, like `2022-05-21T08:00:00Z`.
+pub fn utc_timestamp(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Gregorian calendar from a day count, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// How long [`fetch_to_file`] waits without receiving anything before giving up.
+///
+/// This isn't a limit on the download as a whole: a large file on a slow link can take as long as it
+/// needs, so long as it keeps arriving.
+pub const FETCH_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How often [`fetch_to_file`] checks whether it's been cancelled.
+const FETCH_POLL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Download `url` to `dest`, a chunk at a time, rather than holding the whole thing in memory.
+///
+/// It's written via a temporary name, so an interrupted download isn't mistaken for a complete one.
+/// `progress` is told the number of bytes received so far, and the total if the server said.
+/// Gives up if nothing arrives for [`FETCH_IDLE_TIMEOUT`], or as soon as `cancel` is set
+/// (e.g. by a "stop" button or a Ctrl-C handler), which also stops the transfer itself.
+pub fn fetch_to_file(
+    url: &str,
+    dest: &path::Path,
+    cancel: &Arc<AtomicBool>,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<()> {
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = path::PathBuf::from(partial);
+    let cancelled = || cancel.load(Ordering::SeqCst);
+
+    let mut receive = || -> Result<()> {
+        if cancelled() {
+            bail!("The download was cancelled");
+        }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let token = Arc::clone(cancel);
+        ehttp::streaming::fetch(ehttp::Request::get(url), move |part| {
+            // if we've given up then nobody's listening any more, so stop downloading
+            if token.load(Ordering::SeqCst) || sender.send(part).is_err() {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+
+        let mut file = None;
+        let mut bytes = 0;
+        let mut total = None;
+        let mut last_heard = std::time::Instant::now();
+        loop {
+            if cancelled() {
+                bail!("The download was cancelled");
+            }
+            let part = match receiver.recv_timeout(FETCH_POLL) {
+                Ok(part) => part.map_err(|e| eyre!(e))?,
+                Err(RecvTimeoutError::Timeout) if last_heard.elapsed() >= FETCH_IDLE_TIMEOUT => {
+                    bail!(
+                        "Nothing was received for {} seconds",
+                        FETCH_IDLE_TIMEOUT.as_secs()
+                    )
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => bail!("The download stopped unfinished"),
+            };
+            last_heard = std::time::Instant::now();
+            match part {
+                Part::Response(response) => {
+                    if !response.ok {
+                        bail!("{} {}", response.status, response.status_text);
+                    }
+                    total = response
+                        .headers
+                        .get("content-length")
+                        .and_then(|x| x.parse().ok());
+                    let created = File::create(&partial)
+                        .with_context(|| format!("Could not create {}", partial.display()))?;
+                    file = Some(std::io::BufWriter::new(created));
+                    progress(bytes, total);
+                }
+                // an empty chunk marks the end
+                Part::Chunk(chunk) if chunk.is_empty() => break,
+                Part::Chunk(chunk) => {
+                    file.as_mut()
+                        .context("Data arrived before the response")?
+                        .write_all(&chunk)
+                        .with_context(|| format!("Could not write {}", partial.display()))?;
+                    bytes += chunk.len() as u64;
+                    progress(bytes, total);
+                }
+            }
+        }
+        if let Some(mut written) = file {
+            written
+                .flush()
+                .with_context(|| format!("Could not write {}", partial.display()))?;
+        }
+        std::fs::rename(&partial, dest)
+            .with_context(|| format!("Could not write {}", dest.display()))
+    };
+    let rez = receive();
+    if rez.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    rez.with_context(|| format!("Could not download {url}"))
+}
+
+/// Is this (configured) path actually an `http://` or `https://` URL?
+pub fn is_url(path: &path::Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// Where [`fetch_cached`] keeps its download of `url`: in the system's temporary directory,
+/// named for a hash of the URL (so it's unique) and the URL's file name (so it's recognisable).
+pub fn download_cache_path(url: &str) -> path::PathBuf {
+    use sha2::{Digest, Sha256};
+    let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    let name = url
+        .rsplit('/')
+        .next()
+        .filter(|x| !x.is_empty())
+        .unwrap_or("download");
+    std::env::temp_dir()
+        .join("nparty-downloads")
+        .join(format!("{}-{name}", &hash[..16]))
+}
+
+/// Download `url` to its [`download_cache_path`], unless it's already there from an earlier run.
+pub fn fetch_cached(url: &str) -> Result<path::PathBuf> {
+    let cached = download_cache_path(url);
+    if cached.is_file() {
+        debug!(
+            "Using the earlier download of {url} at {}",
+            cached.display()
+        );
+        return Ok(cached);
+    }
+    info!("Downloading {url}");
+    let dir = cached.parent().unwrap();
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create the download cache {}", dir.display()))?;
+    // a Ctrl-C here quits (see `booths::install_interrupt_handler`), so there's nothing to cancel
+    fetch_to_file(url, &cached, &Arc::default(), |_, _| {})?;
+    Ok(cached)
+}
+
+/// `path` itself if it's a local file; or if it's a URL, the file it's been downloaded to
+/// (see [`fetch_cached`]).
+pub fn local_input(path: &path::Path) -> Result<path::PathBuf> {
+    if is_url(path) {
+        fetch_cached(&path.to_string_lossy())
+    } else {
+        Ok(path.to_owned())
+    }
 }
 
 #[cfg(test)]
@@ -689,10 +1251,153 @@ mod tests {
         assert_eq!(31, "AE".to_number());
         assert_eq!("123 thousand", 123_000.pretty_number());
     }
+    #[test]
+    fn failed_fetch() {
+        let dest = std::env::temp_dir().join(format!("nparty-fetch-{}.txt", std::process::id()));
+        // nothing listens on port 1, so this fails straight away
+        let rez = fetch_to_file("http://127.0.0.1:1/", &dest, &Arc::default(), |_, _| {});
+        assert!(format!("{:#}", rez.unwrap_err()).contains("Could not download"));
+        assert!(!dest.exists());
+        assert!(!dest.with_extension("txt.part").exists());
+    }
+
+    #[test]
+    fn cancelled_fetch() {
+        use std::io::Write as _;
+        // a server that sends a little of what it promises, then nothing more
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/slow.zip", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\nabc");
+            std::thread::sleep(std::time::Duration::from_secs(30));
+        });
+        let dest = std::env::temp_dir().join(format!("nparty-cancel-{}.zip", std::process::id()));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let start = std::time::Instant::now();
+        // cancel as soon as anything arrives
+        let rez = fetch_to_file(&url, &dest, &cancel, |bytes, _| {
+            if bytes > 0 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        });
+        assert!(format!("{:#}", rez.unwrap_err()).contains("cancelled"));
+        assert!(start.elapsed() < FETCH_IDLE_TIMEOUT);
+        assert!(!dest.exists());
+        assert!(!dest.with_extension("zip.part").exists());
+
+        // and a cancelled token doesn't start at all
+        let rez = fetch_to_file(&url, &dest, &cancel, |_, _| {});
+        assert!(format!("{:#}", rez.unwrap_err()).contains("cancelled"));
+    }
+
+    #[test]
+    fn download_cache() {
+        assert!(is_url(path::Path::new("https://example.com/prefs.zip")));
+        assert!(!is_url(path::Path::new("prefs.zip")));
+        let cached = download_cache_path("https://example.com/a/prefs.zip");
+        assert!(cached.to_string_lossy().ends_with("-prefs.zip"));
+        assert_ne!(
+            cached,
+            download_cache_path("https://example.com/b/prefs.zip")
+        );
+    }
+
+    #[test]
+    fn encoding_writer() {
+        let windows_1252 = output_encoding("windows-1252").unwrap();
+        let mut wtr = EncodingWriter::new(Vec::new(), windows_1252);
+        // "é" is two bytes in UTF-8: split it across writes
+        let text = "Café, Ŵ".as_bytes();
+        wtr.write_all(&text[..4]).unwrap();
+        wtr.write_all(&text[4..7]).unwrap();
+        assert_eq!(wtr.inner, b"Caf\xe9, ");
+        // windows-1252 has no "Ŵ"
+        assert!(wtr.write_all(&text[7..]).is_err());
+
+        // and back again, a byte at a time
+        let mut rdr = DecodingReader::new(&b"Caf\xe9, \x80"[..], windows_1252);
+        let mut decoded = Vec::new();
+        let mut byte = [0];
+        while rdr.read(&mut byte).unwrap() > 0 {
+            decoded.push(byte[0]);
+        }
+        assert_eq!(String::from_utf8(decoded).unwrap(), "Café, €");
+
+        assert!(output_encoding("utf-16le").is_err());
+        assert!(output_encoding("klingon").is_err());
+    }
+
+    #[test]
+    fn test_prefs_rows() {
+        let csv = "SA1_id,Alp,Lnp,Total\n3000001,1,3,4\n3000002,,2,2\n";
+        let (sa1s, header) =
+            PrefsMap::from_csv_reader(csv::Reader::from_reader(csv.as_bytes())).unwrap();
+        assert_eq!(header.len(), 4);
+        assert_eq!(sa1s["3000002"], [0.0, 2.0, 2.0]);
+        assert_eq!(sa1s.row_total("3000001"), Some(4.0));
+        assert_eq!(sa1s.row_total("3000003"), None);
+
+        let mut districts = PrefsMap::new();
+        let added = vec![
+            districts.add_scaled("North", &sa1s["3000001"], 0.5),
+            districts.add_scaled("North", &sa1s["3000002"], 1.0),
+        ];
+        assert_eq!(added, [4.0, 4.0]);
+        assert_eq!(districts["North"], [0.5, 3.5, 4.0]);
+    }
+    #[test]
+    fn test_utc_timestamp() {
+        use std::time::{Duration, UNIX_EPOCH};
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let election = UNIX_EPOCH + Duration::from_secs(1_653_120_000);
+        assert_eq!(utc_timestamp(election), "2022-05-21T08:00:00Z");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_399);
+        assert_eq!(utc_timestamp(leap), "2000-02-28T23:59:59Z");
+    }
+    #[test]
+    fn test_read_party_abbrvs_checked() {
+        let good = "2019 Federal Election\n\
+            StateAb,PartyAb,RegisteredPartyAb,PartyNm\n\
+            QLD,GRN,The Greens,Queensland Greens\n";
+        let parties = read_party_abbrvs_checked(good.as_bytes()).unwrap();
+        assert_eq!(parties.get("Queensland Greens").unwrap(), "Grn");
+        assert_eq!(parties.get("The Greens").unwrap(), "Grn");
+
+        let shifted = "Queensland Greens,QLD,GRN\n";
+        assert!(read_party_abbrvs_checked(shifted.as_bytes()).is_err());
+        // the lenient version just skips what it can't read
+        assert_eq!(read_party_abbrvs(good.as_bytes()), parties);
+        assert!(read_party_abbrvs(shifted.as_bytes()).is_empty());
+    }
+    #[test]
+    fn test_read_candidates_eras() {
+        let modern = "txn_nm,nom_ty,state_ab,div_nm,ticket,ballot_position,surname,ballot_given_nm,party_ballot_nm
+2016 Federal Election,S,TAS,,A,1,SMITH,John,Party One
+2016 Federal Election,S,TAS,,A,2,JONES,Amy,Party One
+";
+        let old = "StateAb,Ticket,CandidateID,BallotPosition,Surname,GivenNm,PartyAb,PartyNm
+TAS,A,1,1,SMITH,John,P1,Party One
+TAS,A,2,2,JONES,Amy,P1,Party One
+";
+        assert_eq!(
+            read_candidates(modern.as_bytes()).unwrap(),
+            read_candidates(old.as_bytes()).unwrap()
+        );
+        let err = read_candidates(&b"name,state\nSMITH,TAS\n"[..]).unwrap_err();
+        assert!(err.to_string().contains("ballot_given_nm"));
+    }
+
     #[test]
     fn test_state_ab_conversions() {
         assert_eq!("ACT", StateAb::ACT.to_string());
-        assert_eq!(StateAb::NSW, StateAb::from("nsw"));
+        assert_eq!(StateAb::NSW, "nsw".to_state_ab().unwrap());
         assert!(StateAb::from_str("this is not a state").is_err());
+        assert_eq!(StateAb::ACT, StateAb::from_str("A.C.T.").unwrap());
+        assert_eq!(StateAb::TAS, StateAb::from_str(" Tas. ").unwrap());
+        assert_eq!(StateAb::WA, StateAb::from_str("Western Australia").unwrap());
+        assert!("Queensland Rail".to_state_ab().is_err());
     }
 }